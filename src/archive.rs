@@ -0,0 +1,344 @@
+//! Standalone archive files holding the closure of one or more packages.
+//!
+//! Archives allow closures to be moved between stores without a network connection between them,
+//! e.g. to copy packages onto an air-gapped machine.
+//!
+//! # Specification
+//!
+//! ```text
+//!  Magic value     Version   Manifest length     Manifest          Body
+//! +---------------+---------+-------------------+-----------------+  +------------+
+//! | store-archive | 1       | (u64 BE bytes)    | <JSON>          |  | store-pack |
+//! +---------------+---------+-------------------+-----------------+  +------------+
+//! ```
+//!
+//! The manifest lists the root packages of the archive along with their names, the whole closure
+//! contained in the archive, and the signatures of every package in it. It is followed by a
+//! regular, non-thin [`pack`](crate::pack) file holding every object of the closure in
+//! topological order.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::SeekFrom;
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use futures::{pin_mut, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::closure::WireClosure;
+use crate::copy::{Delta, Destination, Source};
+use crate::pack;
+use crate::{
+    Closure, ContentAddressable, Object, ObjectId, ObjectKind, Objects, PackageName, Signature,
+};
+
+/// The newest version of the archive format, which is written by [`export_closure()`].
+pub const FORMAT_VERSION: u8 = 1;
+
+const MAGIC_VALUE: &[u8] = b"store-archive";
+const MAX_MANIFEST_LEN: u64 = 256 * 1024 * 1024;
+
+/// Writes `roots` and all their dependencies in `store` to `writer` as an archive.
+///
+/// Returns `Err` if any of the given object IDs do not exist in the store, any of the object IDs
+/// do not refer to a `Package` object, or an I/O error occurred.
+pub async fn export_closure<S, W>(
+    store: &S,
+    roots: BTreeSet<ObjectId>,
+    writer: &mut W,
+) -> anyhow::Result<()>
+where
+    S: Source + Objects + ?Sized,
+    W: AsyncWrite + Unpin,
+{
+    // Resolving the delta against a destination which holds nothing yields the whole closure, and
+    // never picks any delta bases, so the pack can be read back without access to another store.
+    let delta = store.find_missing(&Nothing, roots.clone()).await?;
+    write_archive(store, roots, &delta, writer).await
+}
+
+/// Writes an archive holding the objects in `delta.missing` to `writer`.
+async fn write_archive<S, W>(
+    store: &S,
+    roots: BTreeSet<ObjectId>,
+    delta: &Delta,
+    writer: &mut W,
+) -> anyhow::Result<()>
+where
+    S: Source + Objects + ?Sized,
+    W: AsyncWrite + Unpin,
+{
+    let mut names = BTreeMap::new();
+    for &(id, kind, _) in delta.missing.iter() {
+        if kind == ObjectKind::Package {
+            names.insert(id, store.get_package(id)?.name);
+        }
+    }
+
+    let manifest = WireManifest {
+        roots,
+        names,
+        closure: WireClosure::from(&delta.missing),
+        signatures: delta.signatures.clone(),
+    };
+
+    let body = serde_json::to_vec(&manifest)?;
+    writer.write_all(MAGIC_VALUE).await?;
+    writer.write_u8(FORMAT_VERSION).await?;
+    writer.write_u64(body.len() as u64).await?;
+    writer.write_all(&body).await?;
+
+    store.send_pack(delta, writer).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Reads the manifest at the start of an archive from `reader`, without reading the objects.
+///
+/// This is useful for listing the contents of an archive without importing it. Afterwards,
+/// `reader` is left at the start of the pack body.
+///
+/// Returns `Err` if `reader` does not hold an archive, the manifest is malformed, or an I/O error
+/// occurred.
+pub async fn read_manifest<R>(reader: &mut R) -> anyhow::Result<Manifest>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut magic = [0u8; MAGIC_VALUE.len()];
+    reader.read_exact(&mut magic).await?;
+    if magic != MAGIC_VALUE {
+        return Err(anyhow!("file is not a closure archive"));
+    }
+
+    let version = reader.read_u8().await?;
+    if version != FORMAT_VERSION {
+        return Err(anyhow!("unsupported archive version: {}", version));
+    }
+
+    let len = reader.read_u64().await?;
+    if len > MAX_MANIFEST_LEN {
+        return Err(anyhow!("archive manifest of {} bytes is too large", len));
+    }
+
+    let mut body = vec![0u8; len as usize];
+    reader.read_exact(&mut body).await?;
+    let manifest: WireManifest =
+        serde_json::from_slice(&body).context("malformed archive manifest")?;
+
+    let roots = manifest
+        .roots
+        .iter()
+        .map(|id| match manifest.names.get(id) {
+            Some(name) => Ok((*id, name.clone())),
+            None => Err(anyhow!("root package {} is missing from the archive", id)),
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok(Manifest {
+        roots,
+        closure: Closure::from(manifest.closure).with_names(manifest.names),
+        signatures: manifest.signatures,
+    })
+}
+
+/// Reads an archive from `reader` and inserts its objects into `dst`.
+///
+/// The signatures in the archive are passed along to `dst`, which decides whether to trust them.
+///
+/// The pack body is spooled to a temporary file and checked against the manifest in full before
+/// any of it is sent to `dst`, so that a malformed archive never leaves objects behind in `dst`.
+///
+/// Returns the root packages of the archive, or `Err` if the archive is malformed, a package in
+/// the archive references a package which is not included, `dst` refused the pack, or an I/O
+/// error occurred. In that case, none of the packages in the archive are installed.
+pub async fn import_closure<D, R>(dst: &mut D, reader: &mut R) -> anyhow::Result<BTreeSet<ObjectId>>
+where
+    D: Destination + ?Sized,
+    R: AsyncRead + Unpin,
+{
+    let manifest = read_manifest(reader).await?;
+    let listed: HashSet<_> = manifest.closure.iter().map(|&(id, _, _)| id).collect();
+
+    let mut spool = tokio::fs::File::from_std(tempfile::tempfile_in("/var/tmp")?);
+    pack::copy_pack(reader, &mut spool).await?;
+    spool.flush().await?;
+
+    spool.seek(SeekFrom::Start(0)).await?;
+    check_body(&mut spool, &listed).await?;
+
+    // The verified pack is passed on as it is, without decoding it again.
+    spool.seek(SeekFrom::Start(0)).await?;
+    dst.recv_pack(&mut spool, &manifest.signatures).await?;
+
+    Ok(manifest.roots.keys().copied().collect())
+}
+
+/// Checks that the pack read from `reader` holds exactly the objects in `listed`, and that no
+/// package in it references a package outside of `listed`.
+///
+/// Returns `Err` if the pack is malformed, does not match `listed`, or an I/O error occurred.
+async fn check_body<R>(reader: R, listed: &HashSet<ObjectId>) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let objects = pack::pack_reader(reader);
+    pin_mut!(objects);
+    let mut received = HashSet::new();
+
+    while let Some(obj) = objects.next().await {
+        let obj = obj?;
+        let id = obj.object_id();
+        if !listed.contains(&id) {
+            return Err(anyhow!(
+                "archive holds object {} missing from its manifest",
+                id
+            ));
+        }
+
+        if let Object::Package(ref pkg) = obj {
+            if let Some(dep) = pkg.references.iter().find(|dep| !listed.contains(dep)) {
+                return Err(anyhow!(
+                    "package {} references {}, which is missing from the archive",
+                    id,
+                    dep
+                ));
+            }
+        }
+
+        received.insert(id);
+    }
+
+    if received.len() != listed.len() {
+        let missing = listed.len() - received.len();
+        return Err(anyhow!(
+            "archive is missing {} objects from its manifest",
+            missing
+        ));
+    }
+
+    Ok(())
+}
+
+/// Table of contents at the start of an archive, returned by [`read_manifest()`].
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    /// Packages the archive was exported for, along with their names.
+    pub roots: BTreeMap<ObjectId, PackageName>,
+    /// Closure of every object in the archive, including the names of its packages.
+    pub closure: Closure,
+    /// Signatures of the packages in the archive.
+    pub signatures: BTreeMap<ObjectId, BTreeSet<Signature>>,
+}
+
+/// Serialized form of a [`Manifest`].
+#[derive(Debug, Deserialize, Serialize)]
+struct WireManifest {
+    roots: BTreeSet<ObjectId>,
+    names: BTreeMap<ObjectId, PackageName>,
+    closure: WireClosure,
+    signatures: BTreeMap<ObjectId, BTreeSet<Signature>>,
+}
+
+/// A destination which holds no objects at all.
+struct Nothing;
+
+#[async_trait(?Send)]
+impl Destination for Nothing {
+    async fn contains(&self, _: &ObjectId, _: Option<ObjectKind>) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    async fn recv_pack<R>(
+        &mut self,
+        _: &mut R,
+        _: &BTreeMap<ObjectId, BTreeSet<Signature>>,
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        Err(anyhow!("cannot receive packs"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::insert_package;
+    use crate::{LocalStore, SecretKey, TrustPolicy};
+
+    #[tokio::test]
+    async fn exports_lists_and_imports_closure() {
+        let mut src = LocalStore::in_memory();
+        let foo = insert_package(&mut src, "foo", &[]);
+        let bar = insert_package(&mut src, "bar", &[foo]);
+
+        let key = SecretKey::generate("src-1").unwrap();
+        src.sign_package(foo, &key).unwrap();
+        src.sign_package(bar, &key).unwrap();
+
+        let mut archive = Vec::new();
+        let roots: BTreeSet<_> = std::iter::once(bar).collect();
+        export_closure(&src, roots.clone(), &mut archive)
+            .await
+            .unwrap();
+
+        let manifest = read_manifest(&mut &archive[..]).await.unwrap();
+        let names: Vec<_> = manifest.roots.values().map(|n| n.to_string()).collect();
+        assert_eq!(names, vec!["bar"]);
+        assert_eq!(manifest.closure.num_objects(), 6);
+        assert_eq!(manifest.closure.package_name(foo).unwrap().as_ref(), "foo");
+        assert_eq!(manifest.signatures.len(), 2);
+
+        let mut dst = LocalStore::in_memory();
+        let mut policy = TrustPolicy::default();
+        policy.trusted_keys.insert(key.public_key());
+        dst.set_trust_policy(policy).unwrap();
+
+        let imported = import_closure(&mut dst, &mut &archive[..]).await.unwrap();
+        assert_eq!(imported, roots);
+        for &(id, kind, _) in manifest.closure.iter() {
+            assert!(dst.contains_object(&id, Some(kind)).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_archive_missing_references() {
+        let mut src = LocalStore::in_memory();
+        let foo = insert_package(&mut src, "foo", &[]);
+        let bar = insert_package(&mut src, "bar", &[foo]);
+
+        // Leave `foo` and its contents out of the archive.
+        let roots: BTreeSet<_> = std::iter::once(bar).collect();
+        let full = src.compute_closure(roots.clone()).unwrap();
+        let foo_objects = src.compute_closure(std::iter::once(foo).collect()).unwrap();
+        let ids = full
+            .iter()
+            .map(|&(id, _, _)| id)
+            .filter(|id| foo_objects.iter().all(|node| node.0 != *id))
+            .collect();
+        let delta = Delta::new(0, full.subgraph(&ids));
+
+        let mut archive = Vec::new();
+        write_archive(&src, roots, &delta, &mut archive)
+            .await
+            .unwrap();
+
+        let mut dst = LocalStore::in_memory();
+        let policy = TrustPolicy {
+            accept_unsigned: true,
+            ..Default::default()
+        };
+        dst.set_trust_policy(policy).unwrap();
+
+        let err = import_closure(&mut dst, &mut &archive[..])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("missing from the archive"));
+        assert!(!dst.contains_object(&bar, None).unwrap());
+
+        // Nothing was held back for a later transfer to publish either.
+        assert!(dst.checkpoint().await.unwrap().is_empty());
+    }
+}
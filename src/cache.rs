@@ -0,0 +1,294 @@
+//! Static binary caches which can be published by any plain HTTP file server.
+//!
+//! A binary cache is a directory with the following layout:
+//!
+//! ```text
+//! <cache>
+//! ├── info
+//! │   └── <package id>.json
+//! └── objects
+//!     └── ab
+//!         └── cdef01234567890.<kind>
+//! ```
+//!
+//! The `objects` directory uses the same loose object layout as a filesystem store, with each
+//! object file sharded by [`ObjectId::to_path_buf()`]. Every package additionally gets an `info`
+//! file recording its whole closure and the signatures of every package in it, so a client can
+//! resolve the closure of a package with a single request and then fetch only the objects it is
+//! missing, one file at a time.
+//!
+//! A [`BinaryCache`] writes this layout to a local directory as a copy [`Destination`], and an
+//! [`HttpSource`] reads it back over HTTP as a copy [`Source`](crate::copy::Source).
+
+pub use self::http::HttpSource;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncRead;
+
+use crate::closure::{self, WireClosure};
+use crate::copy::Destination;
+use crate::local::FsObjects;
+use crate::pack::ThinPackReader;
+use crate::{util, ObjectId, ObjectKind, Objects, Signature};
+
+mod http;
+
+const OBJECTS_SUBDIR: &str = "objects";
+const INFO_SUBDIR: &str = "info";
+
+/// A binary cache directory being written to.
+///
+/// Objects are received just like in a filesystem store, except that packages are never
+/// instantiated. Instead, the closure of each package is written to its `info` file.
+#[derive(Clone, Debug)]
+pub struct BinaryCache {
+    dir: PathBuf,
+    objects: FsObjects,
+}
+
+impl BinaryCache {
+    /// Opens the binary cache in the directory `path`, creating any missing directories first.
+    ///
+    /// Returns `Err` if the directories could not be created or an I/O error occurred.
+    pub fn init(path: PathBuf) -> anyhow::Result<Self> {
+        let objects_dir = path.join(OBJECTS_SUBDIR);
+        std::fs::create_dir_all(&objects_dir).context("could not create `objects` dir")?;
+        std::fs::create_dir_all(path.join(INFO_SUBDIR)).context("could not create `info` dir")?;
+
+        Ok(BinaryCache {
+            objects: FsObjects::open(objects_dir)?,
+            dir: path,
+        })
+    }
+
+    /// Returns the path to the binary cache directory.
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes the `info` file of `pkg`, listing the signatures of every package in its closure.
+    ///
+    /// Signatures missing from `signatures` are looked up in the `info` files already written for
+    /// the packages `pkg` depends on.
+    fn write_info(
+        &self,
+        pkg: ObjectId,
+        signatures: &BTreeMap<ObjectId, BTreeSet<Signature>>,
+    ) -> anyhow::Result<()> {
+        let path = self.dir.join(info_path(&pkg));
+        if path.exists() {
+            return Ok(());
+        }
+
+        let roots = std::iter::once(pkg).collect();
+        let closure = closure::compute(&self.objects, roots, |_, _| Ok(true))?;
+
+        let mut closure_sigs = BTreeMap::new();
+        for &(id, kind, _) in closure.iter() {
+            if kind != ObjectKind::Package {
+                continue;
+            }
+
+            let sigs = match signatures.get(&id) {
+                Some(sigs) => sigs.clone(),
+                None if id != pkg => self
+                    .read_info(&id)?
+                    .signatures
+                    .remove(&id)
+                    .unwrap_or_default(),
+                None => BTreeSet::new(),
+            };
+
+            if !sigs.is_empty() {
+                closure_sigs.insert(id, sigs);
+            }
+        }
+
+        let info = PackageInfo {
+            closure: WireClosure::from(&closure),
+            signatures: closure_sigs,
+        };
+
+        util::persist_json(&path, &info, 0o444)
+    }
+
+    fn read_info(&self, pkg: &ObjectId) -> anyhow::Result<PackageInfo> {
+        let path = self.dir.join(info_path(pkg));
+        let file =
+            File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+        let info = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Ok(info)
+    }
+}
+
+#[async_trait(?Send)]
+impl Destination for BinaryCache {
+    async fn contains(&self, id: &ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<bool> {
+        // Packages are only published once their `info` file is written, which happens last.
+        if kind == Some(ObjectKind::Package) && !self.dir.join(info_path(id)).exists() {
+            return Ok(false);
+        }
+
+        self.objects.contains_object(id, kind)
+    }
+
+    /// Signatures are published as they are, without being verified. It is up to whoever fetches
+    /// from the cache to decide which signatures to trust.
+    async fn recv_pack<R>(
+        &mut self,
+        reader: &mut R,
+        signatures: &BTreeMap<ObjectId, BTreeSet<Signature>>,
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut reader = ThinPackReader::new(reader);
+
+        while let Some(obj) = reader.next_object(&self.objects).await? {
+            let kind = obj.kind();
+            let id = self.objects.insert_object(obj)?;
+            if kind == ObjectKind::Package {
+                // Computing the closure reads objects on several threads at once, which must not
+                // hold up the async executor.
+                let cache = self.clone();
+                let signatures = signatures.clone();
+                tokio::task::spawn_blocking(move || cache.write_info(id, &signatures)).await??;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Contents of the `info` file of a package.
+#[derive(Debug, Deserialize, Serialize)]
+struct PackageInfo {
+    closure: WireClosure,
+    #[serde(default)]
+    signatures: BTreeMap<ObjectId, BTreeSet<Signature>>,
+}
+
+/// Returns the path of the object file `id` of type `kind`, relative to the cache root.
+fn object_path(id: &ObjectId, kind: ObjectKind) -> PathBuf {
+    let mut path = Path::new(OBJECTS_SUBDIR).join(id.to_path_buf());
+    path.set_extension(kind.as_str());
+    path
+}
+
+/// Returns the path of the `info` file for the package `id`, relative to the cache root.
+fn info_path(id: &ObjectId) -> PathBuf {
+    Path::new(INFO_SUBDIR).join(format!("{}.json", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+
+    use super::*;
+    use crate::test_support::insert_package;
+    use crate::{copy::Source, copy_closure, HttpSource, LocalStore, SecretKey, TrustPolicy};
+
+    /// Serves the files in `root` on a loopback port, like a static HTTP file server.
+    fn serve_dir(root: PathBuf) -> SocketAddr {
+        let make_svc = make_service_fn(move |_| {
+            let root = root.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let path = root.join(req.uri().path().trim_start_matches('/'));
+                    async move {
+                        let response = match std::fs::read(path) {
+                            Ok(body) => Response::new(Body::from(body)),
+                            Err(_) => Response::builder()
+                                .status(StatusCode::NOT_FOUND)
+                                .body(Body::empty())
+                                .unwrap(),
+                        };
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn publishes_and_fetches_closure_over_http() {
+        let mut src = LocalStore::in_memory();
+        let foo = insert_package(&mut src, "foo", &[]);
+        let bar = insert_package(&mut src, "bar", &[foo]);
+        let pkgs: BTreeSet<_> = std::iter::once(bar).collect();
+
+        let key = SecretKey::generate("src-1").unwrap();
+        let foo_sig = src.sign_package(foo, &key).unwrap();
+        let bar_sig = src.sign_package(bar, &key).unwrap();
+
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut cache = BinaryCache::init(dir.path().join("cache")).unwrap();
+        let published = copy_closure(&src, &mut cache, pkgs.clone(), |_| {})
+            .await
+            .unwrap();
+        assert_eq!(published.num_present, 0);
+        assert_eq!(published.missing.num_objects(), 6);
+        assert!(cache.path().join(info_path(&foo)).exists());
+        assert!(cache.path().join(info_path(&bar)).exists());
+
+        let addr = serve_dir(cache.path().to_owned());
+        let http = HttpSource::new(&format!("http://{}/", addr)).unwrap();
+
+        let mut dst = LocalStore::in_memory();
+        insert_package(&mut dst, "foo", &[]);
+        let mut policy = TrustPolicy::default();
+        policy.trusted_keys.insert(key.public_key());
+        dst.set_trust_policy(policy).unwrap();
+        let fetched = copy_closure(&http, &mut dst, pkgs.clone(), |_| {})
+            .await
+            .unwrap();
+        assert_eq!(fetched.num_present, 1);
+        assert_eq!(fetched.missing.num_objects(), 3);
+
+        let pkg = dst.get_package(bar).unwrap();
+        assert!(dst.installed_package(&pkg.install_name()).is_some());
+        assert_eq!(dst.compute_closure(pkgs).unwrap().num_objects(), 6);
+        assert_eq!(
+            dst.signatures(&bar).unwrap(),
+            std::iter::once(bar_sig).collect()
+        );
+
+        // The closure of `bar` lists the signatures of `foo` as well.
+        let info = cache.read_info(&bar).unwrap();
+        assert_eq!(info.signatures[&foo], std::iter::once(foo_sig).collect());
+    }
+
+    #[tokio::test]
+    async fn missing_package_is_an_error() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let cache = BinaryCache::init(dir.path().to_owned()).unwrap();
+        let addr = serve_dir(cache.path().to_owned());
+        let http = HttpSource::new(&format!("http://{}", addr)).unwrap();
+
+        let mut src = LocalStore::in_memory();
+        let foo = insert_package(&mut src, "foo", &[]);
+        let dst = LocalStore::in_memory();
+
+        let pkgs = std::iter::once(foo).collect();
+        let error = http.find_missing(&dst, pkgs).await.unwrap_err();
+        assert!(error.to_string().contains("not found"), "{}", error);
+    }
+}
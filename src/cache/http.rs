@@ -0,0 +1,154 @@
+//! Reading a binary cache over HTTP.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use bytes::Bytes;
+use hyper::client::HttpConnector;
+use hyper::{Client, StatusCode, Uri};
+use tokio::io::AsyncWrite;
+
+use super::{info_path, object_path, PackageInfo};
+use crate::copy::{self, Delta, Destination, Source};
+use crate::pack::PackWriter;
+use crate::{Blob, Closure, ContentAddressable, Hasher, Object, ObjectId, ObjectKind};
+
+/// A binary cache served over plain HTTP, fetched lazily one file at a time.
+///
+/// Finding the missing objects for a set of packages only fetches their `info` files. Objects
+/// themselves are fetched while the pack is being sent, and only if the destination is missing
+/// them. Every object is verified against its ID before it is sent.
+#[derive(Debug)]
+pub struct HttpSource {
+    client: Client<HttpConnector>,
+    base_url: String,
+}
+
+impl HttpSource {
+    /// Creates a new `HttpSource` for the binary cache located at `url`.
+    ///
+    /// Returns `Err` if `url` is not a valid `http://` URL.
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let uri: Uri = url.parse().context("invalid binary cache URL")?;
+        if uri.scheme_str() != Some("http") {
+            return Err(anyhow!("binary cache URL `{}` must use `http`", url));
+        }
+
+        Ok(HttpSource {
+            client: Client::new(),
+            base_url: url.trim_end_matches('/').to_owned(),
+        })
+    }
+
+    /// Fetches the file located at `path`, relative to the root of the binary cache.
+    ///
+    /// Returns `Err` if the server did not respond with `200 OK`, or an I/O error occurred.
+    async fn fetch(&self, path: &Path) -> anyhow::Result<Bytes> {
+        let path = path.to_str().expect("cache paths are always valid UTF-8");
+        let uri: Uri = format!("{}/{}", self.base_url, path).parse()?;
+        let response = self
+            .client
+            .get(uri.clone())
+            .await
+            .with_context(|| format!("failed to fetch {}", uri))?;
+
+        match response.status() {
+            StatusCode::OK => Ok(hyper::body::to_bytes(response.into_body()).await?),
+            StatusCode::NOT_FOUND => Err(anyhow!("{} not found in binary cache", uri)),
+            status => Err(anyhow!("failed to fetch {}: {}", uri, status)),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Source for HttpSource {
+    async fn find_missing<D>(&self, dst: &D, pkgs: BTreeSet<ObjectId>) -> anyhow::Result<Delta>
+    where
+        D: Destination + ?Sized,
+    {
+        let mut graph = BTreeMap::new();
+        let mut signatures = BTreeMap::new();
+        let mut roots = Vec::new();
+
+        for pkg in &pkgs {
+            let body = self.fetch(&info_path(pkg)).await?;
+            let info: PackageInfo = serde_json::from_slice(&body)
+                .with_context(|| format!("malformed info file for package {}", pkg))?;
+            let closure = Closure::from(info.closure);
+
+            let root = closure
+                .iter()
+                .find(|&&(id, kind, _)| id == *pkg && kind == ObjectKind::Package)
+                .copied()
+                .ok_or_else(|| anyhow!("info file for {} does not describe a package", pkg))?;
+
+            roots.push(root);
+            graph.extend(closure.graph().clone());
+            signatures.extend(info.signatures);
+        }
+
+        let (num_present, missing_ids) =
+            copy::find_missing_ids(dst, roots, |node| Ok(graph[node].iter().copied().collect()))
+                .await?;
+
+        signatures.retain(|id, _| missing_ids.contains(id));
+
+        Ok(Delta {
+            num_present,
+            missing: Closure::from_graph(graph).subgraph(&missing_ids),
+            bases: Default::default(),
+            signatures,
+        })
+    }
+
+    async fn send_pack<W>(&self, delta: &Delta, writer: &mut W) -> anyhow::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut writer = PackWriter::new(writer).await?;
+
+        for (id, kind, _) in delta.missing.sort_yield() {
+            let body = self.fetch(&object_path(&id, kind)).await?;
+            let obj = decode_object(id, kind, body)
+                .with_context(|| format!("malformed {} object {}", kind.as_str(), id))?;
+            writer.append(obj).await?;
+        }
+
+        writer.finish().await?;
+
+        Ok(())
+    }
+}
+
+/// Decodes a loose object file fetched from the cache, checking that it matches `id`.
+fn decode_object(id: ObjectId, kind: ObjectKind, body: Bytes) -> anyhow::Result<Object> {
+    let obj = match kind {
+        ObjectKind::Blob => Object::Blob(decode_blob(id, body.to_vec())?),
+        ObjectKind::ChunkedBlob => Object::ChunkedBlob(serde_json::from_slice(&body)?),
+        ObjectKind::Tree => Object::Tree(serde_json::from_slice(&body)?),
+        ObjectKind::Package => Object::Package(serde_json::from_slice(&body)?),
+        ObjectKind::Spec => Object::Spec(serde_json::from_slice(&body)?),
+    };
+
+    if obj.object_id() == id {
+        Ok(obj)
+    } else {
+        Err(anyhow!("object does not match its ID"))
+    }
+}
+
+/// Reconstructs a blob from the contents of its loose file.
+///
+/// Loose blob files keep their executable bit in the file mode, which is lost when served over
+/// HTTP. Since the bit is part of the object ID, it is recovered by checking both possibilities.
+fn decode_blob(id: ObjectId, bytes: Vec<u8>) -> anyhow::Result<Blob> {
+    for &is_executable in &[false, true] {
+        if Hasher::new_blob(is_executable).update(&bytes).finish() == id {
+            return Ok(Blob::from_store_bytes(bytes, is_executable, id));
+        }
+    }
+
+    Err(anyhow!("object does not match its ID"))
+}
@@ -1,13 +1,32 @@
 //! Types and helper functions for computing closures.
 
-use std::collections::{BTreeMap, BTreeSet, HashSet};
-use std::fmt::{self, Debug, Display, Formatter};
+use std::collections::{hash_map, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Formatter};
+use std::io::Write;
+use std::path::PathBuf;
 
 use anyhow::anyhow;
+use once_cell::sync::OnceCell;
+use rayon::prelude::*;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::{ObjectId, ObjectKind, Objects};
+use crate::{
+    util, Entry, InstallName, ObjectId, ObjectKind, Objects, Package, PackageName, ReferenceSink,
+    References,
+};
 
-type Node = (ObjectId, ObjectKind, u64);
+pub(crate) use self::cache::{package_fragment, Fragments};
+pub use self::diff::{ClosureDiff, PackageChange, PackageSummary};
+pub use self::render::{DotDiagram, GraphMlDiagram, MermaidDiagram};
+pub use self::size::PackageSize;
+
+mod cache;
+mod diff;
+mod render;
+mod size;
+
+pub(crate) type Node = (ObjectId, ObjectKind, u64);
 
 /// A filesystem closure for one or more packages.
 ///
@@ -17,6 +36,8 @@ type Node = (ObjectId, ObjectKind, u64);
 #[derive(Clone)]
 pub struct Closure {
     nodes: BTreeMap<Node, BTreeSet<Node>>,
+    names: BTreeMap<ObjectId, PackageName>,
+    referrers: OnceCell<BTreeMap<ObjectId, BTreeSet<Node>>>,
     unpacked_size: u64,
 }
 
@@ -27,7 +48,18 @@ impl Closure {
         self.nodes.len()
     }
 
+    /// Returns the number of `Package` objects in the closure.
+    pub fn num_packages(&self) -> usize {
+        self.nodes
+            .keys()
+            .filter(|&&(_, kind, _)| kind == ObjectKind::Package)
+            .count()
+    }
+
     /// Returns the estimated unpacked size of the objects in the closure, in bytes.
+    ///
+    /// Every object is only counted once, no matter how many packages share it. See
+    /// [`Closure::instantiated_size()`] for the size of the closure once installed.
     #[inline]
     pub fn unpacked_size(&self) -> u64 {
         self.unpacked_size
@@ -38,28 +70,304 @@ impl Closure {
         self.nodes.keys()
     }
 
-    /// Returns a list of graph nodes sorted in topological order.
-    pub fn sort_topological(&self) -> Vec<Node> {
-        let mut sorted = Vec::new();
-        let mut visited = BTreeSet::new();
+    /// Creates a closure directly from its reference graph, mapping each node to its children.
+    pub(crate) fn from_graph(nodes: BTreeMap<Node, BTreeSet<Node>>) -> Self {
+        Closure {
+            unpacked_size: nodes.keys().map(|item| item.2).sum(),
+            names: BTreeMap::new(),
+            referrers: OnceCell::new(),
+            nodes,
+        }
+    }
+
+    /// Attaches the human-readable names of the packages in the closure.
+    pub(crate) fn with_names(mut self, names: BTreeMap<ObjectId, PackageName>) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Returns the human-readable name of the package `id`, if it is known.
+    ///
+    /// Names are recorded for every package visited by [`Objects::compute_closure()`]. Closures
+    /// received from another store only describe the shape of the graph and carry no names.
+    pub fn package_name(&self, id: ObjectId) -> Option<&PackageName> {
+        self.names.get(&id)
+    }
+
+    /// Returns the directory name of the package `id` once installed, if its name is known.
+    pub fn install_name(&self, id: ObjectId) -> Option<InstallName> {
+        self.names.get(&id).map(|name| InstallName::new(name, id))
+    }
+
+    /// Returns the reference graph of the closure, mapping each node to its children.
+    pub(crate) fn graph(&self) -> &BTreeMap<Node, BTreeSet<Node>> {
+        &self.nodes
+    }
+
+    /// Returns a copy of this closure which only contains the objects listed in `ids`.
+    ///
+    /// Edges leading to or from objects that are not listed are dropped as well.
+    pub(crate) fn subgraph(&self, ids: &BTreeSet<ObjectId>) -> Self {
+        let nodes = self
+            .nodes
+            .iter()
+            .filter(|(node, _)| ids.contains(&node.0))
+            .map(|(node, children)| {
+                let children = children
+                    .iter()
+                    .filter(|child| ids.contains(&child.0))
+                    .copied()
+                    .collect();
+                (*node, children)
+            })
+            .collect();
+
+        let names = self
+            .names
+            .iter()
+            .filter(|(id, _)| ids.contains(id))
+            .map(|(id, name)| (*id, name.clone()))
+            .collect();
+
+        Closure::from_graph(nodes).with_names(names)
+    }
+
+    /// Iterates over each element in the closure which directly references the object `id`.
+    ///
+    /// The reverse edges are computed once on first use and then reused by later queries.
+    pub fn referrers(&self, id: ObjectId) -> impl Iterator<Item = &Node> {
+        let referrers = self.referrers.get_or_init(|| {
+            let mut referrers: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+            for (node, children) in &self.nodes {
+                for child in children.iter().filter(|child| child.0 != node.0) {
+                    referrers.entry(child.0).or_default().insert(*node);
+                }
+            }
+            referrers
+        });
+
+        referrers.get(&id).into_iter().flatten()
+    }
+
+    /// Returns every shortest chain of packages through which package `from` depends on `to`.
+    ///
+    /// Each chain starts with `from` and ends with `to`, and only passes through `Package`
+    /// objects. The chains are returned in lexicographic order. If `to` is not reachable from
+    /// `from`, or either package is not part of the closure, the returned list is empty.
+    pub fn why_depends(&self, from: ObjectId, to: ObjectId) -> Vec<Vec<Node>> {
+        let package = |id: ObjectId| {
+            self.nodes
+                .keys()
+                .find(|&&(node_id, kind, _)| node_id == id && kind == ObjectKind::Package)
+                .copied()
+        };
 
-        for node in self.nodes.keys() {
-            self.sort_inner_visit(node, &mut sorted, &mut visited);
+        let (start, end) = match (package(from), package(to)) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return Vec::new(),
+        };
+
+        // Breadth-first search from `start`, recording every predecessor which lies on a shortest
+        // path rather than only the first one found.
+        let mut depths = HashMap::new();
+        let mut preds: HashMap<Node, Vec<Node>> = HashMap::new();
+        let mut queue = VecDeque::new();
+        depths.insert(start, 0usize);
+        queue.push_back(start);
+
+        while let Some(node) = queue.pop_front() {
+            let depth = depths[&node];
+            if matches!(depths.get(&end), Some(&d) if depth >= d) {
+                break;
+            }
+
+            let children = self.nodes[&node]
+                .iter()
+                .filter(|child| child.1 == ObjectKind::Package && child.0 != node.0);
+
+            for child in children {
+                match depths.get(child) {
+                    Some(&d) if d == depth + 1 => preds.entry(*child).or_default().push(node),
+                    Some(_) => {}
+                    None => {
+                        depths.insert(*child, depth + 1);
+                        preds.entry(*child).or_default().push(node);
+                        queue.push_back(*child);
+                    }
+                }
+            }
         }
 
-        sorted
+        if !depths.contains_key(&end) {
+            return Vec::new();
+        }
+
+        // Walk the predecessors back from `end` to enumerate each chain.
+        let mut chains = Vec::new();
+        let mut stack = vec![vec![end]];
+        while let Some(chain) = stack.pop() {
+            let head = chain[chain.len() - 1];
+            if head == start {
+                chains.push(chain.into_iter().rev().collect());
+                continue;
+            }
+
+            for pred in &preds[&head] {
+                let mut next = chain.clone();
+                next.push(*pred);
+                stack.push(next);
+            }
+        }
+
+        chains.sort();
+        chains
+    }
+
+    /// Like [`Closure::why_depends()`], but expands each step with the trees and files that hold
+    /// the reference.
+    ///
+    /// For every package-to-package step in a chain, the tree of the referring package is searched
+    /// for regular files and symlinks which mention the referenced package. Each match yields a
+    /// separate chain which passes through the directory trees leading up to that file. Steps
+    /// where no file holds the reference are kept as a direct package-to-package hop.
+    ///
+    /// Returns `Err` if an object in the closure cannot be read from `objects`.
+    pub fn why_depends_via_files<O>(
+        &self,
+        objects: &O,
+        from: ObjectId,
+        to: ObjectId,
+    ) -> anyhow::Result<Vec<Vec<Hop>>>
+    where
+        O: Objects + ?Sized,
+    {
+        let mut scanned = HashMap::new();
+        let mut routes = HashMap::new();
+        let mut chains = Vec::new();
+
+        for packages in self.why_depends(from, to) {
+            let mut partial = vec![vec![Hop::Package(packages[0].0)]];
+
+            for step in packages.windows(2) {
+                let (pkg, dep) = (step[0].0, step[1].0);
+                let found = match routes.entry((pkg, dep)) {
+                    hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                    hash_map::Entry::Vacant(entry) => {
+                        entry.insert(file_routes(objects, pkg, dep, &mut scanned)?)
+                    }
+                };
+                partial = partial
+                    .into_iter()
+                    .flat_map(|chain| {
+                        let extend = |route: &[Hop]| {
+                            let mut chain = chain.clone();
+                            chain.extend_from_slice(route);
+                            chain.push(Hop::Package(dep));
+                            chain
+                        };
+
+                        if found.is_empty() {
+                            vec![extend(&[])]
+                        } else {
+                            found.iter().map(|route| extend(route)).collect()
+                        }
+                    })
+                    .collect();
+            }
+
+            chains.extend(partial);
+        }
+
+        Ok(chains)
+    }
+
+    /// Returns the combined size of the package `pkg` and the trees and blobs it contains.
+    ///
+    /// Objects belonging to other packages referenced by `pkg` are not counted.
+    fn own_size(&self, pkg: &Node) -> u64 {
+        let mut visited = HashSet::new();
+        let mut stack = vec![*pkg];
+        let mut size = 0;
+
+        while let Some(node) = stack.pop() {
+            if !visited.insert(node) {
+                continue;
+            }
+
+            size += node.2;
+            let children = self.nodes.get(&node).into_iter().flatten();
+            stack.extend(children.filter(|child| child.1 != ObjectKind::Package));
+        }
+
+        size
     }
 
-    fn sort_inner_visit(&self, node: &Node, dst: &mut Vec<Node>, visited: &mut BTreeSet<Node>) {
-        if !visited.insert(*node) {
-            return;
+    /// Returns `Err` if the reference graph of the closure contains a cycle.
+    pub(crate) fn check_acyclic(&self) -> anyhow::Result<()> {
+        let mut done = HashSet::new();
+        let mut parents = HashSet::new();
+        let mut stack = Vec::new();
+
+        for root in self.nodes.keys() {
+            if done.contains(root) {
+                continue;
+            }
+
+            parents.insert(*root);
+            stack.push((root, self.nodes[root].iter()));
+            while let Some((node, children)) = stack.last_mut() {
+                match children.next() {
+                    Some(child) if parents.contains(child) => {
+                        return Err(anyhow!(
+                            "detected cycle in closure reference graph: {} -> {}",
+                            node.0,
+                            child.0
+                        ));
+                    }
+                    Some(child) if done.contains(child) => {}
+                    Some(child) => {
+                        parents.insert(*child);
+                        stack.push((child, self.nodes[child].iter()));
+                    }
+                    None => {
+                        let node = **node;
+                        parents.remove(&node);
+                        done.insert(node);
+                        stack.pop();
+                    }
+                }
+            }
         }
 
-        for child in &self.nodes[node] {
-            self.sort_inner_visit(child, dst, visited);
+        Ok(())
+    }
+
+    /// Returns a list of graph nodes sorted in topological order.
+    pub fn sort_topological(&self) -> Vec<Node> {
+        let mut sorted = Vec::with_capacity(self.nodes.len());
+        let mut visited = HashSet::new();
+        let mut stack = Vec::new();
+
+        for root in self.nodes.keys() {
+            if !visited.insert(*root) {
+                continue;
+            }
+
+            // Each node is emitted only once all of its children have been emitted. An explicit
+            // stack is used so that deep graphs cannot overflow the call stack.
+            stack.push((root, self.nodes[root].iter()));
+            while let Some((node, children)) = stack.last_mut() {
+                match children.find(|child| visited.insert(**child)) {
+                    Some(child) => stack.push((child, self.nodes[child].iter())),
+                    None => {
+                        sorted.push(**node);
+                        stack.pop();
+                    }
+                }
+            }
         }
 
-        dst.push(*node);
+        sorted
     }
 
     /// Returns a list of graph nodes sorted in closure yield order.
@@ -97,9 +405,38 @@ impl Closure {
     /// from the diagram for the sake of reducing noise.
     #[inline]
     pub fn render_dot(&self, show_content: bool) -> DotDiagram<'_> {
-        DotDiagram {
-            inner: self,
-            show_content,
+        DotDiagram::new(self, show_content)
+    }
+
+    /// Returns an object that implements [`Display`](std::fmt::Display) which renders the closure
+    /// as a GraphML document.
+    ///
+    /// Each node carries its label, kind and size as GraphML data attributes. Like in
+    /// [`Closure::render_dot()`], edges point from each object to the objects which reference it,
+    /// and content objects are only included if `show_content` is `true`.
+    #[inline]
+    pub fn render_graphml(&self, show_content: bool) -> GraphMlDiagram<'_> {
+        GraphMlDiagram::new(self, show_content)
+    }
+
+    /// Returns an object that implements [`Display`](std::fmt::Display) which renders the closure
+    /// as a Mermaid flowchart.
+    ///
+    /// Like in [`Closure::render_dot()`], edges point from each object to the objects which
+    /// reference it, and content objects are only included if `show_content` is `true`.
+    #[inline]
+    pub fn render_mermaid(&self, show_content: bool) -> MermaidDiagram<'_> {
+        MermaidDiagram::new(self, show_content)
+    }
+
+    /// Returns a human-readable label for the object `id`.
+    ///
+    /// Packages with a known name are labeled with their [`InstallName`], while every other
+    /// object is labeled with its bare ID.
+    fn label(&self, id: ObjectId) -> String {
+        match self.install_name(id) {
+            Some(name) => name.to_string(),
+            None => id.to_string(),
         }
     }
 }
@@ -110,38 +447,232 @@ impl Debug for Closure {
     }
 }
 
-#[derive(Debug)]
-pub struct DotDiagram<'a> {
-    inner: &'a Closure,
-    show_content: bool,
+/// A single step in a dependency chain returned by [`Closure::why_depends_via_files()`].
+///
+/// Paths are relative to the root of the package which contains the tree or file.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Hop {
+    /// A package object.
+    Package(ObjectId),
+    /// A directory tree inside of a package.
+    Tree { id: ObjectId, path: PathBuf },
+    /// A regular file inside of a package whose contents hold the reference.
+    File { id: ObjectId, path: PathBuf },
+    /// A symbolic link inside of a package whose target holds the reference.
+    Symlink { path: PathBuf, target: PathBuf },
 }
 
-impl<'a> Display for DotDiagram<'a> {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        writeln!(f, "digraph G {{")?;
+/// Searches the tree of package `pkg` for files and symlinks which reference the package `dep`.
+///
+/// Returns the hops leading from the root tree of `pkg` to each match. The references of each
+/// scanned file are memoized in `scanned`, since files are frequently shared between packages.
+fn file_routes<O>(
+    objects: &O,
+    pkg: ObjectId,
+    dep: ObjectId,
+    scanned: &mut HashMap<ObjectId, References>,
+) -> anyhow::Result<Vec<Vec<Hop>>>
+where
+    O: Objects + ?Sized,
+{
+    let tree = objects.get_package(pkg)?.tree;
+    let root = Hop::Tree {
+        id: tree,
+        path: PathBuf::new(),
+    };
 
-        let mut done = HashSet::new();
-        for (node, children) in &self.inner.nodes {
-            if !done.insert(node) {
-                continue;
+    let mut routes = Vec::new();
+    let mut stack = vec![(tree, PathBuf::new(), vec![root])];
+    while let Some((id, dir, hops)) = stack.pop() {
+        for (name, entry) in objects.get_tree(id)?.entries {
+            let path = dir.join(name);
+            let (id, chunks) = match entry {
+                Entry::Tree { id } => {
+                    let mut hops = hops.clone();
+                    hops.push(Hop::Tree {
+                        id,
+                        path: path.clone(),
+                    });
+                    stack.push((id, path, hops));
+                    continue;
+                }
+                Entry::Symlink { target } => {
+                    let mut sink = ReferenceSink::new(std::io::sink());
+                    sink.write_all(target.to_string_lossy().as_bytes())?;
+                    if sink.into_inner().1.contains(&dep) {
+                        let mut hops = hops.clone();
+                        hops.push(Hop::Symlink { path, target });
+                        routes.push(hops);
+                    }
+                    continue;
+                }
+                Entry::Blob { id } => (id, vec![id]),
+                Entry::ChunkedBlob { id } => {
+                    let chunked = objects.get_chunked_blob(id)?;
+                    (id, chunked.chunks.iter().map(|c| c.id).collect())
+                }
+            };
+
+            let references = match scanned.entry(id) {
+                hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                hash_map::Entry::Vacant(entry) => {
+                    // Chunks are scanned through a single sink, so references spanning a chunk
+                    // boundary are still found.
+                    let mut sink = ReferenceSink::new(std::io::sink());
+                    for chunk in chunks {
+                        let mut content = objects.get_blob(chunk)?.into_content()?;
+                        util::copy_wide(&mut content, &mut sink)?;
+                    }
+                    entry.insert(sink.into_inner().1)
+                }
+            };
+
+            if references.contains(&dep) {
+                let mut hops = hops.clone();
+                hops.push(Hop::File { id, path });
+                routes.push(hops);
             }
+        }
+    }
 
-            let (id, kind, _) = node;
-            match kind {
-                ObjectKind::Blob | ObjectKind::Tree if !self.show_content => continue,
-                _ => writeln!(f, r#"  "{}" [shape = box]"#, id)?,
+    routes.sort();
+    Ok(routes)
+}
+
+impl Serialize for Closure {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        ClosureRepr::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Closure {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let repr = ClosureRepr::deserialize(deserializer)?;
+        Closure::try_from(repr).map_err(de::Error::custom)
+    }
+}
+
+/// Self-describing form of a [`Closure`], used by its `Serialize` and `Deserialize` impls.
+///
+/// Every edge points from an object to one of the objects it references.
+#[derive(Debug, Deserialize, Serialize)]
+struct ClosureRepr {
+    nodes: Vec<NodeRepr>,
+    edges: Vec<EdgeRepr>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct NodeRepr {
+    id: ObjectId,
+    kind: ObjectKind,
+    size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<PackageName>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct EdgeRepr {
+    from: ObjectId,
+    to: ObjectId,
+}
+
+impl From<&Closure> for ClosureRepr {
+    fn from(closure: &Closure) -> Self {
+        let nodes = closure
+            .nodes
+            .keys()
+            .map(|&(id, kind, size)| NodeRepr {
+                id,
+                kind,
+                size,
+                name: closure.package_name(id).cloned(),
+            })
+            .collect();
+
+        let edges = closure
+            .nodes
+            .iter()
+            .flat_map(|(node, children)| {
+                children.iter().map(move |child| EdgeRepr {
+                    from: node.0,
+                    to: child.0,
+                })
+            })
+            .collect();
+
+        ClosureRepr { nodes, edges }
+    }
+}
+
+impl TryFrom<ClosureRepr> for Closure {
+    type Error = anyhow::Error;
+
+    fn try_from(repr: ClosureRepr) -> Result<Self, Self::Error> {
+        let mut ids = HashMap::new();
+        let mut nodes = BTreeMap::new();
+        let mut names = BTreeMap::new();
+
+        for NodeRepr {
+            id,
+            kind,
+            size,
+            name,
+        } in repr.nodes
+        {
+            if ids.insert(id, (id, kind, size)).is_some() {
+                return Err(anyhow!("closure lists object {} more than once", id));
+            }
+
+            nodes.insert((id, kind, size), BTreeSet::new());
+            if let Some(name) = name {
+                names.insert(id, name);
             }
+        }
 
-            for (child_id, kind, _) in children {
-                match kind {
-                    ObjectKind::Blob | ObjectKind::Tree if !self.show_content => continue,
-                    _ if child_id != id => writeln!(f, r#"  "{}" -> "{}""#, child_id, id)?,
-                    _ => {}
+        for EdgeRepr { from, to } in repr.edges {
+            match (ids.get(&from), ids.get(&to)) {
+                (Some(from), Some(to)) => {
+                    nodes.get_mut(from).unwrap().insert(*to);
                 }
+                _ => return Err(anyhow!("closure edge {} -> {} is dangling", from, to)),
             }
         }
 
-        write!(f, "}}")
+        Ok(Closure::from_graph(nodes).with_names(names))
+    }
+}
+
+/// Serializable form of a [`Closure`], listing every node along with its children.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct WireClosure(Vec<(Node, Vec<Node>)>);
+
+impl From<&Closure> for WireClosure {
+    fn from(closure: &Closure) -> Self {
+        let nodes = closure
+            .graph()
+            .iter()
+            .map(|(node, children)| (*node, children.iter().copied().collect()))
+            .collect();
+
+        WireClosure(nodes)
+    }
+}
+
+impl From<WireClosure> for Closure {
+    fn from(wire: WireClosure) -> Self {
+        let nodes = wire
+            .0
+            .into_iter()
+            .map(|(node, children)| (node, children.into_iter().collect()))
+            .collect();
+
+        Closure::from_graph(nodes)
     }
 }
 
@@ -154,112 +685,462 @@ impl<'a> Display for DotDiagram<'a> {
 ///
 /// This function traverses the Merkle tree downward from the roots via depth-first search,
 /// optionally filtering out some nodes with the `filter` closure, returning a dependency graph.
+/// Whenever an object is visited, `filter` is called on each of its children that has not been
+/// seen yet, and the children which pass are then fetched from `obj` in parallel. The filter is
+/// called at most once per object. The calling thread blocks until every object has been read, so
+/// async code should call this from `tokio::task::spawn_blocking()` where it can.
+///
+/// Every edge between two objects in the closure is recorded, including the edges leading to
+/// objects which were already reached through another parent, such as a blob shared by two trees.
 ///
 /// This is useful both for computing _full_ closures (if `filter` always returns `Ok(true)`), or
 /// _delta_ closures, which contain only objects that are missing some remote host.
-pub fn compute<O, F>(obj: &O, roots: BTreeSet<ObjectId>, mut filter: F) -> anyhow::Result<Closure>
+pub fn compute<O, F>(obj: &O, roots: BTreeSet<ObjectId>, filter: F) -> anyhow::Result<Closure>
 where
     O: Objects + ?Sized,
     F: FnMut(ObjectId, ObjectKind) -> anyhow::Result<bool>,
 {
-    struct State<'a, O: ?Sized> {
+    compute_with(obj, roots, filter, &mut Fragments::new(&|_| Ok(None)))
+}
+
+/// Like [`compute()`], but takes the children of objects from the memoized package `fragments`
+/// where possible, only reading the objects which no fragment covers from `obj`.
+pub(crate) fn compute_with<O, F>(
+    obj: &O,
+    roots: BTreeSet<ObjectId>,
+    mut filter: F,
+    fragments: &mut Fragments,
+) -> anyhow::Result<Closure>
+where
+    O: Objects + ?Sized,
+    F: FnMut(ObjectId, ObjectKind) -> anyhow::Result<bool>,
+{
+    /// A node whose children are being visited, along with the children left to visit.
+    struct Frame {
+        node: Node,
+        children: std::vec::IntoIter<Node>,
+    }
+
+    struct State<'a, 'b, O: ?Sized> {
         obj: &'a O,
         filter: &'a mut dyn FnMut(ObjectId, ObjectKind) -> anyhow::Result<bool>,
+        fragments: &'a mut Fragments<'b>,
+        nodes: BTreeMap<Node, BTreeSet<Node>>,
+        names: BTreeMap<ObjectId, PackageName>,
         visited: HashSet<Node>,
         parents: HashSet<Node>,
+        fetched: HashMap<Node, Option<Vec<Node>>>,
+        stack: Vec<Frame>,
     }
 
-    fn visit(
-        state: &mut State<impl Objects + ?Sized>,
-        nodes: &mut BTreeMap<Node, BTreeSet<Node>>,
-        item: Node,
-        parent_item: Option<Node>,
-    ) -> anyhow::Result<()> {
-        // Reference cycles are forbidden, so exit early if one is found.
-        if state.parents.contains(&item) {
-            return Err(anyhow!(
-                "detected cycle in closure reference graph: {} -> {}",
-                item.0,
-                parent_item.unwrap().0
-            ));
-        }
-
-        // Return early if we have already visited this node before.
-        if !state.visited.insert(item) {
-            return Ok(());
-        }
+    impl<'a, 'b, O: Objects + ?Sized> State<'a, 'b, O> {
+        /// Runs the filter on each of `items` which has not been seen yet, and fetches the
+        /// children of those which pass it in parallel.
+        fn prefetch(&mut self, items: &[Node]) -> anyhow::Result<()> {
+            let mut accepted = Vec::new();
+            for &item in items {
+                if self.visited.contains(&item) || self.fetched.contains_key(&item) {
+                    continue;
+                }
 
-        // Decide whether to continue the DFS or abandon it in favor of the next item.
-        let (id, kind, _) = item;
-        let children: Vec<_> = if (state.filter)(id, kind)? {
-            nodes.entry(item).or_default();
-            match kind {
-                ObjectKind::Blob => Vec::new(),
-                ObjectKind::Tree => {
-                    let tree = state.obj.get_tree(id)?;
-                    tree.references()
-                        .map(|(id, k)| state.obj.object_size(&id, Some(k)).map(|n| (id, k, n)))
-                        .collect::<Result<_, _>>()?
+                self.fetched.insert(item, None);
+                if (self.filter)(item.0, item.1)? {
+                    accepted.push(item);
                 }
-                ObjectKind::Package => {
-                    let pkg = state.obj.get_package(id)?;
-                    pkg.references
-                        .into_iter()
-                        .map(|id| (id, kind))
-                        .chain(std::iter::once((pkg.tree, ObjectKind::Tree)))
-                        .map(|(id, k)| state.obj.object_size(&id, Some(k)).map(|n| (id, k, n)))
-                        .collect::<Result<_, _>>()?
+            }
+
+            // Objects covered by a memoized fragment need not be read at all.
+            let mut known = Vec::new();
+            let mut unknown = Vec::new();
+            for item in accepted {
+                if item.1 == ObjectKind::Package {
+                    self.fragments.load(item.0)?;
                 }
-                ObjectKind::Spec => {
-                    let spec = state.obj.get_spec(id)?;
-                    spec.dependencies
-                        .into_iter()
-                        .chain(spec.build_dependencies)
-                        .map(|id| (id, kind))
-                        .map(|(id, k)| state.obj.object_size(&id, Some(k)).map(|n| (id, k, n)))
-                        .collect::<Result<_, _>>()?
+
+                match self.fragments.get(&item) {
+                    Some(found) => known.push((item, found)),
+                    None => unknown.push(item),
                 }
             }
-        } else {
-            return Ok(());
-        };
 
-        // Mark this node as a parent, to detect cycles.
-        state.parents.insert(item);
+            let obj = self.obj;
+            let fetched: Vec<_> = unknown
+                .par_iter()
+                .map(|&(id, kind, _)| fetch(obj, id, kind))
+                .collect::<anyhow::Result<_>>()?;
+
+            for (item, (children, name)) in
+                known.into_iter().chain(unknown.into_iter().zip(fetched))
+            {
+                if let Some(name) = name {
+                    self.names.insert(item.0, name);
+                }
+                self.fetched.insert(item, Some(children));
+            }
 
-        // Continue descending into the child nodes in a DFS, if any exist.
-        for child in children {
-            visit(state, nodes, child, Some(item))?;
+            Ok(())
         }
 
-        // All children of this node have been handled, so it's safe to move on.
-        state.parents.remove(&item);
+        /// Starts visiting `item`, which was reached from `parent_item`.
+        fn enter(&mut self, item: Node, parent_item: Option<Node>) -> anyhow::Result<()> {
+            // Reference cycles are forbidden, so exit early if one is found.
+            if self.parents.contains(&item) {
+                return Err(anyhow!(
+                    "detected cycle in closure reference graph: {} -> {}",
+                    item.0,
+                    parent_item.unwrap().0
+                ));
+            }
+
+            // Return early if we have already visited this node before, but still record the edge
+            // from `parent_item` if the node was included in the closure.
+            if !self.visited.insert(item) {
+                if let (Some(parent), true) = (parent_item, self.nodes.contains_key(&item)) {
+                    self.nodes.entry(parent).or_default().insert(item);
+                }
+                return Ok(());
+            }
 
-        // Insert edge connecting `parent_item` to `item`.
-        if let Some(parent) = parent_item {
-            nodes.entry(parent).or_default().insert(item);
+            // Every node is prefetched before it is entered, so abandon the nodes which were
+            // rejected by the filter in favor of the next item.
+            let children = match self.fetched.remove(&item) {
+                Some(Some(children)) => children,
+                _ => return Ok(()),
+            };
+
+            self.nodes.entry(item).or_default();
+            self.prefetch(&children)?;
+
+            // Mark this node as a parent, to detect cycles.
+            self.parents.insert(item);
+            self.stack.push(Frame {
+                node: item,
+                children: children.into_iter(),
+            });
+
+            Ok(())
         }
 
-        Ok(())
+        /// Finishes visiting the node on top of the stack, once all of its children are handled.
+        fn leave(&mut self) {
+            let item = self.stack.pop().unwrap().node;
+            self.parents.remove(&item);
+
+            // Insert edge connecting the parent to `item`.
+            if let Some(parent) = self.stack.last() {
+                self.nodes.entry(parent.node).or_default().insert(item);
+            }
+        }
     }
 
-    let mut nodes = BTreeMap::new();
     let mut state = State {
         obj,
         filter: &mut filter,
+        fragments,
+        nodes: BTreeMap::new(),
+        names: BTreeMap::new(),
         visited: HashSet::new(),
         parents: HashSet::new(),
+        fetched: HashMap::new(),
+        stack: Vec::new(),
     };
 
+    // The graph is walked depth-first with an explicit stack rather than by recursion, so that
+    // deeply nested trees cannot overflow the call stack.
     for root in roots {
         let kind = ObjectKind::Package;
         let size = obj.object_size(&root, Some(kind))?;
         let node = (root, kind, size);
-        visit(&mut state, &mut nodes, node, None)?;
+        state.prefetch(&[node])?;
+        state.enter(node, None)?;
+
+        while let Some(frame) = state.stack.last_mut() {
+            match frame.children.next() {
+                Some(child) => {
+                    let parent = frame.node;
+                    state.enter(child, Some(parent))?;
+                }
+                None => state.leave(),
+            }
+        }
     }
 
-    Ok(Closure {
-        unpacked_size: nodes.keys().map(|item| item.2).sum(),
-        nodes,
-    })
+    Ok(Closure::from_graph(state.nodes).with_names(state.names))
+}
+
+/// Looks up the objects directly referenced by the object `id`, along with the name of the
+/// package if `id` refers to one.
+fn fetch<O>(
+    obj: &O,
+    id: ObjectId,
+    kind: ObjectKind,
+) -> anyhow::Result<(Vec<Node>, Option<PackageName>)>
+where
+    O: Objects + ?Sized,
+{
+    if kind == ObjectKind::Package {
+        let pkg = obj.get_package(id)?;
+        Ok((package_references(obj, &pkg)?, Some(pkg.name)))
+    } else {
+        Ok((references(obj, id, kind)?, None))
+    }
+}
+
+/// Looks up the objects directly referenced by the object `id`, along with their sizes.
+///
+/// Returns `Err` if the object or any of its references do not exist, or an I/O error occurred.
+pub(crate) fn references<O>(obj: &O, id: ObjectId, kind: ObjectKind) -> anyhow::Result<Vec<Node>>
+where
+    O: Objects + ?Sized,
+{
+    match kind {
+        ObjectKind::Blob => Ok(Vec::new()),
+        ObjectKind::ChunkedBlob => {
+            let chunked = obj.get_chunked_blob(id)?;
+            Ok(chunked
+                .chunks
+                .iter()
+                .map(|c| (c.id, ObjectKind::Blob, c.size))
+                .collect())
+        }
+        ObjectKind::Tree => {
+            let tree = obj.get_tree(id)?;
+            let refs: Vec<_> = tree.references().collect();
+            sizes(obj, refs)
+        }
+        ObjectKind::Package => package_references(obj, &obj.get_package(id)?),
+        ObjectKind::Spec => {
+            // The dependencies of a spec are the packages it is built against.
+            let spec = obj.get_spec(id)?;
+            let refs = spec
+                .dependencies
+                .into_iter()
+                .chain(spec.build_dependencies)
+                .map(|id| (id, ObjectKind::Package))
+                .collect();
+            sizes(obj, refs)
+        }
+    }
+}
+
+/// Looks up the packages and tree directly referenced by `pkg`, along with their sizes.
+fn package_references<O>(obj: &O, pkg: &Package) -> anyhow::Result<Vec<Node>>
+where
+    O: Objects + ?Sized,
+{
+    let refs = pkg
+        .references
+        .iter()
+        .map(|&id| (id, ObjectKind::Package))
+        .chain(std::iter::once((pkg.tree, ObjectKind::Tree)))
+        .collect();
+    sizes(obj, refs)
+}
+
+/// Looks up the sizes of the objects in `refs` in parallel.
+fn sizes<O>(obj: &O, refs: Vec<(ObjectId, ObjectKind)>) -> anyhow::Result<Vec<Node>>
+where
+    O: Objects + ?Sized,
+{
+    refs.into_par_iter()
+        .map(|(id, k)| obj.object_size(&id, Some(k)).map(|n| (id, k, n)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        insert_blob, insert_package, insert_package_with, insert_spec, insert_tree,
+    };
+    use crate::LocalStore;
+
+    #[test]
+    fn finds_referrers_and_shortest_chains() {
+        let mut store = LocalStore::in_memory();
+        let libc = insert_package(&mut store, "libc", &[]);
+        let foo = insert_package(&mut store, "foo", &[libc]);
+        let bar = insert_package(&mut store, "bar", &[libc]);
+        let deps = [foo, bar, libc];
+        let app = insert_package(&mut store, "app", &deps);
+
+        let closure = store
+            .compute_closure(std::iter::once(app).collect())
+            .unwrap();
+
+        let referrers: BTreeSet<_> = closure.referrers(libc).map(|&(id, _, _)| id).collect();
+        assert_eq!(referrers, vec![foo, bar, app].into_iter().collect());
+
+        // `app` references `libc` directly, so longer chains through `foo` and `bar` are omitted.
+        let ids = |chains: Vec<Vec<Node>>| -> Vec<Vec<ObjectId>> {
+            chains
+                .into_iter()
+                .map(|chain| chain.into_iter().map(|(id, _, _)| id).collect())
+                .collect()
+        };
+        assert_eq!(ids(closure.why_depends(app, libc)), vec![vec![app, libc]]);
+        assert_eq!(ids(closure.why_depends(foo, libc)), vec![vec![foo, libc]]);
+        assert!(closure.why_depends(libc, app).is_empty());
+
+        let top = insert_package(&mut store, "top", &[foo, bar]);
+        let closure = store
+            .compute_closure(std::iter::once(top).collect())
+            .unwrap();
+        let chains: BTreeSet<_> = ids(closure.why_depends(top, libc)).into_iter().collect();
+        let expected = vec![vec![top, foo, libc], vec![top, bar, libc]];
+        assert_eq!(chains, expected.into_iter().collect());
+    }
+
+    #[test]
+    fn names_files_holding_references() {
+        let mut store = LocalStore::in_memory();
+        let libc = insert_package(&mut store, "libc", &[]);
+
+        let install_name = store.get_package(libc).unwrap().install_name();
+        let script = format!("exec /store/packages/{}/bin/run\n", install_name);
+        let file = insert_blob(&mut store, script.as_bytes(), true);
+        let blob = match file {
+            Entry::Blob { id } => id,
+            _ => unreachable!(),
+        };
+        let bin = insert_tree(&mut store, std::iter::once(("foo", file)));
+        let tree = insert_tree(
+            &mut store,
+            std::iter::once(("bin", Entry::Tree { id: bin })),
+        );
+        let foo = insert_package_with(&mut store, "foo", &[libc], tree);
+
+        let closure = store
+            .compute_closure(std::iter::once(foo).collect())
+            .unwrap();
+        let root = store.get_package(foo).unwrap().tree;
+        let chains = closure.why_depends_via_files(&store, foo, libc).unwrap();
+        assert_eq!(
+            chains,
+            vec![vec![
+                Hop::Package(foo),
+                Hop::Tree {
+                    id: root,
+                    path: PathBuf::new(),
+                },
+                Hop::Tree {
+                    id: bin,
+                    path: "bin".into(),
+                },
+                Hop::File {
+                    id: blob,
+                    path: "bin/foo".into(),
+                },
+                Hop::Package(libc),
+            ]]
+        );
+    }
+
+    #[test]
+    fn serializes_and_renders_with_install_names() {
+        let mut store = LocalStore::in_memory();
+        let libc = insert_package(&mut store, "libc", &[]);
+        let app = insert_package(&mut store, "app", &[libc]);
+        let closure = store
+            .compute_closure(std::iter::once(app).collect())
+            .unwrap();
+
+        let json = serde_json::to_value(&closure).unwrap();
+        let edge = serde_json::json!({ "from": app.to_string(), "to": libc.to_string() });
+        assert_eq!(
+            json["nodes"].as_array().unwrap().len(),
+            closure.num_objects()
+        );
+        assert!(json["edges"].as_array().unwrap().contains(&edge));
+
+        let decoded: Closure = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.graph(), closure.graph());
+        assert_eq!(decoded.package_name(libc).unwrap().as_ref(), "libc");
+
+        let dangling = serde_json::json!({ "nodes": [], "edges": [edge] });
+        assert!(serde_json::from_value::<Closure>(dangling).is_err());
+
+        let libc_name = closure.install_name(libc).unwrap().to_string();
+        let app_name = closure.install_name(app).unwrap().to_string();
+        let mermaid = closure.render_mermaid(false).to_string();
+        assert!(mermaid.contains(&format!("o{}[\"{}\"]", app, app_name)));
+        assert!(mermaid.contains(&format!("o{} --> o{}", libc, app)));
+
+        let graphml = closure.render_graphml(false).to_string();
+        assert!(graphml.contains(&format!(r#"<data key="label">{}</data>"#, libc_name)));
+        assert!(graphml.contains(&format!(r#"<edge source="{}" target="{}"/>"#, libc, app)));
+        assert!(!graphml.contains(r#"<data key="kind">tree</data>"#));
+
+        let dot = closure.render_dot(true).to_string();
+        assert!(dot.contains(&format!(r#"label = "{}""#, app_name)));
+    }
+
+    #[test]
+    fn treats_spec_dependencies_as_packages() {
+        let mut store = LocalStore::in_memory();
+        let lib = insert_package(&mut store, "lib", &[]);
+        let tool = insert_package(&mut store, "tool", &[]);
+        let spec = insert_spec(&mut store, "app", &[lib], &[tool], "true");
+
+        let refs = references(&store, spec, ObjectKind::Spec).unwrap();
+        let mut kinds: Vec<_> = refs.iter().map(|&(id, kind, _)| (id, kind)).collect();
+        kinds.sort();
+        let mut expected = vec![(lib, ObjectKind::Package), (tool, ObjectKind::Package)];
+        expected.sort();
+        assert_eq!(kinds, expected);
+    }
+
+    #[test]
+    fn records_edges_to_visited_nodes() {
+        let mut store = LocalStore::in_memory();
+        let shared = insert_blob(&mut store, b"shared", false);
+        let blob = match shared {
+            Entry::Blob { id } => id,
+            _ => unreachable!(),
+        };
+        let lib_tree = insert_tree(&mut store, vec![("lib.txt", shared.clone())]);
+        let app_tree = insert_tree(&mut store, vec![("app.txt", shared.clone())]);
+        let lib = insert_package_with(&mut store, "lib", &[], lib_tree);
+        let app = insert_package_with(&mut store, "app", &[lib], app_tree);
+
+        // The blob is reached through both trees, whichever of them is visited first.
+        let roots: BTreeSet<_> = std::iter::once(app).collect();
+        let closure = compute(&store, roots.clone(), |_, _| Ok(true)).unwrap();
+        let referrers: BTreeSet<_> = closure.referrers(blob).map(|&(id, _, _)| id).collect();
+        assert_eq!(referrers, vec![lib_tree, app_tree].into_iter().collect());
+
+        // Objects rejected by the filter are left out, along with the edges leading to them.
+        let closure = compute(&store, roots, |_, kind| Ok(kind != ObjectKind::Blob)).unwrap();
+        assert_eq!(closure.referrers(blob).count(), 0);
+        assert!(closure
+            .graph()
+            .values()
+            .flatten()
+            .all(|n| n.1 != ObjectKind::Blob));
+    }
+
+    #[test]
+    fn computes_deep_closure_without_recursion() {
+        let mut store = LocalStore::in_memory();
+        let mut chain = vec![insert_package(&mut store, "pkg-0", &[])];
+        for i in 1..10_000 {
+            let prev = chain[i - 1];
+            chain.push(insert_package(&mut store, &format!("pkg-{}", i), &[prev]));
+        }
+
+        let top = *chain.last().unwrap();
+        let closure = store
+            .compute_closure(std::iter::once(top).collect())
+            .unwrap();
+        assert_eq!(closure.num_packages(), chain.len());
+
+        // Every package must come after the package it references.
+        let packages: Vec<_> = closure
+            .sort_topological()
+            .into_iter()
+            .filter(|node| node.1 == ObjectKind::Package)
+            .map(|node| node.0)
+            .collect();
+        assert_eq!(packages, chain);
+    }
 }
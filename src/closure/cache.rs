@@ -0,0 +1,346 @@
+//! Memoized per-package closures, which let a store skip walking the Merkle tree of a package.
+//!
+//! Package objects are immutable, so the objects belonging to a package never change once it is
+//! installed. A store can therefore record a _fragment_ of the closure for each package: the
+//! package itself, the trees and blobs it contains, and the packages it references directly as
+//! leaves. The full closure of any package set is then the union of the fragments of every package
+//! reachable from it.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+
+use anyhow::anyhow;
+
+use super::{Closure, Node};
+use crate::{ObjectId, ObjectKind, Objects, PackageName};
+
+const MAGIC_VALUE: &[u8] = b"store-closure";
+const FORMAT_VERSION: u8 = 1;
+
+// Tags identifying the kind of each node on disk. These must never change.
+const TAG_BLOB: u8 = 0;
+const TAG_CHUNKED_BLOB: u8 = 1;
+const TAG_TREE: u8 = 2;
+const TAG_PACKAGE: u8 = 3;
+const TAG_SPEC: u8 = 4;
+
+impl Closure {
+    /// Returns the fragment of this closure belonging to the package `pkg`.
+    ///
+    /// The fragment contains `pkg` along with every object reachable from it without passing
+    /// through another package. Packages referenced by `pkg` are kept as leaves, so the edges
+    /// leading to them are preserved. Only the name of `pkg` itself is kept.
+    ///
+    /// The fragment is empty if `pkg` is not a package in this closure.
+    pub(crate) fn fragment(&self, pkg: ObjectId) -> Closure {
+        let root = self
+            .nodes
+            .keys()
+            .find(|&&(id, kind, _)| id == pkg && kind == ObjectKind::Package);
+
+        let mut nodes = BTreeMap::new();
+        let mut stack: Vec<_> = root.into_iter().copied().collect();
+        while let Some(node) = stack.pop() {
+            if nodes.contains_key(&node) {
+                continue;
+            }
+
+            let children = match node {
+                (id, ObjectKind::Package, _) if id != pkg => BTreeSet::new(),
+                _ => self.nodes[&node].clone(),
+            };
+
+            stack.extend(children.iter().copied());
+            nodes.insert(node, children);
+        }
+
+        let names = self
+            .names
+            .get(&pkg)
+            .map(|name| (pkg, name.clone()))
+            .into_iter()
+            .collect();
+
+        Closure::from_graph(nodes).with_names(names)
+    }
+
+    /// Serializes this closure to `writer` in a compact binary format.
+    ///
+    /// Each node is written as its raw ID, kind, size and optional package name, followed by the
+    /// edges of every node as indices into the list of nodes.
+    ///
+    /// Returns `Err` if an I/O error occurred.
+    pub(crate) fn write_compact<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(MAGIC_VALUE)?;
+        writer.write_all(&[FORMAT_VERSION])?;
+        writer.write_all(&(self.nodes.len() as u32).to_be_bytes())?;
+
+        let mut indices = HashMap::with_capacity(self.nodes.len());
+        for (i, &(id, kind, size)) in self.nodes.keys().enumerate() {
+            indices.insert((id, kind, size), i as u32);
+            writer.write_all(id.as_bytes())?;
+            writer.write_all(&[kind_to_u8(kind)])?;
+            writer.write_all(&size.to_be_bytes())?;
+
+            let name = self.names.get(&id).map(|name| name.to_string());
+            let name = name.as_deref().unwrap_or_default();
+            writer.write_all(&(name.len() as u16).to_be_bytes())?;
+            writer.write_all(name.as_bytes())?;
+        }
+
+        for children in self.nodes.values() {
+            writer.write_all(&(children.len() as u32).to_be_bytes())?;
+            for child in children {
+                writer.write_all(&indices[child].to_be_bytes())?;
+            }
+        }
+
+        writer.flush()
+    }
+
+    /// Deserializes a closure written by [`Closure::write_compact()`] from `reader`.
+    ///
+    /// Returns `Err` if the stream is not a compact closure, an edge points to a node which does
+    /// not exist, or an I/O error occurred.
+    pub(crate) fn read_compact<R: Read>(mut reader: R) -> anyhow::Result<Self> {
+        let mut magic = [0u8; MAGIC_VALUE.len() + 1];
+        reader.read_exact(&mut magic)?;
+
+        match &magic[..] {
+            [m @ .., FORMAT_VERSION] if m == MAGIC_VALUE => {}
+            _ => return Err(anyhow!("magic value not found, not a store closure")),
+        }
+
+        let len = read_u32(&mut reader)? as usize;
+        let mut order = Vec::with_capacity(len);
+        let mut names = BTreeMap::new();
+        for _ in 0..len {
+            let mut buf = [0u8; ObjectId::LENGTH + 9];
+            reader.read_exact(&mut buf)?;
+            let id = ObjectId::from_bytes(buf[..ObjectId::LENGTH].try_into()?);
+            let kind = kind_from_u8(buf[ObjectId::LENGTH])?;
+            let size = u64::from_be_bytes(buf[ObjectId::LENGTH + 1..].try_into()?);
+            order.push((id, kind, size));
+
+            let mut name_len = [0u8; 2];
+            reader.read_exact(&mut name_len)?;
+            let mut name = vec![0u8; u16::from_be_bytes(name_len) as usize];
+            reader.read_exact(&mut name)?;
+            if !name.is_empty() {
+                names.insert(id, String::from_utf8(name)?.parse::<PackageName>()?);
+            }
+        }
+
+        let mut nodes = BTreeMap::new();
+        for &node in &order {
+            let count = read_u32(&mut reader)?;
+            let mut children = BTreeSet::new();
+            for _ in 0..count {
+                let index = read_u32(&mut reader)? as usize;
+                let child = order
+                    .get(index)
+                    .ok_or_else(|| anyhow!("closure edge points to missing node {}", index))?;
+                children.insert(*child);
+            }
+            nodes.insert(node, children);
+        }
+
+        Ok(Closure::from_graph(nodes).with_names(names))
+    }
+}
+
+/// Walks the package `pkg` stored in `obj` and returns its closure fragment, as described in
+/// [`Closure::fragment()`].
+///
+/// Unlike [`Objects::compute_closure()`], the packages referenced by `pkg` are not read at all.
+///
+/// Returns `Err` if `pkg` does not refer to a `Package` object, any object it contains does not
+/// exist, or an I/O error occurred.
+pub(crate) fn package_fragment<O>(obj: &O, pkg: ObjectId) -> anyhow::Result<Closure>
+where
+    O: Objects + ?Sized,
+{
+    let kind = ObjectKind::Package;
+    let root = (pkg, kind, obj.object_size(&pkg, Some(kind))?);
+
+    let mut nodes = BTreeMap::new();
+    let mut names = BTreeMap::new();
+    let mut stack = vec![root];
+    while let Some(node) = stack.pop() {
+        if nodes.contains_key(&node) {
+            continue;
+        }
+
+        let children = match node {
+            (id, ObjectKind::Package, _) if id != pkg => Vec::new(),
+            (id, kind, _) => {
+                let (children, name) = super::fetch(obj, id, kind)?;
+                names.extend(name.map(|name| (id, name)));
+                children
+            }
+        };
+
+        stack.extend(children.iter().copied());
+        nodes.insert(node, children.into_iter().collect());
+    }
+
+    Ok(Closure::from_graph(nodes).with_names(names))
+}
+
+fn kind_to_u8(kind: ObjectKind) -> u8 {
+    match kind {
+        ObjectKind::Blob => TAG_BLOB,
+        ObjectKind::ChunkedBlob => TAG_CHUNKED_BLOB,
+        ObjectKind::Tree => TAG_TREE,
+        ObjectKind::Package => TAG_PACKAGE,
+        ObjectKind::Spec => TAG_SPEC,
+    }
+}
+
+fn kind_from_u8(byte: u8) -> anyhow::Result<ObjectKind> {
+    match byte {
+        TAG_BLOB => Ok(ObjectKind::Blob),
+        TAG_CHUNKED_BLOB => Ok(ObjectKind::ChunkedBlob),
+        TAG_TREE => Ok(ObjectKind::Tree),
+        TAG_PACKAGE => Ok(ObjectKind::Package),
+        TAG_SPEC => Ok(ObjectKind::Spec),
+        _ => Err(anyhow!("unrecognized object kind: {}", byte)),
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+/// Looks up the children of objects in memoized package fragments before reading the objects.
+///
+/// Fragments are requested from `lookup` once per package, the first time the package is reached.
+/// Packages for which `lookup` returns `None` are recorded, so the caller can memoize them later.
+pub(crate) struct Fragments<'a> {
+    lookup: &'a dyn Fn(ObjectId) -> anyhow::Result<Option<Closure>>,
+    children: HashMap<Node, Vec<Node>>,
+    names: HashMap<ObjectId, PackageName>,
+    loaded: HashSet<ObjectId>,
+    missed: Vec<ObjectId>,
+}
+
+impl<'a> Fragments<'a> {
+    /// Creates an empty set of fragments which are requested from `lookup` on demand.
+    pub fn new(lookup: &'a dyn Fn(ObjectId) -> anyhow::Result<Option<Closure>>) -> Self {
+        Fragments {
+            lookup,
+            children: HashMap::new(),
+            names: HashMap::new(),
+            loaded: HashSet::new(),
+            missed: Vec::new(),
+        }
+    }
+
+    /// Requests the fragment of the package `pkg`, unless it was already requested before.
+    ///
+    /// Returns `Err` if the fragment could not be looked up.
+    pub fn load(&mut self, pkg: ObjectId) -> anyhow::Result<()> {
+        if !self.loaded.insert(pkg) {
+            return Ok(());
+        }
+
+        let fragment = match (self.lookup)(pkg)? {
+            Some(fragment) => fragment,
+            None => {
+                self.missed.push(pkg);
+                return Ok(());
+            }
+        };
+
+        // Other packages are only leaves in this fragment, so their children come from their own.
+        for (node, children) in fragment.nodes {
+            if node.1 != ObjectKind::Package || node.0 == pkg {
+                self.children.insert(node, children.into_iter().collect());
+            }
+        }
+
+        self.names
+            .extend(fragment.names.into_iter().filter(|(id, _)| *id == pkg));
+        Ok(())
+    }
+
+    /// Returns the children of `node` and its package name, if they are known from a fragment.
+    pub fn get(&self, node: &Node) -> Option<(Vec<Node>, Option<PackageName>)> {
+        let children = self.children.get(node)?.clone();
+        Some((children, self.names.get(&node.0).cloned()))
+    }
+
+    /// Returns the children of `node` and its package name, reading the object from `obj` if no
+    /// fragment lists them.
+    ///
+    /// Returns `Err` if a fragment could not be looked up, or the object could not be read.
+    pub fn fetch<O>(
+        &mut self,
+        obj: &O,
+        node: &Node,
+    ) -> anyhow::Result<(Vec<Node>, Option<PackageName>)>
+    where
+        O: Objects + ?Sized,
+    {
+        if node.1 == ObjectKind::Package {
+            self.load(node.0)?;
+        }
+
+        match self.get(node) {
+            Some(found) => Ok(found),
+            None => super::fetch(obj, node.0, node.1),
+        }
+    }
+
+    /// Returns the packages which had no memoized fragment when they were requested.
+    pub fn missed(&self) -> &[ObjectId] {
+        &self.missed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::insert_package;
+    use crate::LocalStore;
+
+    #[test]
+    fn round_trips_package_fragments() {
+        let mut store = LocalStore::in_memory();
+        let lib = insert_package(&mut store, "lib-1.0", &[]);
+        let app = insert_package(&mut store, "app-2.0", &[lib]);
+        let closure = store
+            .compute_closure(std::iter::once(app).collect())
+            .unwrap();
+
+        // `lib` stays in the fragment of `app` as a leaf, but its contents and name do not.
+        let fragment = closure.fragment(app);
+        assert_eq!(fragment.num_objects(), 4);
+        assert_eq!(
+            package_fragment(&store, app).unwrap().graph(),
+            fragment.graph()
+        );
+        assert_eq!(fragment.num_packages(), 2);
+        assert_eq!(fragment.package_name(app), closure.package_name(app));
+        assert_eq!(fragment.package_name(lib), None);
+        let lib_node = closure.iter().find(|n| n.0 == lib).unwrap();
+        assert!(fragment.graph()[lib_node].is_empty());
+
+        let mut buf = Vec::new();
+        fragment.write_compact(&mut buf).unwrap();
+        let decoded = Closure::read_compact(&buf[..]).unwrap();
+        assert_eq!(decoded.graph(), fragment.graph());
+        assert_eq!(decoded.package_name(app), fragment.package_name(app));
+
+        for kind in ObjectKind::iter() {
+            assert_eq!(kind_from_u8(kind_to_u8(kind)).unwrap(), kind);
+        }
+
+        buf.truncate(buf.len() - 1);
+        assert!(Closure::read_compact(&buf[..]).is_err());
+        assert!(Closure::read_compact(&b"not a closure"[..]).is_err());
+    }
+}
@@ -0,0 +1,290 @@
+//! Comparison of the packages contained in two closures.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+
+use serde::Serialize;
+
+use super::Closure;
+use crate::{ObjectId, ObjectKind, PackageName};
+
+impl Closure {
+    /// Compares the packages in this closure against those in `other`.
+    ///
+    /// `self` is treated as the old package set and `other` as the new one. Packages present in
+    /// both closures are left out of the result. The rest are matched up by the stem of their
+    /// [`PackageName`], such that upgrading `hello-1.0.0` to `hello-1.1.0` is reported as a single
+    /// change rather than a removal and an addition. Packages whose names are unknown, such as
+    /// those in closures received from a remote store, can only be reported as added or removed.
+    ///
+    /// Package sizes include the trees and blobs belonging to each package, but not the packages
+    /// it references.
+    ///
+    /// The returned [`ClosureDiff`] implements [`Display`](std::fmt::Display) for a human-readable
+    /// summary, and [`Serialize`](serde::Serialize) for rendering it as JSON.
+    pub fn diff(&self, other: &Closure) -> ClosureDiff {
+        let old = self.package_summaries();
+        let new = other.package_summaries();
+
+        let mut removed: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for pkg in old.values().filter(|pkg| !new.contains_key(&pkg.id)) {
+            removed.entry(stem(pkg)).or_default().push(pkg.clone());
+        }
+
+        let mut added: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for pkg in new.values().filter(|pkg| !old.contains_key(&pkg.id)) {
+            added.entry(stem(pkg)).or_default().push(pkg.clone());
+        }
+
+        let mut diff = ClosureDiff {
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+            size_delta: other.unpacked_size() as i64 - self.unpacked_size() as i64,
+        };
+
+        for (stem, mut old_pkgs) in removed {
+            let mut new_pkgs = match stem.and_then(|_| added.remove(&stem)) {
+                Some(new_pkgs) => new_pkgs,
+                None => {
+                    diff.removed.extend(old_pkgs);
+                    continue;
+                }
+            };
+
+            // A single package on each side is an upgrade. Otherwise, several versions of the
+            // same package coexist, so only rebuilds of the exact same name can be paired up.
+            if old_pkgs.len() == 1 && new_pkgs.len() == 1 {
+                let change = PackageChange::new(old_pkgs.remove(0), new_pkgs.remove(0));
+                diff.changed.push(change);
+            } else {
+                for old in old_pkgs {
+                    match new_pkgs.iter().position(|new| new.name == old.name) {
+                        Some(i) => {
+                            let change = PackageChange::new(old, new_pkgs.remove(i));
+                            diff.changed.push(change);
+                        }
+                        None => diff.removed.push(old),
+                    }
+                }
+            }
+
+            diff.added.extend(new_pkgs);
+        }
+
+        diff.added.extend(added.into_values().flatten());
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff
+    }
+
+    fn package_summaries(&self) -> BTreeMap<ObjectId, PackageSummary> {
+        self.nodes
+            .keys()
+            .filter(|&&(_, kind, _)| kind == ObjectKind::Package)
+            .map(|node| {
+                let summary = PackageSummary {
+                    name: self.package_name(node.0).cloned(),
+                    id: node.0,
+                    size: self.own_size(node),
+                };
+                (node.0, summary)
+            })
+            .collect()
+    }
+}
+
+fn stem(pkg: &PackageSummary) -> Option<&str> {
+    pkg.name.as_ref().map(|name| name.stem())
+}
+
+/// A list of differences between two closures, returned by [`Closure::diff()`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct ClosureDiff {
+    /// Packages which only exist in the new closure.
+    pub added: Vec<PackageSummary>,
+    /// Packages which only exist in the old closure.
+    pub removed: Vec<PackageSummary>,
+    /// Packages which exist in both closures, but with a different version or ID.
+    pub changed: Vec<PackageChange>,
+    /// Change in the total unpacked size of the closure, in bytes.
+    pub size_delta: i64,
+}
+
+impl ClosureDiff {
+    /// Returns `true` if both closures contain exactly the same packages.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl Display for ClosureDiff {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for pkg in &self.removed {
+            writeln!(f, "- {} ({})", pkg, Bytes(-(pkg.size as i64)))?;
+        }
+
+        for pkg in &self.added {
+            writeln!(f, "+ {} ({})", pkg, Bytes(pkg.size as i64))?;
+        }
+
+        for change in &self.changed {
+            let version = |v: &Option<String>| v.as_deref().unwrap_or("<none>").to_owned();
+            writeln!(
+                f,
+                "~ {}: {} -> {}, {} -> {} ({})",
+                change.stem,
+                version(&change.old_version),
+                version(&change.new_version),
+                change.old_id,
+                change.new_id,
+                Bytes(change.size_delta),
+            )?;
+        }
+
+        write!(f, "closure size: {}", Bytes(self.size_delta))
+    }
+}
+
+/// A package which was added to or removed from a closure.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct PackageSummary {
+    /// The human-readable name of the package, if it is known.
+    pub name: Option<PackageName>,
+    /// The ID of the package object.
+    pub id: ObjectId,
+    /// Size of the package along with its trees and blobs, in bytes.
+    pub size: u64,
+}
+
+impl Display for PackageSummary {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "{}-{}", name, self.id),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+/// A package whose version or contents changed between two closures.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct PackageChange {
+    /// Name of the package without its version string.
+    pub stem: String,
+    /// Version of the package in the old closure, if any.
+    pub old_version: Option<String>,
+    /// Version of the package in the new closure, if any.
+    pub new_version: Option<String>,
+    /// ID of the package object in the old closure.
+    pub old_id: ObjectId,
+    /// ID of the package object in the new closure.
+    pub new_id: ObjectId,
+    /// Change in the size of the package along with its trees and blobs, in bytes.
+    pub size_delta: i64,
+}
+
+impl PackageChange {
+    fn new(old: PackageSummary, new: PackageSummary) -> Self {
+        let version = |pkg: &PackageSummary| {
+            pkg.name
+                .as_ref()
+                .and_then(|name| name.version())
+                .map(String::from)
+        };
+
+        PackageChange {
+            stem: stem(&new).unwrap_or_default().to_owned(),
+            old_version: version(&old),
+            new_version: version(&new),
+            old_id: old.id,
+            new_id: new.id,
+            size_delta: new.size as i64 - old.size as i64,
+        }
+    }
+}
+
+/// Displays a signed byte count.
+struct Bytes(i64);
+
+impl Display for Bytes {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:+} bytes", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::insert_package;
+    use crate::{LocalStore, Objects};
+
+    #[test]
+    fn reports_added_removed_and_changed_packages() {
+        let mut store = LocalStore::in_memory();
+        let old_libc = insert_package(&mut store, "libc-2.31", &[]);
+        let zlib = insert_package(&mut store, "zlib-1.2.11", &[]);
+        let old_app = insert_package(&mut store, "app-1.0", &[old_libc, zlib]);
+        let new_libc = insert_package(&mut store, "libc-2.32", &[]);
+        let curl = insert_package(&mut store, "curl-7.74.0", &[new_libc]);
+        let new_app = insert_package(&mut store, "app-1.1", &[new_libc, curl]);
+
+        let old = store
+            .compute_closure(std::iter::once(old_app).collect())
+            .unwrap();
+        let new = store
+            .compute_closure(std::iter::once(new_app).collect())
+            .unwrap();
+
+        let diff = old.diff(&new);
+        let ids = |pkgs: &[PackageSummary]| pkgs.iter().map(|p| p.id).collect::<Vec<_>>();
+        assert_eq!(ids(&diff.added), vec![curl]);
+        assert_eq!(ids(&diff.removed), vec![zlib]);
+
+        let changes: Vec<_> = diff
+            .changed
+            .iter()
+            .map(|c| {
+                (
+                    c.stem.as_str(),
+                    c.old_version.as_deref(),
+                    c.old_id,
+                    c.new_id,
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("app", Some("1.0"), old_app, new_app),
+                ("libc", Some("2.31"), old_libc, new_libc),
+            ]
+        );
+
+        // "libc-2.31" and "libc-2.32" are the same length, so only the package object differs.
+        let libc = &diff.changed[1];
+        assert_eq!(libc.new_version.as_deref(), Some("2.32"));
+        let old_size = store.object_size(&old_libc, None).unwrap() as i64;
+        let new_size = store.object_size(&new_libc, None).unwrap() as i64;
+        assert_eq!(libc.size_delta, new_size - old_size);
+        assert_eq!(
+            diff.size_delta,
+            new.unpacked_size() as i64 - old.unpacked_size() as i64
+        );
+
+        let text = diff.to_string();
+        assert!(text.contains(&format!("- zlib-1.2.11-{}", zlib)));
+        assert!(text.contains(&format!("+ curl-7.74.0-{}", curl)));
+        assert!(text.contains(&format!(
+            "~ libc: 2.31 -> 2.32, {} -> {}",
+            old_libc, new_libc
+        )));
+
+        let json = serde_json::to_value(&diff).unwrap();
+        assert_eq!(json["added"][0]["name"], "curl-7.74.0");
+        assert_eq!(json["removed"][0]["id"], zlib.to_string());
+        assert_eq!(json["changed"][0]["new_version"], "1.1");
+
+        assert!(new.diff(&new).is_empty());
+    }
+}
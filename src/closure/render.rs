@@ -0,0 +1,194 @@
+//! Renderers which draw a closure as a diagram in various graph description formats.
+
+use std::fmt::{self, Display, Formatter};
+
+use super::Closure;
+use crate::ObjectKind;
+
+/// Returns `true` if objects of type `kind` should be drawn in a diagram.
+fn is_shown(kind: ObjectKind, show_content: bool) -> bool {
+    match kind {
+        ObjectKind::Blob | ObjectKind::ChunkedBlob | ObjectKind::Tree => show_content,
+        ObjectKind::Package | ObjectKind::Spec => true,
+    }
+}
+
+/// A closure rendered as a Graphviz DOT diagram, returned by [`Closure::render_dot()`].
+#[derive(Debug)]
+pub struct DotDiagram<'a> {
+    inner: &'a Closure,
+    show_content: bool,
+}
+
+impl<'a> DotDiagram<'a> {
+    pub(super) fn new(inner: &'a Closure, show_content: bool) -> Self {
+        DotDiagram {
+            inner,
+            show_content,
+        }
+    }
+}
+
+impl<'a> Display for DotDiagram<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "digraph G {{")?;
+
+        for (&(id, kind, _), children) in &self.inner.nodes {
+            if !is_shown(kind, self.show_content) {
+                continue;
+            }
+
+            let label = self.inner.label(id);
+            writeln!(f, r#"  "{}" [shape = box, label = "{}"]"#, id, label)?;
+
+            for &(child_id, kind, _) in children {
+                if is_shown(kind, self.show_content) && child_id != id {
+                    writeln!(f, r#"  "{}" -> "{}""#, child_id, id)?;
+                }
+            }
+        }
+
+        write!(f, "}}")
+    }
+}
+
+/// A closure rendered as a GraphML document, returned by [`Closure::render_graphml()`].
+#[derive(Debug)]
+pub struct GraphMlDiagram<'a> {
+    inner: &'a Closure,
+    show_content: bool,
+}
+
+impl<'a> GraphMlDiagram<'a> {
+    pub(super) fn new(inner: &'a Closure, show_content: bool) -> Self {
+        GraphMlDiagram {
+            inner,
+            show_content,
+        }
+    }
+}
+
+impl<'a> Display for GraphMlDiagram<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            f,
+            r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+        )?;
+        writeln!(
+            f,
+            r#"  <key id="label" for="node" attr.name="label" attr.type="string"/>"#
+        )?;
+        writeln!(
+            f,
+            r#"  <key id="kind" for="node" attr.name="kind" attr.type="string"/>"#
+        )?;
+        writeln!(
+            f,
+            r#"  <key id="size" for="node" attr.name="size" attr.type="long"/>"#
+        )?;
+        writeln!(f, r#"  <graph id="G" edgedefault="directed">"#)?;
+
+        let shown = self
+            .inner
+            .nodes
+            .iter()
+            .filter(|(node, _)| is_shown(node.1, self.show_content));
+
+        for (&(id, kind, size), _) in shown.clone() {
+            writeln!(f, r#"    <node id="{}">"#, id)?;
+            writeln!(
+                f,
+                r#"      <data key="label">{}</data>"#,
+                XmlEscaped(&self.inner.label(id))
+            )?;
+            writeln!(f, r#"      <data key="kind">{}</data>"#, kind.as_str())?;
+            writeln!(f, r#"      <data key="size">{}</data>"#, size)?;
+            writeln!(f, "    </node>")?;
+        }
+
+        for (&(id, _, _), children) in shown {
+            for &(child_id, kind, _) in children {
+                if is_shown(kind, self.show_content) && child_id != id {
+                    writeln!(f, r#"    <edge source="{}" target="{}"/>"#, child_id, id)?;
+                }
+            }
+        }
+
+        writeln!(f, "  </graph>")?;
+        write!(f, "</graphml>")
+    }
+}
+
+/// Text which is escaped for use in XML character data and attribute values when displayed.
+struct XmlEscaped<'a>(&'a str);
+
+impl<'a> Display for XmlEscaped<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut rest = self.0;
+        while let Some(i) = rest.find(|c| "&<>\"'".contains(c)) {
+            f.write_str(&rest[..i])?;
+            f.write_str(match rest.as_bytes()[i] {
+                b'&' => "&amp;",
+                b'<' => "&lt;",
+                b'>' => "&gt;",
+                b'"' => "&quot;",
+                _ => "&apos;",
+            })?;
+            rest = &rest[i + 1..];
+        }
+
+        f.write_str(rest)
+    }
+}
+
+/// A closure rendered as a Mermaid flowchart, returned by [`Closure::render_mermaid()`].
+#[derive(Debug)]
+pub struct MermaidDiagram<'a> {
+    inner: &'a Closure,
+    show_content: bool,
+}
+
+impl<'a> MermaidDiagram<'a> {
+    pub(super) fn new(inner: &'a Closure, show_content: bool) -> Self {
+        MermaidDiagram {
+            inner,
+            show_content,
+        }
+    }
+}
+
+impl<'a> Display for MermaidDiagram<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "flowchart LR")?;
+
+        for (&(id, kind, _), children) in &self.inner.nodes {
+            if !is_shown(kind, self.show_content) {
+                continue;
+            }
+
+            // Node IDs are prefixed, since bare object IDs may be parsed as numbers.
+            write!(f, "\n  o{}[\"{}\"]", id, self.inner.label(id))?;
+
+            for &(child_id, kind, _) in children {
+                if is_shown(kind, self.show_content) && child_id != id {
+                    write!(f, "\n  o{} --> o{}", child_id, id)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_xml_text() {
+        let escaped = XmlEscaped(r#"a&b <c> "d" 'e'"#).to_string();
+        assert_eq!(escaped, "a&amp;b &lt;c&gt; &quot;d&quot; &apos;e&apos;");
+        assert_eq!(XmlEscaped("hello-1.0.0").to_string(), "hello-1.0.0");
+    }
+}
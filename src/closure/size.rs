@@ -0,0 +1,259 @@
+//! Size accounting for closures which takes shared objects into account.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::Serialize;
+
+use super::{Closure, Node};
+use crate::{Entry, ObjectId, ObjectKind, Objects, Package};
+
+impl Closure {
+    /// Computes the size of every package in the closure, in bytes.
+    ///
+    /// Objects shared between several packages, such as blobs hard-linked into more than one
+    /// package directory, are only counted once towards each total. See [`PackageSize`] for
+    /// details on what each figure measures.
+    ///
+    /// Returns `Err` if a package or tree object in the closure cannot be read from `objects`.
+    pub fn package_sizes<O>(&self, objects: &O) -> anyhow::Result<BTreeMap<ObjectId, PackageSize>>
+    where
+        O: Objects + ?Sized,
+    {
+        let unique = self.dominated_sizes();
+
+        self.nodes
+            .keys()
+            .filter(|&&(_, kind, _)| kind == ObjectKind::Package)
+            .map(|pkg| {
+                let size = PackageSize {
+                    own: self.own_size(pkg),
+                    closure: self.reachable(pkg).iter().map(|node| node.2).sum(),
+                    unique: unique[pkg],
+                    copied: copied_size(objects, &objects.get_package(pkg.0)?)?,
+                };
+                Ok((pkg.0, size))
+            })
+            .collect()
+    }
+
+    /// Returns the estimated on-disk size of the closure once every package is instantiated, in
+    /// bytes.
+    ///
+    /// This is the [`unpacked size`](Closure::unpacked_size) of the objects themselves, plus the
+    /// files which cannot be hard-linked into package directories. Blobs listed in
+    /// [`Package::self_references`] must be copied and patched once per package, and chunked
+    /// blobs are always reassembled into a new file.
+    ///
+    /// Returns `Err` if a package or tree object in the closure cannot be read from `objects`.
+    pub fn instantiated_size<O>(&self, objects: &O) -> anyhow::Result<u64>
+    where
+        O: Objects + ?Sized,
+    {
+        self.nodes
+            .keys()
+            .filter(|&&(_, kind, _)| kind == ObjectKind::Package)
+            .try_fold(self.unpacked_size, |total, &(id, _, _)| {
+                Ok(total + copied_size(objects, &objects.get_package(id)?)?)
+            })
+    }
+
+    /// Collects every node reachable from `start`, including itself.
+    fn reachable(&self, start: &Node) -> HashSet<Node> {
+        let mut visited = HashSet::new();
+        let mut stack = vec![start];
+
+        while let Some(node) = stack.pop() {
+            if visited.insert(*node) {
+                stack.extend(self.nodes.get(node).into_iter().flatten());
+            }
+        }
+
+        visited
+    }
+
+    /// Returns the total size of the nodes dominated by each node, including itself.
+    ///
+    /// A node dominates another if every path from a root of the closure to the other node passes
+    /// through it, so these are exactly the nodes which would become unreachable without it. The
+    /// dominator tree is built in a single pass over the nodes in topological order, since every
+    /// referrer of a node is visited before the node itself, and then summed up bottom-up.
+    fn dominated_sizes(&self) -> HashMap<Node, u64> {
+        let order: Vec<_> = self.sort_topological().into_iter().rev().collect();
+        let index: HashMap<_, _> = order.iter().enumerate().map(|(i, n)| (*n, i)).collect();
+
+        let mut referrers = vec![Vec::new(); order.len()];
+        for (node, children) in &self.nodes {
+            for child in children {
+                referrers[index[child]].push(index[node]);
+            }
+        }
+
+        // `None` stands for a virtual root which references every root of the closure.
+        let mut idom: Vec<Option<usize>> = vec![None; order.len()];
+        let mut depth = vec![0; order.len()];
+        let intersect = |idom: &[Option<usize>], depth: &[usize], a, b| {
+            let (mut a, mut b) = (Some(a), Some(b));
+            while a != b {
+                match (a, b) {
+                    (Some(x), Some(y)) if depth[x] >= depth[y] => a = idom[x],
+                    (Some(_), Some(y)) => b = idom[y],
+                    _ => return None,
+                }
+            }
+            a
+        };
+
+        for i in 0..order.len() {
+            let mut preds = referrers[i].iter().copied();
+            idom[i] = match preds.next() {
+                Some(first) => preds.try_fold(first, |a, b| intersect(&idom, &depth, a, b)),
+                None => None,
+            };
+            depth[i] = idom[i].map_or(0, |d| depth[d] + 1);
+        }
+
+        let mut sizes: Vec<_> = order.iter().map(|node| node.2).collect();
+        for i in (0..order.len()).rev() {
+            if let Some(d) = idom[i] {
+                sizes[d] += sizes[i];
+            }
+        }
+
+        order.into_iter().zip(sizes).collect()
+    }
+}
+
+/// Sums up the files which cannot be hard-linked when instantiating `pkg`.
+fn copied_size<O>(objects: &O, pkg: &Package) -> anyhow::Result<u64>
+where
+    O: Objects + ?Sized,
+{
+    let mut total = 0;
+    let mut stack = vec![pkg.tree];
+
+    // Trees are deliberately revisited, since every occurrence of a file is instantiated.
+    while let Some(id) = stack.pop() {
+        for entry in objects.get_tree(id)?.entries.values() {
+            match *entry {
+                Entry::Tree { id } => stack.push(id),
+                Entry::Blob { id } if pkg.self_references.contains_key(&id) => {
+                    total += objects.object_size(&id, Some(ObjectKind::Blob))?;
+                }
+                Entry::ChunkedBlob { id } => {
+                    let chunked = objects.get_chunked_blob(id)?;
+                    total += chunked.chunks.iter().map(|c| c.size).sum::<u64>();
+                }
+                Entry::Blob { .. } | Entry::Symlink { .. } => {}
+            }
+        }
+    }
+
+    Ok(total)
+}
+
+/// Sizes of a single package within a closure, returned by [`Closure::package_sizes()`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct PackageSize {
+    /// Size of the package along with its trees and blobs, excluding referenced packages.
+    pub own: u64,
+    /// Size of the package along with everything it references, directly or indirectly.
+    pub closure: u64,
+    /// Size of the objects which no other package in the closure needs.
+    ///
+    /// This is the amount of space that would be freed by removing the package from the closure.
+    pub unique: u64,
+    /// Size of the files which are copied rather than hard-linked when the package is
+    /// instantiated, as reported by [`Closure::instantiated_size()`].
+    pub copied: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{insert_package, insert_package_with, insert_tree};
+    use crate::{Blob, ContentAddressable, LocalStore, Object};
+
+    #[test]
+    fn counts_shared_and_copied_objects() {
+        let mut store = LocalStore::in_memory();
+
+        let (shared, _) = Blob::from_bytes(b"shared license text".to_vec(), false);
+        let shared_size = shared.size();
+        let shared_id = store.insert_object(Object::Blob(shared)).unwrap();
+
+        let placeholder = format!("/store/packages/hello-{}/bin/hello", ObjectId::zero());
+        let (script, _) = Blob::from_bytes(placeholder.into_bytes(), true);
+        let script_size = script.size();
+        let script_id = store.insert_object(Object::Blob(script)).unwrap();
+
+        let license = Entry::Blob { id: shared_id };
+        let lib_tree = insert_tree(&mut store, std::iter::once(("COPYING", license.clone())));
+        let lib = insert_package_with(&mut store, "lib", &[], lib_tree);
+
+        let entries = vec![
+            ("COPYING", license),
+            ("hello", Entry::Blob { id: script_id }),
+        ];
+        let hello_tree = insert_tree(&mut store, entries);
+        let hello = store
+            .insert_object(Object::Package(Package {
+                name: "hello".parse().unwrap(),
+                system: crate::Platform::host(),
+                references: std::iter::once(lib).collect(),
+                self_references: std::iter::once((script_id, std::iter::once(0).collect()))
+                    .collect(),
+                tree: hello_tree,
+            }))
+            .unwrap();
+
+        let closure = store
+            .compute_closure(std::iter::once(hello).collect())
+            .unwrap();
+        let sizes = closure.package_sizes(&store).unwrap();
+        let node_size = |id: ObjectId| closure.iter().find(|n| n.0 == id).unwrap().2;
+
+        // The license blob is shared with `hello`, so removing `lib` would not free it.
+        let lib_size = sizes[&lib];
+        assert_eq!(
+            lib_size.own,
+            node_size(lib) + node_size(lib_tree) + shared_size
+        );
+        assert_eq!(lib_size.closure, lib_size.own);
+        assert_eq!(lib_size.unique, node_size(lib) + node_size(lib_tree));
+        assert_eq!(lib_size.copied, 0);
+
+        let hello_size = sizes[&hello];
+        let hello_own = node_size(hello) + node_size(hello_tree) + shared_size + script_size;
+        assert_eq!(hello_size.own, hello_own);
+        assert_eq!(hello_size.closure, closure.unpacked_size());
+        assert_eq!(hello_size.unique, closure.unpacked_size());
+        assert_eq!(hello_size.copied, script_size);
+
+        assert_eq!(
+            closure.instantiated_size(&store).unwrap(),
+            closure.unpacked_size() + script_size
+        );
+    }
+
+    #[test]
+    fn counts_objects_behind_shared_packages_once() {
+        let mut store = LocalStore::in_memory();
+        let lib = insert_package(&mut store, "lib", &[]);
+        let left = insert_package(&mut store, "left", &[lib]);
+        let right = insert_package(&mut store, "right", &[lib]);
+        let app = insert_package(&mut store, "app", &[left, right]);
+
+        let closure = store
+            .compute_closure(std::iter::once(app).collect())
+            .unwrap();
+        let sizes = closure.package_sizes(&store).unwrap();
+
+        // `lib` is reached through both `left` and `right`, so only `app` owns it exclusively.
+        for pkg in &[lib, left, right] {
+            assert_eq!(sizes[pkg].unique, sizes[pkg].own);
+        }
+        assert_eq!(sizes[&left].closure, sizes[&left].own + sizes[&lib].own);
+        assert_eq!(sizes[&app].closure, closure.unpacked_size());
+        assert_eq!(sizes[&app].unique, closure.unpacked_size());
+    }
+}
@@ -1,27 +1,72 @@
 //! Functions for copying packages between stores.
 
-use std::collections::BTreeSet;
-use std::future::ready;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Formatter};
+use std::future::{ready, Future};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
+use anyhow::anyhow;
 use async_trait::async_trait;
-use futures::{try_join, FutureExt, StreamExt};
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::{future, ready, stream, try_join, FutureExt, StreamExt};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::sync::CancellationToken;
 
-use crate::pack::{PackStream, Progress};
-use crate::{Closure, ObjectId, ObjectKind};
+use crate::closure::Node;
+use crate::pack::{PackStream, PackagePhase, Progress};
+use crate::{Closure, ObjectId, ObjectKind, Signature};
 
 /// Copies `pkgs` and all their dependencies from `src` to `dest`.
 ///
 /// This will resolve the delta closure between the source and the destination and only synchronize
-/// objects that are missing on the destination.
+/// objects that are missing on the destination. Where possible, missing blobs are sent as binary
+/// deltas against similar blobs which the destination already holds.
+///
+/// If an earlier copy to `dst` was interrupted, the objects it already received and verified are
+/// not sent again, and the returned delta only lists what was still missing.
 ///
 /// If both `src` and `dst` are both remote hosts, the objects yielded by `src` will be routed
 /// through this host before being uploaded to `dst`. This is done for security reasons, where the
 /// credentials for both reside on the local machine. However, it can impose a performance penalty.
+///
+/// The whole delta is sent as a single pack. To stream several packs at once, use
+/// [`copy_closure_with()`] instead.
 pub async fn copy_closure<S, D, F>(
     src: &S,
     dst: &mut D,
     pkgs: BTreeSet<ObjectId>,
+    progress: F,
+) -> anyhow::Result<Delta>
+where
+    S: Source + ?Sized,
+    D: Destination + ?Sized,
+    F: FnMut(&Progress),
+{
+    copy_closure_with(src, dst, pkgs, &CopyOptions::default(), progress).await
+}
+
+/// Copies `pkgs` and all their dependencies from `src` to `dest`, as configured by `options`.
+///
+/// This behaves like [`copy_closure()`], except that the delta is split into independent packs
+/// with [`Delta::split()`], and up to [`CopyOptions::concurrency`] of them are streamed at once.
+/// The packages are only sent once the content they reference has landed at the destination, so
+/// a package is never inserted before everything it references.
+///
+/// If [`CopyOptions::cancel`] is cancelled, the sender stops and the destination is left to read
+/// the packs up to where they were cut off, so that it keeps what it verified for a later resume
+/// and publishes nothing. Each step which makes no progress for longer than
+/// [`CopyOptions::timeout`] is aborted.
+///
+/// Returns `Err` holding a [`CopyError`] if the copy was cancelled or timed out.
+pub async fn copy_closure_with<S, D, F>(
+    src: &S,
+    dst: &mut D,
+    pkgs: BTreeSet<ObjectId>,
+    options: &CopyOptions,
     mut progress: F,
 ) -> anyhow::Result<Delta>
 where
@@ -29,25 +74,177 @@ where
     D: Destination + ?Sized,
     F: FnMut(&Progress),
 {
-    let delta = src.find_missing(dst, pkgs).await?;
+    let mut delta = step(options, src.find_missing(dst, pkgs)).await?;
+    delta.skip_received(&step(options, dst.checkpoint()).await?);
+
+    progress(&Progress::Started {
+        num_objects: delta.missing.num_objects() as u64,
+        num_packages: delta.missing.num_packages() as u64,
+        unpacked_size: delta.missing.unpacked_size(),
+    });
+
+    let max_packs = options.concurrency.min(dst.max_concurrent_packs());
+    for stage in delta.split(max_packs) {
+        if options.cancel.is_cancelled() {
+            return Err(CopyError::Cancelled.into());
+        }
 
-    let (reader, mut writer) = tokio::io::duplex(8 * 1024);
-    let (mut reader, progress_rx) = PackStream::new(reader);
+        // Every sender of progress events is dropped once its future completes, which ends the
+        // stream of progress events for this stage.
+        let (progress_tx, progress_rx) = mpsc::unbounded();
+        let mut readers = Vec::with_capacity(stage.len());
+        let mut writers = Vec::with_capacity(stage.len());
+        let mut progress_rxs = vec![progress_rx];
+        for _ in &stage {
+            let (reader, writer) = tokio::io::duplex(8 * 1024);
+            let (reader, progress_rx) = PackStream::new(reader);
+            readers.push(reader);
+            writers.push(ProgressWriter::new(writer, progress_tx.clone()));
+            progress_rxs.push(progress_rx);
+        }
 
-    let send = src.send_pack(&delta.missing, &mut writer);
-    let recv = dst.recv_pack(&mut reader);
-    let progress = progress_rx.for_each(|p| ready(progress(&p))).map(Ok);
+        let sending = future::try_join_all(
+            stage
+                .iter()
+                .zip(writers)
+                .map(|(part, mut writer)| async move { src.send_pack(part, &mut writer).await }),
+        );
+        let send = async {
+            // Dropping the writers cuts the packs short, which the destination then fails to read.
+            tokio::select! {
+                sent = sending => sent.map(drop),
+                _ = options.cancel.cancelled() => Ok(()),
+            }
+        };
+        let recv = async {
+            let report = move |p: &Progress| drop(progress_tx.unbounded_send(p.clone()));
+            let received = dst.recv_packs(readers, &delta.signatures, &report).await;
+            drop(report);
+            received
+        };
+        let last_progress = Cell::new(tokio::time::Instant::now());
+        let progress = stream::select_all(progress_rxs)
+            .for_each(|p| {
+                last_progress.set(tokio::time::Instant::now());
+                progress(&p);
+                ready(())
+            })
+            .map(Ok);
 
-    try_join!(send, recv, progress)?;
+        let transfer = async { try_join!(send, recv, progress).map(drop) };
+        match with_timeout(options, &last_progress, transfer).await {
+            Err(_) if options.cancel.is_cancelled() => return Err(CopyError::Cancelled.into()),
+            result => result?,
+        }
+    }
 
     Ok(delta)
 }
 
+/// Runs `fut` as a single step of a copy, which is aborted if it is cancelled or times out.
+async fn step<T, F>(options: &CopyOptions, fut: F) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let fut = async {
+        tokio::select! {
+            result = fut => result,
+            _ = options.cancel.cancelled() => Err(CopyError::Cancelled.into()),
+        }
+    };
+
+    let started = Cell::new(tokio::time::Instant::now());
+    with_timeout(options, &started, fut).await
+}
+
+/// Runs `fut`, aborting it once `last_progress` lies further in the past than the timeout in
+/// `options`.
+///
+/// The timer is reset whenever `fut` moves `last_progress` forward, so a slow transfer which keeps
+/// making progress is never aborted.
+async fn with_timeout<T, F>(
+    options: &CopyOptions,
+    last_progress: &Cell<tokio::time::Instant>,
+    fut: F,
+) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let limit = match options.timeout {
+        Some(limit) => limit,
+        None => return fut.await,
+    };
+
+    let stalled = async {
+        loop {
+            let deadline = last_progress.get() + limit;
+            if tokio::time::Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    };
+
+    tokio::select! {
+        result = fut => result,
+        _ = stalled => Err(CopyError::TimedOut.into()),
+    }
+}
+
+/// Options controlling how [`copy_closure_with()`] transfers a closure.
+#[derive(Clone, Debug, Default)]
+pub struct CopyOptions {
+    /// Maximum number of packs streamed at once. The default of 0 is treated like 1.
+    ///
+    /// The destination may lower this limit with [`Destination::max_concurrent_packs()`].
+    pub concurrency: usize,
+    /// Token which stops the copy once it is cancelled.
+    pub cancel: CancellationToken,
+    /// Longest time each step of the copy may go without making progress, or `None` to wait
+    /// indefinitely.
+    ///
+    /// The steps are resolving the delta, querying the destination for a checkpoint, and
+    /// transferring each stage of packs. A transfer makes progress whenever pack data is written
+    /// or read, or a package changes phase, so only a stalled transfer times out. The other steps
+    /// report no progress, so they must finish within the timeout as a whole.
+    pub timeout: Option<Duration>,
+}
+
+/// A reason why [`copy_closure_with()`] stopped before the copy was complete.
+///
+/// This error is returned inside an `anyhow::Error`, from which it can be recovered with
+/// `downcast_ref()`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CopyError {
+    /// The copy was stopped through [`CopyOptions::cancel`].
+    Cancelled,
+    /// A step of the copy made no progress for longer than [`CopyOptions::timeout`].
+    TimedOut,
+}
+
+impl Display for CopyError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CopyError::Cancelled => f.write_str("copy was cancelled"),
+            CopyError::TimedOut => f.write_str("copy timed out"),
+        }
+    }
+}
+
+impl std::error::Error for CopyError {}
+
 /// A source repository to copy from.
 #[async_trait(?Send)]
 pub trait Source {
     /// Computes a delta closure which only contains objects that are missing at the destination.
     ///
+    /// Implementers _may_ also pick a base blob for some of the missing blobs, which is known to
+    /// exist at the destination according to [`Destination::contains()`]. These are recorded in
+    /// [`Delta::bases`] so that [`Source::send_pack()`] can emit them as delta entries.
+    ///
+    /// Implementers _should_ include every signature they hold for the missing packages in
+    /// [`Delta::signatures`], so that the destination can decide whether to trust them.
+    ///
     /// Returns `Err` if any of the given object IDs do not exist in this store, any of the object
     /// IDs do not refer to a `Package` object, a cycle or structural inconsistency is detected in
     /// the reference graph, or an I/O error occurred.
@@ -55,7 +252,12 @@ pub trait Source {
     where
         D: Destination + ?Sized;
 
-    /// Writes the objects in the closure as a pack file and sends it over the `writer`.
+    /// Writes the objects in the delta closure as a pack file and sends it over the `writer`.
+    ///
+    /// Blobs listed in [`Delta::bases`] _may_ be written as delta entries against their base,
+    /// producing a thin pack which can only be read back by a
+    /// [`ThinPackReader`](crate::pack::ThinPackReader). Implementers which do not support delta
+    /// entries can ignore the bases and write every object in [`Delta::missing`] in full.
     ///
     /// Elements _must_ be yielded in topological order for the pack to be considered valid. This
     /// ordering is important because it ensures objects and packages can be inserted into stores
@@ -63,7 +265,7 @@ pub trait Source {
     ///
     /// Returns `Err` if any of the object IDs do not actually exist in this store, or an I/O error
     /// occurred.
-    async fn send_pack<W>(&self, closure: &Closure, writer: &mut W) -> anyhow::Result<()>
+    async fn send_pack<W>(&self, delta: &Delta, writer: &mut W) -> anyhow::Result<()>
     where
         W: AsyncWrite + Unpin;
 }
@@ -78,20 +280,156 @@ pub trait Destination {
     /// to locate and retrieve the object faster. Otherwise, callers can specify `None` and the
     /// repository will attempt to guess the desired object type, if it is not immediately known.
     ///
-    /// Returns `Err` if an I/O error occurred.
-    fn contains(&self, id: &ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<bool>;
+    /// This method is `async` so that repositories on another host can be queried without blocking
+    /// the caller.
+    ///
+    /// Returns `Err` if the repository could not be queried or an I/O error occurred.
+    async fn contains(&self, id: &ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<bool>;
+
+    /// Returns whether the repository contains each of the `objects`, in the same order, as
+    /// [`Destination::contains()`] would report for each one.
+    ///
+    /// Repositories on another host _should_ answer all of them in a single round trip. The
+    /// default implementation calls `Destination::contains()` for each object in turn.
+    ///
+    /// Returns `Err` if the repository could not be queried or an I/O error occurred.
+    async fn contains_many(
+        &self,
+        objects: &[(ObjectId, Option<ObjectKind>)],
+    ) -> anyhow::Result<Vec<bool>> {
+        let mut present = Vec::with_capacity(objects.len());
+        for (id, kind) in objects {
+            present.push(self.contains(id, *kind).await?);
+        }
+
+        Ok(present)
+    }
+
+    /// Returns the IDs of objects received by an earlier [`Destination::recv_pack()`] which was
+    /// interrupted, and which are still being held for the transfer to resume.
+    ///
+    /// These objects do not need to be sent again, even though [`Destination::contains()`] does
+    /// not report them until the transfer completes. The default implementation holds nothing.
+    ///
+    /// Returns `Err` if the held objects could not be listed.
+    async fn checkpoint(&self) -> anyhow::Result<BTreeSet<ObjectId>> {
+        Ok(BTreeSet::new())
+    }
+
+    /// Returns the number of packs which [`Destination::recv_packs()`] can usefully receive at
+    /// once. The default implementation returns 1, since it receives packs one after another.
+    fn max_concurrent_packs(&self) -> usize {
+        1
+    }
 
     /// Copies the packfile stream from `reader` to the destination.
     ///
+    /// The pack may be thin, containing delta entries against base blobs which the destination
+    /// reported to contain.
+    ///
+    /// The `signatures` of the packages in the pack are sent along with it. Implementers which
+    /// instantiate packages _should_ refuse any package which is not signed by a key they trust.
+    ///
     /// Elements _must_ be yielded in topological order for the pack to be considered valid. This
     /// ordering is important because it ensures objects and packages can be inserted into stores
     /// in a consistent order, where all references are inserted before their referrers.
     ///
     /// Returns `Err` if the pack stream could not be decoded, the yielded objects were not sorted
-    /// in topological order, or an I/O error occurred.
-    async fn recv_pack<R>(&mut self, reader: &mut R) -> anyhow::Result<()>
+    /// in topological order, a package was refused, or an I/O error occurred.
+    async fn recv_pack<R>(
+        &mut self,
+        reader: &mut R,
+        signatures: &BTreeMap<ObjectId, BTreeSet<Signature>>,
+    ) -> anyhow::Result<()>
     where
         R: AsyncRead + Unpin;
+
+    /// Copies several packfile streams from `readers` to the destination at once.
+    ///
+    /// No object in one of the packs may reference an object in another, as produced by
+    /// [`Delta::split()`], so that their objects can be inserted in any interleaving. The
+    /// `signatures` cover the packages in every pack.
+    ///
+    /// Implementers _may_ report the phases of each received package by calling `progress` with a
+    /// [`Progress::Package`] event, as soon as each phase begins.
+    ///
+    /// The default implementation receives the packs one after another with
+    /// [`Destination::recv_pack()`], and does not report any progress.
+    ///
+    /// Returns `Err` if any of the packs could not be received, for the same reasons as
+    /// [`Destination::recv_pack()`].
+    async fn recv_packs<R>(
+        &mut self,
+        readers: Vec<R>,
+        signatures: &BTreeMap<ObjectId, BTreeSet<Signature>>,
+        _progress: &dyn for<'p> Fn(&'p Progress),
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        for mut reader in readers {
+            self.recv_pack(&mut reader, signatures).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Walks the reference graph downward from `roots`, asking `dst` whether it contains each object.
+///
+/// The graph is walked one level at a time, and `dst` is asked about every object on a level at
+/// once with [`Destination::contains_many()`], so a remote destination costs one round trip per
+/// level rather than per object.
+///
+/// Objects which `dst` already contains are pruned from the walk along with everything beneath
+/// them, and `children` is only called for objects which are missing. This lets a [`Source`]
+/// resolve a delta closure without asking about objects that are known to be present.
+///
+/// Returns the number of objects found to be present and the IDs of the missing objects, or `Err`
+/// if `children` failed or `dst` could not be queried.
+pub(crate) async fn find_missing_ids<D, F>(
+    dst: &D,
+    roots: Vec<Node>,
+    mut children: F,
+) -> anyhow::Result<(usize, BTreeSet<ObjectId>)>
+where
+    D: Destination + ?Sized,
+    F: FnMut(&Node) -> anyhow::Result<Vec<Node>>,
+{
+    let mut num_present = 0;
+    let mut missing = BTreeSet::new();
+    let mut visited = BTreeSet::new();
+    let mut level = roots;
+
+    while !level.is_empty() {
+        level.retain(|node| visited.insert(node.0));
+        let query: Vec<_> = level
+            .iter()
+            .map(|&(id, kind, _)| (id, Some(kind)))
+            .collect();
+        let present = dst.contains_many(&query).await?;
+        if present.len() != query.len() {
+            return Err(anyhow!(
+                "destination answered {} of {} queries",
+                present.len(),
+                query.len()
+            ));
+        }
+
+        let mut next = Vec::new();
+        for (node, present) in level.into_iter().zip(present) {
+            if present {
+                num_present += 1;
+            } else {
+                missing.insert(node.0);
+                next.extend(children(&node)?);
+            }
+        }
+
+        level = next;
+    }
+
+    Ok((num_present, missing))
 }
 
 /// A partial closure describing the delta between two package stores.
@@ -103,4 +441,586 @@ pub struct Delta {
     pub num_present: usize,
     /// Closure of objects known to be missing on the destination.
     pub missing: Closure,
+    /// Missing blobs which may be sent as deltas, mapped to a base blob held by the destination.
+    pub bases: BTreeMap<ObjectId, ObjectId>,
+    /// Signatures of the missing packages, sent along with the pack.
+    pub signatures: BTreeMap<ObjectId, BTreeSet<Signature>>,
+}
+
+impl Delta {
+    /// Creates a delta with the objects in `missing`, without any delta bases or signatures.
+    ///
+    /// This is all a [`Source`] needs to return from [`Source::find_missing()`] if it neither
+    /// sends delta entries nor holds signatures.
+    pub fn new(num_present: usize, missing: Closure) -> Self {
+        Delta {
+            num_present,
+            missing,
+            bases: BTreeMap::new(),
+            signatures: BTreeMap::new(),
+        }
+    }
+
+    /// Removes the objects in `received` from the delta, along with any delta bases chosen for
+    /// them, so that a transfer can be resumed from a [`Destination::checkpoint()`].
+    ///
+    /// Signatures are kept for every package, since the destination checks them again once the
+    /// transfer completes.
+    pub fn skip_received(&mut self, received: &BTreeSet<ObjectId>) {
+        if received.is_empty() {
+            return;
+        }
+
+        let remaining = self
+            .missing
+            .iter()
+            .map(|&(id, _, _)| id)
+            .filter(|id| !received.contains(id))
+            .collect();
+
+        self.missing = self.missing.subgraph(&remaining);
+        self.bases.retain(|id, _| remaining.contains(id));
+    }
+
+    /// Splits the delta into stages of independent deltas, each holding at most `max_packs`.
+    ///
+    /// The first stage holds the missing content objects, grouped by the connected subgraphs
+    /// they form, such as the trees and blobs of unrelated packages. No delta references another
+    /// delta in the same stage, so their packs can be sent at once. The second stage holds every
+    /// missing package in a single delta, which must only be sent once the first stage has landed
+    /// at the destination. Packages are small, so however deep their dependency chains run, they
+    /// are sent in one pack in topological order rather than one stage per level of depth.
+    ///
+    /// Within the first stage, the groups are spread over the deltas to balance their unpacked
+    /// sizes. If `max_packs` is 1 or less, or nothing is missing, the whole delta is returned as a
+    /// single stage, so there is always at least one stage to send.
+    pub fn split(&self, max_packs: usize) -> Vec<Vec<Delta>> {
+        if max_packs <= 1 || self.missing.num_objects() == 0 {
+            let all = self.missing.iter().map(|&(id, _, _)| id).collect();
+            return vec![vec![self.part(&all)]];
+        }
+
+        // Content objects never reference packages, so they can all be sent before any package.
+        let is_content = |node: &Node| !matches!(node.1, ObjectKind::Package | ObjectKind::Spec);
+        let graph = self.missing.graph();
+        let packages: BTreeSet<_> = graph
+            .keys()
+            .filter(|node| !is_content(node))
+            .map(|node| node.0)
+            .collect();
+
+        // Group the content objects into the connected subgraphs they form.
+        let mut neighbors: BTreeMap<Node, Vec<Node>> = BTreeMap::new();
+        for (node, children) in graph.iter().filter(|(node, _)| is_content(node)) {
+            for child in children {
+                neighbors.entry(*node).or_default().push(*child);
+                neighbors.entry(*child).or_default().push(*node);
+            }
+        }
+
+        let mut groups = Vec::new();
+        let mut visited = BTreeSet::new();
+        for &node in graph.keys().filter(|node| is_content(node)) {
+            let mut group = Vec::new();
+            let mut stack = vec![node];
+            while let Some(node) = stack.pop() {
+                if visited.insert(node) {
+                    group.push(node);
+                    stack.extend(neighbors.get(&node).into_iter().flatten().copied());
+                }
+            }
+
+            if !group.is_empty() {
+                groups.push(group);
+            }
+        }
+
+        // Assign the largest groups first, each to the part with the least data so far.
+        groups.sort_by_key(|g| std::cmp::Reverse(g.iter().map(|n| n.2).sum::<u64>()));
+        let mut parts = vec![(0, BTreeSet::new()); max_packs.min(groups.len())];
+        for group in groups {
+            let (size, ids) = parts.iter_mut().min_by_key(|(size, _)| *size).unwrap();
+            *size += group.iter().map(|n| n.2).sum::<u64>();
+            ids.extend(group.iter().map(|n| n.0));
+        }
+
+        let content = parts.iter().map(|(_, ids)| self.part(ids)).collect();
+        let packages = std::iter::once(&packages)
+            .filter(|ids| !ids.is_empty())
+            .map(|ids| self.part(ids))
+            .collect();
+
+        vec![content, packages]
+            .into_iter()
+            .filter(|stage: &Vec<Delta>| !stage.is_empty())
+            .collect()
+    }
+
+    /// Returns the part of the delta which only contains the objects listed in `ids`.
+    fn part(&self, ids: &BTreeSet<ObjectId>) -> Delta {
+        Delta {
+            num_present: 0,
+            missing: self.missing.subgraph(ids),
+            bases: self
+                .bases
+                .iter()
+                .filter(|(id, _)| ids.contains(id))
+                .map(|(&id, &base)| (id, base))
+                .collect(),
+            signatures: self
+                .signatures
+                .iter()
+                .filter(|(id, _)| ids.contains(id))
+                .map(|(&id, sigs)| (id, sigs.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Writer which reports the number of bytes written through it as [`Progress::Written`] events.
+struct ProgressWriter<W> {
+    inner: W,
+    progress: UnboundedSender<Progress>,
+}
+
+impl<W> ProgressWriter<W> {
+    fn new(inner: W, progress: UnboundedSender<Progress>) -> Self {
+        ProgressWriter { inner, progress }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ProgressWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        let bytes = written as u64;
+        self.progress
+            .unbounded_send(Progress::Written { bytes })
+            .ok();
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Keeps track of the [`Progress`] events of a copy, to estimate its throughput and the time left.
+///
+/// Progress is measured by the unpacked size of the objects received so far, against the total
+/// announced by [`Progress::Started`]. Since each object is counted as soon as it begins, large
+/// objects make the estimate coarser, though large files are usually split into chunks anyway.
+///
+/// # Example
+///
+/// ```no_run
+/// # async fn example(src: &foo::LocalStore, dst: &mut foo::LocalStore) -> anyhow::Result<()> {
+/// use foo::copy::{copy_closure, ProgressTracker};
+///
+/// let mut tracker = ProgressTracker::new();
+/// copy_closure(src, dst, Default::default(), |p| {
+///     tracker.update(p);
+///     println!("{:.0} B/s, {:?} left", tracker.throughput(), tracker.eta());
+/// })
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ProgressTracker {
+    started: Instant,
+    total_bytes: u64,
+    received_bytes: u64,
+    total_packages: u64,
+    installed_packages: u64,
+}
+
+impl ProgressTracker {
+    /// Creates a new `ProgressTracker`, which starts measuring time right away.
+    pub fn new() -> Self {
+        ProgressTracker {
+            started: Instant::now(),
+            total_bytes: 0,
+            received_bytes: 0,
+            total_packages: 0,
+            installed_packages: 0,
+        }
+    }
+
+    /// Records the progress event `p`.
+    pub fn update(&mut self, p: &Progress) {
+        match *p {
+            Progress::Started {
+                num_packages,
+                unpacked_size,
+                ..
+            } => {
+                self.started = Instant::now();
+                self.total_bytes = unpacked_size;
+                self.total_packages = num_packages;
+            }
+            Progress::Begin { size, .. } => self.received_bytes += size,
+            Progress::Package {
+                phase: PackagePhase::Installed,
+                ..
+            } => self.installed_packages += 1,
+            _ => {}
+        }
+    }
+
+    /// Returns the estimated unpacked size of the objects received so far, in bytes.
+    pub fn received_bytes(&self) -> u64 {
+        self.received_bytes
+    }
+
+    /// Returns the estimated unpacked size of every object to be received, in bytes.
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    /// Returns the number of packages installed at the destination so far.
+    pub fn installed_packages(&self) -> u64 {
+        self.installed_packages
+    }
+
+    /// Returns the number of packages to be installed at the destination.
+    pub fn total_packages(&self) -> u64 {
+        self.total_packages
+    }
+
+    /// Returns the average number of bytes received per second so far.
+    pub fn throughput(&self) -> f64 {
+        self.throughput_after(self.started.elapsed())
+    }
+
+    /// Returns the estimated time until every object has been received, or `None` if nothing has
+    /// been received yet.
+    pub fn eta(&self) -> Option<Duration> {
+        self.eta_after(self.started.elapsed())
+    }
+
+    fn throughput_after(&self, elapsed: Duration) -> f64 {
+        match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.received_bytes as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    fn eta_after(&self, elapsed: Duration) -> Option<Duration> {
+        let remaining = self.total_bytes.saturating_sub(self.received_bytes);
+        match self.throughput_after(elapsed) {
+            _ if remaining == 0 => Some(Duration::from_secs(0)),
+            rate if rate > 0.0 => Some(Duration::from_secs_f64(remaining as f64 / rate)),
+            _ => None,
+        }
+    }
+}
+
+impl Default for ProgressTracker {
+    fn default() -> Self {
+        ProgressTracker::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    use crate::test_support::{insert_package, insert_package_of_blobs};
+    use crate::{LocalStore, Objects, TrustPolicy};
+
+    /// Destination which never answers any query.
+    struct Stalled;
+
+    #[async_trait(?Send)]
+    impl Destination for Stalled {
+        async fn contains(&self, _: &ObjectId, _: Option<ObjectKind>) -> anyhow::Result<bool> {
+            future::pending().await
+        }
+
+        async fn recv_pack<R>(
+            &mut self,
+            _: &mut R,
+            _: &BTreeMap<ObjectId, BTreeSet<Signature>>,
+        ) -> anyhow::Result<()>
+        where
+            R: AsyncRead + Unpin,
+        {
+            unreachable!("no pack is ever sent")
+        }
+    }
+
+    /// Destination which holds nothing, and reads packs in small pieces with a pause after each.
+    struct Slow {
+        pause: Duration,
+    }
+
+    #[async_trait(?Send)]
+    impl Destination for Slow {
+        async fn contains(&self, _: &ObjectId, _: Option<ObjectKind>) -> anyhow::Result<bool> {
+            Ok(false)
+        }
+
+        async fn recv_pack<R>(
+            &mut self,
+            reader: &mut R,
+            _: &BTreeMap<ObjectId, BTreeSet<Signature>>,
+        ) -> anyhow::Result<()>
+        where
+            R: AsyncRead + Unpin,
+        {
+            let mut buf = [0u8; 4096];
+            while reader.read(&mut buf).await? > 0 {
+                tokio::time::sleep(self.pause).await;
+            }
+            Ok(())
+        }
+    }
+
+    /// Destination which holds nothing, and counts how many times it was queried.
+    #[derive(Default)]
+    struct Empty {
+        queries: Cell<usize>,
+    }
+
+    #[async_trait(?Send)]
+    impl Destination for Empty {
+        async fn contains(&self, _: &ObjectId, _: Option<ObjectKind>) -> anyhow::Result<bool> {
+            unreachable!("objects are only queried in batches")
+        }
+
+        async fn contains_many(
+            &self,
+            objects: &[(ObjectId, Option<ObjectKind>)],
+        ) -> anyhow::Result<Vec<bool>> {
+            self.queries.set(self.queries.get() + 1);
+            Ok(vec![false; objects.len()])
+        }
+
+        async fn recv_pack<R>(
+            &mut self,
+            _: &mut R,
+            _: &BTreeMap<ObjectId, BTreeSet<Signature>>,
+        ) -> anyhow::Result<()>
+        where
+            R: AsyncRead + Unpin,
+        {
+            unreachable!("no pack is ever sent")
+        }
+    }
+
+    #[tokio::test]
+    async fn queries_destination_once_per_level() {
+        let mut src = LocalStore::in_memory();
+        let lib = insert_package(&mut src, "lib", &[]);
+        let app = insert_package(&mut src, "app", &[lib]);
+
+        // Levels: `app`, then `lib` and the tree of `app`, then the tree of `lib` and `app.txt`, and
+        // finally `lib.txt`.
+        let dst = Empty::default();
+        let delta = src
+            .find_missing(&dst, std::iter::once(app).collect())
+            .await
+            .unwrap();
+        assert_eq!(dst.queries.get(), 4);
+        assert_eq!(delta.num_present, 0);
+        assert_eq!(delta.missing.num_objects(), 6);
+        assert_eq!(delta.missing.num_packages(), 2);
+        assert_eq!(delta.missing.package_name(lib).unwrap().to_string(), "lib");
+    }
+
+    #[tokio::test]
+    async fn cancels_copy_without_publishing() {
+        let mut src = LocalStore::in_memory();
+        let pkg = insert_package_of_blobs(&mut src, "big", 8);
+        let pkgs: BTreeSet<_> = std::iter::once(pkg).collect();
+
+        let mut dst = LocalStore::in_memory();
+        dst.set_trust_policy(TrustPolicy {
+            accept_unsigned: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let options = CopyOptions::default();
+        let cancel = options.cancel.clone();
+        let error = copy_closure_with(&src, &mut dst, pkgs.clone(), &options, |p| {
+            if let Progress::Begin { .. } = p {
+                cancel.cancel();
+            }
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&CopyError::Cancelled));
+
+        for &(id, kind, _) in src.compute_closure(pkgs.clone()).unwrap().iter() {
+            assert!(!dst.contains_object(&id, Some(kind)).unwrap());
+        }
+
+        let delta = copy_closure(&src, &mut dst, pkgs, |_| {}).await.unwrap();
+        assert!(delta.missing.num_objects() > 0);
+        assert!(dst
+            .contains_object(&pkg, Some(ObjectKind::Package))
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn times_out_stalled_destination() {
+        let mut src = LocalStore::in_memory();
+        let pkg = insert_package_of_blobs(&mut src, "big", 8);
+        let pkgs: BTreeSet<_> = std::iter::once(pkg).collect();
+
+        let options = CopyOptions {
+            timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let error = copy_closure_with(&src, &mut Stalled, pkgs, &options, |_| {})
+            .await
+            .unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&CopyError::TimedOut));
+    }
+
+    #[tokio::test]
+    async fn keeps_slow_transfers_alive_while_they_progress() {
+        let mut src = LocalStore::in_memory();
+        let pkg = insert_package_of_blobs(&mut src, "big", 4);
+        let pkgs: BTreeSet<_> = std::iter::once(pkg).collect();
+
+        // The whole transfer takes several times longer than the timeout.
+        let options = CopyOptions {
+            timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let mut dst = Slow {
+            pause: Duration::from_millis(5),
+        };
+        let started = Instant::now();
+        copy_closure_with(&src, &mut dst, pkgs, &options, |_| {})
+            .await
+            .unwrap();
+        assert!(started.elapsed() > Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn publishes_fully_received_copy_with_parallel_packs() {
+        let mut src = LocalStore::in_memory();
+        let foo = insert_package(&mut src, "foo", &[]);
+        let pkgs: BTreeSet<_> = std::iter::once(foo).collect();
+
+        let mut dst = LocalStore::in_memory();
+        dst.set_trust_policy(TrustPolicy {
+            accept_unsigned: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        // Every object arrives, but the transfer is cut off right before the end of the pack.
+        let delta = src.find_missing(&dst, pkgs.clone()).await.unwrap();
+        let mut pack = Vec::new();
+        src.send_pack(&delta, &mut pack).await.unwrap();
+        let truncated = &pack[..pack.len() - 1];
+        assert!(dst
+            .recv_pack(&mut &truncated[..], &delta.signatures)
+            .await
+            .is_err());
+        assert_eq!(dst.checkpoint().await.unwrap().len(), 3);
+
+        // Nothing is left to send, but the held objects must still be published.
+        let options = CopyOptions {
+            concurrency: 2,
+            ..Default::default()
+        };
+        let delta = copy_closure_with(&src, &mut dst, pkgs, &options, |_| {})
+            .await
+            .unwrap();
+        assert_eq!(delta.missing.num_objects(), 0);
+
+        let pkg = dst.get_package(foo).unwrap();
+        assert!(dst.installed_package(&pkg.install_name()).is_some());
+        assert!(dst.checkpoint().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn estimates_throughput_and_eta() {
+        let mut tracker = ProgressTracker::new();
+        tracker.update(&Progress::Started {
+            num_objects: 4,
+            num_packages: 1,
+            unpacked_size: 4000,
+        });
+        assert_eq!(tracker.eta_after(Duration::from_secs(1)), None);
+
+        let id = ObjectId::zero();
+        let kind = ObjectKind::Blob;
+        tracker.update(&Progress::Begin {
+            id,
+            kind,
+            size: 1000,
+        });
+        assert_eq!(tracker.received_bytes(), 1000);
+        assert_eq!(tracker.throughput_after(Duration::from_secs(2)), 500.0);
+        assert_eq!(
+            tracker.eta_after(Duration::from_secs(2)),
+            Some(Duration::from_secs(6))
+        );
+
+        tracker.update(&Progress::Begin {
+            id,
+            kind,
+            size: 3000,
+        });
+        let phase = PackagePhase::Installed;
+        tracker.update(&Progress::Package { id, phase });
+        assert_eq!(tracker.installed_packages(), tracker.total_packages());
+        assert_eq!(
+            tracker.eta_after(Duration::from_secs(3)),
+            Some(Duration::from_secs(0))
+        );
+    }
+
+    #[tokio::test]
+    async fn copies_closure_in_parallel_packs() {
+        let mut src = LocalStore::in_memory();
+        let libc = insert_package(&mut src, "libc", &[]);
+        let foo = insert_package(&mut src, "foo", &[libc]);
+        let bar = insert_package(&mut src, "bar", &[libc]);
+        let app = insert_package(&mut src, "app", &[foo, bar]);
+
+        let mut dst = LocalStore::in_memory();
+        dst.set_trust_policy(TrustPolicy {
+            accept_unsigned: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let pkgs: BTreeSet<_> = std::iter::once(app).collect();
+        let delta = src.find_missing(&dst, pkgs.clone()).await.unwrap();
+        let stages = delta.split(4);
+        let sizes: Vec<_> = stages.iter().map(|stage| stage.len()).collect();
+        assert_eq!(sizes, [4, 1]);
+        assert_eq!(stages[1][0].missing.num_packages(), 4);
+
+        let options = CopyOptions {
+            concurrency: 4,
+            ..Default::default()
+        };
+        let delta = copy_closure_with(&src, &mut dst, pkgs.clone(), &options, |_| {})
+            .await
+            .unwrap();
+        assert_eq!(delta.missing.num_objects(), 12);
+
+        for &id in &[libc, foo, bar, app] {
+            let pkg = dst.get_package(id).unwrap();
+            assert!(dst.installed_package(&pkg.install_name()).is_some());
+        }
+        assert_eq!(dst.compute_closure(pkgs).unwrap().num_objects(), 12);
+    }
 }
@@ -1,28 +1,50 @@
 //! Prototype content-addressable Nix-like store backed by a Merkle tree.
 
-pub use self::closure::Closure;
-pub use self::copy::copy_closure;
-pub use self::local::{Backend, LocalStore};
+pub use self::archive::{export_closure, import_closure};
+pub use self::cache::{BinaryCache, HttpSource};
+pub use self::closure::{Closure, ClosureDiff, Hop, PackageChange, PackageSize, PackageSummary};
+pub use self::copy::{copy_closure, copy_closure_with};
+pub use self::local::{
+    Backend, Filesystem, FsckReport, GcReport, LocalStore, MemEntry, Memory, Mismatch, Problem,
+    RepackReport, RepairReport,
+};
 pub use self::object::*;
+pub use self::remote::RemoteStore;
+pub use self::sign::{PublicKey, SecretKey, Signature, TrustPolicy};
 
 use std::collections::BTreeSet;
 
 use anyhow::anyhow;
 
+pub mod archive;
+pub mod cache;
 pub mod copy;
+pub mod remote;
 
 mod closure;
 mod local;
 mod object;
+mod sign;
 mod util;
 
+#[cfg(test)]
+mod test_support;
+
 /// A build server and content-addressable store of packages.
 pub trait Store: Objects {
-    fn build_spec(&self, spec: ObjectId) -> anyhow::Result<()>; // TODO: Determine return value.
+    /// Builds the `Spec` object `spec` and installs the resulting package in the store.
+    ///
+    /// Returns the ID of the new `Package` object, or `Err` if `spec` does not refer to a `Spec`
+    /// object, a dependency is missing, the builder failed, or an I/O error occurred.
+    fn build_spec(&mut self, spec: ObjectId) -> anyhow::Result<ObjectId>;
 }
 
 /// A content-addressable repository of Merkle tree objects.
-pub trait Objects {
+///
+/// Implementers must be `Sync`, since closures are computed by reading objects from several
+/// threads at once. Earlier versions of this trait did not require it, so implementers holding
+/// state which is not `Sync`, such as a `RefCell`, need to switch to a `Mutex` or `RwLock`.
+pub trait Objects: Sync {
     /// Inserts a tree object into the store, returning its unique ID.
     ///
     /// Implementers _must_ ensure that this method behaves as a completely atomic transaction.
@@ -69,6 +91,40 @@ pub trait Objects {
         })
     }
 
+    /// Looks up a `ChunkedBlob` object with the given ID and retrieves it, if it exists.
+    ///
+    /// Returns `Err` if the object does not exist, the given ID does not refer to a `ChunkedBlob`
+    /// object, or an I/O error occurred.
+    fn get_chunked_blob(&self, id: ObjectId) -> anyhow::Result<ChunkedBlob> {
+        self.get_object(id, Some(ObjectKind::ChunkedBlob))
+            .and_then(|o| {
+                o.into_chunked_blob()
+                    .map_err(|_| anyhow!("{} is not a chunked blob object", id))
+            })
+    }
+
+    /// Inserts a regular file into the store, returning the tree entry which refers to it.
+    ///
+    /// If the file was split into chunks, every chunk is inserted as a `Blob` object before the
+    /// `ChunkedBlob` object listing them, so the store never holds a dangling chunk list.
+    ///
+    /// Returns `Err` if any object could not be inserted into the store or an I/O error occurred.
+    fn insert_file(&mut self, file: FileContent) -> anyhow::Result<Entry> {
+        let entry = Entry::from_file(&file);
+        match file {
+            FileContent::Whole(blob) => {
+                self.insert_object(Object::Blob(blob))?;
+            }
+            FileContent::Chunked(file) => {
+                for o in file.into_objects()? {
+                    self.insert_object(o?)?;
+                }
+            }
+        }
+
+        Ok(entry)
+    }
+
     /// Looks up a `Tree` object with the given ID and retrieves it, if it exists.
     ///
     /// Returns `Err` if the object does not exist, the given ID does not refer to a `Tree` object,
@@ -1,27 +1,47 @@
 //! Local store interface and provided implementations.
 
 pub use self::fs::Filesystem;
+pub use self::fsck::{FsckReport, Mismatch, Problem};
+pub use self::gc::GcReport;
+pub use self::memory::{MemEntry, Memory};
+pub use self::repack::RepackReport;
+pub use self::repair::RepairReport;
 
-use std::collections::BTreeSet;
+pub(crate) use self::fs::FsObjects;
+
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
-use futures::{pin_mut, StreamExt};
+use futures::{stream, StreamExt};
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::copy::{Delta, Destination, Source};
-use crate::pack::{pack_reader, PackWriter};
-use crate::{closure, Closure, Object, ObjectId, ObjectKind, Objects, Package, Store};
+use self::quarantine::Quarantine;
+use crate::copy::{self, Delta, Destination, Source};
+use crate::pack::{PackWriter, PackagePhase, Progress, ThinPackReader};
+use crate::{
+    closure, Closure, ContentAddressable, InstallName, Object, ObjectId, ObjectKind, Objects,
+    Package, SecretKey, Signature, TrustPolicy,
+};
 
+mod build;
 mod fs;
+mod fsck;
+mod gc;
 mod install;
+mod memory;
+mod quarantine;
+mod repack;
+mod repair;
+mod thin;
 
 /// A content-addressable store of installed software packages.
 #[derive(Debug)]
 pub struct LocalStore<B: Backend = Filesystem> {
     objects: B::Objects,
     packages: B::Packages,
+    quarantine: Option<Quarantine>,
 }
 
 impl<B: Backend> LocalStore<B> {
@@ -30,7 +50,11 @@ impl<B: Backend> LocalStore<B> {
     /// Returns `Err` if the path does not exist or is not a valid store directory.
     pub fn open<P: Into<PathBuf>>(path: P) -> anyhow::Result<Self> {
         let (objects, packages) = B::open(path.into())?;
-        Ok(LocalStore { objects, packages })
+        Ok(LocalStore {
+            objects,
+            packages,
+            quarantine: None,
+        })
     }
 
     /// Initializes a new store directory at `path` and opens it.
@@ -43,7 +67,11 @@ impl<B: Backend> LocalStore<B> {
     /// store directory could not be created at `path` due to permissions or other I/O errors.
     pub fn init<P: Into<PathBuf>>(path: P) -> anyhow::Result<Self> {
         let (objects, packages) = B::init(path.into())?;
-        Ok(LocalStore { objects, packages })
+        Ok(LocalStore {
+            objects,
+            packages,
+            quarantine: None,
+        })
     }
 
     /// Initializes a store inside the empty directory referred to by `path` and opens it.
@@ -55,17 +83,72 @@ impl<B: Backend> LocalStore<B> {
     /// or I/O errors.
     pub fn init_bare<P: Into<PathBuf>>(path: P) -> anyhow::Result<Self> {
         let (objects, packages) = B::init_bare(path.into())?;
-        Ok(LocalStore { objects, packages })
+        Ok(LocalStore {
+            objects,
+            packages,
+            quarantine: None,
+        })
+    }
+
+    /// Signs the package `pkg` with `key` and records the signature in the store.
+    ///
+    /// Returns `Err` if `pkg` does not refer to a `Package` object in the store, or an I/O error
+    /// occurred.
+    pub fn sign_package(&mut self, pkg: ObjectId, key: &SecretKey) -> anyhow::Result<Signature> {
+        self.get_package(pkg)?;
+        let sig = key.sign(&pkg);
+        self.packages
+            .add_signatures(&pkg, &std::iter::once(sig.clone()).collect())?;
+        Ok(sig)
+    }
+
+    /// Returns the signatures recorded for the package `pkg`, which may be empty.
+    ///
+    /// Returns `Err` if an I/O error occurred.
+    pub fn signatures(&self, pkg: &ObjectId) -> anyhow::Result<BTreeSet<Signature>> {
+        self.packages.signatures(pkg)
+    }
+
+    /// Returns the policy deciding which packages this store accepts from other stores.
+    ///
+    /// Returns `Err` if the store configuration could not be read.
+    pub fn trust_policy(&self) -> anyhow::Result<TrustPolicy> {
+        self.packages.trust_policy()
+    }
+
+    /// Replaces the policy deciding which packages this store accepts from other stores.
+    ///
+    /// Returns `Err` if the store configuration could not be written.
+    pub fn set_trust_policy(&mut self, policy: TrustPolicy) -> anyhow::Result<()> {
+        self.packages.set_trust_policy(policy)
+    }
+
+    /// Returns the closure fragment memoized for the package `id`, or `None` if there is none.
+    ///
+    /// The memoized fragments are only an optimization, so one which cannot be read is treated
+    /// like a missing one. The package is then walked again, and its fragment memoized anew.
+    fn cached_fragment(&self, id: ObjectId) -> Option<Closure> {
+        self.packages.cached_closure(&id).unwrap_or(None)
     }
 }
 
 impl<B: Backend> Objects for LocalStore<B> {
     fn insert_object(&mut self, o: Object) -> anyhow::Result<ObjectId> {
+        let is_package = matches!(o, Object::Package(_));
         if let Object::Package(ref pkg) = &o {
             self.packages.install(pkg, &self.objects)?;
         }
 
-        self.objects.insert_object(o)
+        // Packages never change once installed, so their closure fragment can be memoized now.
+        // The package is already installed at this point, so failing to memoize it is ignored.
+        let id = self.objects.insert_object(o)?;
+        if is_package && self.cached_fragment(id).is_none() {
+            if let Ok(fragment) = closure::package_fragment(&self.objects, id) {
+                let _ = self.packages.cache_closure(&id, &fragment);
+            }
+        }
+
+        Ok(id)
     }
 
     fn get_object(&self, id: ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<Object> {
@@ -79,11 +162,21 @@ impl<B: Backend> Objects for LocalStore<B> {
     fn object_size(&self, id: &ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<u64> {
         self.objects.object_size(id, kind)
     }
-}
 
-impl<B: Backend> Store for LocalStore<B> {
-    fn build_spec(&self, _spec: ObjectId) -> anyhow::Result<()> {
-        unimplemented!()
+    /// Closures are assembled from the fragments memoized for each package, so only the packages
+    /// which have none yet are walked. Their fragments are then memoized for next time.
+    fn compute_closure(&self, pkgs: BTreeSet<ObjectId>) -> anyhow::Result<Closure> {
+        let lookup = |id| Ok(self.cached_fragment(id));
+        let mut fragments = closure::Fragments::new(&lookup);
+        let closure = closure::compute_with(&self.objects, pkgs, |_, _| Ok(true), &mut fragments)?;
+
+        for &id in fragments.missed() {
+            // The cache is only an optimization, so a store which cannot be written to, e.g. one
+            // mounted read-only, can still compute closures.
+            let _ = self.packages.cache_closure(&id, &closure.fragment(id));
+        }
+
+        Ok(closure)
     }
 }
 
@@ -99,30 +192,69 @@ impl<B: Backend> Source for LocalStore<B> {
         // https://matthew-brett.github.io/curious-git/git_push_algorithm.html
         // https://github.com/git/git/blob/master/Documentation/technical/pack-protocol.txt
 
-        let mut num_present = 0;
-        let missing = closure::compute(&self.objects, pkgs, |id, kind| {
-            let exists = dst.contains(&id, Some(kind))?;
-            if exists {
-                num_present += 1;
+        let roots = pkgs
+            .iter()
+            .map(|&id| {
+                let kind = ObjectKind::Package;
+                self.objects
+                    .object_size(&id, Some(kind))
+                    .map(|n| (id, kind, n))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        // The closure is built from the objects read along the way, so nothing is read twice.
+        let lookup = |id| Ok(self.cached_fragment(id));
+        let mut fragments = closure::Fragments::new(&lookup);
+        let mut nodes = BTreeMap::new();
+        let mut names = BTreeMap::new();
+        let (num_present, missing_ids) = copy::find_missing_ids(dst, roots, |node| {
+            let (children, name) = fragments.fetch(&self.objects, node)?;
+            names.extend(name.map(|name| (node.0, name)));
+            nodes.insert(*node, children.iter().copied().collect());
+            Ok(children)
+        })
+        .await?;
+
+        let missing = Closure::from_graph(nodes)
+            .with_names(names)
+            .subgraph(&missing_ids);
+        missing.check_acyclic()?;
+
+        let bases = self.find_delta_bases(dst, &missing).await?;
+
+        let mut signatures = BTreeMap::new();
+        for &(id, kind, _) in missing.iter() {
+            if kind == ObjectKind::Package {
+                let sigs = self.packages.signatures(&id)?;
+                if !sigs.is_empty() {
+                    signatures.insert(id, sigs);
+                }
             }
-            Ok(!exists)
-        })?;
+        }
 
         Ok(Delta {
             num_present,
             missing,
+            bases,
+            signatures,
         })
     }
 
-    async fn send_pack<W>(&self, closure: &Closure, writer: &mut W) -> anyhow::Result<()>
+    async fn send_pack<W>(&self, delta: &Delta, writer: &mut W) -> anyhow::Result<()>
     where
         W: AsyncWrite + Unpin,
     {
         let mut writer = PackWriter::new(writer).await?;
 
-        for (id, kind, _) in closure.sort_yield() {
+        for (id, kind, _) in delta.missing.sort_yield() {
             let obj = self.objects.get_object(id, Some(kind))?;
-            writer.append(obj).await?;
+            match (obj, delta.bases.get(&id)) {
+                (Object::Blob(blob), Some(&base)) => {
+                    let base = self.objects.get_blob(base)?;
+                    writer.append_delta(blob, base).await?;
+                }
+                (obj, _) => writer.append(obj).await?,
+            }
         }
 
         writer.finish().await?;
@@ -133,23 +265,140 @@ impl<B: Backend> Source for LocalStore<B> {
 
 #[async_trait(?Send)]
 impl<B: Backend> Destination for LocalStore<B> {
-    fn contains(&self, id: &ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<bool> {
+    async fn contains(&self, id: &ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<bool> {
         self.objects.contains_object(id, kind)
     }
 
-    async fn recv_pack<R>(&mut self, reader: &mut R) -> anyhow::Result<()>
+    /// Returns the objects held in the quarantine from an interrupted transfer.
+    async fn checkpoint(&self) -> anyhow::Result<BTreeSet<ObjectId>> {
+        match (&self.quarantine, self.packages.quarantine_path()) {
+            (Some(quarantine), _) => Ok(quarantine.held().map(|(id, _)| id).collect()),
+            (None, Some(path)) => Quarantine::list(&path),
+            (None, None) => Ok(BTreeSet::new()),
+        }
+    }
+
+    fn max_concurrent_packs(&self) -> usize {
+        usize::MAX
+    }
+
+    async fn recv_pack<R>(
+        &mut self,
+        reader: &mut R,
+        signatures: &BTreeMap<ObjectId, BTreeSet<Signature>>,
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.recv_packs(vec![reader], signatures, &|_| {}).await
+    }
+
+    /// Received objects are held in a quarantine until every pack has been read and its footer
+    /// verified, so nothing is added to the store if a pack is incomplete. The packs are decoded
+    /// at once, and their objects are held in whichever order they arrive.
+    ///
+    /// If a pack is out of order or contains a refused package, or the objects cannot be published,
+    /// the quarantine is discarded. If a pack stream fails instead, the objects verified so far are
+    /// kept and reported by [`Destination::checkpoint()`], so that the transfer can be resumed later
+    /// on. Packages held over from an earlier transfer are dropped if this transfer does not carry
+    /// trusted signatures for them, as they belong to some other transfer.
+    ///
+    /// Each package is reported to `progress` once it has been received, and again when it is
+    /// instantiated and installed.
+    async fn recv_packs<R>(
+        &mut self,
+        readers: Vec<R>,
+        signatures: &BTreeMap<ObjectId, BTreeSet<Signature>>,
+        progress: &dyn for<'p> Fn(&'p Progress),
+    ) -> anyhow::Result<()>
     where
         R: AsyncRead + Unpin,
     {
-        let stream = pack_reader(reader);
-        pin_mut!(stream);
+        let policy = self.packages.trust_policy()?;
+        let mut quarantine = match self.quarantine.take() {
+            Some(quarantine) => quarantine,
+            None => Quarantine::open(self.packages.quarantine_path().as_deref())?,
+        };
+
+        // The packs do not reference each other and each one is in topological order, so every
+        // object still arrives after everything it references.
+        let objects = &self.objects;
+        let mut received = stream::select_all(readers.into_iter().map(|reader| {
+            stream::unfold(Some(ThinPackReader::new(reader)), move |state| async move {
+                let mut reader = state?;
+                match reader.next_object(objects).await {
+                    Ok(Some(obj)) => Some((Ok(obj), Some(reader))),
+                    Ok(None) => None,
+                    Err(e) => Some((Err(e), None)),
+                }
+            })
+            .boxed_local()
+        }));
+
+        while let Some(result) = received.next().await {
+            let obj = match result {
+                Ok(obj) => obj,
+                Err(e) => {
+                    // Everything held so far was verified, so keep it for the transfer to resume.
+                    self.quarantine = Some(quarantine);
+                    return Err(e);
+                }
+            };
+
+            let package = match obj {
+                Object::Package(ref pkg) => Some(pkg.object_id()),
+                _ => None,
+            };
+
+            let checked = match package {
+                Some(id) => {
+                    let sigs = signatures.get(&id).cloned().unwrap_or_default();
+                    policy.check(&id, &sigs).map(drop)
+                }
+                None => Ok(()),
+            };
+
+            if let Err(e) = checked.and_then(|_| quarantine.hold(obj, objects)) {
+                quarantine.discard()?;
+                return Err(e);
+            }
 
-        while let Some(result) = stream.next().await {
-            let obj = result?;
-            self.insert_object(obj)?;
+            if let Some(id) = package {
+                let phase = PackagePhase::Received;
+                progress(&Progress::Package { id, phase });
+            }
         }
 
-        Ok(())
+        drop(received);
+
+        // The footers have been verified, so the packs are complete and the objects can be
+        // published. Packages held over from an interrupted transfer are checked against the new
+        // signatures. Those which fail belong to some other transfer, so they are dropped.
+        let mut trusted = BTreeMap::new();
+        let mut foreign = BTreeSet::new();
+        for (id, kind) in quarantine.held() {
+            if kind == ObjectKind::Package {
+                let sigs = signatures.get(&id).cloned().unwrap_or_default();
+                match policy.check(&id, &sigs) {
+                    Ok(sigs) => {
+                        trusted.insert(id, sigs);
+                    }
+                    Err(_) => {
+                        foreign.insert(id);
+                    }
+                }
+            }
+        }
+
+        // Record the signatures first, so that no package is ever visible without them.
+        for (id, sigs) in &trusted {
+            if let Err(e) = self.packages.add_signatures(id, sigs) {
+                quarantine.discard()?;
+                return Err(e);
+            }
+        }
+
+        quarantine.release(self, &foreign, progress).await
     }
 }
 
@@ -158,7 +407,10 @@ pub trait Backend {
     /// Type of `objects` repository to use.
     type Objects: Objects;
     /// Type of `packages` repository to use.
-    type Packages: Packages<Objects = Self::Objects>;
+    ///
+    /// This must be `Sync` along with the `objects` repository, so that a [`LocalStore`] can be
+    /// read from several threads at once.
+    type Packages: Packages<Objects = Self::Objects> + Sync;
 
     /// Opens the store on the directory located in `path`.
     ///
@@ -196,6 +448,11 @@ pub trait Packages {
     /// nonetheless required when staging packages and patching out self-references.
     fn path(&self) -> &Path;
 
+    /// Lists the install names of every package instantiated in the repository.
+    ///
+    /// Returns `Err` if an I/O error occurred.
+    fn list(&self) -> anyhow::Result<Vec<InstallName>>;
+
     /// Instantiates a `Package` object from the underlying Merkle tree.
     ///
     /// Implementers _must_ ensure that this method behaves as a completely atomic transaction.
@@ -227,6 +484,82 @@ pub trait Packages {
             ));
         }
 
-        self.instantiate(pkg, &objects)
+        self.instantiate(pkg, objects)
+    }
+
+    /// Returns the signatures recorded for the package `id`, which may be empty.
+    ///
+    /// The default implementation records no signatures, so it always returns an empty set.
+    ///
+    /// Returns `Err` if an I/O error occurred.
+    fn signatures(&self, _id: &ObjectId) -> anyhow::Result<BTreeSet<Signature>> {
+        Ok(BTreeSet::new())
+    }
+
+    /// Records the signatures `sigs` for the package `id`, alongside any already recorded.
+    ///
+    /// The default implementation cannot record signatures, and always returns `Err`.
+    ///
+    /// Returns `Err` if an I/O error occurred.
+    fn add_signatures(&mut self, id: &ObjectId, _sigs: &BTreeSet<Signature>) -> anyhow::Result<()> {
+        Err(anyhow!("cannot record signatures for {} in this store", id))
+    }
+
+    /// Returns the closure fragment memoized for the package `id` by [`Packages::cache_closure()`],
+    /// or `None` if there is none.
+    ///
+    /// Returns `Err` if the memoized fragment could not be read.
+    fn cached_closure(&self, _id: &ObjectId) -> anyhow::Result<Option<Closure>> {
+        Ok(None)
+    }
+
+    /// Memoizes `fragment` as the part of the closure which belongs to the package `id`.
+    ///
+    /// The fragment holds the package, the trees and blobs it contains, and the packages it
+    /// references directly as leaves. Packages are immutable, so it never has to be invalidated
+    /// until the package itself is deleted. This takes `&self` so that fragments can be memoized
+    /// while computing a closure. Backends which do not memoize fragments can ignore this.
+    ///
+    /// Returns `Err` if the fragment could not be written.
+    fn cache_closure(&self, _id: &ObjectId, _fragment: &Closure) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the path of the pack file where objects received from other stores are held until
+    /// their pack is complete, or `None` to hold them in memory instead.
+    ///
+    /// Keeping this file around lets an interrupted transfer resume even after the store has been
+    /// closed and reopened.
+    fn quarantine_path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Returns the directory where packages are built before they are installed, or `None` to
+    /// build them in `/var/tmp` instead.
+    ///
+    /// The directory is created on demand. It should be on the same filesystem as the store, and
+    /// must not be scanned by anything which expects only installed packages, such as garbage
+    /// collection.
+    fn tmp_dir(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Returns the policy deciding which packages may be received from other stores.
+    ///
+    /// The default implementation always returns [`TrustPolicy::default()`], which refuses every
+    /// package until a key is trusted.
+    ///
+    /// Returns `Err` if the store configuration could not be read.
+    fn trust_policy(&self) -> anyhow::Result<TrustPolicy> {
+        Ok(TrustPolicy::default())
+    }
+
+    /// Replaces the policy deciding which packages may be received from other stores.
+    ///
+    /// The default implementation cannot store a policy, and always returns `Err`.
+    ///
+    /// Returns `Err` if the store configuration could not be written.
+    fn set_trust_policy(&mut self, _policy: TrustPolicy) -> anyhow::Result<()> {
+        Err(anyhow!("cannot change the trust policy of this store"))
     }
 }
@@ -0,0 +1,263 @@
+//! Building packages from `Spec` objects with a local shell builder.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Context};
+
+use super::{Backend, LocalStore, Packages};
+use crate::{ObjectId, Objects, Store};
+
+const SHELL_BIN: &str = "sh";
+
+impl<B: Backend> Store for LocalStore<B> {
+    /// Every dependency and build dependency of the spec must already be in the store. Each one
+    /// is installed, if it is not already, before the builder runs.
+    ///
+    /// The builder is run with `sh -e` in a fresh build directory, which is also its working
+    /// directory and `$HOME`. The environment is cleared, except for these variables:
+    ///
+    /// * `out`: the directory the builder must create and write the package contents to.
+    /// * `dependencies`: the install paths of `Spec::dependencies`, separated by spaces.
+    /// * `build_dependencies`: the install paths of `Spec::build_dependencies`, likewise.
+    /// * `PATH`: the `bin` directory of every dependency, followed by the `PATH` of the host.
+    ///
+    /// The build directory is created inside [`Packages::tmp_dir()`], or in `/var/tmp` if the store
+    /// has none, and is deleted once the build finishes, successful or not. `$out` is padded to be
+    /// at least as long as the final install path, so that self-references can be patched in
+    /// place.
+    fn build_spec(&mut self, spec: ObjectId) -> anyhow::Result<ObjectId> {
+        let spec = self.get_spec(spec)?;
+        let name = format!("{}-{}", spec.name, spec.version);
+
+        let mut install_dirs = |ids: &_| -> anyhow::Result<Vec<PathBuf>> {
+            let mut dirs = Vec::new();
+            for &id in ids {
+                let pkg = self.get_package(id).with_context(|| {
+                    format!("dependency {} of {} is not in the store", id, name)
+                })?;
+                self.packages.install(&pkg, &self.objects)?;
+                dirs.push(self.packages.path().join(pkg.install_name()));
+            }
+            Ok(dirs)
+        };
+
+        let deps = install_dirs(&spec.dependencies)?;
+        let build_deps = install_dirs(&spec.build_dependencies)?;
+
+        let tmp_dir = self
+            .packages
+            .tmp_dir()
+            .unwrap_or_else(|| PathBuf::from("/var/tmp"));
+        std::fs::create_dir_all(&tmp_dir)
+            .with_context(|| format!("could not create {}", tmp_dir.display()))?;
+        let build_dir = tempfile::Builder::new()
+            .prefix("build-")
+            .tempdir_in(&tmp_dir)?;
+
+        // `$out` has the same file name as the install path, so only their parents are compared.
+        let mut out_parent = build_dir.path().to_owned();
+        let pkgs_dir = self.packages.path();
+        let shortfall = pkgs_dir
+            .as_os_str()
+            .len()
+            .saturating_sub(out_parent.as_os_str().len());
+        if shortfall > 0 {
+            out_parent.push("_".repeat(shortfall));
+            std::fs::create_dir(&out_parent)?;
+        }
+
+        let out_dir = out_parent.join(format!("{}-{}", name, ObjectId::zero()));
+
+        let bins = deps.iter().chain(&build_deps).map(|dir| dir.join("bin"));
+        let host_path = std::env::var_os("PATH").unwrap_or_default();
+        let path = std::env::join_paths(bins.chain(std::env::split_paths(&host_path)))?;
+
+        let mut cmd = Command::new(SHELL_BIN);
+        let output = cmd
+            .env_clear()
+            .env("PATH", path)
+            .env("HOME", build_dir.path())
+            .env("out", &out_dir)
+            .env("dependencies", join_spaced(&deps))
+            .env("build_dependencies", join_spaced(&build_deps))
+            .current_dir(build_dir.path())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .arg("-e")
+            .arg("-c")
+            .arg(&spec.builder)
+            .output()
+            .with_context(|| format!("failed to run builder for {}", name))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!(
+                "builder for {} returned non-zero status ({}): [{}]",
+                name,
+                output.status,
+                stderr.trim_end()
+            ));
+        }
+
+        if !out_dir.is_dir() {
+            return Err(anyhow!("builder for {} did not create `$out`", name));
+        }
+
+        self.install_path(&out_dir, &spec)
+    }
+}
+
+/// Joins `paths` into a single string separated by spaces.
+fn join_spaced(paths: &[PathBuf]) -> OsString {
+    let mut joined = OsString::new();
+    for (i, path) in paths.iter().enumerate() {
+        if i > 0 {
+            joined.push(" ");
+        }
+        joined.push(path);
+    }
+    joined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    use crate::test_support::insert_spec;
+    use crate::{MemEntry, Memory, Package};
+
+    fn installed_dir<B: Backend>(store: &LocalStore<B>, pkg: &Package) -> PathBuf {
+        store.packages.path().join(pkg.install_name())
+    }
+
+    #[test]
+    fn builds_spec_with_dependencies() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+
+        let greet = insert_spec(
+            &mut store,
+            "greet",
+            &[],
+            &[],
+            r#"
+            mkdir -p "$out/bin"
+            printf '#!/bin/sh\necho hello\n' > "$out/bin/greet"
+            chmod +x "$out/bin/greet"
+            "#,
+        );
+        let greet = store.build_spec(greet).unwrap();
+
+        // `greet` is found on the `PATH` at build time, and referenced again at run time.
+        let app = insert_spec(
+            &mut store,
+            "app",
+            &[greet],
+            &[],
+            r#"
+            mkdir -p "$out/bin"
+            greet > "$out/message"
+            printf '#!/bin/sh\nexec %s/bin/greet\n' "$dependencies" > "$out/bin/app"
+            chmod +x "$out/bin/app"
+            echo "$out/bin/app" > "$out/self"
+            "#,
+        );
+        let app = store.build_spec(app).unwrap();
+
+        let pkg = store.get_package(app).unwrap();
+        assert_eq!(pkg.name.to_string(), "app-1.0.0");
+        assert_eq!(pkg.references, std::iter::once(greet).collect());
+        assert_eq!(pkg.self_references.len(), 1);
+
+        let app_dir = installed_dir(&store, &pkg);
+        let message = std::fs::read_to_string(app_dir.join("message")).unwrap();
+        assert_eq!(message, "hello\n");
+
+        let output = Command::new(app_dir.join("bin/app")).output().unwrap();
+        assert_eq!(output.stdout, b"hello\n");
+
+        // Self-references point to the final install path, padded with slashes.
+        let own_path = std::fs::read_to_string(app_dir.join("self")).unwrap();
+        assert!(own_path.starts_with(&format!("{}/", app_dir.display())));
+        assert!(own_path.ends_with("/bin/app\n"));
+
+        // The build directory is kept out of `packages`, and cleaned up afterwards.
+        let installed: Vec<_> = std::fs::read_dir(store.packages.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(installed.len(), 2, "{:?}", installed);
+        let tmp_dir = store.packages.tmp_dir().unwrap();
+        let leftovers: Vec<_> = std::fs::read_dir(tmp_dir).unwrap().collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+
+    #[test]
+    fn pads_out_dir_to_install_path() {
+        // The install path of this store is much longer than any build directory in `/var/tmp`.
+        let root = PathBuf::from("/").join("x".repeat(200));
+        let mut store = LocalStore::<Memory>::init(root).unwrap();
+
+        let spec = insert_spec(
+            &mut store,
+            "long",
+            &[],
+            &[],
+            r#"mkdir "$out"; printf '%s' "$out" > "$out/self""#,
+        );
+        let pkg = store.build_spec(spec).unwrap();
+        let pkg = store.get_package(pkg).unwrap();
+        assert_eq!(pkg.self_references.len(), 1);
+
+        let files = store.installed_package(&pkg.install_name()).unwrap();
+        let own_path = installed_dir(&store, &pkg);
+        match &files[Path::new("self")] {
+            MemEntry::File { content, .. } => {
+                let content = String::from_utf8(content.to_vec()).unwrap();
+                assert!(content.starts_with(&own_path.display().to_string()));
+                assert!(content
+                    .trim_start_matches(own_path.to_str().unwrap())
+                    .chars()
+                    .all(|c| c == '/'));
+            }
+            other => panic!("expected file, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_failed_builds() {
+        let mut store = LocalStore::in_memory();
+
+        let failing = insert_spec(&mut store, "failing", &[], &[], "echo oops >&2; exit 3");
+        let error = store.build_spec(failing).unwrap_err().to_string();
+        assert!(error.contains("oops"), "{}", error);
+
+        let empty = insert_spec(&mut store, "empty", &[], &[], "true");
+        let error = store.build_spec(empty).unwrap_err().to_string();
+        assert!(error.contains("did not create"), "{}", error);
+
+        let missing = insert_spec(&mut store, "missing", &[ObjectId::zero()], &[], "true");
+        let error = store.build_spec(missing).unwrap_err().to_string();
+        assert!(error.contains("not in the store"), "{}", error);
+
+        // Build dependencies must not be referenced by the output.
+        let tool = insert_spec(&mut store, "tool", &[], &[], r#"mkdir "$out""#);
+        let tool = store.build_spec(tool).unwrap();
+        let leaky = insert_spec(
+            &mut store,
+            "leaky",
+            &[],
+            &[tool],
+            r#"mkdir "$out"; echo "$build_dependencies" > "$out/tool""#,
+        );
+        assert!(store.build_spec(leaky).is_err());
+        let installed = store.packages.list().unwrap();
+        assert!(installed
+            .iter()
+            .all(|name| !name.to_string().starts_with("leaky")));
+    }
+}
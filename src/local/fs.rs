@@ -1,17 +1,31 @@
 //! Filesystem-backed store implementation.
 
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{BufReader, BufWriter, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
 use anyhow::{anyhow, Context};
 use filetime::FileTime;
 
 use super::{install, Backend, Objects, Packages};
+use crate::pack::{self, IndexEntry, PackIndex};
 use crate::{
-    Blob, ContentAddressable, Entry, Object, ObjectExt, ObjectId, ObjectKind, Package, Tree,
+    util, Blob, Closure, ContentAddressable, Entry, InstallName, Object, ObjectExt, ObjectId,
+    ObjectKind, Package, Signature, Tree, TrustPolicy,
 };
 
 const OBJECTS_SUBDIR: &str = "objects";
+const PACKS_SUBDIR: &str = "pack";
 const PACKAGES_SUBDIR: &str = "packages";
+const SIGNATURES_SUBDIR: &str = "signatures";
+const CLOSURES_SUBDIR: &str = "closures";
+const TRUST_FILE: &str = "trust.json";
+const QUARANTINE_FILE: &str = "quarantine.pack";
+const TMP_SUBDIR: &str = "tmp";
+pub(super) const GCROOTS_SUBDIR: &str = "gcroots";
 
 /// A store implementation backed by the local filesystem.
 ///
@@ -31,7 +45,7 @@ impl Backend for Filesystem {
         let packages_dir = path.join(PACKAGES_SUBDIR);
 
         if objects_dir.is_dir() && packages_dir.is_dir() {
-            Ok((FsObjects(objects_dir), FsPackages(packages_dir)))
+            Ok((FsObjects::open(objects_dir)?, FsPackages(packages_dir)))
         } else if path.exists() {
             Err(anyhow!("`{}` is not a store directory", path.display()))
         } else {
@@ -47,6 +61,8 @@ impl Backend for Filesystem {
             std::fs::create_dir(&path).context("could not create new store directory")?;
             std::fs::create_dir(&objects_dir).context("could not create `objects` dir")?;
             std::fs::create_dir(&packages_dir).context("could not create `packages` dir")?;
+            std::fs::create_dir(path.join(GCROOTS_SUBDIR))
+                .context("could not create `gcroots` dir")?;
         }
 
         Self::open(path)
@@ -61,56 +77,299 @@ impl Backend for Filesystem {
         if entries.count() == 0 {
             std::fs::create_dir(&objects_dir).context("could not create `objects` dir")?;
             std::fs::create_dir(&packages_dir).context("could not create `packages` dir")?;
+            std::fs::create_dir(path.join(GCROOTS_SUBDIR))
+                .context("could not create `gcroots` dir")?;
         } else if !objects_dir.exists() || !packages_dir.exists() {
             return Err(anyhow!("could not init store, expected empty directory"));
         }
 
-        Ok((FsObjects(objects_dir), FsPackages(packages_dir)))
+        Ok((FsObjects::open(objects_dir)?, FsPackages(packages_dir)))
     }
 }
 
 /// A filesystem-backed `objects` directory.
+///
+/// Objects are stored either as loose files named `ab/cdef01234567890.<kind>`, or in pack files
+/// located in the `pack` subdirectory, each with an accompanying index. Lookups check the loose
+/// files first, falling back to the packs.
 #[derive(Clone, Debug)]
-pub struct FsObjects(PathBuf);
+pub struct FsObjects {
+    dir: PathBuf,
+    // Shared between clones, so they all see the packs written by `rewrite_packs()`.
+    packs: Arc<RwLock<Vec<Arc<PackFile>>>>,
+}
 
-impl Objects for FsObjects {
-    fn insert_object(&mut self, o: Object) -> anyhow::Result<ObjectId> {
-        /// Ensures the parent directory of `p` exists, creating it atomically if it does not.
-        fn ensure_parent_dir<F>(p: &Path, persist_obj: F) -> anyhow::Result<()>
-        where
-            F: FnOnce(&Path) -> anyhow::Result<()>,
-        {
-            let parent_dir = p.parent().expect("object path must have parent dir");
-
-            if parent_dir.exists() {
-                persist_obj(p)
-            } else {
-                let temp_dir = tempfile::tempdir_in("/var/tmp")?;
-                let temp_file = temp_dir.path().join(p.file_name().unwrap());
-                persist_obj(&temp_file)?;
-
-                let temp_dir = temp_dir.into_path();
-                match std::fs::rename(&temp_dir, &parent_dir) {
-                    Ok(()) => Ok(()),
-                    Err(_) if parent_dir.is_dir() => match std::fs::rename(&temp_file, p) {
-                        Ok(()) => Ok(()),
-                        Err(_) if p.is_file() => Ok(()),
-                        Err(e) => Err(e.into()),
-                    },
-                    Err(e) => Err(e.into()),
+impl FsObjects {
+    pub(crate) fn open(dir: PathBuf) -> anyhow::Result<Self> {
+        let packs = load_packs(&dir.join(PACKS_SUBDIR))?;
+        let packs = packs.into_iter().map(Arc::new).collect();
+        Ok(FsObjects {
+            dir,
+            packs: Arc::new(RwLock::new(packs)),
+        })
+    }
+
+    /// Returns the absolute path to the `objects` directory.
+    pub(super) fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the path where the object `id` of type `kind` is stored as a loose file, whether it
+    /// exists or not.
+    pub(super) fn object_path(&self, id: &ObjectId, kind: ObjectKind) -> PathBuf {
+        let mut path = self.dir.join(id.to_path_buf());
+        path.set_extension(kind.as_str());
+        path
+    }
+
+    /// Writes the blob `id` out to a loose file if it is only stored in a pack, and returns the
+    /// path to its loose file.
+    ///
+    /// Package directories are constructed by hard-linking loose blob files, so blobs stored in a
+    /// pack file must be unpacked before they can be instantiated.
+    ///
+    /// Returns `Err` if the blob does not exist in the store, or an I/O error occurred.
+    pub(super) fn unpack_blob(&self, id: &ObjectId) -> anyhow::Result<PathBuf> {
+        let path = self.object_path(id, ObjectKind::Blob);
+
+        if !path.exists() {
+            let (pack, entry) = self
+                .find_packed(id, Some(ObjectKind::Blob))
+                .ok_or_else(|| anyhow!("blob object {} not found", id))?;
+
+            match pack.read(&entry)? {
+                Object::Blob(blob) => ensure_parent_dir(&path, |p| blob.persist(p))?,
+                other => return Err(anyhow!("expected blob, found {:?}", other.kind())),
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// Lists every loose object file in the `objects` directory.
+    ///
+    /// Files and directories which do not follow the `ab/cdef01234567890.<kind>` naming scheme are
+    /// silently skipped.
+    pub(super) fn list_loose(&self) -> anyhow::Result<Vec<(ObjectId, ObjectKind, PathBuf)>> {
+        let mut objects = Vec::new();
+
+        for prefix in std::fs::read_dir(&self.dir)? {
+            let prefix = prefix?;
+            let prefix_name = prefix.file_name();
+            let prefix_str = match prefix_name.to_str() {
+                Some(s) if s.len() == 2 && prefix.file_type()?.is_dir() => s,
+                _ => continue,
+            };
+
+            for file in std::fs::read_dir(prefix.path())? {
+                let path = file?.path();
+                let parsed = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| format!("{}{}", prefix_str, stem).parse().ok())
+                    .zip(path.extension().and_then(|ext| ext.to_str()?.parse().ok()));
+
+                if let Some((id, kind)) = parsed {
+                    objects.push((id, kind, path));
+                }
+            }
+        }
+
+        objects.sort();
+        Ok(objects)
+    }
+
+    /// Lists every object stored in a pack file, along with its size in bytes.
+    ///
+    /// Objects may also exist as loose files, e.g. blobs which were unpacked to be instantiated.
+    pub(super) fn list_packed(&self) -> Vec<(ObjectId, ObjectKind, u64)> {
+        let mut objects: Vec<_> = self
+            .packs
+            .read()
+            .unwrap()
+            .iter()
+            .flat_map(|pack| pack.index.iter())
+            .map(|entry| (entry.id(), entry.kind(), entry.size()))
+            .collect();
+
+        objects.sort();
+        objects.dedup_by_key(|&mut (id, _, _)| id);
+        objects
+    }
+
+    /// Replaces every existing pack file with a single new pack file containing `objects`.
+    ///
+    /// Objects are read from wherever they are currently stored, so loose files should only be
+    /// deleted after this method returns. If `objects` is empty, all existing pack files are
+    /// deleted and no new one is written.
+    ///
+    /// Returns the size of the new pack file in bytes, or `Err` if an object could not be read or
+    /// an I/O error occurred.
+    pub(super) fn rewrite_packs(
+        &mut self,
+        mut objects: Vec<(ObjectId, ObjectKind)>,
+    ) -> anyhow::Result<u64> {
+        objects.sort();
+        objects.dedup_by_key(|&mut (id, _)| id);
+
+        let pack_dir = self.dir.join(PACKS_SUBDIR);
+        let mut new_packs = Vec::new();
+        let mut pack_size = 0;
+
+        if !objects.is_empty() {
+            std::fs::create_dir_all(&pack_dir).context("could not create `pack` dir")?;
+
+            // Name the pack after its contents, like the objects themselves.
+            let mut hasher = blake3::Hasher::new();
+            for (id, _) in &objects {
+                hasher.update(id.as_bytes());
+            }
+            let pack_name = format!("pack-{}", hasher.finalize().to_hex());
+            let pack_path = pack_dir.join(format!("{}.pack", pack_name));
+            let index_path = pack_dir.join(format!("{}.idx", pack_name));
+
+            let mut temp_pack = tempfile::NamedTempFile::new_in(&pack_dir)?;
+            let mut entries = Vec::with_capacity(objects.len());
+            {
+                let mut writer = BufWriter::new(temp_pack.as_file_mut());
+                let mut offset = pack::write_magic(&mut writer)?;
+                for &(id, kind) in &objects {
+                    let object = self.get_object(id, Some(kind))?;
+                    entries.push(pack::write_entry(&mut writer, &mut offset, object)?);
                 }
+                pack::write_footer(&mut writer)?;
+                writer.flush()?;
+            }
+            temp_pack.as_file().sync_all()?;
+
+            let mut temp_index = tempfile::NamedTempFile::new_in(&pack_dir)?;
+            let index = PackIndex::new(entries);
+            index.write_to(BufWriter::new(temp_index.as_file_mut()))?;
+            temp_index.as_file().sync_all()?;
+
+            // Packs without an index are ignored, so always persist the index last.
+            util::normalize_perms(temp_pack.path(), 0o444)?;
+            util::normalize_perms(temp_index.path(), 0o444)?;
+            temp_pack.persist(&pack_path)?;
+            temp_index.persist(&index_path)?;
+
+            pack_size = std::fs::metadata(&pack_path)?.len();
+            new_packs.push(Arc::new(PackFile {
+                path: pack_path,
+                index,
+            }));
+        }
+
+        let old_packs = std::mem::replace(&mut *self.packs.write().unwrap(), new_packs.clone());
+        for old in old_packs {
+            if new_packs.iter().all(|new| new.path != old.path) {
+                std::fs::remove_file(old.path.with_extension("idx"))?;
+                std::fs::remove_file(&old.path)?;
             }
         }
 
+        Ok(pack_size)
+    }
+
+    fn find_packed(
+        &self,
+        id: &ObjectId,
+        kind: Option<ObjectKind>,
+    ) -> Option<(Arc<PackFile>, IndexEntry)> {
+        self.packs.read().unwrap().iter().find_map(|pack| {
+            pack.index
+                .find(id)
+                .filter(|entry| !matches!(kind, Some(k) if entry.kind() != k))
+                .map(|entry| (pack.clone(), *entry))
+        })
+    }
+}
+
+/// A pack file in the `objects/pack` directory, along with its parsed index.
+#[derive(Debug)]
+struct PackFile {
+    path: PathBuf,
+    index: PackIndex,
+}
+
+impl PackFile {
+    /// Reads the object described by `entry` out of the pack file.
+    fn read(&self, entry: &IndexEntry) -> anyhow::Result<Object> {
+        let mut file = File::open(&self.path)?;
+        pack::read_entry_at(&mut file, entry)
+            .with_context(|| format!("failed to read from {}", self.path.display()))
+    }
+}
+
+/// Loads the index of every pack file in `pack_dir`.
+///
+/// Pack files without a matching `.idx` file are skipped, since they may be incomplete.
+fn load_packs(pack_dir: &Path) -> anyhow::Result<Vec<PackFile>> {
+    let mut packs = Vec::new();
+
+    if !pack_dir.is_dir() {
+        return Ok(packs);
+    }
+
+    for entry in std::fs::read_dir(pack_dir)? {
+        let index_path = entry?.path();
+        let pack_path = index_path.with_extension("pack");
+        if index_path.extension() != Some("idx".as_ref()) || !pack_path.is_file() {
+            continue;
+        }
+
+        let file = File::open(&index_path)?;
+        let index = PackIndex::read_from(BufReader::new(file))
+            .with_context(|| format!("failed to read pack index {}", index_path.display()))?;
+        packs.push(PackFile {
+            path: pack_path,
+            index,
+        });
+    }
+
+    packs.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(packs)
+}
+
+/// Ensures the parent directory of `p` exists, creating it atomically if it does not.
+fn ensure_parent_dir<F>(p: &Path, persist_obj: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&Path) -> anyhow::Result<()>,
+{
+    let parent_dir = p.parent().expect("object path must have parent dir");
+
+    if parent_dir.exists() {
+        persist_obj(p)
+    } else {
+        let temp_dir = tempfile::tempdir_in("/var/tmp")?;
+        let temp_file = temp_dir.path().join(p.file_name().unwrap());
+        persist_obj(&temp_file)?;
+
+        let temp_dir = temp_dir.into_path();
+        match std::fs::rename(&temp_dir, parent_dir) {
+            Ok(()) => Ok(()),
+            Err(_) if parent_dir.is_dir() => match std::fs::rename(&temp_file, p) {
+                Ok(()) => Ok(()),
+                Err(_) if p.is_file() => Ok(()),
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Objects for FsObjects {
+    fn insert_object(&mut self, o: Object) -> anyhow::Result<ObjectId> {
         // Prepare to serialize object into: `<store>/objects/ab/cdef01234567890.<kind>`
         let id = o.object_id();
-        let mut path = self.0.join(id.to_path_buf());
-        path.set_extension(o.kind().as_str());
+        let kind = o.kind();
+        let path = self.object_path(&id, kind);
 
         // Persist into the `objects` directory, ensuring the parent directory exists.
-        if !path.exists() {
+        if !path.exists() && self.find_packed(&id, Some(kind)).is_none() {
             match o {
                 Object::Blob(blob) => ensure_parent_dir(&path, |p| blob.persist(p))?,
+                Object::ChunkedBlob(c) => ensure_parent_dir(&path, |p| c.persist(p))?,
                 Object::Tree(tree) => ensure_parent_dir(&path, |p| tree.persist(p))?,
                 Object::Package(pkg) => ensure_parent_dir(&path, |p| pkg.persist(p))?,
                 Object::Spec(spec) => ensure_parent_dir(&path, |p| spec.persist(p))?,
@@ -121,7 +380,7 @@ impl Objects for FsObjects {
     }
 
     fn get_object(&self, id: ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<Object> {
-        let mut path = self.0.join(id.to_path_buf());
+        let mut path = self.dir.join(id.to_path_buf());
 
         // Use `kind`, if specified, as a perf optimization to guess the file extension.
         let kind_exists = if kind.is_some() {
@@ -141,6 +400,11 @@ impl Objects for FsObjects {
                 let blob = Blob::from_store_path(path, id)?;
                 Ok(Object::Blob(blob))
             }
+            Some(ObjectKind::ChunkedBlob) => {
+                let file = std::fs::File::open(path)?;
+                let chunked = serde_json::from_reader(file)?;
+                Ok(Object::ChunkedBlob(chunked))
+            }
             Some(ObjectKind::Tree) => {
                 let file = std::fs::File::open(path)?;
                 let tree = serde_json::from_reader(file)?;
@@ -156,40 +420,53 @@ impl Objects for FsObjects {
                 let spec = serde_json::from_reader(file)?;
                 Ok(Object::Spec(spec))
             }
-            None => Err(anyhow!("object {} not found", id)),
+            None => match self.find_packed(&id, kind) {
+                Some((pack, entry)) => pack.read(&entry),
+                None => Err(anyhow!("object {} not found", id)),
+            },
         }
     }
 
     fn contains_object(&self, id: &ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<bool> {
-        let mut path = self.0.join(id.to_path_buf());
+        let mut path = self.dir.join(id.to_path_buf());
 
         // Use `kind`, if specified, as a perf optimization to guess the file extension.
-        if let Some(k) = kind {
+        let is_loose = if let Some(k) = kind {
             path.set_extension(k.as_str());
-            Ok(path.exists())
+            path.exists()
         } else {
-            Ok(ObjectKind::iter().any(|k| {
+            ObjectKind::iter().any(|k| {
                 path.set_extension(k.as_str());
                 path.exists()
-            }))
-        }
+            })
+        };
+
+        Ok(is_loose || self.find_packed(id, kind).is_some())
     }
 
     fn object_size(&self, id: &ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<u64> {
-        let mut path = self.0.join(id.to_path_buf());
+        let mut path = self.dir.join(id.to_path_buf());
 
         // Use `kind`, if specified, as a perf optimization to guess the file extension.
-        if let Some(k) = kind {
+        let is_loose = if let Some(k) = kind {
             path.set_extension(k.as_str());
+            path.exists()
         } else {
             ObjectKind::iter().any(|k| {
                 path.set_extension(k.as_str());
                 path.exists()
-            });
+            })
         };
 
-        let metadata = std::fs::metadata(path)?;
-        Ok(metadata.len())
+        if is_loose {
+            let metadata = std::fs::metadata(path)?;
+            Ok(metadata.len())
+        } else {
+            match self.find_packed(id, kind) {
+                Some((_, entry)) => Ok(entry.size()),
+                None => Err(anyhow!("object {} not found", id)),
+            }
+        }
     }
 }
 
@@ -204,22 +481,31 @@ impl Packages for FsPackages {
         &self.0
     }
 
+    fn list(&self) -> anyhow::Result<Vec<InstallName>> {
+        let mut names = Vec::new();
+
+        for entry in std::fs::read_dir(&self.0)? {
+            // Skip staging and trash directories, along with anything else that isn't a package.
+            let file_name = entry?.file_name();
+            match file_name.to_str() {
+                Some(s) if !s.starts_with('.') => names.extend(s.parse().ok()),
+                _ => continue,
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
     fn instantiate(&mut self, pkg: &Package, objects: &Self::Objects) -> anyhow::Result<()> {
         let target_dir = self.0.join(pkg.install_name());
 
         if target_dir.exists() {
             Ok(())
         } else {
-            let tree = objects.get_tree(pkg.tree)?;
-
-            // Serialize the tree to a temporary directory first. This way, if an error occurs,
-            // the `packages` directory will not be left in an inconsistent state.
-            let temp_dir = tempfile::tempdir_in("/var/tmp")?;
-            let mut builder = TreeBuilder::new(objects, pkg, &target_dir);
-            builder.construct(tree, temp_dir.path())?;
+            let finished_dir = self.stage(pkg, objects)?;
 
             // Atomically move the package directory to its final location.
-            let finished_dir = temp_dir.into_path();
             match std::fs::rename(finished_dir, &target_dir) {
                 Ok(()) => Ok(()),
                 Err(e) if e.raw_os_error() == Some(39) => Ok(()),
@@ -227,6 +513,142 @@ impl Packages for FsPackages {
             }
         }
     }
+
+    fn signatures(&self, id: &ObjectId) -> anyhow::Result<BTreeSet<Signature>> {
+        match File::open(self.signatures_path(id)) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn add_signatures(&mut self, id: &ObjectId, sigs: &BTreeSet<Signature>) -> anyhow::Result<()> {
+        let mut recorded = self.signatures(id)?;
+        let old_len = recorded.len();
+        recorded.extend(sigs.iter().cloned());
+
+        if recorded.len() > old_len {
+            util::persist_json(&self.signatures_path(id), &recorded, 0o444)?;
+        }
+
+        Ok(())
+    }
+
+    fn cached_closure(&self, id: &ObjectId) -> anyhow::Result<Option<Closure>> {
+        let path = self.closure_path(id);
+        match File::open(&path) {
+            Ok(file) => Closure::read_compact(BufReader::new(file))
+                .map(Some)
+                .with_context(|| format!("failed to read {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn cache_closure(&self, id: &ObjectId, fragment: &Closure) -> anyhow::Result<()> {
+        util::persist_with(&self.closure_path(id), 0o444, |writer| {
+            Ok(fragment.write_compact(writer)?)
+        })
+    }
+
+    fn quarantine_path(&self) -> Option<PathBuf> {
+        Some(self.store_dir().join(QUARANTINE_FILE))
+    }
+
+    fn tmp_dir(&self) -> Option<PathBuf> {
+        Some(self.store_dir().join(TMP_SUBDIR))
+    }
+
+    fn trust_policy(&self) -> anyhow::Result<TrustPolicy> {
+        let path = self.store_dir().join(TRUST_FILE);
+        match File::open(&path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))
+                .with_context(|| format!("failed to read {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(TrustPolicy::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set_trust_policy(&mut self, policy: TrustPolicy) -> anyhow::Result<()> {
+        util::persist_json(&self.store_dir().join(TRUST_FILE), &policy, 0o644)
+    }
+}
+
+impl FsPackages {
+    /// Returns the path where the signatures of the package `id` are recorded, whether it exists
+    /// or not.
+    pub(super) fn signatures_path(&self, id: &ObjectId) -> PathBuf {
+        self.store_dir()
+            .join(SIGNATURES_SUBDIR)
+            .join(format!("{}.json", id))
+    }
+
+    /// Returns the path where the closure fragment of the package `id` is memoized, whether it
+    /// exists or not.
+    pub(super) fn closure_path(&self, id: &ObjectId) -> PathBuf {
+        self.closures_dir().join(format!("{}.closure", id))
+    }
+
+    /// Returns the directory holding the memoized closure fragments, whether it exists or not.
+    pub(super) fn closures_dir(&self) -> PathBuf {
+        self.store_dir().join(CLOSURES_SUBDIR)
+    }
+
+    fn store_dir(&self) -> &Path {
+        self.0
+            .parent()
+            .expect("`packages` dir must be inside a store dir")
+    }
+
+    /// Discards the existing package directory for `pkg`, if any, and instantiates it again.
+    ///
+    /// The new directory is fully constructed before the old one is swapped out, so if an error
+    /// occurs, the existing package directory is left untouched.
+    pub(super) fn reinstantiate(
+        &mut self,
+        pkg: &Package,
+        objects: &FsObjects,
+    ) -> anyhow::Result<()> {
+        let install_name = pkg.install_name();
+        let target_dir = self.0.join(&install_name);
+        let finished_dir = self.stage(pkg, objects)?;
+
+        // Package names cannot start with `.`, so this can never collide with a real package.
+        let trash_dir = self.0.join(format!(".trash-{}", install_name));
+        let has_old = match std::fs::rename(&target_dir, &trash_dir) {
+            Ok(()) => true,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e).context(format!("failed to move {}", target_dir.display())),
+        };
+
+        if let Err(e) = std::fs::rename(&finished_dir, &target_dir) {
+            if has_old {
+                std::fs::rename(&trash_dir, &target_dir)?;
+            }
+            return Err(e).context(format!("failed to persist {}", target_dir.display()));
+        }
+
+        if has_old {
+            std::fs::remove_dir_all(&trash_dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Serializes the tree of `pkg` to a temporary directory and returns its path.
+    ///
+    /// This way, if an error occurs, the `packages` directory will not be left in an inconsistent
+    /// state.
+    fn stage(&self, pkg: &Package, objects: &FsObjects) -> anyhow::Result<PathBuf> {
+        let target_dir = self.0.join(pkg.install_name());
+        let tree = objects.get_tree(pkg.tree)?;
+
+        let temp_dir = tempfile::tempdir_in("/var/tmp")?;
+        let mut builder = TreeBuilder::new(objects, pkg, &target_dir);
+        builder.construct(tree, temp_dir.path())?;
+
+        Ok(temp_dir.into_path())
+    }
 }
 
 // Use a struct with fields and methods because recursive closures are impossible in Rust.
@@ -247,7 +669,7 @@ impl<'a> TreeBuilder<'a> {
 
     fn construct(&mut self, tree: Tree, tree_dir: &Path) -> anyhow::Result<()> {
         if !tree_dir.exists() {
-            std::fs::create_dir_all(&tree_dir)?;
+            std::fs::create_dir_all(tree_dir)?;
             println!("=> created tree subdir: {}", tree_dir.display());
         }
 
@@ -259,21 +681,19 @@ impl<'a> TreeBuilder<'a> {
                     self.construct(subtree, &dst)?;
                 }
                 Entry::Blob { id } => {
-                    let mut src = self.objects.0.join(id.to_path_buf());
-                    src.set_extension(ObjectKind::Blob.as_str());
+                    let src = self.objects.unpack_blob(id)?;
 
-                    if let Some(ref offsets) = self.pkg.self_references.get(&id) {
+                    if let Some(offsets) = self.pkg.self_references.get(id) {
                         std::fs::copy(&src, &dst)?;
 
-                        let mut file = std::fs::File::open(&dst)?;
-                        install::rewrite_paths(&mut file, &self.root_dir, offsets)?;
-
-                        let metadata = file.metadata()?;
-                        let perms = metadata.permissions();
-                        file.set_permissions(perms)?;
+                        // The copy inherits the read-only permissions of the blob object, so make
+                        // it writable only for as long as it takes to patch it.
+                        let mode = std::fs::metadata(&dst)?.permissions().mode();
+                        std::fs::set_permissions(&dst, Permissions::from_mode(mode | 0o200))?;
 
-                        let zero = FileTime::zero();
-                        filetime::set_symlink_file_times(&dst, zero, zero)?;
+                        let mut file = OpenOptions::new().write(true).open(&dst)?;
+                        install::rewrite_paths(&mut file, self.root_dir, offsets)?;
+                        util::normalize_perms(&dst, mode)?;
                     } else {
                         std::fs::hard_link(&src, &dst).map_err(|e| match e.kind() {
                             std::io::ErrorKind::NotFound => anyhow!("blob object {} not found", id),
@@ -286,8 +706,20 @@ impl<'a> TreeBuilder<'a> {
                         );
                     }
                 }
+                Entry::ChunkedBlob { id } => {
+                    let chunked = self.objects.get_chunked_blob(*id)?;
+                    let mut file = File::create(&dst)?;
+                    install::write_chunks(self.objects, &chunked, &mut file)?;
+
+                    if let Some(offsets) = self.pkg.self_references.get(id) {
+                        install::rewrite_paths(&mut file, self.root_dir, offsets)?;
+                    }
+
+                    let mode = if chunked.is_executable { 0o544 } else { 0o444 };
+                    util::normalize_perms(&dst, mode)?;
+                }
                 Entry::Symlink { target } => {
-                    std::os::unix::fs::symlink(&target, &dst)?;
+                    std::os::unix::fs::symlink(target, &dst)?;
                     let zero = FileTime::zero();
                     filetime::set_symlink_file_times(&dst, zero, zero)?;
                     println!(
@@ -299,7 +731,7 @@ impl<'a> TreeBuilder<'a> {
             }
         }
 
-        filetime::set_file_mtime(&tree_dir, FileTime::zero())?;
+        filetime::set_file_mtime(tree_dir, FileTime::zero())?;
 
         Ok(())
     }
@@ -0,0 +1,631 @@
+//! Integrity checks for filesystem-backed stores.
+
+use std::collections::BTreeSet;
+use std::io::Cursor;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use super::{install, Filesystem, LocalStore, Packages};
+use crate::closure;
+use crate::object::Hasher;
+use crate::{
+    util, Blob, Closure, ContentAddressable, Entry, InstallName, ObjectId, ObjectKind, Objects,
+    Package,
+};
+
+impl LocalStore<Filesystem> {
+    /// Verifies the integrity of every object and instantiated package in the store.
+    ///
+    /// This performs the following checks, in order:
+    ///
+    /// 1. Every object is rehashed and its ID compared against its file name or pack index entry.
+    /// 2. Every blob and tree referenced by a tree object exists in the store, along with every
+    ///    chunk of every chunked blob.
+    /// 3. Every package referenced by a package object, along with its tree, exists in the store.
+    /// 4. Every instantiated package directory matches the tree it was constructed from.
+    /// 5. Every memoized closure fragment in `closures` can be read, and matches its package.
+    ///
+    /// Because blobs are hard-linked into package directories, a file modified inside `packages`
+    /// will also be reported as a hash mismatch on the corresponding blob object.
+    ///
+    /// Problems with individual objects and package files, including files which could not be
+    /// read, are collected into the returned report rather than aborting the check. Returns `Err`
+    /// only if the `objects` or `packages` directory itself could not be read.
+    pub fn fsck(&self) -> anyhow::Result<FsckReport> {
+        let mut report = FsckReport::default();
+        let mut chunked_blobs = Vec::new();
+        let mut trees = Vec::new();
+        let mut packages = Vec::new();
+
+        let loose = self.objects.list_loose()?;
+        let loose_ids: BTreeSet<_> = loose.iter().map(|&(id, _, _)| id).collect();
+        let packed = self
+            .objects
+            .list_packed()
+            .into_iter()
+            .filter_map(|(id, kind, _)| {
+                // Packed objects which were also unpacked are checked through their loose file.
+                if loose_ids.contains(&id) {
+                    None
+                } else {
+                    let actual = self
+                        .objects
+                        .get_object(id, Some(kind))
+                        .map(|o| o.object_id());
+                    Some((id, kind, actual))
+                }
+            });
+
+        let loose = loose
+            .into_iter()
+            .map(|(id, kind, path)| (id, kind, rehash(&path, kind)));
+
+        for (id, kind, result) in loose.chain(packed) {
+            report.num_objects += 1;
+
+            match result {
+                Ok(actual) if actual == id => match kind {
+                    ObjectKind::ChunkedBlob => chunked_blobs.push(id),
+                    ObjectKind::Tree => trees.push(id),
+                    ObjectKind::Package => packages.push(id),
+                    _ => {}
+                },
+                Ok(actual) => report
+                    .problems
+                    .push(Problem::HashMismatch { id, kind, actual }),
+                Err(e) => report.problems.push(Problem::Unreadable {
+                    id,
+                    kind,
+                    error: e.to_string(),
+                }),
+            }
+        }
+
+        for id in chunked_blobs {
+            let chunked = match self.objects.get_chunked_blob(id) {
+                Ok(chunked) => chunked,
+                Err(e) => {
+                    let (kind, error) = (ObjectKind::ChunkedBlob, e.to_string());
+                    report
+                        .problems
+                        .push(Problem::Unreadable { id, kind, error });
+                    continue;
+                }
+            };
+
+            for chunk in &chunked.chunks {
+                if !self
+                    .objects
+                    .contains_object(&chunk.id, Some(ObjectKind::Blob))?
+                {
+                    report.problems.push(Problem::MissingChunk {
+                        chunked_blob: id,
+                        chunk: chunk.id,
+                    });
+                }
+            }
+        }
+
+        for id in trees {
+            let tree = match self.objects.get_tree(id) {
+                Ok(tree) => tree,
+                Err(e) => {
+                    let (kind, error) = (ObjectKind::Tree, e.to_string());
+                    report
+                        .problems
+                        .push(Problem::Unreadable { id, kind, error });
+                    continue;
+                }
+            };
+
+            for (name, entry) in &tree.entries {
+                let (entry_id, kind) = match *entry {
+                    Entry::Tree { id } => (id, ObjectKind::Tree),
+                    Entry::Blob { id } => (id, ObjectKind::Blob),
+                    Entry::ChunkedBlob { id } => (id, ObjectKind::ChunkedBlob),
+                    Entry::Symlink { .. } => continue,
+                };
+
+                if !self.objects.contains_object(&entry_id, Some(kind))? {
+                    report.problems.push(Problem::MissingEntry {
+                        tree: id,
+                        name: name.clone(),
+                        id: entry_id,
+                        kind,
+                    });
+                }
+            }
+        }
+
+        for id in packages {
+            let pkg = match self.objects.get_package(id) {
+                Ok(pkg) => pkg,
+                Err(e) => {
+                    let (kind, error) = (ObjectKind::Package, e.to_string());
+                    report
+                        .problems
+                        .push(Problem::Unreadable { id, kind, error });
+                    continue;
+                }
+            };
+
+            for &reference in &pkg.references {
+                if !self
+                    .objects
+                    .contains_object(&reference, Some(ObjectKind::Package))?
+                {
+                    let problem = Problem::MissingReference {
+                        package: id,
+                        reference,
+                    };
+                    report.problems.push(problem);
+                }
+            }
+
+            if !self
+                .objects
+                .contains_object(&pkg.tree, Some(ObjectKind::Tree))?
+            {
+                let problem = Problem::MissingTree {
+                    package: id,
+                    tree: pkg.tree,
+                };
+                report.problems.push(problem);
+            }
+        }
+
+        for entry in std::fs::read_dir(self.packages.path())? {
+            let path = entry?.path();
+            let install_name: InstallName = match path.file_name().and_then(|s| s.to_str()) {
+                Some(s) if !s.starts_with('.') => match s.parse() {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+
+            report.num_packages += 1;
+
+            match self.objects.get_package(install_name.id()) {
+                Ok(pkg) => {
+                    let mut checker = PackageChecker {
+                        store: self,
+                        pkg: &pkg,
+                        root_dir: &path,
+                        problems: &mut report.problems,
+                    };
+                    checker.check_tree(pkg.tree, Path::new(""));
+                }
+                Err(_) => report
+                    .problems
+                    .push(Problem::OrphanedPackage { install_name }),
+            }
+        }
+
+        let closures = match std::fs::read_dir(self.packages.closures_dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(report),
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in closures {
+            let path = entry?.path();
+            let package = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(s) if path.extension() == Some("closure".as_ref()) => match s.parse() {
+                    Ok(id) => id,
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+
+            if let Some(error) = self.check_closure(package, &path) {
+                report
+                    .problems
+                    .push(Problem::CorruptClosure { package, error });
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Compares the closure fragment memoized at `path` against the package `id`.
+    ///
+    /// Returns a description of the problem, or `None` if the fragment is intact or the package
+    /// could not be walked, which is reported elsewhere.
+    fn check_closure(&self, id: ObjectId, path: &Path) -> Option<String> {
+        if !self
+            .objects
+            .contains_object(&id, Some(ObjectKind::Package))
+            .unwrap_or(true)
+        {
+            return Some("package is missing from the store".into());
+        }
+
+        let cached = std::fs::File::open(path)
+            .map_err(Into::into)
+            .and_then(|file| Closure::read_compact(std::io::BufReader::new(file)));
+        let cached = match cached {
+            Ok(cached) => cached,
+            Err(e) => return Some(e.to_string()),
+        };
+
+        match closure::package_fragment(&self.objects, id) {
+            Ok(actual) if actual.graph() != cached.graph() => {
+                Some("fragment does not match the package".into())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Recomputes the object ID of the object file located at `path`.
+fn rehash(path: &Path, kind: ObjectKind) -> anyhow::Result<ObjectId> {
+    let mut hasher = match kind {
+        ObjectKind::Blob => return Ok(Blob::whole_from_path(path)?.object_id()),
+        ObjectKind::ChunkedBlob => Hasher::new_chunked_blob(),
+        ObjectKind::Tree => Hasher::new_tree(),
+        ObjectKind::Package => Hasher::new_package(),
+        ObjectKind::Spec => Hasher::new_spec(),
+    };
+
+    let bytes = std::fs::read(path)?;
+    Ok(hasher.update(&bytes).finish())
+}
+
+// Use a struct with fields and methods because recursive closures are impossible in Rust.
+struct PackageChecker<'a> {
+    store: &'a LocalStore<Filesystem>,
+    pkg: &'a Package,
+    root_dir: &'a Path,
+    problems: &'a mut Vec<Problem>,
+}
+
+impl<'a> PackageChecker<'a> {
+    fn check_tree(&mut self, tree_id: ObjectId, rel_dir: &Path) {
+        // Missing and corrupt trees are already reported above, so skip over them here.
+        let tree = match self.store.objects.get_tree(tree_id) {
+            Ok(tree) => tree,
+            Err(_) => return,
+        };
+
+        let dir = self.root_dir.join(rel_dir);
+        let unexpected: std::io::Result<BTreeSet<_>> = std::fs::read_dir(&dir)
+            .and_then(|entries| entries.map(|e| e.map(|e| e.file_name())).collect());
+        let mut unexpected = match unexpected {
+            Ok(names) => names,
+            Err(_) => return self.mismatch(rel_dir.to_owned(), Mismatch::Unreadable),
+        };
+
+        for (name, entry) in &tree.entries {
+            let rel_path = rel_dir.join(name);
+            let path = self.root_dir.join(&rel_path);
+            unexpected.remove(std::ffi::OsStr::new(name));
+
+            let metadata = match path.symlink_metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => {
+                    self.mismatch(rel_path, Mismatch::Missing);
+                    continue;
+                }
+            };
+
+            let matches = match entry {
+                Entry::Tree { id } if metadata.is_dir() => {
+                    self.check_tree(*id, &rel_path);
+                    continue;
+                }
+                Entry::Blob { id } if metadata.is_file() => {
+                    self.blob_matches(*id, &path, &metadata)
+                }
+                Entry::ChunkedBlob { id } if metadata.is_file() => {
+                    self.chunked_blob_matches(*id, &path, &metadata)
+                }
+                Entry::Symlink { target } if metadata.file_type().is_symlink() => path
+                    .read_link()
+                    .map(|actual| actual == *target)
+                    .map_err(Into::into),
+                _ => {
+                    self.mismatch(rel_path, Mismatch::WrongType);
+                    continue;
+                }
+            };
+
+            match matches {
+                Ok(true) => {}
+                Ok(false) => self.mismatch(rel_path, Mismatch::ContentDiffers),
+                Err(_) => self.mismatch(rel_path, Mismatch::Unreadable),
+            }
+        }
+
+        for name in unexpected {
+            self.mismatch(rel_dir.join(name), Mismatch::Unexpected);
+        }
+    }
+
+    fn blob_matches(
+        &self,
+        id: ObjectId,
+        path: &Path,
+        metadata: &std::fs::Metadata,
+    ) -> anyhow::Result<bool> {
+        if let Some(offsets) = self.pkg.self_references.get(&id) {
+            // Missing and corrupt blobs are already reported above.
+            let blob = match self.store.objects.get_blob(id) {
+                Ok(blob) => blob,
+                Err(_) => return Ok(true),
+            };
+
+            // Self-referencing blobs are copied, so compare against the expected patched content.
+            let blob_is_executable = blob.is_executable();
+            let mut expected = Cursor::new(Vec::new());
+            util::copy_wide(&mut blob.into_content()?, &mut expected)?;
+            install::rewrite_paths(&mut expected, self.root_dir, offsets)?;
+            let is_executable = metadata.permissions().mode() & 0o100 != 0;
+            Ok(
+                is_executable == blob_is_executable
+                    && std::fs::read(path)? == expected.into_inner(),
+            )
+        } else {
+            let blob_path = self.store.objects.object_path(&id, ObjectKind::Blob);
+            match blob_path.metadata() {
+                // Hard-linked to the blob object, whose hash is verified separately.
+                Ok(m) if metadata.dev() == m.dev() && metadata.ino() == m.ino() => Ok(true),
+                _ => Ok(Blob::whole_from_path(path)?.object_id() == id),
+            }
+        }
+    }
+
+    fn chunked_blob_matches(
+        &self,
+        id: ObjectId,
+        path: &Path,
+        metadata: &std::fs::Metadata,
+    ) -> anyhow::Result<bool> {
+        if let Some(offsets) = self.pkg.self_references.get(&id) {
+            // Missing and corrupt chunks are already reported above.
+            let chunked = match self.store.objects.get_chunked_blob(id) {
+                Ok(chunked) => chunked,
+                Err(_) => return Ok(true),
+            };
+
+            let mut expected = Cursor::new(Vec::new());
+            if install::write_chunks(&self.store.objects, &chunked, &mut expected).is_err() {
+                return Ok(true);
+            }
+
+            install::rewrite_paths(&mut expected, self.root_dir, offsets)?;
+            let is_executable = metadata.permissions().mode() & 0o100 != 0;
+            Ok(is_executable == chunked.is_executable
+                && std::fs::read(path)? == expected.into_inner())
+        } else {
+            // Chunked files are always assembled into a fresh copy, so they must be rehashed.
+            Ok(Blob::file_from_path(path)?.0.object_id() == id)
+        }
+    }
+
+    fn mismatch(&mut self, path: PathBuf, kind: Mismatch) {
+        self.problems.push(Problem::PackageMismatch {
+            package: self.pkg.object_id(),
+            path,
+            kind,
+        });
+    }
+}
+
+/// A summary of the problems found by an integrity check.
+///
+/// This struct is created by [`LocalStore::fsck()`]. See its documentation for more.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Number of objects checked.
+    pub num_objects: usize,
+    /// Number of instantiated package directories checked.
+    pub num_packages: usize,
+    /// Problems found in the store, if any.
+    pub problems: Vec<Problem>,
+}
+
+impl FsckReport {
+    /// Returns `true` if no problems were found.
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A single integrity problem found in the store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Problem {
+    /// The contents of an object do not hash to the ID in its file name.
+    HashMismatch {
+        /// ID of the object, according to its file name.
+        id: ObjectId,
+        /// Kind of the object.
+        kind: ObjectKind,
+        /// ID that the contents actually hash to.
+        actual: ObjectId,
+    },
+    /// An object could not be read or deserialized.
+    Unreadable {
+        /// ID of the object.
+        id: ObjectId,
+        /// Kind of the object.
+        kind: ObjectKind,
+        /// Description of the error.
+        error: String,
+    },
+    /// A tree object contains an entry whose object is missing from the store.
+    MissingEntry {
+        /// ID of the tree object.
+        tree: ObjectId,
+        /// File name of the entry.
+        name: String,
+        /// ID of the missing object.
+        id: ObjectId,
+        /// Kind of the missing object.
+        kind: ObjectKind,
+    },
+    /// A chunked blob object lists a chunk which is missing from the store.
+    MissingChunk {
+        /// ID of the chunked blob object.
+        chunked_blob: ObjectId,
+        /// ID of the missing chunk.
+        chunk: ObjectId,
+    },
+    /// A package object references another package which is missing from the store.
+    MissingReference {
+        /// ID of the package object.
+        package: ObjectId,
+        /// ID of the missing package.
+        reference: ObjectId,
+    },
+    /// A package object points to a tree which is missing from the store.
+    MissingTree {
+        /// ID of the package object.
+        package: ObjectId,
+        /// ID of the missing tree.
+        tree: ObjectId,
+    },
+    /// A package directory exists in `packages`, but its package object is missing.
+    OrphanedPackage {
+        /// Name of the package directory.
+        install_name: InstallName,
+    },
+    /// A memoized closure fragment in `closures` is unreadable, or does not match its package.
+    CorruptClosure {
+        /// ID of the package the fragment was memoized for.
+        package: ObjectId,
+        /// Description of the problem.
+        error: String,
+    },
+    /// An instantiated package directory does not match its tree.
+    PackageMismatch {
+        /// ID of the package object.
+        package: ObjectId,
+        /// Path of the offending entry, relative to the package directory.
+        path: PathBuf,
+        /// Way in which the entry differs from the tree.
+        kind: Mismatch,
+    },
+}
+
+/// A way in which an instantiated package directory can differ from its tree.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mismatch {
+    /// The entry exists in the tree, but not on disk.
+    Missing,
+    /// The entry exists on disk, but not in the tree.
+    Unexpected,
+    /// The entry on disk is of a different type (file, directory, symlink) than in the tree.
+    WrongType,
+    /// The file contents, executable bit, or symlink target differ from the tree.
+    ContentDiffers,
+    /// The entry on disk could not be read.
+    Unreadable,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::test_support::{insert_blob, insert_package, insert_package_with, insert_tree};
+    use crate::{Object, Platform, References, Tree};
+
+    #[test]
+    fn accepts_healthy_store() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let file = insert_blob(&mut store, b"hello", false);
+        let link = Entry::Symlink {
+            target: "hello.txt".into(),
+        };
+        let tree = insert_tree(&mut store, vec![("hello.txt", file), ("link", link)]);
+        insert_package_with(&mut store, "hello", &[], tree);
+
+        let report = store.fsck().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.num_objects, 3);
+        assert_eq!(report.num_packages, 1);
+    }
+
+    #[test]
+    fn detects_modified_package_file() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let pkg = insert_package(&mut store, "hello", &[]);
+        let pkg = store.get_package(pkg).unwrap();
+
+        let pkg_dir = store.packages.path().join(pkg.install_name());
+        let file = pkg_dir.join("hello.txt");
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        std::fs::write(&file, b"goodbye").unwrap();
+        std::fs::write(pkg_dir.join("extra"), b"").unwrap();
+
+        let report = store.fsck().unwrap();
+        let blob_id = Blob::from_bytes(b"hello".to_vec(), false).0.object_id();
+        let corrupt_id = Blob::from_bytes(b"goodbye".to_vec(), false).0.object_id();
+        assert!(report.problems.contains(&Problem::HashMismatch {
+            id: blob_id,
+            kind: ObjectKind::Blob,
+            actual: corrupt_id,
+        }));
+        assert!(report.problems.contains(&Problem::PackageMismatch {
+            package: pkg.object_id(),
+            path: "extra".into(),
+            kind: Mismatch::Unexpected,
+        }));
+    }
+
+    #[test]
+    fn checks_self_referencing_files() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+
+        let packages_dir = store.packages.path().to_owned();
+        let placeholder = packages_dir.join(format!("hello-{}", ObjectId::zero()));
+        let content = placeholder.to_str().unwrap().as_bytes().to_vec();
+        let (blob, _) = Blob::from_bytes(content, true);
+        let blob_id = store.insert_object(Object::Blob(blob)).unwrap();
+
+        let mut entries = BTreeMap::new();
+        entries.insert("hello".into(), Entry::Blob { id: blob_id });
+        let tree_id = store.insert_object(Object::Tree(Tree { entries })).unwrap();
+
+        let pkg = Package {
+            name: "hello".parse().unwrap(),
+            system: Platform::host(),
+            references: References::new(),
+            self_references: std::iter::once((blob_id, std::iter::once(0).collect())).collect(),
+            tree: tree_id,
+        };
+        store.insert_object(Object::Package(pkg.clone())).unwrap();
+
+        let pkg_dir = packages_dir.join(pkg.install_name());
+        let installed = std::fs::read(pkg_dir.join("hello")).unwrap();
+        assert_eq!(installed, pkg_dir.to_str().unwrap().as_bytes());
+
+        let report = store.fsck().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn detects_missing_objects() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let pkg = insert_package(&mut store, "hello", &[]);
+        let pkg = store.get_package(pkg).unwrap();
+
+        let tree_path = store.objects.object_path(&pkg.tree, ObjectKind::Tree);
+        std::fs::remove_file(tree_path).unwrap();
+
+        let report = store.fsck().unwrap();
+        assert_eq!(
+            report.problems,
+            vec![Problem::MissingTree {
+                package: pkg.object_id(),
+                tree: pkg.tree,
+            }]
+        );
+    }
+}
@@ -0,0 +1,358 @@
+//! Garbage collection of unreachable objects and packages.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context};
+
+use super::fs::GCROOTS_SUBDIR;
+use super::{Filesystem, LocalStore, Packages};
+use crate::{InstallName, ObjectId, ObjectKind, Objects};
+
+impl LocalStore<Filesystem> {
+    /// Registers the package `pkg` as a garbage collection root called `name`.
+    ///
+    /// Roots are stored as symlinks in the `gcroots` directory pointing to the instantiated package
+    /// directory. Registering a root with a name that already exists atomically replaces it.
+    ///
+    /// Returns `Err` if `name` is not a valid file name, `pkg` is not installed in the store, or an
+    /// I/O error occurred.
+    pub fn add_root(&mut self, name: &str, pkg: ObjectId) -> anyhow::Result<()> {
+        validate_root_name(name)?;
+
+        let install_name = self.get_package(pkg)?.install_name();
+        let target = Path::new("..")
+            .join(self.packages.path().file_name().unwrap())
+            .join(&install_name);
+
+        let roots_dir = self.gcroots_dir();
+        std::fs::create_dir_all(&roots_dir).context("could not create `gcroots` dir")?;
+
+        // Create the symlink under a temporary name first, then atomically move it into place.
+        let temp_link = roots_dir.join(format!(".tmp-{}", name));
+        let _ = std::fs::remove_file(&temp_link);
+        std::os::unix::fs::symlink(&target, &temp_link)?;
+        std::fs::rename(&temp_link, roots_dir.join(name))
+            .with_context(|| format!("failed to persist GC root `{}`", name))?;
+
+        Ok(())
+    }
+
+    /// Unregisters the garbage collection root called `name`.
+    ///
+    /// The package it pointed to is not deleted until the next call to [`collect_garbage()`].
+    ///
+    /// Returns `Err` if no such root exists or an I/O error occurred.
+    ///
+    /// [`collect_garbage()`]: LocalStore::collect_garbage
+    pub fn remove_root(&mut self, name: &str) -> anyhow::Result<()> {
+        validate_root_name(name)?;
+
+        let link = self.gcroots_dir().join(name);
+        std::fs::remove_file(&link).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => anyhow!("GC root `{}` not found", name),
+            _ => e.into(),
+        })
+    }
+
+    /// Returns all registered garbage collection roots, mapped to the packages they point to.
+    ///
+    /// Returns `Err` if a root does not point to a package directory or an I/O error occurred.
+    pub fn roots(&self) -> anyhow::Result<BTreeMap<String, ObjectId>> {
+        let roots_dir = self.gcroots_dir();
+        let mut roots = BTreeMap::new();
+
+        if !roots_dir.exists() {
+            return Ok(roots);
+        }
+
+        for entry in std::fs::read_dir(&roots_dir)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) if !name.starts_with('.') => name,
+                _ => continue,
+            };
+
+            let target = std::fs::read_link(entry.path())?;
+            let install_name: InstallName = target
+                .file_name()
+                .and_then(|s| s.to_str())
+                .ok_or_else(|| anyhow!("GC root `{}` does not point to a package", name))?
+                .parse()
+                .with_context(|| format!("GC root `{}` does not point to a package", name))?;
+
+            roots.insert(name, install_name.id());
+        }
+
+        Ok(roots)
+    }
+
+    /// Deletes all objects and packages which are not reachable from any garbage collection root.
+    ///
+    /// This is a simple mark-and-sweep collector. The closure of every root is computed first, and
+    /// then every object and package directory not contained in it is deleted. Unreachable package
+    /// directories are deleted before any objects, so the store remains consistent if collection
+    /// is interrupted partway through.
+    ///
+    /// `Spec` objects are never part of a closure, so they are kept as long as a package built from
+    /// them is reachable, or if no package built from them exists at all. The packages a spec
+    /// depends on are not kept alive by it, since they can be fetched again before building.
+    ///
+    /// If `dry_run` is `true`, nothing is deleted, but the returned report still lists everything
+    /// that would have been.
+    ///
+    /// Returns `Err` if a root points to a package which does not exist in the store, the closure
+    /// could not be computed, or an I/O error occurred.
+    pub fn collect_garbage(&mut self, dry_run: bool) -> anyhow::Result<GcReport> {
+        let roots: BTreeSet<_> = self.roots()?.into_values().collect();
+        let mut live: BTreeSet<_> = self
+            .compute_closure(roots)?
+            .iter()
+            .map(|&(id, _, _)| id)
+            .collect();
+
+        let mut report = GcReport::default();
+
+        // Mark unreachable package directories. Files hard-linked into `objects` are accounted
+        // for below, so only count self-referencing files which were copied.
+        let mut dead_pkgs = Vec::new();
+        let mut live_names = BTreeSet::new();
+        let mut dead_names = BTreeSet::new();
+        for entry in std::fs::read_dir(self.packages.path())? {
+            let path = entry?.path();
+            let install_name: InstallName = match path.file_name().and_then(|s| s.to_str()) {
+                Some(s) if !s.starts_with('.') => match s.parse() {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+
+            if live.contains(&install_name.id()) {
+                live_names.insert(install_name.name().to_owned());
+            } else {
+                dead_names.insert(install_name.name().to_owned());
+                report.bytes_freed += copied_size(&path)?;
+                report.removed_packages.push(install_name);
+                dead_pkgs.push(path);
+            }
+        }
+
+        // Nothing references a spec, so a spec only becomes garbage once every package built from
+        // it does. Specs which were never built are kept as well.
+        let loose_specs = self
+            .objects
+            .list_loose()?
+            .into_iter()
+            .map(|(id, k, _)| (id, k));
+        let packed_specs = self
+            .objects
+            .list_packed()
+            .into_iter()
+            .map(|(id, k, _)| (id, k));
+        let specs: BTreeSet<_> = loose_specs
+            .chain(packed_specs)
+            .filter(|&(_, kind)| kind == ObjectKind::Spec)
+            .map(|(id, _)| id)
+            .collect();
+        for id in specs {
+            let spec = self.get_spec(id)?;
+            let name = format!("{}-{}", spec.name, spec.version);
+            if live_names.contains(&name) || !dead_names.contains(&name) {
+                live.insert(id);
+            }
+        }
+
+        // Mark unreachable objects, both loose and packed.
+        let mut dead_objs = Vec::new();
+        for (id, kind, path) in self.objects.list_loose()? {
+            if !live.contains(&id) {
+                report.bytes_freed += std::fs::symlink_metadata(&path)?.len();
+                report.removed_objects.push((id, kind));
+                dead_objs.push(path);
+            }
+        }
+
+        let dead_loose: BTreeSet<_> = report.removed_objects.iter().map(|&(id, _)| id).collect();
+        let mut live_packed = Vec::new();
+        let mut has_dead_packed = false;
+        for (id, kind, size) in self.objects.list_packed() {
+            if live.contains(&id) {
+                live_packed.push((id, kind));
+            } else {
+                if !dead_loose.contains(&id) {
+                    report.removed_objects.push((id, kind));
+                }
+                report.bytes_freed += size;
+                has_dead_packed = true;
+            }
+        }
+
+        if dry_run {
+            return Ok(report);
+        }
+
+        // Sweep packages first, then objects.
+        for path in dead_pkgs {
+            // Move the directory out of the way first so a half-deleted package is never visible.
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            let trash = path.with_file_name(format!(".gc-{}", file_name));
+            std::fs::rename(&path, &trash)?;
+            std::fs::remove_dir_all(&trash)
+                .with_context(|| format!("failed to delete {}", path.display()))?;
+        }
+
+        for path in dead_objs {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("failed to delete {}", path.display()))?;
+
+            // Clean up the parent `ab/` directory if it is now empty. Failure is harmless.
+            let _ = std::fs::remove_dir(path.parent().unwrap());
+        }
+
+        // Pack files are immutable, so rewrite them without the unreachable objects.
+        if has_dead_packed {
+            self.objects.rewrite_packs(live_packed)?;
+        }
+
+        // Signatures and memoized closures are only meaningful while their package is still
+        // around.
+        for &(id, kind) in &report.removed_objects {
+            if kind == ObjectKind::Package {
+                for path in [
+                    self.packages.signatures_path(&id),
+                    self.packages.closure_path(&id),
+                ] {
+                    match std::fs::remove_file(path) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn gcroots_dir(&self) -> PathBuf {
+        let store_dir = self.objects.path().parent().unwrap();
+        store_dir.join(GCROOTS_SUBDIR)
+    }
+}
+
+fn validate_root_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        Err(anyhow!("invalid GC root name {:?}", name))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the total size of all files in `dir` which are not hard-linked elsewhere.
+fn copied_size(dir: &Path) -> anyhow::Result<u64> {
+    let mut total = 0;
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.path().symlink_metadata()?;
+        if metadata.is_dir() {
+            total += copied_size(&entry.path())?;
+        } else if metadata.is_file() && metadata.nlink() == 1 {
+            total += metadata.len();
+        }
+    }
+
+    Ok(total)
+}
+
+/// A summary of the objects and packages deleted by the garbage collector.
+///
+/// This struct is created by [`LocalStore::collect_garbage()`]. See its documentation for more.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// Objects which were found to be unreachable from any root.
+    pub removed_objects: Vec<(ObjectId, ObjectKind)>,
+    /// Instantiated packages which were found to be unreachable from any root.
+    pub removed_packages: Vec<InstallName>,
+    /// Number of bytes freed on disk, or which would be freed if this was a dry run.
+    pub bytes_freed: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{insert_package, insert_spec};
+    use crate::SecretKey;
+
+    #[test]
+    fn manages_roots() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let foo = insert_package(&mut store, "foo", &[]);
+
+        store.add_root("current", foo).unwrap();
+        assert_eq!(store.roots().unwrap().get("current"), Some(&foo));
+        assert!(store.add_root("../escape", foo).is_err());
+
+        store.remove_root("current").unwrap();
+        assert!(store.roots().unwrap().is_empty());
+        assert!(store.remove_root("current").is_err());
+    }
+
+    #[test]
+    fn collects_unreachable_objects() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let foo = insert_package(&mut store, "foo", &[]);
+        let bar = insert_package(&mut store, "bar", &[foo]);
+        let baz = insert_package(&mut store, "baz", &[]);
+        let baz_name = store.get_package(baz).unwrap().install_name();
+
+        store.add_root("bar", bar).unwrap();
+
+        let key = SecretKey::generate("local-1").unwrap();
+        store.sign_package(bar, &key).unwrap();
+        store.sign_package(baz, &key).unwrap();
+
+        let dry = store.collect_garbage(true).unwrap();
+        assert_eq!(dry.removed_objects.len(), 3);
+        assert_eq!(dry.removed_packages, vec![baz_name.clone()]);
+        assert!(dry.bytes_freed > 0);
+        assert!(store.contains_object(&baz, None).unwrap());
+
+        let report = store.collect_garbage(false).unwrap();
+        assert_eq!(report.removed_objects, dry.removed_objects);
+        assert_eq!(report.bytes_freed, dry.bytes_freed);
+        assert!(!store.contains_object(&baz, None).unwrap());
+        assert!(!store.packages.path().join(&baz_name).exists());
+        assert!(store.contains_object(&foo, None).unwrap());
+        assert!(store.contains_object(&bar, None).unwrap());
+        assert!(store.signatures(&baz).unwrap().is_empty());
+        assert_eq!(store.signatures(&bar).unwrap().len(), 1);
+
+        let again = store.collect_garbage(false).unwrap();
+        assert!(again.removed_objects.is_empty());
+        assert_eq!(again.bytes_freed, 0);
+    }
+
+    #[test]
+    fn keeps_specs_of_reachable_packages() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let foo = insert_package(&mut store, "foo-1.0.0", &[]);
+        let foo_spec = insert_spec(&mut store, "foo", &[], &[], "true");
+        insert_package(&mut store, "bar-1.0.0", &[]);
+        let bar_spec = insert_spec(&mut store, "bar", &[], &[], "true");
+        let baz_spec = insert_spec(&mut store, "baz", &[], &[], "true");
+
+        store.add_root("foo", foo).unwrap();
+        let report = store.collect_garbage(false).unwrap();
+        assert!(report
+            .removed_objects
+            .contains(&(bar_spec, ObjectKind::Spec)));
+        assert!(store.contains_object(&foo_spec, None).unwrap());
+        assert!(!store.contains_object(&bar_spec, None).unwrap());
+        assert!(store.contains_object(&baz_spec, None).unwrap());
+    }
+}
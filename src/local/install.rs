@@ -10,8 +10,8 @@ use anyhow::{anyhow, Context};
 use super::{Backend, LocalStore, Packages};
 use crate::object::RewriteSink;
 use crate::{
-    util, Blob, Entry, Object, ObjectId, Objects, Offsets, Package, Platform, References, Spec,
-    Tree,
+    util, Blob, ChunkedBlob, Entry, FileContent, Object, ObjectId, Objects, Offsets, Package,
+    Platform, References, Spec, Tree,
 };
 
 const PATCHELF_BIN: &str = "patchelf";
@@ -48,7 +48,7 @@ impl<B: Backend> LocalStore<B> {
 
         self.insert_object(Object::Package(Package {
             name,
-            system: spec.target.unwrap_or_else(|| Platform::host()),
+            system: spec.target.unwrap_or_else(Platform::host),
             references,
             self_references: self_refs,
             tree: tree_id,
@@ -83,6 +83,29 @@ where
     Ok(())
 }
 
+/// Reassembles the file described by `chunked` by writing each of its chunks to `writer` in turn.
+///
+/// Returns the total number of bytes written, or `Err` if a chunk is missing from `objects` or an
+/// I/O error occurred.
+pub(super) fn write_chunks<O, W>(
+    objects: &O,
+    chunked: &ChunkedBlob,
+    writer: &mut W,
+) -> anyhow::Result<u64>
+where
+    O: Objects + ?Sized,
+    W: Write,
+{
+    let mut written = 0;
+
+    for chunk in &chunked.chunks {
+        let blob = objects.get_blob(chunk.id)?;
+        written += util::copy_wide(&mut blob.into_content()?, writer)?;
+    }
+
+    Ok(written)
+}
+
 /// Recursively inserts the contents of `tree_dir` in the store as a tree object, patching out any
 /// self-references to `out_dir` detected in blobs and symlinks by converting them to relative
 /// paths. This is to maintain the content addressable invariant of the store.
@@ -125,15 +148,16 @@ where
             entries.insert(file_name, Entry::Tree { id });
         } else if file_type.is_file() {
             let pkgs_dir = store.packages.path();
-            let (blob, refs, offsets) = make_content_addressed(&path, out_dir, &pkgs_dir, spec)?;
+            let (file, refs, offsets) = make_content_addressed(&path, out_dir, pkgs_dir, spec)?;
 
-            let id = store.insert_object(Object::Blob(blob))?;
+            let id = file.object_id();
+            let entry = store.insert_file(file)?;
             references.extend(refs);
             if !offsets.is_empty() {
                 self_references.insert(id, offsets);
             }
 
-            entries.insert(file_name, Entry::Blob { id });
+            entries.insert(file_name, entry);
         } else if file_type.is_symlink() {
             let target = path.read_link()?;
             let norm_target = target.canonicalize()?;
@@ -155,7 +179,7 @@ where
     Ok((tree_id, references, self_references))
 }
 
-/// Prepares `file` for insertion into the store as a blob object.
+/// Prepares `file` for insertion into the store as a blob object, or a chunked blob if it is large.
 ///
 /// This function scans the contents of `file` for run-time references, replacing any detected
 /// self-references to `out_dir` as a fixed value (in this case, the final install directory but
@@ -165,13 +189,14 @@ where
 /// However, if `file` is an executable with self-references, its RPATHs will be patched _in-place_
 /// before its contents are streamed, hashed, and rewritten into the temp file.
 ///
-/// Returns the new `Blob`, any detected run-time references, and locations of any self-references.
+/// Returns the new file object, any detected run-time references, and locations of any
+/// self-references.
 fn make_content_addressed(
     file: &Path,
     out_dir: &Path,
     pkgs_dir: &Path,
     spec: &Spec,
-) -> anyhow::Result<(Blob, References, Offsets)> {
+) -> anyhow::Result<(FileContent, References, Offsets)> {
     debug_assert!(file.starts_with(out_dir));
     debug_assert!(pkgs_dir.is_absolute());
 
@@ -203,16 +228,16 @@ fn make_content_addressed(
     ));
 
     // Rewrite any self-references to the install dir with a zeroed-out placeholder install dir.
-    let writer = Blob::from_writer(is_executable);
+    let writer = Blob::file_from_writer(is_executable);
     let mut rewrite = RewriteSink::new(writer, out_dir, &zeroed_install_dir)?;
     util::copy_wide(&mut reader, &mut rewrite)?;
     let (writer, offsets) = rewrite.into_inner()?;
-    let (blob, mut references) = writer.finish();
+    let (file, mut references) = writer.finish_file();
 
     // Do not count the placeholder hash as a reference.
     references.remove(&ObjectId::zero());
 
-    Ok((blob, references, offsets))
+    Ok((file, references, offsets))
 }
 
 /// Patches all executable RPATHs that start with `prefix` to use relative paths.
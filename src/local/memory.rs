@@ -0,0 +1,520 @@
+//! In-memory store implementation.
+
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use anyhow::anyhow;
+
+use super::{install, Backend, LocalStore, Objects, Packages};
+use crate::{
+    Blob, ChunkedBlob, Closure, ContentAddressable, Entry, InstallName, Object, ObjectId,
+    ObjectKind, Package, Signature, Spec, Tree, TrustPolicy,
+};
+
+const PACKAGES_SUBDIR: &str = "packages";
+const DEFAULT_STORE_DIR: &str = "/store";
+
+/// A store implementation backed entirely by main memory.
+///
+/// Nothing is ever written to disk, which makes this backend well suited for unit testing. The
+/// `path` passed to [`Backend::open()`] is purely virtual: it is never created, but it is still
+/// used as the install prefix when patching out package self-references.
+#[derive(Debug)]
+pub enum Memory {}
+
+impl Backend for Memory {
+    type Objects = MemObjects;
+    type Packages = MemPackages;
+
+    fn open(path: PathBuf) -> anyhow::Result<(Self::Objects, Self::Packages)> {
+        if !path.is_absolute() {
+            return Err(anyhow!("`{}` is not an absolute path", path.display()));
+        }
+
+        let packages = MemPackages {
+            path: path.join(PACKAGES_SUBDIR),
+            installed: BTreeMap::new(),
+            signatures: BTreeMap::new(),
+            closures: RwLock::new(BTreeMap::new()),
+            trust: TrustPolicy::default(),
+        };
+
+        Ok((MemObjects::default(), packages))
+    }
+
+    fn init(path: PathBuf) -> anyhow::Result<(Self::Objects, Self::Packages)> {
+        Self::open(path)
+    }
+
+    fn init_bare(path: PathBuf) -> anyhow::Result<(Self::Objects, Self::Packages)> {
+        Self::open(path)
+    }
+}
+
+impl LocalStore<Memory> {
+    /// Creates a new empty store which resides entirely in memory.
+    ///
+    /// Packages are instantiated as if the store were located at `/store`.
+    pub fn in_memory() -> Self {
+        LocalStore::init(DEFAULT_STORE_DIR).expect("default store dir is an absolute path")
+    }
+
+    /// Returns the contents of the instantiated package directory `name`, if it is installed.
+    ///
+    /// Entries are keyed by their path relative to the root of the package directory.
+    pub fn installed_package(&self, name: &InstallName) -> Option<&BTreeMap<PathBuf, MemEntry>> {
+        self.packages.installed.get(name)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Stored {
+    Blob {
+        content: Arc<[u8]>,
+        is_executable: bool,
+    },
+    ChunkedBlob(ChunkedBlob),
+    Tree(Tree),
+    Package(Package),
+    Spec(Spec),
+}
+
+impl Stored {
+    fn kind(&self) -> ObjectKind {
+        match *self {
+            Stored::Blob { .. } => ObjectKind::Blob,
+            Stored::ChunkedBlob(_) => ObjectKind::ChunkedBlob,
+            Stored::Tree(_) => ObjectKind::Tree,
+            Stored::Package(_) => ObjectKind::Package,
+            Stored::Spec(_) => ObjectKind::Spec,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match *self {
+            Stored::Blob { ref content, .. } => content.len() as u64,
+            Stored::ChunkedBlob(ref c) => c.size(),
+            Stored::Tree(ref t) => t.size(),
+            Stored::Package(ref p) => p.size(),
+            Stored::Spec(ref s) => s.size(),
+        }
+    }
+}
+
+/// An in-memory `objects` repository.
+#[derive(Clone, Debug, Default)]
+pub struct MemObjects(BTreeMap<ObjectId, Stored>);
+
+impl MemObjects {
+    fn get_stored(&self, id: &ObjectId, kind: Option<ObjectKind>) -> Option<&Stored> {
+        self.0
+            .get(id)
+            .filter(|o| !matches!(kind, Some(k) if o.kind() != k))
+    }
+}
+
+impl Objects for MemObjects {
+    fn insert_object(&mut self, o: Object) -> anyhow::Result<ObjectId> {
+        let id = o.object_id();
+
+        if let btree_map::Entry::Vacant(slot) = self.0.entry(id) {
+            let stored = match o {
+                Object::Blob(blob) => {
+                    let is_executable = blob.is_executable();
+                    let mut content = Vec::with_capacity(blob.size() as usize);
+                    blob.into_content()?.read_to_end(&mut content)?;
+                    Stored::Blob {
+                        content: content.into(),
+                        is_executable,
+                    }
+                }
+                Object::ChunkedBlob(c) => Stored::ChunkedBlob(c),
+                Object::Tree(tree) => Stored::Tree(tree),
+                Object::Package(pkg) => Stored::Package(pkg),
+                Object::Spec(spec) => Stored::Spec(spec),
+            };
+
+            slot.insert(stored);
+        }
+
+        Ok(id)
+    }
+
+    fn get_object(&self, id: ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<Object> {
+        match self.get_stored(&id, kind) {
+            Some(Stored::Blob {
+                content,
+                is_executable,
+            }) => {
+                let blob = Blob::from_store_bytes(content.to_vec(), *is_executable, id);
+                Ok(Object::Blob(blob))
+            }
+            Some(Stored::ChunkedBlob(c)) => Ok(Object::ChunkedBlob(c.clone())),
+            Some(Stored::Tree(tree)) => Ok(Object::Tree(tree.clone())),
+            Some(Stored::Package(pkg)) => Ok(Object::Package(pkg.clone())),
+            Some(Stored::Spec(spec)) => Ok(Object::Spec(spec.clone())),
+            None => Err(anyhow!("object {} not found", id)),
+        }
+    }
+
+    fn contains_object(&self, id: &ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<bool> {
+        Ok(self.get_stored(id, kind).is_some())
+    }
+
+    fn object_size(&self, id: &ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<u64> {
+        self.get_stored(id, kind)
+            .map(Stored::size)
+            .ok_or_else(|| anyhow!("object {} not found", id))
+    }
+}
+
+/// An entry inside of an instantiated in-memory package directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MemEntry {
+    /// A directory.
+    Directory,
+    /// A regular file or executable.
+    ///
+    /// Files without self-references share their content with the blob object in the store,
+    /// mirroring the hard links created by the filesystem backend.
+    File {
+        /// Contents of the file, with any self-references already patched.
+        content: Arc<[u8]>,
+        /// Whether the executable bit is set.
+        is_executable: bool,
+    },
+    /// A symbolic link.
+    Symlink {
+        /// Path that the link points to.
+        target: PathBuf,
+    },
+}
+
+/// An in-memory `packages` repository.
+#[derive(Debug)]
+pub struct MemPackages {
+    path: PathBuf,
+    installed: BTreeMap<InstallName, BTreeMap<PathBuf, MemEntry>>,
+    signatures: BTreeMap<ObjectId, BTreeSet<Signature>>,
+    closures: RwLock<BTreeMap<ObjectId, Closure>>,
+    trust: TrustPolicy,
+}
+
+impl Packages for MemPackages {
+    type Objects = MemObjects;
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<InstallName>> {
+        Ok(self.installed.keys().cloned().collect())
+    }
+
+    fn instantiate(&mut self, pkg: &Package, objects: &Self::Objects) -> anyhow::Result<()> {
+        let install_name = pkg.install_name();
+
+        if !self.installed.contains_key(&install_name) {
+            // Build the whole directory first so a failure leaves no partial package behind.
+            let root_dir = self.path.join(&install_name);
+            let tree = objects.get_tree(pkg.tree)?;
+            let mut entries = BTreeMap::new();
+            construct(objects, pkg, &root_dir, tree, Path::new(""), &mut entries)?;
+            self.installed.insert(install_name, entries);
+        }
+
+        Ok(())
+    }
+
+    fn signatures(&self, id: &ObjectId) -> anyhow::Result<BTreeSet<Signature>> {
+        Ok(self.signatures.get(id).cloned().unwrap_or_default())
+    }
+
+    fn add_signatures(&mut self, id: &ObjectId, sigs: &BTreeSet<Signature>) -> anyhow::Result<()> {
+        if !sigs.is_empty() {
+            let recorded = self.signatures.entry(*id).or_default();
+            recorded.extend(sigs.iter().cloned());
+        }
+
+        Ok(())
+    }
+
+    fn cached_closure(&self, id: &ObjectId) -> anyhow::Result<Option<Closure>> {
+        Ok(self.closures.read().unwrap().get(id).cloned())
+    }
+
+    fn cache_closure(&self, id: &ObjectId, fragment: &Closure) -> anyhow::Result<()> {
+        let mut closures = self.closures.write().unwrap();
+        closures.entry(*id).or_insert_with(|| fragment.clone());
+        Ok(())
+    }
+
+    fn trust_policy(&self) -> anyhow::Result<TrustPolicy> {
+        Ok(self.trust.clone())
+    }
+
+    fn set_trust_policy(&mut self, policy: TrustPolicy) -> anyhow::Result<()> {
+        self.trust = policy;
+        Ok(())
+    }
+}
+
+fn construct(
+    objects: &MemObjects,
+    pkg: &Package,
+    root_dir: &Path,
+    tree: Tree,
+    tree_dir: &Path,
+    dst: &mut BTreeMap<PathBuf, MemEntry>,
+) -> anyhow::Result<()> {
+    dst.insert(tree_dir.to_owned(), MemEntry::Directory);
+
+    for (name, entry) in tree.entries {
+        let path = tree_dir.join(name);
+        match entry {
+            Entry::Tree { id } => {
+                let subtree = objects.get_tree(id)?;
+                construct(objects, pkg, root_dir, subtree, &path, dst)?;
+            }
+            Entry::Blob { id } => {
+                let (content, is_executable) = match objects.get_stored(&id, Some(ObjectKind::Blob))
+                {
+                    Some(Stored::Blob {
+                        content,
+                        is_executable,
+                    }) => (content.clone(), *is_executable),
+                    _ => return Err(anyhow!("blob object {} not found", id)),
+                };
+
+                let content = if let Some(offsets) = pkg.self_references.get(&id) {
+                    let mut file = Cursor::new(content.to_vec());
+                    install::rewrite_paths(&mut file, root_dir, offsets)?;
+                    file.into_inner().into()
+                } else {
+                    content
+                };
+
+                let file = MemEntry::File {
+                    content,
+                    is_executable,
+                };
+                dst.insert(path, file);
+            }
+            Entry::ChunkedBlob { id } => {
+                let chunked = objects.get_chunked_blob(id)?;
+                let mut file = Cursor::new(Vec::with_capacity(chunked.file_size() as usize));
+                install::write_chunks(objects, &chunked, &mut file)?;
+
+                if let Some(offsets) = pkg.self_references.get(&id) {
+                    install::rewrite_paths(&mut file, root_dir, offsets)?;
+                }
+
+                let file = MemEntry::File {
+                    content: file.into_inner().into(),
+                    is_executable: chunked.is_executable,
+                };
+                dst.insert(path, file);
+            }
+            Entry::Symlink { target } => {
+                dst.insert(path, MemEntry::Symlink { target });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::copy::Source;
+    use crate::pack::{PackagePhase, Progress};
+    use crate::test_support::insert_package;
+    use crate::{copy_closure, References, SecretKey};
+
+    #[test]
+    fn rejects_relative_path() {
+        assert!(LocalStore::<Memory>::init("store").is_err());
+    }
+
+    #[test]
+    fn computes_closure() {
+        let mut store = LocalStore::in_memory();
+        let foo = insert_package(&mut store, "foo", &[]);
+        let bar = insert_package(&mut store, "bar", &[foo]);
+
+        let closure = store
+            .compute_closure(std::iter::once(bar).collect())
+            .unwrap();
+        let ids: BTreeSet<_> = closure.iter().map(|&(id, _, _)| id).collect();
+        assert_eq!(closure.num_objects(), 6);
+        assert!(ids.contains(&foo));
+        assert!(ids.contains(&bar));
+    }
+
+    #[tokio::test]
+    async fn uses_memoized_package_closures() {
+        let mut store = LocalStore::in_memory();
+        let foo = insert_package(&mut store, "foo", &[]);
+        let bar = insert_package(&mut store, "bar", &[foo]);
+        let expected = store
+            .compute_closure(std::iter::once(bar).collect())
+            .unwrap();
+
+        // Drop the contents of `foo`, so the closure can only be found through its fragment.
+        let fragment = store.packages.cached_closure(&foo).unwrap().unwrap();
+        assert_eq!(fragment.num_objects(), 3);
+        let tree = store.get_package(foo).unwrap().tree;
+        let blob = store.get_tree(tree).unwrap().references().next().unwrap().0;
+        store.objects.0.remove(&tree);
+        store.objects.0.remove(&blob);
+
+        let closure = store
+            .compute_closure(std::iter::once(bar).collect())
+            .unwrap();
+        assert_eq!(closure.graph(), expected.graph());
+        assert_eq!(closure.package_name(foo), expected.package_name(foo));
+
+        let dst = LocalStore::in_memory();
+        let delta = store
+            .find_missing(&dst, std::iter::once(bar).collect())
+            .await
+            .unwrap();
+        assert_eq!(delta.missing.graph(), expected.graph());
+    }
+
+    #[test]
+    fn refuses_package_with_missing_references() {
+        let mut store = LocalStore::in_memory();
+        let mut other = LocalStore::in_memory();
+        let foo = insert_package(&mut other, "foo", &[]);
+
+        let (blob, _) = Blob::from_bytes(b"bar".to_vec(), false);
+        let tree = Tree {
+            entries: std::iter::once((
+                "bar".into(),
+                Entry::Blob {
+                    id: blob.object_id(),
+                },
+            ))
+            .collect(),
+        };
+        store.insert_object(Object::Blob(blob)).unwrap();
+        let tree_id = store.insert_object(Object::Tree(tree)).unwrap();
+
+        let result = store.insert_object(Object::Package(Package {
+            name: "bar".parse().unwrap(),
+            system: crate::Platform::host(),
+            references: std::iter::once(foo).collect(),
+            self_references: BTreeMap::new(),
+            tree: tree_id,
+        }));
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rewrites_self_references() {
+        let mut store = LocalStore::in_memory();
+        let placeholder = format!("/store/packages/hello-{}/bin/hello", ObjectId::zero());
+        let (blob, _) = Blob::from_bytes(placeholder.clone().into_bytes(), true);
+        let blob_id = store.insert_object(Object::Blob(blob)).unwrap();
+
+        let mut entries = BTreeMap::new();
+        entries.insert("hello".into(), Entry::Blob { id: blob_id });
+        let tree_id = store.insert_object(Object::Tree(Tree { entries })).unwrap();
+
+        let offsets = std::iter::once(0).collect();
+        let pkg = Package {
+            name: "hello".parse().unwrap(),
+            system: crate::Platform::host(),
+            references: References::new(),
+            self_references: std::iter::once((blob_id, offsets)).collect(),
+            tree: tree_id,
+        };
+        let install_name = pkg.install_name();
+        store.insert_object(Object::Package(pkg)).unwrap();
+
+        let installed = store.installed_package(&install_name).unwrap();
+        let expected = format!("/store/packages/{}/bin/hello", install_name);
+        match installed.get(Path::new("hello")) {
+            Some(MemEntry::File {
+                content,
+                is_executable: true,
+            }) => assert_eq!(&content[..], expected.as_bytes()),
+            other => panic!("unexpected entry: {:?}", other),
+        }
+
+        // The blob object itself must be left untouched.
+        let mut content = String::new();
+        let blob = store.get_blob(blob_id).unwrap();
+        blob.into_content()
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, placeholder);
+    }
+
+    #[tokio::test]
+    async fn copies_closure_between_stores() {
+        let mut src = LocalStore::in_memory();
+        let foo = insert_package(&mut src, "foo", &[]);
+        let bar = insert_package(&mut src, "bar", &[foo]);
+
+        let key = SecretKey::generate("src-1").unwrap();
+        let sig = src.sign_package(bar, &key).unwrap();
+
+        let mut dst = LocalStore::in_memory();
+        insert_package(&mut dst, "foo", &[]);
+        let mut policy = TrustPolicy::default();
+        policy.trusted_keys.insert(key.public_key());
+        dst.set_trust_policy(policy).unwrap();
+
+        let pkgs: BTreeSet<_> = std::iter::once(bar).collect();
+        let mut events = Vec::new();
+        let delta = copy_closure(&src, &mut dst, pkgs.clone(), |p| events.push(p.clone()))
+            .await
+            .unwrap();
+        assert_eq!(delta.num_present, 1);
+        assert_eq!(delta.missing.num_objects(), 3);
+
+        assert!(matches!(
+            events[0],
+            Progress::Started {
+                num_objects: 3,
+                num_packages: 1,
+                ..
+            }
+        ));
+        assert!(events
+            .iter()
+            .any(|p| matches!(p, Progress::Written { bytes } if *bytes > 0)));
+        let phases: Vec<_> = events
+            .iter()
+            .filter_map(|p| match *p {
+                Progress::Package { id, phase } if id == bar => Some(phase),
+                _ => None,
+            })
+            .collect();
+        let expected = [
+            PackagePhase::Received,
+            PackagePhase::Instantiating,
+            PackagePhase::Installed,
+        ];
+        assert_eq!(phases, expected);
+
+        let closure = dst.compute_closure(pkgs).unwrap();
+        assert_eq!(closure.num_objects(), 6);
+
+        let pkg = dst.get_package(bar).unwrap();
+        assert!(dst.installed_package(&pkg.install_name()).is_some());
+        assert_eq!(
+            dst.signatures(&bar).unwrap(),
+            std::iter::once(sig).collect()
+        );
+    }
+}
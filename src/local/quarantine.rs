@@ -0,0 +1,452 @@
+//! Holding received objects aside until the whole pack has been verified.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context};
+use fs2::FileExt;
+
+use crate::pack::{self, IndexEntry, PackagePhase, Progress};
+use crate::{ContentAddressable, Object, ObjectId, ObjectKind, Objects};
+
+/// A pack file holding objects received from another store.
+///
+/// Each object is checked as it arrives: every object it references must either already be in the
+/// store or have arrived earlier. Nothing is visible in the store until [`Quarantine::release()`]
+/// is called.
+///
+/// Objects are written to the pack file as soon as they are verified, so that an interrupted
+/// transfer can be resumed later on without sending them again. If the quarantine has a path, it
+/// survives the store being closed and reopened, and it is locked for as long as it is open so
+/// that only one transfer at a time can write to it. Otherwise, the pack is kept in memory.
+#[derive(Debug)]
+pub(super) struct Quarantine {
+    path: Option<PathBuf>,
+    writer: BufWriter<Spool>,
+    offset: u64,
+    entries: Vec<IndexEntry>,
+    held: BTreeMap<ObjectId, ObjectKind>,
+}
+
+impl Quarantine {
+    /// Opens the quarantine pack file at `path`, picking up any objects left behind by an earlier
+    /// transfer, or starts an in-memory pack if `path` is `None`.
+    ///
+    /// Returns `Err` if the file could not be opened or created, it is locked by another transfer,
+    /// or an I/O error occurred.
+    pub fn open(path: Option<&Path>) -> anyhow::Result<Self> {
+        let (mut spool, entries, offset) = match path {
+            Some(path) => {
+                let mut file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(path)?;
+                file.try_lock_exclusive()
+                    .with_context(|| format!("{} is in use by another transfer", path.display()))?;
+
+                if file.metadata()?.len() == 0 {
+                    let offset = pack::write_magic(&mut file)?;
+                    (Spool::File(file), Vec::new(), offset)
+                } else {
+                    let (entries, offset) = pack::recover_entries(&mut file)
+                        .with_context(|| format!("failed to read {}", path.display()))?;
+                    // Drop the incomplete entry at the end of the file, if any.
+                    file.set_len(offset)?;
+                    file.seek(SeekFrom::Start(offset))?;
+                    (Spool::File(file), entries, offset)
+                }
+            }
+            None => {
+                let mut buffer = Cursor::new(Vec::new());
+                let offset = pack::write_magic(&mut buffer)?;
+                (Spool::Memory(buffer), Vec::new(), offset)
+            }
+        };
+
+        spool.flush()?;
+        let held = entries.iter().map(|e| (e.id(), e.kind())).collect();
+
+        Ok(Quarantine {
+            path: path.map(ToOwned::to_owned),
+            writer: BufWriter::new(spool),
+            offset,
+            entries,
+            held,
+        })
+    }
+
+    /// Returns the IDs of the objects held in the quarantine pack file at `path`, without opening
+    /// it for writing.
+    ///
+    /// Returns `Err` if the file exists but could not be read.
+    pub fn list(path: &Path) -> anyhow::Result<BTreeSet<ObjectId>> {
+        if !path.exists() {
+            return Ok(BTreeSet::new());
+        }
+
+        let mut file = File::open(path)?;
+        let (entries, _) = pack::recover_entries(&mut file)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Ok(entries.iter().map(|e| e.id()).collect())
+    }
+
+    /// Returns the IDs of the objects held in the quarantine.
+    pub fn held(&self) -> impl Iterator<Item = (ObjectId, ObjectKind)> + '_ {
+        self.held.iter().map(|(&id, &kind)| (id, kind))
+    }
+
+    /// Adds `obj` to the quarantine, checking that everything it references is present either in
+    /// `store` or in the quarantine.
+    ///
+    /// Returns `Err` if a reference is missing, meaning the pack was incomplete or not in
+    /// topological order, or an I/O error occurred.
+    pub fn hold<O>(&mut self, obj: Object, store: &O) -> anyhow::Result<()>
+    where
+        O: Objects + ?Sized,
+    {
+        let id = obj.object_id();
+        if self.held.contains_key(&id) {
+            return Ok(());
+        }
+
+        for (ref_id, ref_kind) in references(&obj) {
+            let is_present = self.held.get(&ref_id) == Some(&ref_kind)
+                || store.contains_object(&ref_id, Some(ref_kind))?;
+
+            if !is_present {
+                return Err(anyhow!(
+                    "{} object {} references {} object {}, which is missing from the pack or \
+                     out of topological order",
+                    obj.kind().as_str(),
+                    id,
+                    ref_kind.as_str(),
+                    ref_id
+                ));
+            }
+        }
+
+        let kind = obj.kind();
+        let entry = pack::write_entry(&mut self.writer, &mut self.offset, obj)?;
+        self.writer.flush()?;
+        self.entries.push(entry);
+        self.held.insert(id, kind);
+
+        Ok(())
+    }
+
+    /// Moves every held object into `store`, in the order they were received, and deletes the
+    /// quarantine pack file.
+    ///
+    /// Packages listed in `skip` are dropped instead of being inserted. This must only be called
+    /// once the whole pack has been read and verified. The phases of every package being inserted
+    /// are reported to `progress`, and control is yielded to the executor after each package so
+    /// that the events can be delivered as they happen.
+    ///
+    /// Returns `Err` if an object could not be inserted, or an I/O error occurred. Objects inserted
+    /// up to that point are left in the store, which is fine since their references are complete.
+    /// The quarantine pack file is deleted either way.
+    pub async fn release<O>(
+        self,
+        store: &mut O,
+        skip: &BTreeSet<ObjectId>,
+        progress: &dyn Fn(&Progress),
+    ) -> anyhow::Result<()>
+    where
+        O: Objects + ?Sized,
+    {
+        let Quarantine {
+            path,
+            writer,
+            entries,
+            ..
+        } = self;
+
+        let released = insert_entries(writer, &entries, store, skip, progress).await;
+        if let Some(path) = path {
+            std::fs::remove_file(path)?;
+        }
+
+        released
+    }
+
+    /// Throws away every held object and deletes the quarantine pack file.
+    ///
+    /// Returns `Err` if the file could not be deleted.
+    pub fn discard(self) -> anyhow::Result<()> {
+        if let Some(ref path) = self.path {
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Finishes the quarantine pack file in `writer` and inserts the objects in `entries` into
+/// `store`, except for the packages listed in `skip`.
+async fn insert_entries<O>(
+    mut writer: BufWriter<Spool>,
+    entries: &[IndexEntry],
+    store: &mut O,
+    skip: &BTreeSet<ObjectId>,
+    progress: &dyn Fn(&Progress),
+) -> anyhow::Result<()>
+where
+    O: Objects + ?Sized,
+{
+    pack::write_footer(&mut writer)?;
+    let mut spool = writer.into_inner().map_err(|e| e.into_error())?;
+
+    for entry in entries {
+        if skip.contains(&entry.id()) {
+            continue;
+        }
+
+        let obj = pack::read_entry_at(&mut spool, entry)?;
+        if let Object::Package(_) = obj {
+            let id = entry.id();
+            report_phase(progress, id, PackagePhase::Instantiating).await;
+            store.insert_object(obj)?;
+            report_phase(progress, id, PackagePhase::Installed).await;
+        } else {
+            store.insert_object(obj)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reports `phase` of the package `id` to `progress`, then yields to the executor so that the
+/// receiver sees the event before the package moves on to its next phase.
+async fn report_phase(progress: &dyn Fn(&Progress), id: ObjectId, phase: PackagePhase) {
+    progress(&Progress::Package { id, phase });
+    tokio::task::yield_now().await
+}
+
+/// Storage backing a quarantine pack file.
+#[derive(Debug)]
+enum Spool {
+    File(File),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Read for Spool {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Spool::File(file) => file.read(buf),
+            Spool::Memory(buffer) => buffer.read(buf),
+        }
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Spool::File(file) => file.write(buf),
+            Spool::Memory(buffer) => buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Spool::File(file) => file.flush(),
+            Spool::Memory(buffer) => buffer.flush(),
+        }
+    }
+}
+
+impl Seek for Spool {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Spool::File(file) => file.seek(pos),
+            Spool::Memory(buffer) => buffer.seek(pos),
+        }
+    }
+}
+
+/// Returns the objects directly referenced by `obj`.
+///
+/// The dependencies of a `Spec` are the packages it is built against, so they are `Package`
+/// objects rather than other specs.
+fn references(obj: &Object) -> Vec<(ObjectId, ObjectKind)> {
+    match obj {
+        Object::Blob(_) => Vec::new(),
+        Object::ChunkedBlob(chunked) => chunked.references().collect(),
+        Object::Tree(tree) => tree.references().collect(),
+        Object::Package(pkg) => pkg
+            .references
+            .iter()
+            .map(|&id| (id, ObjectKind::Package))
+            .chain(std::iter::once((pkg.tree, ObjectKind::Tree)))
+            .collect(),
+        Object::Spec(spec) => spec
+            .dependencies
+            .iter()
+            .chain(&spec.build_dependencies)
+            .map(|&id| (id, ObjectKind::Package))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::copy::{Destination, Source};
+    use crate::local::Packages;
+    use crate::pack::PackWriter;
+    use crate::test_support::{insert_package, insert_spec};
+    use crate::{copy_closure, LocalStore, SecretKey, TrustPolicy};
+
+    #[test]
+    fn locks_quarantine_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quarantine.pack");
+
+        let held = Quarantine::open(Some(&path)).unwrap();
+        let error = Quarantine::open(Some(&path)).unwrap_err();
+        assert!(error.to_string().contains("in use"), "{}", error);
+
+        held.discard().unwrap();
+        Quarantine::open(Some(&path)).unwrap().discard().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn discards_truncated_pack() {
+        let mut src = LocalStore::in_memory();
+        let foo = insert_package(&mut src, "foo", &[]);
+        let pkgs: BTreeSet<_> = std::iter::once(foo).collect();
+
+        let mut dst = LocalStore::in_memory();
+        dst.set_trust_policy(TrustPolicy {
+            accept_unsigned: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let delta = src.find_missing(&dst, pkgs.clone()).await.unwrap();
+        let mut pack = Vec::new();
+        src.send_pack(&delta, &mut pack).await.unwrap();
+
+        // Every object is intact, only the end of the pack is missing.
+        let truncated = &pack[..pack.len() - 1];
+        let result = dst.recv_pack(&mut &truncated[..], &delta.signatures).await;
+        assert!(result.is_err());
+        for &(id, kind, _) in src.compute_closure(pkgs).unwrap().iter() {
+            assert!(!dst.contains_object(&id, Some(kind)).unwrap());
+        }
+        assert!(dst.packages.list().unwrap().is_empty());
+
+        dst.recv_pack(&mut &pack[..], &delta.signatures)
+            .await
+            .unwrap();
+        assert!(dst
+            .contains_object(&foo, Some(ObjectKind::Package))
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn refuses_pack_out_of_topological_order() {
+        let mut src = LocalStore::in_memory();
+        let foo = insert_package(&mut src, "foo", &[]);
+        let closure = src.compute_closure(std::iter::once(foo).collect()).unwrap();
+
+        // Write the package first, before the tree and blob it references.
+        let mut pack = Vec::new();
+        let mut writer = PackWriter::new(&mut pack).await.unwrap();
+        for (id, kind, _) in closure.sort_yield().into_iter().rev() {
+            let obj = src.get_object(id, Some(kind)).unwrap();
+            writer.append(obj).await.unwrap();
+        }
+        writer.finish().await.unwrap();
+
+        let mut dst = LocalStore::in_memory();
+        dst.set_trust_policy(TrustPolicy {
+            accept_unsigned: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let error = dst
+            .recv_pack(&mut &pack[..], &BTreeMap::new())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("topological order"), "{}", error);
+        for &(id, kind, _) in closure.iter() {
+            assert!(!dst.contains_object(&id, Some(kind)).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn receives_specs_of_present_packages() {
+        let mut src = LocalStore::in_memory();
+        let foo = insert_package(&mut src, "foo", &[]);
+        let bar = insert_spec(&mut src, "bar", &[foo], &[], "true");
+
+        let mut dst = LocalStore::in_memory();
+        dst.set_trust_policy(TrustPolicy {
+            accept_unsigned: true,
+            ..Default::default()
+        })
+        .unwrap();
+        copy_closure(&src, &mut dst, std::iter::once(foo).collect(), |_| {})
+            .await
+            .unwrap();
+
+        // The dependencies of a spec are packages, which the destination already holds.
+        let mut pack = Vec::new();
+        let mut writer = PackWriter::new(&mut pack).await.unwrap();
+        let spec = src.get_object(bar, Some(ObjectKind::Spec)).unwrap();
+        writer.append(spec).await.unwrap();
+        writer.finish().await.unwrap();
+
+        dst.recv_pack(&mut &pack[..], &BTreeMap::new())
+            .await
+            .unwrap();
+        assert!(dst.contains_object(&bar, Some(ObjectKind::Spec)).unwrap());
+    }
+
+    #[tokio::test]
+    async fn drops_held_packages_of_other_transfers() {
+        let key = SecretKey::generate("trusted").unwrap();
+        let mut src = LocalStore::in_memory();
+        let foo = insert_package(&mut src, "foo", &[]);
+        let bar = insert_package(&mut src, "bar", &[]);
+        src.sign_package(foo, &key).unwrap();
+        src.sign_package(bar, &key).unwrap();
+
+        let mut dst = LocalStore::in_memory();
+        let mut policy = TrustPolicy::default();
+        policy.trusted_keys.insert(key.public_key());
+        dst.set_trust_policy(policy).unwrap();
+
+        // Interrupt the transfer of `foo` right before the end of its pack.
+        let delta = src
+            .find_missing(&dst, std::iter::once(foo).collect())
+            .await
+            .unwrap();
+        let mut pack = Vec::new();
+        src.send_pack(&delta, &mut pack).await.unwrap();
+        let truncated = &pack[..pack.len() - 1];
+        assert!(dst
+            .recv_pack(&mut &truncated[..], &delta.signatures)
+            .await
+            .is_err());
+        assert!(dst.checkpoint().await.unwrap().contains(&foo));
+
+        // An unrelated transfer carries no signature for `foo`, so it neither fails nor publishes it.
+        copy_closure(&src, &mut dst, std::iter::once(bar).collect(), |_| {})
+            .await
+            .unwrap();
+        assert!(dst
+            .contains_object(&bar, Some(ObjectKind::Package))
+            .unwrap());
+        assert!(!dst
+            .contains_object(&foo, Some(ObjectKind::Package))
+            .unwrap());
+        assert!(dst.checkpoint().await.unwrap().is_empty());
+    }
+}
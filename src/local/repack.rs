@@ -0,0 +1,178 @@
+//! Moving loose objects into pack files.
+
+use std::collections::BTreeSet;
+use std::os::unix::fs::MetadataExt;
+
+use anyhow::Context;
+
+use super::{Filesystem, LocalStore};
+use crate::ObjectKind;
+
+impl LocalStore<Filesystem> {
+    /// Moves loose objects in the `objects` directory into a single pack file.
+    ///
+    /// Every tree, package and spec object is packed, along with every blob which is not currently
+    /// hard-linked into an instantiated package. Existing pack files are merged into the new one,
+    /// minus any blobs which have since been unpacked and instantiated. The loose files are only
+    /// deleted once the new pack file and its index have been persisted.
+    ///
+    /// Packed objects remain transparently accessible through the [`Objects`](crate::Objects)
+    /// trait. Packed blobs are unpacked again on demand whenever a package using them is
+    /// instantiated.
+    ///
+    /// Returns `Err` if an object could not be read or an I/O error occurred.
+    pub fn repack(&mut self) -> anyhow::Result<RepackReport> {
+        let mut report = RepackReport::default();
+        let mut objects = Vec::new();
+        let mut loose_paths = Vec::new();
+        let mut instantiated = BTreeSet::new();
+
+        for (id, kind, path) in self.objects.list_loose()? {
+            // Blobs hard-linked into a package directory must remain loose.
+            if kind == ObjectKind::Blob && std::fs::metadata(&path)?.nlink() > 1 {
+                instantiated.insert(id);
+            } else {
+                objects.push((id, kind));
+                loose_paths.push(path);
+            }
+        }
+
+        let packed = self.objects.list_packed();
+        let is_stale = packed.iter().any(|(id, _, _)| instantiated.contains(id));
+        if loose_paths.is_empty() && !is_stale {
+            return Ok(report);
+        }
+
+        objects.extend(
+            packed
+                .into_iter()
+                .filter(|(id, _, _)| !instantiated.contains(id))
+                .map(|(id, kind, _)| (id, kind)),
+        );
+
+        report.pack_size = self.objects.rewrite_packs(objects)?;
+        report.num_objects = self.objects.list_packed().len();
+        report.packed_objects = loose_paths.len();
+
+        for path in loose_paths {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("failed to delete {}", path.display()))?;
+
+            // Clean up the parent `ab/` directory if it is now empty. Failure is harmless.
+            let _ = std::fs::remove_dir(path.parent().unwrap());
+        }
+
+        Ok(report)
+    }
+}
+
+/// A summary of the objects moved into pack files.
+///
+/// This struct is created by [`LocalStore::repack()`]. See its documentation for more.
+#[derive(Debug, Default)]
+pub struct RepackReport {
+    /// Number of loose objects which were moved into the pack file.
+    pub packed_objects: usize,
+    /// Total number of objects in the new pack file.
+    pub num_objects: usize,
+    /// Size of the new pack file in bytes, or `0` if nothing needed to be repacked.
+    pub pack_size: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local::Packages;
+    use crate::test_support::{insert_blob, insert_package_with, insert_tree};
+    use crate::{Entry, ObjectId, Objects};
+
+    /// Inserts a tree holding a single executable file called `file`.
+    fn insert_file_tree(store: &mut LocalStore, content: &[u8]) -> ObjectId {
+        let file = insert_blob(store, content, true);
+        insert_tree(store, std::iter::once(("file", file)))
+    }
+
+    #[test]
+    fn packs_loose_objects() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let store_dir = dir.path().join("store");
+        let mut store: LocalStore = LocalStore::init(&store_dir).unwrap();
+        let installed_tree = insert_file_tree(&mut store, b"installed");
+        let pkg_id = insert_package_with(&mut store, "foo", &[], installed_tree);
+        let loose_tree = insert_file_tree(&mut store, b"not installed");
+
+        let report = store.repack().unwrap();
+        assert_eq!(report.packed_objects, 4);
+        assert_eq!(report.num_objects, 4);
+        assert!(report.pack_size > 0);
+        assert_eq!(store.objects.list_loose().unwrap().len(), 1);
+
+        let store: LocalStore = LocalStore::open(&store_dir).unwrap();
+        assert!(store.contains_object(&pkg_id, None).unwrap());
+        let tree = store.get_tree(loose_tree).unwrap();
+        let blob_id = match tree.entries["file"] {
+            Entry::Blob { id } => id,
+            _ => panic!("expected blob entry"),
+        };
+        let blob = store.get_blob(blob_id).unwrap();
+        assert!(blob.is_executable());
+        assert_eq!(store.object_size(&blob_id, None).unwrap(), 13);
+
+        let report = store.fsck().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.num_objects, 5);
+    }
+
+    #[test]
+    fn shares_packs_between_clones() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let tree = insert_file_tree(&mut store, b"hello");
+        let objects = store.objects.clone();
+
+        // The loose tree is deleted, so the clone can only find it in the new pack.
+        store.repack().unwrap();
+        assert!(store.objects.list_loose().unwrap().is_empty());
+        assert!(objects.get_tree(tree).is_ok());
+        assert_eq!(objects.list_packed().len(), 2);
+    }
+
+    #[test]
+    fn unpacks_blobs_on_instantiation() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let tree = insert_file_tree(&mut store, b"hello");
+        store.repack().unwrap();
+        assert!(store.objects.list_loose().unwrap().is_empty());
+
+        let pkg = insert_package_with(&mut store, "foo", &[], tree);
+        let pkg = store.get_package(pkg).unwrap();
+        let file = store.packages.path().join(pkg.install_name()).join("file");
+        assert_eq!(std::fs::read(file).unwrap(), b"hello");
+        assert_eq!(store.objects.list_loose().unwrap().len(), 2);
+
+        // The instantiated blob is dropped from the pack, but everything else stays packed.
+        let report = store.repack().unwrap();
+        assert_eq!(report.num_objects, 2);
+        assert_eq!(store.objects.list_loose().unwrap().len(), 1);
+        assert!(store.fsck().unwrap().is_ok());
+    }
+
+    #[test]
+    fn collects_packed_garbage() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let tree = insert_file_tree(&mut store, b"live");
+        let pkg = insert_package_with(&mut store, "foo", &[], tree);
+        let dead_tree = insert_file_tree(&mut store, b"dead");
+        store.add_root("foo", pkg).unwrap();
+        store.repack().unwrap();
+
+        let report = store.collect_garbage(false).unwrap();
+        assert_eq!(report.removed_objects.len(), 2);
+        assert!(!store.contains_object(&dead_tree, None).unwrap());
+        assert!(store.contains_object(&tree, None).unwrap());
+        assert_eq!(store.objects.list_packed().len(), 2);
+        assert!(store.fsck().unwrap().is_ok());
+    }
+}
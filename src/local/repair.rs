@@ -0,0 +1,506 @@
+//! Repairing corrupted blobs and package directories.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use filetime::FileTime;
+use futures::{pin_mut, try_join, StreamExt};
+use tokio::io::AsyncRead;
+
+use super::fs::FsObjects;
+use super::{Filesystem, LocalStore, Packages};
+use crate::closure;
+use crate::copy::{Destination, Source};
+use crate::pack::pack_reader;
+use crate::{
+    Blob, ContentAddressable, Entry, InstallName, Object, ObjectId, ObjectKind, Objects, Signature,
+};
+use crate::{FsckReport, Problem};
+
+impl LocalStore<Filesystem> {
+    /// Attempts to fix the problems listed in `report`, as produced by [`LocalStore::fsck()`].
+    ///
+    /// Three kinds of problems can be repaired:
+    ///
+    /// 1. Blob objects which are corrupt or missing are fetched again from `src`. Any installed
+    ///    packages containing hard links to the old blob are then relinked to the new one.
+    /// 2. Package directories which do not match their trees are rebuilt from scratch from the
+    ///    `objects` directory. The old directory is only swapped out once the new one has been
+    ///    fully constructed.
+    /// 3. Memoized closure fragments which are corrupt are deleted, and memoized again from their
+    ///    package if it is still in the store.
+    ///
+    /// Blobs can only be fetched again if `src` contains at least one package whose tree includes
+    /// them. Problems which cannot be repaired are left in the returned report.
+    ///
+    /// Returns `Err` if the blobs could not be fetched from `src`, a package could not be rebuilt,
+    /// or an I/O error occurred.
+    pub async fn repair<S>(&mut self, report: &FsckReport, src: &S) -> anyhow::Result<RepairReport>
+    where
+        S: Source + ?Sized,
+    {
+        let mut repair = RepairReport::default();
+        let mut damaged_blobs = BTreeSet::new();
+        let mut damaged_pkgs = BTreeSet::new();
+        let mut damaged_closures = BTreeSet::new();
+
+        for problem in &report.problems {
+            match *problem {
+                Problem::HashMismatch {
+                    id,
+                    kind: ObjectKind::Blob,
+                    ..
+                }
+                | Problem::Unreadable {
+                    id,
+                    kind: ObjectKind::Blob,
+                    ..
+                }
+                | Problem::MissingEntry {
+                    id,
+                    kind: ObjectKind::Blob,
+                    ..
+                }
+                | Problem::MissingChunk { chunk: id, .. } => {
+                    damaged_blobs.insert(id);
+                }
+                Problem::PackageMismatch { package, .. } => {
+                    damaged_pkgs.insert(package);
+                }
+                Problem::CorruptClosure { package, .. } => {
+                    damaged_closures.insert(package);
+                }
+                _ => repair.unrepaired.push(problem.clone()),
+            }
+        }
+
+        if !damaged_blobs.is_empty() {
+            // Find every package which contains one of the damaged blobs, or a chunked blob made
+            // up of them.
+            let mut users: BTreeMap<ObjectId, Vec<FileBlob>> = BTreeMap::new();
+            let loose = self
+                .objects
+                .list_loose()?
+                .into_iter()
+                .map(|(id, k, _)| (id, k));
+            let packed = self
+                .objects
+                .list_packed()
+                .into_iter()
+                .map(|(id, k, _)| (id, k));
+            let pkg_ids: BTreeSet<_> = loose
+                .chain(packed)
+                .filter(|&(_, kind)| kind == ObjectKind::Package)
+                .map(|(id, _)| id)
+                .collect();
+
+            for pkg_id in pkg_ids {
+                let pkg = self.objects.get_package(pkg_id)?;
+                let mut files = Vec::new();
+                list_file_blobs(&self.objects, pkg.tree, Path::new(""), &mut files)?;
+                files.retain(|file| damaged_blobs.contains(&file.blob));
+                if !files.is_empty() {
+                    users.insert(pkg_id, files);
+                }
+            }
+
+            let roots = users.keys().copied().collect();
+            let chunked = users
+                .values()
+                .flatten()
+                .filter_map(|file| file.chunked_blob)
+                .collect();
+            let fetched = self
+                .fetch_blobs(src, roots, &chunked, &damaged_blobs)
+                .await?;
+
+            for id in &damaged_blobs {
+                if fetched.contains(id) {
+                    repair.fetched_blobs.push(*id);
+                } else {
+                    let error = "no source package contains this blob".to_string();
+                    let (id, kind) = (*id, ObjectKind::Blob);
+                    repair
+                        .unrepaired
+                        .push(Problem::Unreadable { id, kind, error });
+                }
+            }
+
+            // Relink hard-linked copies of the old blobs in every installed package.
+            for (pkg_id, files) in users {
+                let pkg = self.objects.get_package(pkg_id)?;
+                let pkg_dir = self.packages.path().join(pkg.install_name());
+                if !pkg_dir.exists() {
+                    continue;
+                }
+
+                for file in files {
+                    if !fetched.contains(&file.blob) {
+                        continue;
+                    } else if file.chunked_blob.is_some()
+                        || pkg.self_references.contains_key(&file.blob)
+                    {
+                        // Chunked and self-referencing files are copies, so they must be rebuilt.
+                        damaged_pkgs.insert(pkg_id);
+                    } else {
+                        let blob_path = self.objects.object_path(&file.blob, ObjectKind::Blob);
+                        relink(&blob_path, &pkg_dir.join(file.path))?;
+                        repair.relinked_files += 1;
+                    }
+                }
+            }
+        }
+
+        for id in damaged_pkgs {
+            let pkg = self.objects.get_package(id)?;
+            self.packages.reinstantiate(&pkg, &self.objects)?;
+            repair.rebuilt_packages.push(pkg.install_name());
+        }
+
+        for id in damaged_closures {
+            match std::fs::remove_file(self.packages.closure_path(&id)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+
+            if self
+                .objects
+                .contains_object(&id, Some(ObjectKind::Package))?
+            {
+                let fragment = closure::package_fragment(&self.objects, id)?;
+                self.packages.cache_closure(&id, &fragment)?;
+            }
+            repair.rebuilt_closures.push(id);
+        }
+
+        Ok(repair)
+    }
+
+    /// Fetches the blobs in `wanted` from `src` and atomically replaces their object files.
+    ///
+    /// Blobs which are chunks are only found if the chunked blobs containing them are listed in
+    /// `chunked`.
+    ///
+    /// Returns the set of blobs which were successfully fetched, or `Err` if a fetched blob could
+    /// not be written, or does not hash to its ID once written.
+    async fn fetch_blobs<S>(
+        &mut self,
+        src: &S,
+        roots: BTreeSet<ObjectId>,
+        chunked: &BTreeSet<ObjectId>,
+        wanted: &BTreeSet<ObjectId>,
+    ) -> anyhow::Result<BTreeSet<ObjectId>>
+    where
+        S: Source + ?Sized,
+    {
+        let mut fetched = BTreeSet::new();
+        if roots.is_empty() {
+            return Ok(fetched);
+        }
+
+        let probe = Probe {
+            roots: &roots,
+            chunked,
+            wanted,
+        };
+        let mut delta = src.find_missing(&probe, roots.clone()).await?;
+
+        // The probe only pretends to hold the other blobs, so they cannot serve as delta bases.
+        delta.bases.clear();
+
+        let (mut reader, mut writer) = tokio::io::duplex(8 * 1024);
+        let send = src.send_pack(&delta, &mut writer);
+        let recv = async {
+            let stream = pack_reader(&mut reader);
+            pin_mut!(stream);
+
+            while let Some(result) = stream.next().await {
+                if let Object::Blob(blob) = result? {
+                    let id = blob.object_id();
+                    if wanted.contains(&id) {
+                        let path = self.objects.object_path(&id, ObjectKind::Blob);
+                        std::fs::create_dir_all(path.parent().unwrap())?;
+
+                        // Persisting keeps any file already at `path`, so remove the damaged one.
+                        match std::fs::remove_file(&path) {
+                            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                                return Err(e.into())
+                            }
+                            _ => {}
+                        }
+
+                        blob.persist(&path)?;
+                        if Blob::whole_from_path(&path)?.object_id() != id {
+                            return Err(anyhow!("blob {} is still corrupt after repair", id));
+                        }
+
+                        fetched.insert(id);
+                    }
+                }
+            }
+
+            Ok::<_, anyhow::Error>(())
+        };
+
+        try_join!(send, recv)?;
+
+        Ok(fetched)
+    }
+}
+
+/// Atomically replaces `dst` with a hard link to `src`, preserving the parent directory mtime.
+fn relink(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let parent = dst.parent().unwrap();
+    let temp = parent.join(format!(
+        ".relink-{}",
+        dst.file_name().unwrap().to_string_lossy()
+    ));
+
+    let _ = std::fs::remove_file(&temp);
+    std::fs::hard_link(src, &temp)?;
+    std::fs::rename(&temp, dst).with_context(|| format!("failed to relink {}", dst.display()))?;
+    filetime::set_file_mtime(parent, FileTime::zero())?;
+
+    Ok(())
+}
+
+/// A file in a package tree which is made up of a blob object.
+struct FileBlob {
+    /// Path of the file, relative to the package directory.
+    path: PathBuf,
+    /// ID of the blob object, which is a chunk of `chunked_blob` if that is set.
+    blob: ObjectId,
+    /// ID of the chunked blob object the file was assembled from, if any.
+    chunked_blob: Option<ObjectId>,
+}
+
+/// Lists every file in the tree `tree_id` along with the blobs it is made up of.
+///
+/// Unlike `thin::list_blobs()`, a chunked file is listed once for every one of its chunks.
+fn list_file_blobs(
+    objects: &FsObjects,
+    tree_id: ObjectId,
+    rel_dir: &Path,
+    dst: &mut Vec<FileBlob>,
+) -> anyhow::Result<()> {
+    let tree = objects.get_tree(tree_id)?;
+
+    for (name, entry) in tree.entries {
+        let path = rel_dir.join(name);
+        match entry {
+            Entry::Tree { id } => list_file_blobs(objects, id, &path, dst)?,
+            Entry::Blob { id } => dst.push(FileBlob {
+                path,
+                blob: id,
+                chunked_blob: None,
+            }),
+            Entry::ChunkedBlob { id } => {
+                for chunk in objects.get_chunked_blob(id)?.chunks {
+                    dst.push(FileBlob {
+                        path: path.clone(),
+                        blob: chunk.id,
+                        chunked_blob: Some(id),
+                    });
+                }
+            }
+            Entry::Symlink { .. } => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// A fake destination used to request specific blobs from a `Source`.
+///
+/// It claims to be missing the root packages, every tree and the `chunked` blobs, so the source
+/// descends all the way down to the blobs, but only admits to missing the blobs which are
+/// actually `wanted`.
+struct Probe<'a> {
+    roots: &'a BTreeSet<ObjectId>,
+    chunked: &'a BTreeSet<ObjectId>,
+    wanted: &'a BTreeSet<ObjectId>,
+}
+
+#[async_trait(?Send)]
+impl<'a> Destination for Probe<'a> {
+    async fn contains(&self, id: &ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<bool> {
+        match kind {
+            Some(ObjectKind::Blob) => Ok(!self.wanted.contains(id)),
+            Some(ObjectKind::ChunkedBlob) => Ok(!self.chunked.contains(id)),
+            Some(ObjectKind::Tree) => Ok(false),
+            Some(ObjectKind::Package) => Ok(!self.roots.contains(id)),
+            _ => Ok(true),
+        }
+    }
+
+    async fn recv_pack<R>(
+        &mut self,
+        _reader: &mut R,
+        _signatures: &BTreeMap<ObjectId, BTreeSet<Signature>>,
+    ) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        Err(anyhow!("cannot receive packs"))
+    }
+}
+
+/// A summary of the repairs made to the store.
+///
+/// This struct is created by [`LocalStore::repair()`]. See its documentation for more.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Blobs which were fetched again from the source.
+    pub fetched_blobs: Vec<ObjectId>,
+    /// Number of files in installed packages which were relinked to a fetched blob.
+    pub relinked_files: usize,
+    /// Packages whose directories were rebuilt from scratch.
+    pub rebuilt_packages: Vec<InstallName>,
+    /// Packages whose memoized closure fragments were deleted, and memoized again if possible.
+    pub rebuilt_closures: Vec<ObjectId>,
+    /// Problems which could not be repaired.
+    pub unrepaired: Vec<Problem>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::test_support::{insert_large_package, insert_package, random_bytes};
+    use crate::Memory;
+
+    #[tokio::test]
+    async fn refetches_corrupt_blob() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let pkg = insert_package(&mut store, "hello", &[]);
+        let pkg = store.get_package(pkg).unwrap();
+
+        let mut source = LocalStore::<Memory>::in_memory();
+        insert_package(&mut source, "hello", &[]);
+
+        let file = store
+            .packages
+            .path()
+            .join(pkg.install_name())
+            .join("hello.txt");
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        std::fs::write(&file, b"goodbye").unwrap();
+
+        let report = store.fsck().unwrap();
+        assert!(!report.is_ok());
+
+        let repair = store.repair(&report, &source).await.unwrap();
+        assert_eq!(repair.fetched_blobs.len(), 1);
+        assert_eq!(repair.relinked_files, 1);
+        assert!(repair.unrepaired.is_empty());
+
+        let report = store.fsck().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(std::fs::read(&file).unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn rebuilds_mismatched_package() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let pkg = insert_package(&mut store, "hello", &[]);
+        let pkg = store.get_package(pkg).unwrap();
+
+        let pkg_dir = store.packages.path().join(pkg.install_name());
+        std::fs::remove_file(pkg_dir.join("hello.txt")).unwrap();
+        std::fs::write(pkg_dir.join("extra"), b"").unwrap();
+
+        let report = store.fsck().unwrap();
+        assert_eq!(report.problems.len(), 2);
+
+        let source = LocalStore::<Memory>::in_memory();
+        let repair = store.repair(&report, &source).await.unwrap();
+        assert_eq!(repair.rebuilt_packages, vec![pkg.install_name()]);
+
+        let report = store.fsck().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+    }
+
+    #[tokio::test]
+    async fn refetches_corrupt_chunk() {
+        let data = random_bytes(&[], 3 * 1024 * 1024);
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let pkg = insert_large_package(&mut store, "big", &data);
+        let pkg = store.get_package(pkg).unwrap();
+
+        let mut source = LocalStore::<Memory>::in_memory();
+        insert_large_package(&mut source, "big", &data);
+
+        // Flip some bytes of a chunk in place, leaving the object file where it is.
+        let chunked = match store.get_tree(pkg.tree).unwrap().entries["data.bin"] {
+            Entry::ChunkedBlob { id } => store.get_chunked_blob(id).unwrap(),
+            ref other => panic!("expected chunked blob entry, found {:?}", other),
+        };
+        let chunk = chunked.chunks[1].id;
+        let path = store.objects.object_path(&chunk, ObjectKind::Blob);
+        let mut content = std::fs::read(&path).unwrap();
+        content[..16].copy_from_slice(b"corrupted chunk!");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        std::fs::write(&path, content).unwrap();
+
+        let report = store.fsck().unwrap();
+        assert!(matches!(
+            report.problems[..],
+            [Problem::HashMismatch { id, .. }] if id == chunk
+        ));
+
+        let repair = store.repair(&report, &source).await.unwrap();
+        assert_eq!(repair.fetched_blobs, vec![chunk]);
+        assert_eq!(repair.rebuilt_packages, vec![pkg.install_name()]);
+        assert!(repair.unrepaired.is_empty());
+
+        let report = store.fsck().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        let file = store
+            .packages
+            .path()
+            .join(pkg.install_name())
+            .join("data.bin");
+        assert!(std::fs::read(file).unwrap() == data);
+    }
+
+    #[tokio::test]
+    async fn rebuilds_corrupt_closure_cache() {
+        let dir = tempfile::tempdir_in("/var/tmp").unwrap();
+        let mut store: LocalStore = LocalStore::init(dir.path().join("store")).unwrap();
+        let lib = insert_package(&mut store, "lib", &[]);
+        let app = insert_package(&mut store, "app", &[lib]);
+        let pkgs: BTreeSet<_> = std::iter::once(app).collect();
+        let expected = store.compute_closure(pkgs.clone()).unwrap();
+
+        let path = store.packages.closure_path(&app);
+        let corrupt = |path: &Path| {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            std::fs::write(path, b"garbage").unwrap();
+        };
+
+        // A corrupt fragment is walked again, instead of failing the closure.
+        corrupt(&path);
+        let closure = store.compute_closure(pkgs.clone()).unwrap();
+        assert_eq!(closure.graph(), expected.graph());
+        assert!(store.fsck().unwrap().is_ok());
+
+        corrupt(&path);
+        let report = store.fsck().unwrap();
+        assert!(matches!(
+            report.problems[..],
+            [Problem::CorruptClosure { package, .. }] if package == app
+        ));
+
+        let source = LocalStore::<Memory>::in_memory();
+        let repair = store.repair(&report, &source).await.unwrap();
+        assert_eq!(repair.rebuilt_closures, vec![app]);
+        assert!(store.fsck().unwrap().is_ok());
+        assert!(store.packages.cached_closure(&app).unwrap().is_some());
+    }
+}
@@ -0,0 +1,207 @@
+//! Choosing delta bases for thin packs.
+
+use std::collections::hash_map;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use super::{Backend, LocalStore, Packages};
+use crate::copy::Destination;
+use crate::{Closure, Entry, ObjectId, ObjectKind, Objects};
+
+impl<B: Backend> LocalStore<B> {
+    /// Picks a base blob held by `dst` for the blobs in `missing` which have a counterpart in
+    /// another version of the same package.
+    ///
+    /// For every missing package, the installed packages sharing its name (ignoring the version
+    /// suffix) which `dst` already holds are considered. A missing blob is paired with the blob
+    /// found at the same path in one of them, since an upgraded file is usually very similar to
+    /// its predecessor.
+    ///
+    /// Returns `Err` if an object could not be read, `dst` could not be queried, or an I/O error
+    /// occurred.
+    pub(super) async fn find_delta_bases<D>(
+        &self,
+        dst: &D,
+        missing: &Closure,
+    ) -> anyhow::Result<BTreeMap<ObjectId, ObjectId>>
+    where
+        D: Destination + ?Sized,
+    {
+        let mut bases = BTreeMap::new();
+        let missing_blobs: BTreeSet<_> = missing
+            .iter()
+            .filter(|&&(_, kind, _)| kind == ObjectKind::Blob)
+            .map(|&(id, _, _)| id)
+            .collect();
+
+        if missing_blobs.is_empty() {
+            return Ok(bases);
+        }
+
+        // Group the installed packages by stem up front, so each missing package only looks at
+        // its own candidates.
+        let installed = self.packages.list()?;
+        let mut candidates: HashMap<&str, Vec<ObjectId>> = HashMap::new();
+        for name in &installed {
+            let stem = package_stem(name.name());
+            candidates.entry(stem).or_default().push(name.id());
+        }
+
+        // Files of every candidate held by `dst`, listed at most once per candidate.
+        let mut candidate_files: HashMap<ObjectId, Option<Vec<(PathBuf, ObjectId)>>> =
+            HashMap::new();
+
+        for &(id, kind, _) in missing.iter() {
+            if kind != ObjectKind::Package {
+                continue;
+            }
+
+            let pkg = self.objects.get_package(id)?;
+            let stem = package_stem(pkg.name.as_ref());
+
+            for &base_id in candidates.get(stem).into_iter().flatten() {
+                if base_id == id {
+                    continue;
+                }
+
+                if let hash_map::Entry::Vacant(entry) = candidate_files.entry(base_id) {
+                    let files = if dst.contains(&base_id, Some(ObjectKind::Package)).await? {
+                        let base_pkg = self.objects.get_package(base_id)?;
+                        let mut files = Vec::new();
+                        list_blobs(&self.objects, base_pkg.tree, Path::new(""), &mut files)?;
+                        Some(files)
+                    } else {
+                        None
+                    };
+                    entry.insert(files);
+                }
+            }
+
+            let mut base_files = BTreeMap::new();
+            for base_id in candidates.get(stem).into_iter().flatten() {
+                let files = candidate_files.get(base_id).into_iter().flatten().flatten();
+                for (path, blob_id) in files {
+                    base_files.insert(path, *blob_id);
+                }
+            }
+
+            if base_files.is_empty() {
+                continue;
+            }
+
+            let mut files = Vec::new();
+            list_blobs(&self.objects, pkg.tree, Path::new(""), &mut files)?;
+
+            for (path, blob_id) in files {
+                if let (true, Some(&base)) =
+                    (missing_blobs.contains(&blob_id), base_files.get(&path))
+                {
+                    bases.entry(blob_id).or_insert(base);
+                }
+            }
+        }
+
+        Ok(bases)
+    }
+}
+
+/// Recursively lists every blob in the tree `tree_id` along with its relative path.
+pub(super) fn list_blobs<O: Objects + ?Sized>(
+    objects: &O,
+    tree_id: ObjectId,
+    rel_dir: &Path,
+    dst: &mut Vec<(PathBuf, ObjectId)>,
+) -> anyhow::Result<()> {
+    let tree = objects.get_tree(tree_id)?;
+
+    for (name, entry) in tree.entries {
+        match entry {
+            Entry::Tree { id } => list_blobs(objects, id, &rel_dir.join(name), dst)?,
+            Entry::Blob { id } => dst.push((rel_dir.join(name), id)),
+            // Chunked blobs are deduplicated chunk by chunk instead.
+            Entry::ChunkedBlob { .. } | Entry::Symlink { .. } => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Strips the version suffix from a package name, e.g. `hello-1.0.0` becomes `hello`.
+///
+/// The version is assumed to begin at the first hyphen followed by a digit.
+fn package_stem(name: &str) -> &str {
+    name.match_indices('-')
+        .find(|&(i, _)| name[i + 1..].starts_with(|c: char| c.is_ascii_digit()))
+        .map_or(name, |(i, _)| &name[..i])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::pack::Progress;
+    use crate::test_support::{insert_blob, insert_package_with, insert_tree, random_bytes};
+    use crate::{copy_closure, Blob, ContentAddressable, TrustPolicy};
+
+    #[test]
+    fn strips_version_suffix() {
+        assert_eq!(package_stem("hello-1.0.0"), "hello");
+        assert_eq!(package_stem("python3.9-numpy-1.19.4"), "python3.9-numpy");
+        assert_eq!(package_stem("hello"), "hello");
+    }
+
+    #[tokio::test]
+    async fn sends_deltas_against_older_versions() {
+        let old_content = random_bytes(&[], 64 * 1024);
+        let mut new_content = old_content.clone();
+        new_content[1000..1016].copy_from_slice(b"patched version!");
+        new_content.extend_from_slice(b"appended data");
+
+        let mut src = LocalStore::in_memory();
+        let mut insert_library = |name, content: &[u8]| {
+            let lib = insert_blob(&mut src, content, true);
+            let tree = insert_tree(&mut src, std::iter::once(("lib.so", lib)));
+            insert_package_with(&mut src, name, &[], tree)
+        };
+        let old_pkg = insert_library("lib-1.0", &old_content);
+        let new_pkg = insert_library("lib-1.1", &new_content);
+
+        let mut dst = LocalStore::in_memory();
+        dst.set_trust_policy(TrustPolicy {
+            accept_unsigned: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let pkgs: BTreeSet<_> = std::iter::once(old_pkg).collect();
+        copy_closure(&src, &mut dst, pkgs, |_| {}).await.unwrap();
+
+        let mut received_bytes = 0;
+        let pkgs: BTreeSet<_> = std::iter::once(new_pkg).collect();
+        let delta = copy_closure(&src, &mut dst, pkgs, |p| {
+            if let Progress::Finished {
+                received_bytes: n, ..
+            } = *p
+            {
+                received_bytes = n;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(delta.bases.len(), 1);
+        assert!(received_bytes > 0 && received_bytes < 16 * 1024);
+
+        let (new_blob, _) = Blob::from_bytes(new_content.clone(), true);
+        let (&blob_id, _) = delta.bases.iter().next().unwrap();
+        assert_eq!(blob_id, new_blob.object_id());
+
+        let mut content = Vec::new();
+        let blob = dst.get_blob(blob_id).unwrap();
+        blob.into_content()
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, new_content);
+    }
+}
@@ -1,10 +1,10 @@
 use std::collections::{BTreeMap, BTreeSet};
 
-use foo::{Blob, Entry, LocalStore, Object, Objects, Package, Platform, Tree};
+use foo::{Blob, Entry, LocalStore, Object, Objects, Package, Platform, SecretKey, Tree};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-    // let mut store = Store::in_memory();
+    // let mut store = LocalStore::in_memory();
     let mut store: LocalStore = LocalStore::init("./store")?;
 
     let txt_id = store.insert_object(Object::Blob(
@@ -72,7 +72,15 @@ async fn main() -> anyhow::Result<()> {
         store.compute_closure(pkgs.clone())?
     );
 
+    let key = SecretKey::generate("store-1")?;
+    for &pkg in &pkgs {
+        store.sign_package(pkg, &key)?;
+    }
+
     let mut store2: LocalStore = LocalStore::init("./store2")?;
+    let mut policy = store2.trust_policy()?;
+    policy.trusted_keys.insert(key.public_key());
+    store2.set_trust_policy(policy)?;
 
     println!("copying delta from store -> store2");
     let info = foo::copy_closure(&store, &mut store2, pkgs, |p| println!("{:?}", p)).await?;
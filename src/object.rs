@@ -5,6 +5,7 @@ pub use self::name::{InstallName, PackageName};
 pub use self::platform::Platform;
 pub use self::reference::{Offsets, References};
 
+pub(crate) use self::id::Hasher;
 pub(crate) use self::reference::RewriteSink;
 
 use std::collections::{BTreeMap, BTreeSet};
@@ -20,20 +21,23 @@ use semver::Version;
 use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
 
+use self::chunker::Chunker;
 use self::id::HashWriter;
-use self::reference::ReferenceSink;
+pub(crate) use self::reference::ReferenceSink;
 use self::spooled::SpooledTempFile;
 use crate::util;
 
 pub mod pack;
 pub mod platform;
 
+mod chunker;
 mod id;
 mod name;
 mod reference;
 mod spooled;
 
 const BLOB_FILE_EXT: &str = "blob";
+const CHUNKED_BLOB_FILE_EXT: &str = "chunks";
 const TREE_FILE_EXT: &str = "tree";
 const PACKAGE_FILE_EXT: &str = "pkg";
 const SPEC_FILE_EXT: &str = "spec";
@@ -50,10 +54,12 @@ pub trait ContentAddressable {
 }
 
 /// A list specifying all types of `Store` objects.
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
 pub enum ObjectKind {
     /// Plain file or executable.
     Blob,
+    /// Large file or executable split into chunks, each of which is a `Blob` object.
+    ChunkedBlob,
     /// Filesystem directory possibly containing other `Blob` and `Tree` objects, one level deep.
     Tree,
     /// Installed package with a name, platform, package references, and an output directory tree.
@@ -67,6 +73,7 @@ impl ObjectKind {
     pub fn iter() -> impl Iterator<Item = Self> {
         use std::iter::once;
         once(ObjectKind::Blob)
+            .chain(once(ObjectKind::ChunkedBlob))
             .chain(once(ObjectKind::Tree))
             .chain(once(ObjectKind::Package))
             .chain(once(ObjectKind::Spec))
@@ -78,6 +85,7 @@ impl ObjectKind {
     pub const fn as_str(self) -> &'static str {
         match self {
             ObjectKind::Blob => BLOB_FILE_EXT,
+            ObjectKind::ChunkedBlob => CHUNKED_BLOB_FILE_EXT,
             ObjectKind::Tree => TREE_FILE_EXT,
             ObjectKind::Package => PACKAGE_FILE_EXT,
             ObjectKind::Spec => SPEC_FILE_EXT,
@@ -91,6 +99,7 @@ impl FromStr for ObjectKind {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             BLOB_FILE_EXT => Ok(ObjectKind::Blob),
+            CHUNKED_BLOB_FILE_EXT => Ok(ObjectKind::ChunkedBlob),
             TREE_FILE_EXT => Ok(ObjectKind::Tree),
             PACKAGE_FILE_EXT => Ok(ObjectKind::Package),
             SPEC_FILE_EXT => Ok(ObjectKind::Spec),
@@ -104,6 +113,8 @@ impl FromStr for ObjectKind {
 pub enum Object {
     /// Plain file or executable.
     Blob(Blob),
+    /// Large file or executable split into chunks, each of which is a `Blob` object.
+    ChunkedBlob(ChunkedBlob),
     /// Filesystem directory possibly containing other `Blob` and `Tree` objects, one level deep.
     Tree(Tree),
     /// Installed package with a name, platform, package references, and an output directory tree.
@@ -118,6 +129,7 @@ impl Object {
     pub fn kind(&self) -> ObjectKind {
         match *self {
             Object::Blob(_) => ObjectKind::Blob,
+            Object::ChunkedBlob(_) => ObjectKind::ChunkedBlob,
             Object::Tree(_) => ObjectKind::Tree,
             Object::Package(_) => ObjectKind::Package,
             Object::Spec(_) => ObjectKind::Spec,
@@ -128,6 +140,7 @@ impl Object {
     ///
     /// Returns `Err(self)` if this object is not actually a `Blob`.
     #[inline]
+    #[allow(clippy::result_large_err)]
     pub fn into_blob(self) -> Result<Blob, Self> {
         match self {
             Object::Blob(b) => Ok(b),
@@ -135,10 +148,23 @@ impl Object {
         }
     }
 
+    /// Attempts to consume this object and return a `ChunkedBlob`.
+    ///
+    /// Returns `Err(self)` if this object is not actually a `ChunkedBlob`.
+    #[inline]
+    #[allow(clippy::result_large_err)]
+    pub fn into_chunked_blob(self) -> Result<ChunkedBlob, Self> {
+        match self {
+            Object::ChunkedBlob(c) => Ok(c),
+            other => Err(other),
+        }
+    }
+
     /// Attempts to consume this object and return a `Tree`.
     ///
     /// Returns `Err(self)` if this object is not actually a `Tree`.
     #[inline]
+    #[allow(clippy::result_large_err)]
     pub fn into_tree(self) -> Result<Tree, Self> {
         match self {
             Object::Tree(t) => Ok(t),
@@ -150,6 +176,7 @@ impl Object {
     ///
     /// Returns `Err(self)` if this object is not actually a `Package`.
     #[inline]
+    #[allow(clippy::result_large_err)]
     pub fn into_package(self) -> Result<Package, Self> {
         match self {
             Object::Package(o) => Ok(o),
@@ -161,6 +188,7 @@ impl Object {
     ///
     /// Returns `Err(self)` if this object is not actually a `Spec`.
     #[inline]
+    #[allow(clippy::result_large_err)]
     pub fn into_spec(self) -> Result<Spec, Self> {
         match self {
             Object::Spec(o) => Ok(o),
@@ -173,6 +201,7 @@ impl ContentAddressable for Object {
     fn object_id(&self) -> ObjectId {
         match *self {
             Object::Blob(ref o) => o.object_id(),
+            Object::ChunkedBlob(ref c) => c.object_id(),
             Object::Tree(ref t) => t.object_id(),
             Object::Package(ref o) => o.object_id(),
             Object::Spec(ref o) => o.object_id(),
@@ -182,6 +211,7 @@ impl ContentAddressable for Object {
     fn size(&self) -> u64 {
         match *self {
             Object::Blob(ref o) => o.size(),
+            Object::ChunkedBlob(ref c) => c.size(),
             Object::Tree(ref t) => t.size(),
             Object::Package(ref o) => o.size(),
             Object::Spec(ref o) => o.size(),
@@ -228,6 +258,10 @@ impl Blob {
         (blob, references)
     }
 
+    /// The size, in bytes, at or above which [`Blob::file_from_path()`] and
+    /// [`Blob::file_from_writer()`] split a file into content-defined chunks.
+    pub const CHUNKING_THRESHOLD: u64 = 2 * chunker::MAX_SIZE as u64;
+
     /// Hashes and returns a new `Blob` object from the file located at `path`.
     ///
     /// This constructor is generally more efficient than [`Blob::from_writer()`]. It uses
@@ -260,15 +294,91 @@ impl Blob {
         )
     }
 
+    /// Hashes and returns a new file object from the file located at `path`.
+    ///
+    /// Files of at least [`Blob::CHUNKING_THRESHOLD`] bytes are split into content-defined chunks,
+    /// yielding [`FileContent::Chunked`]. Smaller files yield a single [`FileContent::Whole`] blob.
+    /// The file is otherwise read the same way as in [`Blob::from_path()`].
+    ///
+    /// Returns `Err` if `path` does not exist or does not refer to a file, the user does not have
+    /// permission to read the file, or another I/O error occurred.
+    pub fn file_from_path<P: AsRef<Path>>(path: P) -> io::Result<(FileContent, References)> {
+        util::open_large_read(
+            path.as_ref(),
+            |cursor, is_executable| {
+                let (blob, refs) = Blob::from_bytes(cursor.into_inner(), is_executable);
+                Ok((FileContent::Whole(blob), refs))
+            },
+            |mmap, is_executable| {
+                let mut writer = Blob::file_from_writer(is_executable);
+                writer.write_all(mmap.get_ref())?;
+                Ok(writer.finish_file())
+            },
+            |mut file, is_executable| {
+                let mut writer = Blob::file_from_writer(is_executable);
+                util::copy_wide(&mut file, &mut writer)?;
+                Ok(writer.finish_file())
+            },
+        )
+    }
+
+    /// Hashes the file located at `path` as a single `Blob`, regardless of its size.
+    ///
+    /// Large files are not scanned for run-time references. See [`Blob::whole_from_writer()`].
+    pub(crate) fn whole_from_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        util::open_large_read(
+            path.as_ref(),
+            |cursor, is_executable| Ok(Blob::from_bytes(cursor.into_inner(), is_executable).0),
+            |mmap, is_executable| {
+                let mut writer = Blob::whole_from_writer(is_executable);
+                writer.write_all(mmap.get_ref())?;
+                Ok(writer.finish().0)
+            },
+            |mut file, is_executable| {
+                let mut writer = Blob::whole_from_writer(is_executable);
+                util::copy_wide(&mut file, &mut writer)?;
+                Ok(writer.finish().0)
+            },
+        )
+    }
+
     /// Returns a writer which creates a new `Blob` object from a stream of bytes.
     ///
     /// This writer will initially buffer the I/O stream in memory, spilling over into a temporary
     /// file on disk if the internal buffer grows beyond a 1 MiB threshold.
     pub fn from_writer(is_executable: bool) -> BlobWriter {
         let hasher = id::Hasher::new_blob(is_executable);
-        let spooled = SpooledTempFile::new(1 * 1024 * 1024);
+        let spooled = SpooledTempFile::new(1024 * 1024);
         BlobWriter {
             inner: ReferenceSink::new(HashWriter::with_hasher(hasher, spooled)),
+            chunks: None,
+            is_executable,
+            size: 0,
+        }
+    }
+
+    /// Returns a writer which creates a new file object from a stream of bytes.
+    ///
+    /// If the stream turns out to be at least [`Blob::CHUNKING_THRESHOLD`] bytes long, it is split
+    /// into content-defined chunks as it is written. The writer should be finished with
+    /// [`BlobWriter::finish_file()`]; see its documentation for more.
+    pub fn file_from_writer(is_executable: bool) -> BlobWriter {
+        BlobWriter {
+            chunks: Some(ChunkState::default()),
+            ..Blob::from_writer(is_executable)
+        }
+    }
+
+    /// Returns a writer which always creates a single `Blob` object, regardless of its size.
+    ///
+    /// This is used wherever the ID of the blob is already known, e.g. when receiving it from a
+    /// pack file, so the stream is not scanned for run-time references either.
+    pub(crate) fn whole_from_writer(is_executable: bool) -> BlobWriter {
+        let hasher = id::Hasher::new_blob(is_executable);
+        let spooled = SpooledTempFile::new(1024 * 1024);
+        BlobWriter {
+            inner: ReferenceSink::passthrough(HashWriter::with_hasher(hasher, spooled)),
+            chunks: None,
             is_executable,
             size: 0,
         }
@@ -285,6 +395,15 @@ impl Blob {
         })
     }
 
+    pub(crate) fn from_store_bytes(bytes: Vec<u8>, is_executable: bool, id: ObjectId) -> Self {
+        Blob {
+            size: bytes.len() as u64,
+            stream: Kind::Inline(Cursor::new(bytes)),
+            is_executable,
+            object_id: id,
+        }
+    }
+
     /// Returns `true` if this blob has its executable bit set.
     #[inline]
     pub fn is_executable(&self) -> bool {
@@ -382,12 +501,13 @@ impl Seek for Contents {
     }
 }
 
-/// A writer which creates a new `Blob` from a byte stream.
+/// A writer which creates a new file object from a byte stream.
 ///
 /// This struct is created by [`Blob::from_writer()`]. See its documentation for more.
 #[derive(Debug)]
 pub struct BlobWriter {
     inner: ReferenceSink<HashWriter<SpooledTempFile>>,
+    chunks: Option<ChunkState>,
     is_executable: bool,
     size: u64,
 }
@@ -405,12 +525,39 @@ impl BlobWriter {
 
         (blob, references)
     }
+
+    /// Returns the finished file object and its run-time references, if any were detected.
+    ///
+    /// If the writer was created by [`Blob::file_from_writer()`], the file is returned as
+    /// [`FileContent::Chunked`] if at least [`Blob::CHUNKING_THRESHOLD`] bytes were written.
+    /// Otherwise, it is returned as a single [`FileContent::Whole`] blob.
+    pub fn finish_file(mut self) -> (FileContent, References) {
+        let chunks = match self.chunks.take() {
+            Some(state) if self.size >= Blob::CHUNKING_THRESHOLD => state.finish(),
+            _ => {
+                let (blob, references) = self.finish();
+                return (FileContent::Whole(blob), references);
+            }
+        };
+
+        let manifest = ChunkedBlob {
+            is_executable: self.is_executable,
+            chunks,
+        };
+
+        let (content, references) = self.finish();
+        let file = ChunkedFile { manifest, content };
+        (FileContent::Chunked(file), references)
+    }
 }
 
 impl Write for BlobWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.inner.write(buf)?;
         self.size += len as u64;
+        if let Some(state) = self.chunks.as_mut() {
+            state.update(&buf[..len]);
+        }
         Ok(len)
     }
 
@@ -419,15 +566,207 @@ impl Write for BlobWriter {
     }
 }
 
+/// Tracks the chunk boundaries and chunk hashes of a [`BlobWriter`].
+#[derive(Debug)]
+struct ChunkState {
+    chunker: Chunker,
+    hasher: id::Hasher,
+    len: u64,
+    chunks: Vec<Chunk>,
+}
+
+impl Default for ChunkState {
+    fn default() -> Self {
+        ChunkState {
+            chunker: Chunker::default(),
+            hasher: id::Hasher::new_blob(false),
+            len: 0,
+            chunks: Vec::new(),
+        }
+    }
+}
+
+impl ChunkState {
+    fn update(&mut self, mut buf: &[u8]) {
+        while let Some(n) = self.chunker.next_boundary(buf) {
+            self.hasher.update(&buf[..n]);
+            self.len += n as u64;
+            self.end_chunk();
+            buf = &buf[n..];
+        }
+
+        self.hasher.update(buf);
+        self.len += buf.len() as u64;
+    }
+
+    fn end_chunk(&mut self) {
+        let hasher = std::mem::replace(&mut self.hasher, id::Hasher::new_blob(false));
+        let size = std::mem::take(&mut self.len);
+        self.chunks.push(Chunk {
+            id: hasher.finish(),
+            size,
+        });
+    }
+
+    fn finish(mut self) -> Vec<Chunk> {
+        if self.len > 0 {
+            self.end_chunk();
+        }
+
+        self.chunks
+    }
+}
+
+/// A regular file or executable, as created by [`Blob::file_from_path()`] or
+/// [`Blob::file_from_writer()`].
+#[derive(Debug)]
+pub enum FileContent {
+    /// A file stored as a single `Blob` object.
+    Whole(Blob),
+    /// A large file split into content-defined chunks.
+    Chunked(ChunkedFile),
+}
+
+impl FileContent {
+    /// Returns the type of object that refers to this file in a tree.
+    pub fn kind(&self) -> ObjectKind {
+        match self {
+            FileContent::Whole(_) => ObjectKind::Blob,
+            FileContent::Chunked(_) => ObjectKind::ChunkedBlob,
+        }
+    }
+
+    /// Returns the ID of the object that refers to this file in a tree.
+    ///
+    /// This is the ID of the `Blob` object, or of the `ChunkedBlob` manifest if it was chunked.
+    pub fn object_id(&self) -> ObjectId {
+        match self {
+            FileContent::Whole(blob) => blob.object_id(),
+            FileContent::Chunked(file) => file.manifest.object_id(),
+        }
+    }
+
+    /// Returns the total size of the file, in bytes.
+    pub fn size(&self) -> u64 {
+        match self {
+            FileContent::Whole(blob) => blob.size(),
+            FileContent::Chunked(file) => file.content.size(),
+        }
+    }
+}
+
+/// A large file split into content-defined chunks.
+///
+/// This struct is created by [`Blob::file_from_path()`] and [`BlobWriter::finish_file()`]. See their
+/// documentation for more.
+#[derive(Debug)]
+pub struct ChunkedFile {
+    manifest: ChunkedBlob,
+    content: Blob,
+}
+
+impl ChunkedFile {
+    /// Returns the `ChunkedBlob` manifest listing the chunks of the file.
+    #[inline]
+    pub fn manifest(&self) -> &ChunkedBlob {
+        &self.manifest
+    }
+
+    /// Consumes the file and returns every chunk as a `Blob` object, followed by the `ChunkedBlob`
+    /// manifest itself.
+    ///
+    /// This is the order in which the objects must be inserted into a store. Chunks are read one
+    /// at a time, so at most one chunk is held in memory at once.
+    ///
+    /// Returns `Err` if an I/O error occurred.
+    pub fn into_objects(self) -> io::Result<impl Iterator<Item = io::Result<Object>>> {
+        let mut content = self.content.into_content()?;
+        let chunks: Vec<_> = self.manifest.chunks.clone();
+
+        let blobs = chunks.into_iter().map(move |chunk| {
+            let mut buf = vec![0u8; chunk.size as usize];
+            content.read_exact(&mut buf)?;
+            let blob = Blob::from_store_bytes(buf, false, chunk.id);
+            Ok(Object::Blob(blob))
+        });
+
+        let manifest = std::iter::once(Ok(Object::ChunkedBlob(self.manifest)));
+        Ok(blobs.chain(manifest))
+    }
+}
+
+/// A single chunk of a [`ChunkedBlob`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+pub struct Chunk {
+    /// ID of the non-executable `Blob` object holding the content of the chunk.
+    pub id: ObjectId,
+    /// Size of the chunk, in bytes.
+    pub size: u64,
+}
+
+/// Represents a large file or executable split into content-defined chunks.
+///
+/// Each chunk is stored as a separate, non-executable `Blob` object, and the file is reassembled
+/// by concatenating the chunks in order. Since chunk boundaries depend only on the surrounding
+/// content, a small change to a large file only produces a handful of new chunks, and unchanged
+/// chunks are deduplicated in the store and skipped when copying closures.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+pub struct ChunkedBlob {
+    /// Whether the reassembled file has its executable bit set.
+    pub is_executable: bool,
+    /// The chunks making up the file, in order.
+    pub chunks: Vec<Chunk>,
+}
+
+impl ChunkedBlob {
+    /// Returns the total size of the reassembled file, in bytes.
+    pub fn file_size(&self) -> u64 {
+        self.chunks.iter().map(|c| c.size).sum()
+    }
+
+    /// Iterates over all object IDs that this chunked blob references.
+    pub fn references(&self) -> impl Iterator<Item = (ObjectId, ObjectKind)> + '_ {
+        self.chunks.iter().map(|c| (c.id, ObjectKind::Blob))
+    }
+}
+
+impl ObjectExt for ChunkedBlob {
+    fn hasher() -> id::Hasher {
+        id::Hasher::new_chunked_blob()
+    }
+}
+
+impl ContentAddressable for ChunkedBlob {
+    fn object_id(&self) -> ObjectId {
+        self.interned_id_size().0
+    }
+
+    fn size(&self) -> u64 {
+        self.interned_id_size().1
+    }
+}
+
 /// A list of possible entries inside of a directory tree.
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum Entry {
     Tree { id: ObjectId },
     Blob { id: ObjectId },
+    ChunkedBlob { id: ObjectId },
     Symlink { target: PathBuf },
 }
 
+impl Entry {
+    /// Returns the tree entry referring to the regular file `file`.
+    pub fn from_file(file: &FileContent) -> Self {
+        let id = file.object_id();
+        match file {
+            FileContent::Whole(_) => Entry::Blob { id },
+            FileContent::Chunked(_) => Entry::ChunkedBlob { id },
+        }
+    }
+}
+
 /// Represents a directory tree object.
 ///
 /// Tree objects are only one level deep and may contain other trees, blobs, and symlinks.
@@ -443,6 +782,7 @@ impl Tree {
         self.entries.values().filter_map(|entry| match entry {
             Entry::Tree { id } => Some((*id, ObjectKind::Tree)),
             Entry::Blob { id } => Some((*id, ObjectKind::Blob)),
+            Entry::ChunkedBlob { id } => Some((*id, ObjectKind::ChunkedBlob)),
             Entry::Symlink { .. } => None,
         })
     }
@@ -572,7 +912,7 @@ pub(crate) trait ObjectExt: Serialize + Hash + Sized {
                 let json = serde_json::to_vec(self).unwrap();
                 (Self::hasher().update(&json).finish(), json.len() as u64)
             })
-            .clone()
+            .to_owned()
     }
 
     /// Persists the object to disk as a read-only JSON file located at `dst`.
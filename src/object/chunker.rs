@@ -0,0 +1,183 @@
+//! Content-defined chunking of large blobs.
+//!
+//! Boundaries are found with a FastCDC-style gear hash: a rolling hash over roughly the last 64
+//! bytes of input is updated for every byte, and a chunk ends wherever its top bits are all zero.
+//! Since boundaries only depend on nearby content, inserting or removing bytes in a file only
+//! affects the chunks surrounding the edit, and every other chunk keeps its object ID.
+//!
+//! Chunk sizes are normalized around [`AVG_SIZE`] by using a stricter mask below the average size
+//! and a looser one above it, and are always clamped between [`MIN_SIZE`] and [`MAX_SIZE`].
+
+/// Minimum size of a chunk, in bytes. Boundaries are never searched for before this point.
+pub const MIN_SIZE: usize = 64 * 1024;
+/// Target average size of a chunk, in bytes.
+pub const AVG_SIZE: usize = 256 * 1024;
+/// Maximum size of a chunk, in bytes. A boundary is forced here if none was found.
+pub const MAX_SIZE: usize = 1024 * 1024;
+
+// `AVG_SIZE` is 2^18, so require two more bits below it and two fewer bits above it.
+const MASK_STRICT: u64 = !0 << (64 - 20);
+const MASK_LOOSE: u64 = !0 << (64 - 16);
+
+/// Random values mixed into the rolling hash for each possible byte, generated with SplitMix64.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Incrementally finds chunk boundaries in a stream of bytes.
+#[derive(Debug, Default)]
+pub struct Chunker {
+    hash: u64,
+    len: usize,
+}
+
+impl Chunker {
+    /// Scans `buf`, which continues the current chunk, for the end of that chunk.
+    ///
+    /// Returns `Some(n)` if the chunk ends after the first `n` bytes of `buf`, in which case the
+    /// chunker is reset to begin a new chunk. Otherwise, returns `None` if the whole of `buf`
+    /// belongs to the current chunk.
+    pub fn next_boundary(&mut self, buf: &[u8]) -> Option<usize> {
+        let mut i = 0;
+
+        // Skip hashing entirely until the minimum chunk size is reached.
+        if self.len < MIN_SIZE {
+            let skip = (MIN_SIZE - self.len).min(buf.len());
+            self.len += skip;
+            i = skip;
+        }
+
+        while i < buf.len() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[buf[i] as usize]);
+            self.len += 1;
+            i += 1;
+
+            let mask = if self.len < AVG_SIZE {
+                MASK_STRICT
+            } else {
+                MASK_LOOSE
+            };
+
+            if self.hash & mask == 0 || self.len >= MAX_SIZE {
+                *self = Chunker::default();
+                return Some(i);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::path::Path;
+
+    use super::*;
+    use crate::test_support::{insert_large_package, random_bytes};
+    use crate::{copy_closure, Entry, LocalStore, MemEntry, ObjectKind, Objects, TrustPolicy};
+
+    fn chunk_sizes(data: &[u8]) -> Vec<usize> {
+        let mut chunker = Chunker::default();
+        let mut sizes = Vec::new();
+        let mut rest = data;
+
+        // Feed the data in odd-sized pieces to exercise boundaries spanning several writes.
+        while !rest.is_empty() {
+            let mut piece = &rest[..rest.len().min(10_000)];
+            rest = &rest[piece.len()..];
+            let mut current = sizes.pop().unwrap_or(0);
+            while let Some(n) = chunker.next_boundary(piece) {
+                sizes.push(current + n);
+                current = 0;
+                piece = &piece[n..];
+            }
+            sizes.push(current + piece.len());
+        }
+
+        sizes.retain(|&n| n > 0);
+        sizes
+    }
+
+    #[test]
+    fn boundaries_are_content_defined() {
+        let mut data = vec![0u8; 4 * 1024 * 1024];
+        blake3::Hasher::new().finalize_xof().fill(&mut data);
+
+        let sizes = chunk_sizes(&data);
+        assert_eq!(sizes.iter().sum::<usize>(), data.len());
+        assert!(sizes[..sizes.len() - 1]
+            .iter()
+            .all(|&n| (MIN_SIZE..=MAX_SIZE).contains(&n)));
+
+        // Prepending a few bytes only shifts the first chunk.
+        let mut shifted = b"prefix".to_vec();
+        shifted.extend_from_slice(&data);
+        let shifted_sizes = chunk_sizes(&shifted);
+        assert_eq!(shifted_sizes[0], sizes[0] + 6);
+        assert_eq!(shifted_sizes[1..], sizes[1..]);
+    }
+
+    #[tokio::test]
+    async fn copies_only_changed_chunks() {
+        let old_data = random_bytes(&[], 3 * 1024 * 1024);
+        let mut new_data = old_data.clone();
+        new_data[1_500_000..1_500_016].copy_from_slice(b"patched version!");
+
+        let mut src = LocalStore::in_memory();
+        let old_pkg = insert_large_package(&mut src, "big-1.0", &old_data);
+        let new_pkg = insert_large_package(&mut src, "big-1.1", &new_data);
+
+        // Instantiated packages contain the reassembled file.
+        let pkg = src.get_package(new_pkg).unwrap();
+        let tree = src.get_tree(pkg.tree).unwrap();
+        assert!(matches!(
+            tree.entries["data.bin"],
+            Entry::ChunkedBlob { .. }
+        ));
+        let installed = src.installed_package(&pkg.install_name()).unwrap();
+        match installed.get(Path::new("data.bin")) {
+            Some(MemEntry::File {
+                content,
+                is_executable: false,
+            }) => assert!(content[..] == new_data[..]),
+            other => panic!("unexpected entry: {:?}", other),
+        }
+
+        let mut dst = LocalStore::in_memory();
+        dst.set_trust_policy(TrustPolicy {
+            accept_unsigned: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let pkgs: BTreeSet<_> = std::iter::once(old_pkg).collect();
+        copy_closure(&src, &mut dst, pkgs, |_| {}).await.unwrap();
+
+        let pkgs: BTreeSet<_> = std::iter::once(new_pkg).collect();
+        let delta = copy_closure(&src, &mut dst, pkgs, |_| {}).await.unwrap();
+        let new_chunks = delta
+            .missing
+            .iter()
+            .filter(|&&(_, kind, _)| kind == ObjectKind::Blob)
+            .count();
+        assert!((1..=2).contains(&new_chunks), "{} chunks sent", new_chunks);
+
+        let pkg = dst.get_package(new_pkg).unwrap();
+        let installed = dst.installed_package(&pkg.install_name()).unwrap();
+        match installed.get(Path::new("data.bin")) {
+            Some(MemEntry::File { content, .. }) => assert!(content[..] == new_data[..]),
+            other => panic!("unexpected entry: {:?}", other),
+        }
+    }
+}
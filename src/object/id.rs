@@ -80,13 +80,13 @@ impl FromStr for ObjectId {
 
 impl PartialOrd for ObjectId {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.0.as_bytes().partial_cmp(&other.0.as_bytes())
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for ObjectId {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.0.as_bytes().cmp(&other.0.as_bytes())
+        self.0.as_bytes().cmp(other.0.as_bytes())
     }
 }
 
@@ -120,6 +120,12 @@ impl Hasher {
         Hasher::with_header(if is_executable { b"exec:" } else { b"blob:" })
     }
 
+    /// Constructs a new `Hasher` for a chunked blob object.
+    #[inline]
+    pub fn new_chunked_blob() -> Self {
+        Hasher::with_header(b"chunks:")
+    }
+
     /// Constructs a new `Hasher` for a tree object.
     #[inline]
     pub fn new_tree() -> Self {
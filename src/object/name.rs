@@ -11,7 +11,7 @@ use smol_str::SmolStr;
 use super::ObjectId;
 
 /// The human-readable name of a package.
-#[derive(Clone, Debug, Hash, Serialize)]
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct PackageName(SmolStr);
 
 impl PackageName {
@@ -54,6 +54,43 @@ impl PackageName {
 
         Ok(PackageName(SmolStr::new(s)))
     }
+
+    /// Returns the name of the package with any trailing version string removed.
+    ///
+    /// The version is assumed to begin at the first `-` character which is followed by something
+    /// other than a letter, so `foo-bar-1.2.0` has the stem `foo-bar`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use foo::PackageName;
+    /// let name: PackageName = "hello-world-1.0.0".parse().unwrap();
+    /// assert_eq!(name.stem(), "hello-world");
+    /// assert_eq!(name.version(), Some("1.0.0"));
+    /// ```
+    pub fn stem(&self) -> &str {
+        self.split_version().0
+    }
+
+    /// Returns the version string at the end of the package name, if there is one.
+    ///
+    /// See [`PackageName::stem()`] for details on how the version is located.
+    pub fn version(&self) -> Option<&str> {
+        self.split_version().1
+    }
+
+    fn split_version(&self) -> (&str, Option<&str>) {
+        let s = self.0.as_str();
+        let split = s
+            .match_indices('-')
+            .map(|(i, _)| i)
+            .find(|&i| matches!(s[i + 1..].chars().next(), Some(c) if !c.is_ascii_alphabetic()));
+
+        match split {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        }
+    }
 }
 
 impl AsRef<str> for PackageName {
@@ -113,7 +150,7 @@ pub struct InstallName(String);
 
 impl InstallName {
     /// Computes the directory name where the package should be installed.
-    pub(super) fn new(name: &PackageName, id: ObjectId) -> Self {
+    pub(crate) fn new(name: &PackageName, id: ObjectId) -> Self {
         InstallName(format!("{}-{}", name, id))
     }
 
@@ -136,7 +173,7 @@ impl InstallName {
     /// assert_eq!(install_name.name(), "hello-1.0.0");
     /// ```
     pub fn name(&self) -> &str {
-        self.0.rsplitn(2, '-').nth(1).unwrap()
+        self.0.rsplit_once('-').unwrap().0
     }
 
     /// Returns the package ID component of the `InstallName`.
@@ -159,7 +196,7 @@ impl InstallName {
     /// assert_eq!(install_name.id(), id);
     /// ```
     pub fn id(&self) -> ObjectId {
-        self.0.rsplitn(2, '-').nth(0).unwrap().parse().unwrap()
+        self.0.rsplit_once('-').unwrap().1.parse().unwrap()
     }
 }
 
@@ -175,6 +212,19 @@ impl Display for InstallName {
     }
 }
 
+impl FromStr for InstallName {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, id) = s
+            .rsplit_once('-')
+            .ok_or_else(|| anyhow!("install name {:?} is missing an object ID", s))?;
+        let name = PackageName::parse(name)?;
+        let id = id.parse()?;
+        Ok(InstallName::new(&name, id))
+    }
+}
+
 impl From<InstallName> for String {
     fn from(name: InstallName) -> Self {
         name.0
@@ -9,35 +9,78 @@
 //! It is intended as a simple and lightweight method of transferring a [`Closure`](crate::Closure)
 //! of [`Object`](crate::Object)s from one host to another.
 //!
+//! When a pack file is stored on disk, a companion [`PackIndex`] can be produced alongside it by
+//! [`PackWriter::finish_with_index()`]. This allows [`IndexedPackReader`] to seek directly to any
+//! individual object instead of streaming the whole pack file from the start.
+//!
+//! Two versions of the format exist. [`PackWriter`] emits [`FORMAT_VERSION`] by default, while
+//! [`pack_reader()`], [`PackStream`] and [`IndexedPackReader`] accept either version, depending on
+//! the version byte found in the header.
+//!
 //! ## Header
 //!
 //! ```text
 //!  Magic value  Version
 //! +------------+-------+
-//! | store-pack |   1   | (11 bytes)
+//! | store-pack | 1 / 2 | (11 bytes)
 //! +------------+-------+
 //! ```
 //!
-//! ## Packed `Object`s
+//! ## Packed `Object`s (version 1)
 //!
 //! ```text
 //!                 Entry header (41 bytes)                  Object content
 //! +---------------+---------------+---------------------+  +-----------+
-//! | ID (32 bytes) | Kind (1 byte) | Size (u64 NE bytes) |  | <content> |  ... (repeat x n)
+//! | ID (32 bytes) | Kind (1 byte) | Size (u64 BE bytes) |  | <content> |  ... (repeat x n)
 //! +---------------+---------------+---------------------+  +-----------+
 //! ```
 //!
+//! ## Packed `Object`s (version 2)
+//!
+//! ```text
+//!                                  Entry header (49 bytes)                            Compressed content
+//! +---------------+---------------+---------------------+--------------------------+  +------------+
+//! | ID (32 bytes) | Kind (1 byte) | Size (u64 BE bytes) | Wire size (u64 BE bytes) |  | <zstd>     |  ... (repeat x n)
+//! +---------------+---------------+---------------------+--------------------------+  +------------+
+//! ```
+//!
+//! The content of each entry is compressed individually with zstd. `Size` is the size of the
+//! object itself, while `Wire size` is the size of the compressed content following the header.
+//!
+//! ## Delta entries (version 2 only)
+//!
+//! ```text
+//!   Entry header (49 bytes)      Base ID         Compressed content
+//! +-------------------------+  +--------------+  +---------------------------+
+//! | <kind 5 or 6>           |  | (32 bytes)   |  | <zstd, base as dictionary> |
+//! +-------------------------+  +--------------+  +---------------------------+
+//! ```
+//!
+//! A blob may instead be sent as a binary delta against a base blob which the receiver is known
+//! to hold already, making the pack "thin". The content is compressed with the whole base blob
+//! loaded as a zstd dictionary, so unchanged regions shrink to a handful of back-references.
+//! `Wire size` covers both the base ID and the compressed content. Thin packs can only be read
+//! by a [`ThinPackReader`] which has access to the base objects.
+//!
 //! ## Footer
 //!
 //! ```text
 //! +-----------------------------------------------------+
-//! |             Null entry header (41 bytes)            |
+//! |      Null entry header (41 bytes, or 49 in v2)      |
+//! +-----------------------------------------------------+
+//! ```
+//!
+//! ## Trailer (version 2 only)
+//!
+//! ```text
+//! +-----------------------------------------------------+
+//! |   BLAKE3 hash of all preceding bytes (32 bytes)     |
 //! +-----------------------------------------------------+
 //! ```
 
 use std::convert::{TryFrom, TryInto};
 use std::fmt::{self, Debug, Formatter};
-use std::io::{self, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -49,16 +92,29 @@ use futures::{ready, stream, Stream, StreamExt};
 use serde::Serialize;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio_util::io::ReaderStream;
+use zstd::stream::raw::{CParameter, DParameter};
+
+pub use self::index::{IndexEntry, PackIndex};
 
 use super::{Blob, ContentAddressable, Object, ObjectId, ObjectKind};
-use crate::util;
+use crate::util::{self, AbortGuard, LimitedWriter};
+use crate::Objects;
+
+mod index;
+
+/// The newest version of the pack format, which is written by [`PackWriter::new()`].
+pub const FORMAT_VERSION: u8 = 2;
 
 const MAGIC_VALUE: &[u8] = b"store-pack";
-const FORMAT_VERSION: u8 = 1;
 const PACK_MAGIC_LEN: usize = MAGIC_VALUE.len() + 1;
-const HEADER_LEN: usize = ObjectId::LENGTH + 9;
-
-#[derive(Clone, Copy, PartialEq)]
+const MAX_HEADER_LEN: usize = ObjectId::LENGTH + 17;
+const TRAILER_LEN: usize = blake3::OUT_LEN;
+const COMPRESSION_LEVEL: i32 = 3;
+const MAX_DELTA_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_META_SIZE: u64 = 64 * 1024 * 1024;
+const DELTA_WINDOW_LOG: u32 = 27;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u8)]
 enum EntryKind {
     Blob = 0,
@@ -66,6 +122,9 @@ enum EntryKind {
     Tree = 2,
     Package = 3,
     Spec = 4,
+    BlobDelta = 5,
+    ExecDelta = 6,
+    ChunkedBlob = 7,
 }
 
 impl TryFrom<u8> for EntryKind {
@@ -78,15 +137,37 @@ impl TryFrom<u8> for EntryKind {
             2 => Ok(EntryKind::Tree),
             3 => Ok(EntryKind::Package),
             4 => Ok(EntryKind::Spec),
+            5 => Ok(EntryKind::BlobDelta),
+            6 => Ok(EntryKind::ExecDelta),
+            7 => Ok(EntryKind::ChunkedBlob),
             b => Err(anyhow!("unrecognized object kind byte: {}", b)),
         }
     }
 }
 
+impl EntryKind {
+    fn of(o: &Object) -> Self {
+        match o {
+            Object::Blob(blob) if blob.is_executable() => EntryKind::Exec,
+            Object::Blob(_) => EntryKind::Blob,
+            Object::ChunkedBlob(_) => EntryKind::ChunkedBlob,
+            Object::Tree(_) => EntryKind::Tree,
+            Object::Package(_) => EntryKind::Package,
+            Object::Spec(_) => EntryKind::Spec,
+        }
+    }
+
+    fn is_delta(self) -> bool {
+        matches!(self, EntryKind::BlobDelta | EntryKind::ExecDelta)
+    }
+}
+
 impl From<EntryKind> for ObjectKind {
     fn from(kind: EntryKind) -> Self {
         match kind {
             EntryKind::Blob | EntryKind::Exec => ObjectKind::Blob,
+            EntryKind::BlobDelta | EntryKind::ExecDelta => ObjectKind::Blob,
+            EntryKind::ChunkedBlob => ObjectKind::ChunkedBlob,
             EntryKind::Tree => ObjectKind::Tree,
             EntryKind::Package => ObjectKind::Package,
             EntryKind::Spec => ObjectKind::Spec,
@@ -94,38 +175,217 @@ impl From<EntryKind> for ObjectKind {
     }
 }
 
+/// A supported version of the pack format.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Format {
+    /// Uncompressed entries, terminated by a null header.
+    V1,
+    /// Individually compressed entries, terminated by a null header and a checksum trailer.
+    V2,
+}
+
+impl TryFrom<u8> for Format {
+    type Error = anyhow::Error;
+
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(Format::V1),
+            2 => Ok(Format::V2),
+            v => Err(anyhow!("unsupported pack format version: {}", v)),
+        }
+    }
+}
+
+impl Format {
+    fn from_magic(magic: &[u8]) -> anyhow::Result<Self> {
+        match magic {
+            [m @ .., version] if m == MAGIC_VALUE => Format::try_from(*version),
+            _ => Err(anyhow!("magic value not found, not a store pack file")),
+        }
+    }
+
+    fn magic(self) -> [u8; PACK_MAGIC_LEN] {
+        let mut magic = [0u8; PACK_MAGIC_LEN];
+        magic[..MAGIC_VALUE.len()].copy_from_slice(MAGIC_VALUE);
+        magic[MAGIC_VALUE.len()] = match self {
+            Format::V1 => 1,
+            Format::V2 => 2,
+        };
+        magic
+    }
+
+    fn header_len(self) -> usize {
+        match self {
+            Format::V1 => ObjectId::LENGTH + 9,
+            Format::V2 => MAX_HEADER_LEN,
+        }
+    }
+
+    fn make_header(self, id: ObjectId, kind: EntryKind, size: u64, wire_size: u64) -> Vec<u8> {
+        let mut buf = vec![0u8; self.header_len()];
+        buf[..ObjectId::LENGTH].copy_from_slice(id.as_bytes());
+        buf[ObjectId::LENGTH] = kind as u8;
+        buf[ObjectId::LENGTH + 1..][..8].copy_from_slice(&size.to_be_bytes());
+        if self == Format::V2 {
+            buf[ObjectId::LENGTH + 9..].copy_from_slice(&wire_size.to_be_bytes());
+        }
+        buf
+    }
+
+    /// Parses an entry header, returning `Ok(None)` if it is the null header marking the footer.
+    fn parse_header(self, header: &[u8]) -> anyhow::Result<Option<Header>> {
+        if header.iter().all(|&b| b == 0) {
+            return Ok(None);
+        }
+
+        let id = header[..ObjectId::LENGTH]
+            .try_into()
+            .map(ObjectId::from_bytes)?;
+        let kind = EntryKind::try_from(header[ObjectId::LENGTH])?;
+        if kind.is_delta() && self == Format::V1 {
+            return Err(anyhow!(
+                "delta entries are not supported in version 1 packs"
+            ));
+        }
+
+        let size = header[ObjectId::LENGTH + 1..][..8]
+            .try_into()
+            .map(u64::from_be_bytes)?;
+        let wire_size = match self {
+            Format::V1 => size,
+            Format::V2 => header[ObjectId::LENGTH + 9..]
+                .try_into()
+                .map(u64::from_be_bytes)?,
+        };
+
+        Ok(Some(Header {
+            id,
+            kind,
+            size,
+            wire_size,
+        }))
+    }
+}
+
+struct Header {
+    id: ObjectId,
+    kind: EntryKind,
+    size: u64,
+    wire_size: u64,
+}
+
 /// Serializes store objects into a binary pack file.
 #[derive(Debug)]
 pub struct PackWriter<W> {
     inner: W,
+    format: Format,
+    hasher: blake3::Hasher,
+    offset: u64,
+    entries: Vec<IndexEntry>,
 }
 
 impl<W: AsyncWrite + Unpin> PackWriter<W> {
-    /// Creates a new `PackWriter<W>`.
+    /// Creates a new `PackWriter<W>` which writes the newest pack format, [`FORMAT_VERSION`].
     ///
     /// Returns `Err` if the magic value and pack format version could not be written.
-    pub async fn new(mut inner: W) -> anyhow::Result<Self> {
-        let mut magic = [0u8; PACK_MAGIC_LEN];
-        magic[..MAGIC_VALUE.len()].copy_from_slice(MAGIC_VALUE);
-        magic[MAGIC_VALUE.len()] = FORMAT_VERSION;
-        inner.write_all(&magic).await?;
-        inner.flush().await?;
-        Ok(PackWriter { inner })
+    pub async fn new(inner: W) -> anyhow::Result<Self> {
+        PackWriter::with_version(inner, FORMAT_VERSION).await
+    }
+
+    /// Creates a new `PackWriter<W>` which writes the pack format `version`.
+    ///
+    /// This is useful for sending packs to older readers which only understand version 1.
+    ///
+    /// Returns `Err` if `version` is not supported, or the magic value and pack format version
+    /// could not be written.
+    pub async fn with_version(inner: W, version: u8) -> anyhow::Result<Self> {
+        let format = Format::try_from(version)?;
+        let mut writer = PackWriter {
+            inner,
+            format,
+            hasher: blake3::Hasher::new(),
+            offset: 0,
+            entries: Vec::new(),
+        };
+
+        writer.write(&format.magic()).await?;
+        writer.inner.flush().await?;
+        Ok(writer)
     }
 
     /// Appends the given object to the pack, writing it to the underlying buffer.
     ///
     /// Returns `Err` if a serialization or I/O error occurred.
     pub async fn append(&mut self, o: Object) -> anyhow::Result<()> {
-        match o {
-            Object::Blob(blob) => {
-                let kind = if blob.is_executable() {
-                    EntryKind::Exec
-                } else {
-                    EntryKind::Blob
-                };
-                let header = make_header(blob.object_id(), kind, blob.size());
-                self.inner.write_all(&header).await?;
+        let id = o.object_id();
+        let kind = EntryKind::of(&o);
+        let offset = self.offset;
+
+        let size = match o {
+            Object::Blob(blob) => self.write_blob(id, kind, blob).await?,
+            Object::ChunkedBlob(c) => self.write_meta_object(id, kind, &c).await?,
+            Object::Tree(tree) => self.write_meta_object(id, kind, &tree).await?,
+            Object::Package(pkg) => self.write_meta_object(id, kind, &pkg).await?,
+            Object::Spec(spec) => self.write_meta_object(id, kind, &spec).await?,
+        };
+
+        self.inner.flush().await?;
+        self.entries.push(IndexEntry::new(id, kind, offset, size));
+
+        Ok(())
+    }
+
+    /// Appends the blob `o` to the pack as a binary delta against `base`, making the pack thin.
+    ///
+    /// The receiver _must_ already hold `base` in order to reconstruct `o`, and must read the pack
+    /// with a [`ThinPackReader`]. If this writer emits version 1 packs, or either blob is too large
+    /// to be delta-compressed, `o` is appended in full instead.
+    ///
+    /// Returns `Err` if a compression or I/O error occurred.
+    pub async fn append_delta(&mut self, o: Blob, base: Blob) -> anyhow::Result<()> {
+        if self.format == Format::V1 || o.size() > MAX_DELTA_SIZE || base.size() > MAX_DELTA_SIZE {
+            return self.append(Object::Blob(o)).await;
+        }
+
+        let id = o.object_id();
+        let base_id = base.object_id();
+        let size = o.size();
+        let kind = match o.is_executable() {
+            true => EntryKind::ExecDelta,
+            false => EntryKind::BlobDelta,
+        };
+        let offset = self.offset;
+
+        let guard = AbortGuard::default();
+        let target = guard.reader(o.into_content()?);
+        let (temp, wire_size) = tokio::task::spawn_blocking(move || encode_delta(target, base))
+            .await
+            .unwrap()?;
+
+        let wire_size = ObjectId::LENGTH as u64 + wire_size;
+        let header = self.format.make_header(id, kind, size, wire_size);
+        self.write(&header).await?;
+        self.write(base_id.as_bytes()).await?;
+        self.copy_from(&mut tokio::fs::File::from_std(temp)).await?;
+
+        self.inner.flush().await?;
+        self.entries.push(IndexEntry::new(id, kind, offset, size));
+
+        Ok(())
+    }
+
+    async fn write_blob(
+        &mut self,
+        id: ObjectId,
+        kind: EntryKind,
+        blob: Blob,
+    ) -> anyhow::Result<u64> {
+        let size = blob.size();
+
+        match self.format {
+            Format::V1 => {
+                let header = self.format.make_header(id, kind, size, size);
+                self.write(&header).await?;
 
                 let (reader, mut sync_writer) = os_pipe::pipe()?;
                 let mut reader = unsafe { tokio::fs::File::from_raw_fd(reader.into_raw_fd()) };
@@ -136,53 +396,461 @@ impl<W: AsyncWrite + Unpin> PackWriter<W> {
                     Ok(())
                 });
 
-                tokio::io::copy(&mut reader, &mut self.inner).await?;
+                self.copy_from(&mut reader).await?;
                 handle.await.unwrap()?;
             }
-            Object::Tree(tree) => self.write_meta_object(&tree, EntryKind::Tree).await?,
-            Object::Package(pkg) => self.write_meta_object(&pkg, EntryKind::Package).await?,
-            Object::Spec(spec) => self.write_meta_object(&spec, EntryKind::Spec).await?,
-        }
+            Format::V2 => {
+                // The header must list the compressed size, so compress to a temporary file first.
+                // If this future is dropped, the guard stops the compression right away.
+                let guard = AbortGuard::default();
+                let mut content = guard.reader(blob.into_content()?);
+                let handle = tokio::task::spawn_blocking(move || -> io::Result<_> {
+                    let mut temp = tempfile::tempfile_in("/var/tmp")?;
+                    zstd::stream::copy_encode(&mut content, &mut temp, COMPRESSION_LEVEL)?;
+                    let wire_size = temp.stream_position()?;
+                    temp.seek(SeekFrom::Start(0))?;
+                    Ok((temp, wire_size))
+                });
 
-        self.inner.flush().await?;
+                let (temp, wire_size) = handle.await.unwrap()?;
+                let header = self.format.make_header(id, kind, size, wire_size);
+                self.write(&header).await?;
+                self.copy_from(&mut tokio::fs::File::from_std(temp)).await?;
+            }
+        }
 
-        Ok(())
+        Ok(size)
     }
 
-    async fn write_meta_object<O>(&mut self, obj: &O, kind: EntryKind) -> anyhow::Result<()>
+    async fn write_meta_object<O>(
+        &mut self,
+        id: ObjectId,
+        kind: EntryKind,
+        obj: &O,
+    ) -> anyhow::Result<u64>
     where
-        O: ContentAddressable + Serialize,
+        O: Serialize,
     {
         let body = serde_json::to_vec(&obj)?;
-        let header = make_header(obj.object_id(), kind, body.len() as u64);
-        let combined: Vec<_> = header.iter().copied().chain(body).collect();
-        self.inner.write_all(&combined).await?;
-        Ok(())
+        let size = body.len() as u64;
+        let content = match self.format {
+            Format::V1 => body,
+            Format::V2 => zstd::encode_all(&body[..], COMPRESSION_LEVEL)?,
+        };
+
+        let header = self
+            .format
+            .make_header(id, kind, size, content.len() as u64);
+        let combined: Vec<_> = header.into_iter().chain(content).collect();
+        self.write(&combined).await?;
+        Ok(size)
+    }
+
+    async fn copy_from<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> io::Result<()> {
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            match reader.read(&mut buffer).await? {
+                0 => return Ok(()),
+                n => self.write(&buffer[..n]).await?,
+            }
+        }
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.hasher.update(bytes);
+        self.offset += bytes.len() as u64;
+        self.inner.write_all(bytes).await
     }
 
     /// Writes the pack footer and unwraps this `PackWriter<W>`, returning the underlying buffer.
     ///
     /// Returns `Err` if the footer could not be written or the buffer could not be flushed.
     pub async fn finish(self) -> anyhow::Result<W> {
-        let mut inner = self.inner;
-        inner.write_all(&[0u8; HEADER_LEN]).await?;
-        inner.flush().await?;
-        Ok(inner)
+        self.finish_with_index().await.map(|(inner, _)| inner)
+    }
+
+    /// Writes the pack footer and unwraps this `PackWriter<W>`, returning the underlying buffer
+    /// along with an index of every object appended to the pack.
+    ///
+    /// The index is intended to be stored next to the pack file on disk, so individual objects can
+    /// be read back later with an [`IndexedPackReader`].
+    ///
+    /// Returns `Err` if the footer could not be written or the buffer could not be flushed.
+    pub async fn finish_with_index(mut self) -> anyhow::Result<(W, PackIndex)> {
+        let footer = vec![0u8; self.format.header_len()];
+        self.write(&footer).await?;
+
+        if self.format == Format::V2 {
+            let checksum = self.hasher.finalize();
+            self.inner.write_all(checksum.as_bytes()).await?;
+        }
+
+        self.inner.flush().await?;
+        Ok((self.inner, PackIndex::new(self.entries)))
     }
 }
 
-fn make_header(id: ObjectId, kind: EntryKind, size: u64) -> [u8; HEADER_LEN] {
-    let mut buf = [0u8; HEADER_LEN];
-    buf[..ObjectId::LENGTH].copy_from_slice(id.as_bytes());
-    buf[ObjectId::LENGTH] = kind as u8;
-    buf[ObjectId::LENGTH + 1..].copy_from_slice(&size.to_be_bytes());
-    buf
+/// Synchronously writes the magic value and pack format version to `writer`.
+///
+/// Packs written synchronously always use the uncompressed version 1 format, which is cheaper to
+/// read individual objects from.
+///
+/// Returns the number of bytes written.
+pub(crate) fn write_magic<W: Write>(writer: &mut W) -> io::Result<u64> {
+    writer.write_all(&Format::V1.magic())?;
+    Ok(PACK_MAGIC_LEN as u64)
+}
+
+/// Synchronously writes the pack footer to `writer`.
+pub(crate) fn write_footer<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(&vec![0u8; Format::V1.header_len()])
+}
+
+/// Synchronously appends `o` to `writer` as a single pack entry, like [`PackWriter::append()`].
+///
+/// `offset` is the position of `writer` within the pack file. It is recorded in the returned index
+/// entry and then advanced past the end of the entry.
+///
+/// Returns `Err` if a serialization or I/O error occurred.
+pub(crate) fn write_entry<W: Write>(
+    writer: &mut W,
+    offset: &mut u64,
+    o: Object,
+) -> anyhow::Result<IndexEntry> {
+    let id = o.object_id();
+    let kind = EntryKind::of(&o);
+    let size = match o {
+        Object::Blob(blob) => {
+            let size = blob.size();
+            writer.write_all(&Format::V1.make_header(id, kind, size, size))?;
+            util::copy_wide(&mut blob.into_content()?, writer)?
+        }
+        Object::ChunkedBlob(c) => write_meta_entry(writer, id, kind, &c)?,
+        Object::Tree(tree) => write_meta_entry(writer, id, kind, &tree)?,
+        Object::Package(pkg) => write_meta_entry(writer, id, kind, &pkg)?,
+        Object::Spec(spec) => write_meta_entry(writer, id, kind, &spec)?,
+    };
+
+    let entry = IndexEntry::new(id, kind, *offset, size);
+    *offset += (Format::V1.header_len() as u64) + size;
+    Ok(entry)
+}
+
+fn write_meta_entry<W, O>(
+    writer: &mut W,
+    id: ObjectId,
+    kind: EntryKind,
+    obj: &O,
+) -> anyhow::Result<u64>
+where
+    W: Write,
+    O: Serialize,
+{
+    let body = serde_json::to_vec(obj)?;
+    let size = body.len() as u64;
+    writer.write_all(&Format::V1.make_header(id, kind, size, size))?;
+    writer.write_all(&body)?;
+    Ok(size)
+}
+
+/// Synchronously reads the pack entry starting at the current position of `reader`.
+///
+/// Returns `Ok(None)` if the pack footer was found instead of an entry.
+///
+/// Returns `Err` if the entry failed to parse, the cryptographic hash of the object did not
+/// match, or an I/O error occurred.
+fn read_entry<R: Read>(reader: &mut R, format: Format) -> anyhow::Result<Option<Object>> {
+    let mut header = [0u8; MAX_HEADER_LEN];
+    let header = &mut header[..format.header_len()];
+    reader.read_exact(header)?;
+
+    let Header {
+        id,
+        kind,
+        size,
+        wire_size,
+    } = match format.parse_header(header)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    if kind.is_delta() {
+        return Err(anyhow!(
+            "cannot read delta entry for {} without its base object",
+            id
+        ));
+    }
+
+    let mut content = reader.take(wire_size);
+    let object = match kind {
+        EntryKind::Blob | EntryKind::Exec => {
+            // Fail as soon as the decoded content grows past the expected size.
+            let writer = Blob::whole_from_writer(kind == EntryKind::Exec);
+            let mut writer = LimitedWriter::new(writer, size);
+            match format {
+                Format::V1 => util::copy_wide(&mut content, &mut writer).map(|_| ())?,
+                Format::V2 => zstd::stream::copy_decode(&mut content, &mut writer)?,
+            }
+
+            let blob = writer.into_inner().finish().0;
+            check_size(blob.size(), size)?;
+            Object::Blob(blob)
+        }
+        _ => {
+            check_meta_size(id, wire_size, size)?;
+            let mut buffer = vec![0u8; wire_size as usize];
+            content.read_exact(&mut buffer)?;
+            let buffer = decompress(format, buffer, size)?;
+            parse_meta_object(kind, &buffer)?
+        }
+    };
+
+    verify_object(object, id).map(Some)
+}
+
+/// Synchronously reads the object described by `entry` from the seekable pack file `reader`.
+///
+/// Returns `Err` if the entry failed to parse, the object ID does not match the index, the
+/// cryptographic hash of the object did not match, or an I/O error occurred.
+pub(crate) fn read_entry_at<R: Read + Seek>(
+    reader: &mut R,
+    entry: &IndexEntry,
+) -> anyhow::Result<Object> {
+    let format = read_format(reader)?;
+    read_indexed_entry(reader, format, entry)
+}
+
+/// Synchronously scans an unfinished pack file written with [`write_entry()`], such as one left
+/// behind by an interrupted write, stopping at the footer or the first incomplete entry.
+///
+/// Returns the index entries of every complete object along with the offset where the first
+/// incomplete entry begins, which is where writing should resume.
+///
+/// Returns `Err` if `reader` is not a pack file or an I/O error occurred.
+pub(crate) fn recover_entries<R: Read + Seek>(
+    reader: &mut R,
+) -> anyhow::Result<(Vec<IndexEntry>, u64)> {
+    let format = read_format(reader)?;
+    let mut reader = io::BufReader::new(reader);
+    let mut entries = Vec::new();
+    let mut offset = PACK_MAGIC_LEN as u64;
+
+    while let Ok(Some(object)) = read_entry(&mut reader, format) {
+        let end = reader.stream_position()?;
+        let size = end - offset - format.header_len() as u64;
+        entries.push(IndexEntry::new(
+            object.object_id(),
+            EntryKind::of(&object),
+            offset,
+            size,
+        ));
+        offset = end;
+    }
+
+    Ok((entries, offset))
+}
+
+fn read_format<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Format> {
+    let mut magic = [0u8; PACK_MAGIC_LEN];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut magic)?;
+    Format::from_magic(&magic)
+}
+
+fn read_indexed_entry<R: Read + Seek>(
+    reader: &mut R,
+    format: Format,
+    entry: &IndexEntry,
+) -> anyhow::Result<Object> {
+    reader.seek(SeekFrom::Start(entry.offset()))?;
+
+    match read_entry(&mut io::BufReader::new(reader), format)? {
+        Some(object) if object.object_id() == entry.id() => Ok(object),
+        _ => Err(anyhow!(
+            "pack file is corrupt, expected object {} at offset {}",
+            entry.id(),
+            entry.offset()
+        )),
+    }
+}
+
+/// Returns `Err` if a tree, package, spec or chunked blob entry is too large to be buffered in
+/// memory, before anything is allocated for it.
+fn check_meta_size(id: ObjectId, wire_size: u64, size: u64) -> anyhow::Result<()> {
+    if wire_size > MAX_META_SIZE || size > MAX_META_SIZE {
+        Err(anyhow!(
+            "entry for {} is too large: {} bytes, or {} bytes on the wire",
+            id,
+            size,
+            wire_size
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn decompress(format: Format, content: Vec<u8>, size: u64) -> anyhow::Result<Vec<u8>> {
+    let content = match format {
+        Format::V1 => content,
+        Format::V2 => {
+            // Stop decoding one byte past the expected size, so the mismatch is still caught.
+            let mut decoded = Vec::new();
+            zstd::stream::read::Decoder::new(&content[..])?
+                .take(size + 1)
+                .read_to_end(&mut decoded)?;
+            decoded
+        }
+    };
+
+    check_size(content.len() as u64, size)?;
+    Ok(content)
+}
+
+fn check_size(actual: u64, expected: u64) -> anyhow::Result<()> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "size mismatch: pack file lists {} bytes, but entry contained {}",
+            expected,
+            actual
+        ))
+    }
+}
+
+fn parse_meta_object(kind: EntryKind, buffer: &[u8]) -> anyhow::Result<Object> {
+    match kind {
+        EntryKind::ChunkedBlob => Ok(Object::ChunkedBlob(serde_json::from_slice(buffer)?)),
+        EntryKind::Tree => Ok(Object::Tree(serde_json::from_slice(buffer)?)),
+        EntryKind::Package => Ok(Object::Package(serde_json::from_slice(buffer)?)),
+        EntryKind::Spec => Ok(Object::Spec(serde_json::from_slice(buffer)?)),
+        _ => unreachable!("blobs are not meta objects"),
+    }
+}
+
+fn verify_object(object: Object, object_id: ObjectId) -> anyhow::Result<Object> {
+    if object.object_id() == object_id {
+        Ok(object)
+    } else {
+        Err(anyhow!(
+            "hash mismatch: {:?} hashed to {}, but pack file lists {}",
+            object.kind(),
+            object.object_id(),
+            object_id
+        ))
+    }
+}
+
+fn verify_checksum(hasher: &blake3::Hasher, trailer: &[u8]) -> anyhow::Result<()> {
+    let trailer: [u8; TRAILER_LEN] = trailer.try_into()?;
+    if hasher.finalize() == blake3::Hash::from(trailer) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "pack checksum mismatch, stream is truncated or corrupt"
+        ))
+    }
+}
+
+/// Compresses `target` with the content of `base` loaded as a zstd dictionary.
+///
+/// Returns a temporary file holding the compressed delta, rewound to the start, along with its
+/// size in bytes.
+fn encode_delta<R: Read>(mut target: R, base: Blob) -> io::Result<(std::fs::File, u64)> {
+    let mut dictionary = Vec::with_capacity(base.size() as usize);
+    base.into_content()?.read_to_end(&mut dictionary)?;
+
+    let mut encoder = zstd::stream::raw::Encoder::with_dictionary(COMPRESSION_LEVEL, &dictionary)?;
+    encoder.set_parameter(CParameter::WindowLog(DELTA_WINDOW_LOG))?;
+    encoder.set_parameter(CParameter::EnableLongDistanceMatching(true))?;
+
+    let temp = tempfile::tempfile_in("/var/tmp")?;
+    let mut writer = zstd::stream::zio::Writer::new(temp, encoder);
+    util::copy_wide(&mut target, &mut writer)?;
+    writer.finish()?;
+
+    let (mut temp, _) = writer.into_inner();
+    let wire_size = temp.stream_position()?;
+    temp.seek(SeekFrom::Start(0))?;
+    Ok((temp, wire_size))
+}
+
+/// Reconstructs a blob from a delta produced by [`encode_delta()`] and its `base`.
+///
+/// Decoding fails as soon as the reconstructed blob grows past `size` bytes.
+fn decode_delta(base: Blob, delta: &[u8], is_executable: bool, size: u64) -> io::Result<Blob> {
+    let mut dictionary = Vec::with_capacity(base.size() as usize);
+    base.into_content()?.read_to_end(&mut dictionary)?;
+
+    let mut decoder = zstd::stream::raw::Decoder::with_dictionary(&dictionary)?;
+    decoder.set_parameter(DParameter::WindowLogMax(DELTA_WINDOW_LOG))?;
+
+    let writer = LimitedWriter::new(Blob::whole_from_writer(is_executable), size);
+    let mut writer = zstd::stream::zio::Writer::new(writer, decoder);
+    writer.write_all(delta)?;
+    writer.finish()?;
+
+    let (writer, _) = writer.into_inner();
+    let blob = writer.into_inner().finish().0;
+    Ok(blob)
+}
+
+/// Copies a single pack file from `reader` to `writer` without decoding its entries.
+///
+/// Only the entry headers are parsed, so that copying stops right after the footer (and the
+/// checksum trailer, for version 2 packs) instead of waiting for `reader` to reach EOF. Neither the
+/// object hashes nor the checksum are verified; that is left to whoever reads the copy.
+///
+/// Returns the number of bytes copied, or `Err` if the stream is not a pack file, it ended before
+/// the footer, or an I/O error occurred.
+pub(crate) async fn copy_pack<R, W>(reader: &mut R, writer: &mut W) -> anyhow::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut magic = [0u8; PACK_MAGIC_LEN];
+    reader.read_exact(&mut magic).await?;
+    let format = Format::from_magic(&magic)?;
+    writer.write_all(&magic).await?;
+    let mut copied = magic.len() as u64;
+
+    let mut header = [0u8; MAX_HEADER_LEN];
+    let header = &mut header[..format.header_len()];
+    loop {
+        reader.read_exact(header).await?;
+        writer.write_all(header).await?;
+        copied += header.len() as u64;
+
+        let wire_size = match format.parse_header(header)? {
+            Some(Header { wire_size, .. }) => wire_size,
+            None => break,
+        };
+
+        let n = tokio::io::copy(&mut (&mut *reader).take(wire_size), writer).await?;
+        if n != wire_size {
+            return Err(anyhow!("pack stream ended in the middle of an entry"));
+        }
+        copied += n;
+    }
+
+    if format == Format::V2 {
+        let mut trailer = [0u8; TRAILER_LEN];
+        reader.read_exact(&mut trailer).await?;
+        writer.write_all(&trailer).await?;
+        copied += trailer.len() as u64;
+    }
+
+    writer.flush().await?;
+    Ok(copied)
 }
 
 /// Deserializes a binary pack file into a stream of `Object`s.
 ///
 /// The stream may yield `Err` if the stream is not a pack file, an object entry failed to parse,
-/// the cryptographic hash for an object did not match, or an I/O error occurred.
+/// the cryptographic hash for an object did not match, the checksum of the whole stream did not
+/// match, the pack is thin, or an I/O error occurred. The stream ends after the first `Err`.
+///
+/// To read thin packs containing delta entries, use a [`ThinPackReader`] instead.
 pub fn pack_reader<'a, R>(reader: R) -> impl Stream<Item = anyhow::Result<Object>> + 'a
 where
     R: AsyncRead + Unpin + 'a,
@@ -192,101 +860,279 @@ where
     // traits one day, and `AsyncRead` is defined in terms of `async fn`, we could restore the old
     // implementation from the Git history, and sprinkle some `async`/`.await` keywords on it.
 
-    async fn next_entry<R>(reader: &mut R, is_start: bool) -> anyhow::Result<Option<Object>>
+    // The stream ends after the first error, since the reader is left at an unknown position.
+    stream::unfold(Some(ThinPackReader::new(reader)), |state| async move {
+        let mut reader = state?;
+        match reader.next_entry(None).await {
+            Ok(Some(object)) => Some((Ok(object), Some(reader))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
+
+/// Deserializes a binary pack file which may contain delta entries into `Object`s.
+///
+/// Unlike [`pack_reader()`], this is not a `Stream`, because delta entries must borrow a store
+/// holding their base objects while they are being reconstructed. Borrowing the store for each
+/// call to [`ThinPackReader::next_object()`], rather than for the whole lifetime of the reader,
+/// allows the caller to insert each object into that same store in between calls.
+pub struct ThinPackReader<R> {
+    reader: HashingReader<R>,
+    format: Option<Format>,
+}
+
+impl<R: AsyncRead + Unpin> ThinPackReader<R> {
+    /// Creates a new `ThinPackReader<R>` wrapping the given pack file stream.
+    pub fn new(reader: R) -> Self {
+        ThinPackReader {
+            reader: HashingReader {
+                inner: reader,
+                hasher: blake3::Hasher::new(),
+            },
+            format: None,
+        }
+    }
+
+    /// Reads the next object from the pack, or returns `Ok(None)` once the footer is reached.
+    ///
+    /// Delta entries are reconstructed against their base blob in `bases`, and the cryptographic
+    /// hash of the reconstructed blob is verified just like any other object.
+    ///
+    /// Returns `Err` if the stream is not a pack file, an object entry failed to parse, the base
+    /// of a delta entry does not exist in `bases`, the cryptographic hash for an object did not
+    /// match, the checksum of the whole stream did not match, or an I/O error occurred. The reader
+    /// is left at an unknown position afterwards, so it should not be used again.
+    pub async fn next_object<O>(&mut self, bases: &O) -> anyhow::Result<Option<Object>>
     where
-        R: AsyncRead + Unpin,
+        O: Objects + ?Sized,
     {
-        if is_start {
+        self.next_entry(Some(&|id| bases.get_blob(id))).await
+    }
+
+    async fn next_entry(
+        &mut self,
+        bases: Option<&dyn Fn(ObjectId) -> anyhow::Result<Blob>>,
+    ) -> anyhow::Result<Option<Object>> {
+        let reader = &mut self.reader;
+        if self.format.is_none() {
             let mut magic = [0u8; PACK_MAGIC_LEN];
             reader.read_exact(&mut magic).await?;
-
-            match &magic[..] {
-                [m @ .., FORMAT_VERSION] if m == MAGIC_VALUE => {}
-                _ => return Err(anyhow!("magic value not found, not a store pack file")),
-            }
+            self.format = Some(Format::from_magic(&magic)?);
         }
 
-        let mut header = [0u8; HEADER_LEN];
-        reader.read_exact(&mut header).await?;
-
-        if header.iter().all(|&b| b == 0) {
-            return Ok(None);
-        }
+        let format = self.format.expect("format was read above");
+        let mut header = [0u8; MAX_HEADER_LEN];
+        let header = &mut header[..format.header_len()];
+        reader.read_exact(header).await?;
+
+        let Header {
+            id,
+            kind,
+            size,
+            wire_size,
+        } = match format.parse_header(header)? {
+            Some(header) => header,
+            None if format == Format::V2 => {
+                // The trailer is not part of the checksum, so read it from the inner reader.
+                let mut trailer = [0u8; TRAILER_LEN];
+                reader.inner.read_exact(&mut trailer).await?;
+                verify_checksum(&reader.hasher, &trailer)?;
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
 
-        let (object_id, kind, size) = parse_header(header)?;
         let object = match kind {
             EntryKind::Blob | EntryKind::Exec => {
                 let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+                let is_executable = kind == EntryKind::Exec;
 
                 let handle = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
-                    let mut writer = Blob::from_writer(kind == EntryKind::Exec);
-
-                    while let Some(result) = rx.blocking_recv() {
-                        let bytes: Bytes = result?;
-                        writer.write_all(&bytes)?;
+                    // Fail as soon as the decoded content grows past the expected size.
+                    let writer = Blob::whole_from_writer(is_executable);
+                    let mut writer = LimitedWriter::new(writer, size);
+
+                    match format {
+                        Format::V1 => {
+                            while let Some(result) = rx.blocking_recv() {
+                                let bytes: Bytes = result?;
+                                writer.write_all(&bytes)?;
+                            }
+                        }
+                        Format::V2 => {
+                            let mut decoder = zstd::stream::write::Decoder::new(&mut writer)?;
+                            while let Some(result) = rx.blocking_recv() {
+                                let bytes: Bytes = result?;
+                                decoder.write_all(&bytes)?;
+                            }
+                            decoder.flush()?;
+                        }
                     }
 
-                    let (blob, _) = writer.finish();
+                    let blob = writer.into_inner().finish().0;
                     Ok(blob)
                 });
 
-                ReaderStream::new(reader.take(size))
-                    .fold(tx, |tx, r| async { tx.send(r).await.map(|_| tx).unwrap() })
-                    .await;
+                // If decoding fails, the receiver is dropped and sending stops. The decode error
+                // is then returned from the task below.
+                let mut chunks = ReaderStream::new(reader.take(wire_size));
+                while let Some(chunk) = chunks.next().await {
+                    if tx.send(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                drop(tx);
 
-                let blob = handle.await.unwrap()?;
+                let blob = handle.await??;
+                check_size(blob.size(), size)?;
                 Object::Blob(blob)
             }
-            EntryKind::Tree => {
-                let mut buffer = vec![0u8; size as usize].into_boxed_slice();
-                reader.read_exact(&mut buffer).await?;
-                let tree = serde_json::from_slice(&buffer)?;
-                Object::Tree(tree)
-            }
-            EntryKind::Package => {
-                let mut buffer = vec![0u8; size as usize].into_boxed_slice();
-                reader.read_exact(&mut buffer).await?;
-                let pkg = serde_json::from_slice(&buffer)?;
-                Object::Package(pkg)
+            EntryKind::BlobDelta | EntryKind::ExecDelta => {
+                let delta_len = wire_size
+                    .checked_sub(ObjectId::LENGTH as u64)
+                    .ok_or_else(|| anyhow!("delta entry for {} is too short", id))?;
+                if delta_len > MAX_DELTA_SIZE {
+                    return Err(anyhow!(
+                        "delta entry for {} is too large: {} bytes",
+                        id,
+                        delta_len
+                    ));
+                }
+
+                let mut base_id = [0u8; ObjectId::LENGTH];
+                reader.read_exact(&mut base_id).await?;
+                let base_id = ObjectId::from_bytes(base_id);
+                let mut delta = vec![0u8; delta_len as usize];
+                reader.read_exact(&mut delta).await?;
+
+                let base = match bases {
+                    Some(bases) => bases(base_id)?,
+                    None => {
+                        return Err(anyhow!(
+                            "received delta entry for {} against {}, but this is not a thin pack reader",
+                            id,
+                            base_id
+                        ))
+                    }
+                };
+
+                let is_executable = kind == EntryKind::ExecDelta;
+                let blob = tokio::task::spawn_blocking(move || {
+                    decode_delta(base, &delta, is_executable, size)
+                })
+                .await??;
+
+                check_size(blob.size(), size)?;
+                Object::Blob(blob)
             }
-            EntryKind::Spec => {
-                let mut buffer = vec![0u8; size as usize].into_boxed_slice();
+            _ => {
+                check_meta_size(id, wire_size, size)?;
+                let mut buffer = vec![0u8; wire_size as usize];
                 reader.read_exact(&mut buffer).await?;
-                let spec = serde_json::from_slice(&buffer)?;
-                Object::Spec(spec)
+                let buffer = decompress(format, buffer, size)?;
+                parse_meta_object(kind, &buffer)?
             }
         };
 
-        if object.object_id() == object_id {
-            Ok(Some(object))
-        } else {
-            Err(anyhow!(
-                "hash mismatch: {:?} hashed to {}, but pack file lists {}",
-                object.kind(),
-                object.object_id(),
-                object_id
-            ))
+        verify_object(object, id).map(Some)
+    }
+}
+
+impl<R: Debug> Debug for ThinPackReader<R> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct(stringify!(ThinPackReader))
+            .field("inner", &self.reader.inner)
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+/// Feeds every byte read from the inner reader into a BLAKE3 hasher.
+struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let self_ = &mut *self;
+        let old_len = buf.filled().len();
+        ready!(Pin::new(&mut self_.inner).poll_read(cx, buf))?;
+        self_.hasher.update(&buf.filled()[old_len..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Reads individual `Object`s out of a seekable pack file, using its [`PackIndex`].
+///
+/// Unlike [`pack_reader()`], this does not need to read the pack file from start to finish, making
+/// it suitable for pulling a handful of objects out of a large pack file cached on disk. The hash
+/// of each object is still verified as it is read, but the checksum trailer of version 2 packs is
+/// not.
+#[derive(Debug)]
+pub struct IndexedPackReader<R> {
+    inner: R,
+    format: Format,
+    index: PackIndex,
+}
+
+impl<R: Read + Seek> IndexedPackReader<R> {
+    /// Creates a new `IndexedPackReader<R>` from a pack file and its index.
+    ///
+    /// Returns `Err` if `inner` is not a store pack file or an I/O error occurred.
+    pub fn new(mut inner: R, index: PackIndex) -> anyhow::Result<Self> {
+        let format = read_format(&mut inner)?;
+        Ok(IndexedPackReader {
+            inner,
+            format,
+            index,
+        })
+    }
+
+    /// Returns the index of the pack file.
+    #[inline]
+    pub fn index(&self) -> &PackIndex {
+        &self.index
+    }
+
+    /// Reads the object `id` from the pack file, or returns `Ok(None)` if the pack does not contain
+    /// it.
+    ///
+    /// Returns `Err` if the entry failed to parse, the cryptographic hash for the object did not
+    /// match, or an I/O error occurred.
+    pub fn read_object(&mut self, id: &ObjectId) -> anyhow::Result<Option<Object>> {
+        match self.index.find(id) {
+            Some(entry) => read_indexed_entry(&mut self.inner, self.format, entry).map(Some),
+            None => Ok(None),
         }
     }
 
-    stream::unfold((reader, true), |(mut reader, is_start)| async move {
-        next_entry(&mut reader, is_start)
-            .await
-            .transpose()
-            .map(|res| (res, (reader, false)))
-    })
+    /// Unwraps this `IndexedPackReader<R>`, returning the underlying reader.
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
 }
 
 /// Wraps a pack file stream and emits progress notifications from a channel.
 ///
 /// This struct will immediately return an I/O error in the first call to
-/// [`AsyncRead::poll_read()`] if the underlying reader does not yield a valid pack file.
+/// [`AsyncRead::poll_read()`] if the underlying reader does not yield a valid pack file. For
+/// version 2 packs, it also returns an I/O error if the checksum trailer does not match.
 ///
 /// To create a new pack file, see the documentation for [`PackWriter`].
 pub struct PackStream<R> {
     inner: R,
     progress: Option<UnboundedSender<Progress>>,
     state: StreamState,
+    format: Format,
+    hasher: blake3::Hasher,
+    trailer_start: Option<u64>,
     received_bytes: u64,
     num_objects: u64,
 }
@@ -302,6 +1148,9 @@ impl<R: AsyncRead + Unpin> PackStream<R> {
             inner,
             progress: Some(tx),
             state: StreamState::Start { magic: Vec::new() },
+            format: Format::V1,
+            hasher: blake3::Hasher::new(),
+            trailer_start: None,
             received_bytes: 0,
             num_objects: 0,
         };
@@ -317,16 +1166,31 @@ impl<R: AsyncRead + Unpin> AsyncRead for PackStream<R> {
         buf: &mut ReadBuf,
     ) -> Poll<io::Result<()>> {
         if self.state == StreamState::Finished {
-            let msg = format!("unexpected trailing data in pack stream: {:?}", buf);
+            let old_len = buf.filled().len();
+            ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+            let len = buf.filled().len() - old_len;
+            if len == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            buf.set_filled(old_len);
+            let msg = format!("unexpected trailing data in pack stream: {} bytes", len);
             return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, msg)));
         }
 
         let self_ = &mut *self;
         let old_len = buf.filled().len();
+        let has_capacity = buf.remaining() > 0;
         ready!(Pin::new(&mut self_.inner).poll_read(cx, buf))?;
         let len = buf.filled().len() - old_len;
 
+        if len == 0 && has_capacity {
+            let msg = "pack stream ended before the footer";
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg)));
+        }
+
         let filled = buf.filled();
+        let start = self_.received_bytes;
         self_.received_bytes += len as u64;
         let prog = self_.progress.as_mut().unwrap();
 
@@ -334,6 +1198,7 @@ impl<R: AsyncRead + Unpin> AsyncRead for PackStream<R> {
             StreamState::Start { magic } => magic.extend_from_slice(&filled[old_len..]),
             StreamState::Header { header } => header.extend_from_slice(&filled[old_len..]),
             StreamState::Counting { current, .. } => *current += len as u64,
+            StreamState::Trailer { trailer } => trailer.extend_from_slice(&filled[old_len..]),
             StreamState::Finished => unreachable!(),
         }
 
@@ -341,47 +1206,56 @@ impl<R: AsyncRead + Unpin> AsyncRead for PackStream<R> {
             match &mut self_.state {
                 StreamState::Start { magic } if magic.len() < PACK_MAGIC_LEN => break,
                 StreamState::Start { magic } => {
-                    match &magic[..PACK_MAGIC_LEN] {
-                        [m @ .., FORMAT_VERSION] if m == MAGIC_VALUE => {}
-                        _ => {
-                            let msg = "magic value not found, not a store pack file";
-                            let error = io::Error::new(io::ErrorKind::InvalidData, msg);
-                            return Poll::Ready(Err(error));
-                        }
-                    }
-
+                    self_.format = Format::from_magic(&magic[..PACK_MAGIC_LEN])
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
                     self_.state = StreamState::Header {
                         header: magic.split_off(PACK_MAGIC_LEN),
                     };
                 }
-                StreamState::Header { header } if header.len() < HEADER_LEN => break,
-                StreamState::Header { header } if header[..HEADER_LEN].iter().all(|&b| b == 0) => {
-                    prog.unbounded_send(Progress::Finished {
-                        received_bytes: self_.received_bytes,
-                        num_objects: self_.num_objects,
-                    })
-                    .ok();
-
-                    self_.state = StreamState::Finished;
-                }
+                StreamState::Header { header } if header.len() < self_.format.header_len() => break,
                 StreamState::Header { header } => {
-                    let buf = header[..HEADER_LEN].try_into().expect("length matches");
-                    let (id, kind, size) = parse_header(buf)
+                    let header_len = self_.format.header_len();
+                    let parsed = self_
+                        .format
+                        .parse_header(&header[..header_len])
                         .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
-                    self_.num_objects += 1;
-
-                    let kind = kind.into();
-                    prog.unbounded_send(Progress::Begin { id, kind, size }).ok();
-
-                    let excess = (header.len() - HEADER_LEN) as u64;
-                    self_.state = StreamState::Counting {
-                        current: excess,
-                        total: size,
-                    };
-
-                    if excess == 0 {
-                        break;
+                    match parsed {
+                        None if self_.format == Format::V2 => {
+                            let trailer = header.split_off(header_len);
+                            self_.trailer_start = Some(self_.received_bytes - trailer.len() as u64);
+                            self_.state = StreamState::Trailer { trailer };
+                        }
+                        None => {
+                            prog.unbounded_send(Progress::Finished {
+                                received_bytes: self_.received_bytes,
+                                num_objects: self_.num_objects,
+                            })
+                            .ok();
+
+                            self_.state = StreamState::Finished;
+                        }
+                        Some(Header {
+                            id,
+                            kind,
+                            size,
+                            wire_size,
+                        }) => {
+                            self_.num_objects += 1;
+
+                            let kind = kind.into();
+                            prog.unbounded_send(Progress::Begin { id, kind, size }).ok();
+
+                            let excess = (header.len() - header_len) as u64;
+                            self_.state = StreamState::Counting {
+                                current: excess,
+                                total: wire_size,
+                            };
+
+                            if excess == 0 {
+                                break;
+                            }
+                        }
                     }
                 }
                 StreamState::Counting { current, total } if *current < *total => {
@@ -398,6 +1272,7 @@ impl<R: AsyncRead + Unpin> AsyncRead for PackStream<R> {
                     let excess = filled[index as usize..].to_vec();
                     self_.state = StreamState::Header { header: excess };
                 }
+                StreamState::Trailer { .. } => break,
                 StreamState::Finished => {
                     self_.progress = None;
                     break;
@@ -405,6 +1280,36 @@ impl<R: AsyncRead + Unpin> AsyncRead for PackStream<R> {
             }
         }
 
+        // Everything up to the start of the trailer is covered by the checksum.
+        let hashed_len = match self_.trailer_start {
+            Some(trailer_start) => trailer_start.saturating_sub(start).min(len as u64) as usize,
+            None => len,
+        };
+        self_.hasher.update(&filled[old_len..][..hashed_len]);
+
+        if let StreamState::Trailer { trailer } = &self_.state {
+            if trailer.len() >= TRAILER_LEN {
+                let error = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+                if trailer.len() > TRAILER_LEN {
+                    let excess = trailer.len() - TRAILER_LEN;
+                    let msg = format!("unexpected trailing data in pack stream: {} bytes", excess);
+                    return Poll::Ready(Err(error(anyhow!(msg))));
+                }
+
+                verify_checksum(&self_.hasher, trailer).map_err(error)?;
+
+                if let Some(prog) = self_.progress.take() {
+                    prog.unbounded_send(Progress::Finished {
+                        received_bytes: self_.received_bytes,
+                        num_objects: self_.num_objects,
+                    })
+                    .ok();
+                }
+
+                self_.state = StreamState::Finished;
+            }
+        }
+
         Poll::Ready(Ok(()))
     }
 }
@@ -413,6 +1318,7 @@ impl<R: Debug> Debug for PackStream<R> {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct(stringify!(PackStream))
             .field("inner", &self.inner)
+            .field("format", &self.format)
             .field("received_bytes", &self.received_bytes)
             .field("num_objects", &self.num_objects)
             .finish()
@@ -424,14 +1330,26 @@ enum StreamState {
     Start { magic: Vec<u8> },
     Header { header: Vec<u8> },
     Counting { current: u64, total: u64 },
+    Trailer { trailer: Vec<u8> },
     Finished,
 }
 
 /// A discrete unit of progress reported while streaming a pack file.
 ///
-/// This enum is created by [`PackStream::new()`]. See its documentation for more.
+/// The `Begin`, `Read` and `Finished` events are created by [`PackStream::new()`] as the pack is
+/// received. The remaining events are reported by [`copy_closure()`](crate::copy_closure), by the
+/// sender and by the destination.
 #[derive(Clone, Debug)]
 pub enum Progress {
+    /// A copy has begun, and the given totals are expected to be transferred.
+    Started {
+        /// Number of objects which will be sent.
+        num_objects: u64,
+        /// Number of packages among the objects which will be sent.
+        num_packages: u64,
+        /// Estimated unpacked size of the objects which will be sent, in bytes.
+        unpacked_size: u64,
+    },
     /// A new packfile entry has begun.
     Begin {
         /// The declared cryptographic hash of the contained object.
@@ -446,6 +1364,11 @@ pub enum Progress {
         /// Number of bytes read.
         bytes: u64,
     },
+    /// Several bytes of packfile were written by the sender.
+    Written {
+        /// Number of bytes written.
+        bytes: u64,
+    },
     /// The packfile footer was found and the I/O stream has ended.
     Finished {
         /// Total size of the packfile that was received.
@@ -453,24 +1376,29 @@ pub enum Progress {
         /// Number of objects received.
         num_objects: u64,
     },
+    /// A package moved on to the next phase at the destination.
+    Package {
+        /// Object ID of the package.
+        id: ObjectId,
+        /// Phase the package has reached.
+        phase: PackagePhase,
+    },
 }
 
-fn parse_header(header: [u8; HEADER_LEN]) -> anyhow::Result<(ObjectId, EntryKind, u64)> {
-    let object_id = header[..ObjectId::LENGTH]
-        .try_into()
-        .map(ObjectId::from_bytes)?;
-    let kind = EntryKind::try_from(header[ObjectId::LENGTH])?;
-    let size = header[ObjectId::LENGTH + 1..]
-        .try_into()
-        .map(u64::from_be_bytes)?;
-
-    Ok((object_id, kind, size))
+/// A phase reached by a package at the destination, reported by [`Progress::Package`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PackagePhase {
+    /// The package object was received and verified, but is not visible in the store yet.
+    Received,
+    /// The whole pack was received, and the package is being instantiated.
+    Instantiating,
+    /// The package was instantiated and inserted into the store.
+    Installed,
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::BTreeMap;
-    use std::io::SeekFrom;
 
     use futures::pin_mut;
     use tokio::io::AsyncSeekExt;
@@ -517,10 +1445,9 @@ mod tests {
         ]
     }
 
-    #[tokio::test]
-    async fn round_trip() {
+    async fn write_example_pack(version: u8) -> (std::io::Cursor<Vec<u8>>, PackIndex) {
         let empty_buffer = std::io::Cursor::new(Vec::new());
-        let mut writer = PackWriter::new(empty_buffer)
+        let mut writer = PackWriter::with_version(empty_buffer, version)
             .await
             .expect("failed to init writer");
 
@@ -531,9 +1458,23 @@ mod tests {
                 .expect("failed to serialize object");
         }
 
-        let mut full_buffer = writer.finish().await.expect("failed to flush");
-        full_buffer.seek(SeekFrom::Start(0)).await.unwrap();
-        let (reader, _) = PackStream::new(full_buffer);
+        let (mut full_buffer, index) = writer.finish_with_index().await.expect("failed to flush");
+        AsyncSeekExt::seek(&mut full_buffer, SeekFrom::Start(0))
+            .await
+            .unwrap();
+        (full_buffer, index)
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        for version in 1..=FORMAT_VERSION {
+            check_round_trip(version).await;
+        }
+    }
+
+    async fn check_round_trip(version: u8) {
+        let (full_buffer, _) = write_example_pack(version).await;
+        let (reader, mut progress) = PackStream::new(full_buffer);
 
         let mut blob_ids = Vec::new();
         let reader = pack_reader(reader).enumerate();
@@ -560,5 +1501,178 @@ mod tests {
                 (i, other) => panic!("received unexpected object ({}): {:?}", i, other),
             }
         }
+
+        let mut finished = false;
+        while let Ok(Some(p)) = progress.try_next() {
+            if let Progress::Finished { num_objects, .. } = p {
+                assert_eq!(num_objects, 4);
+                finished = true;
+            }
+        }
+        assert!(finished, "progress did not finish for version {}", version);
+    }
+
+    #[tokio::test]
+    async fn detects_truncated_stream() {
+        let (full_buffer, index) = write_example_pack(FORMAT_VERSION).await;
+        let ids: Vec<_> = example_objects().iter().map(|o| o.object_id()).collect();
+
+        // Cut the stream off at an entry boundary and forge a footer with a bogus checksum.
+        let cut = index.find(&ids[2]).unwrap().offset() as usize;
+        let mut truncated = full_buffer.into_inner();
+        truncated.truncate(cut);
+        truncated.extend_from_slice(&[0u8; MAX_HEADER_LEN + TRAILER_LEN]);
+
+        let stream = pack_reader(&truncated[..]);
+        pin_mut!(stream);
+        let results: Vec<_> = stream.collect().await;
+        assert_eq!(results.len(), 3);
+        assert!(results[..2].iter().all(|r| r.is_ok()));
+        assert!(results[2].is_err());
+
+        let (mut stream, _) = PackStream::new(&truncated[..]);
+        let mut sink = Vec::new();
+        assert!(stream.read_to_end(&mut sink).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_corrupt_and_trailing_data() {
+        let ids: Vec<_> = example_objects().iter().map(|o| o.object_id()).collect();
+        let (buffer, index) = write_example_pack(FORMAT_VERSION).await;
+        let mut buffer = buffer.into_inner();
+
+        let (mut stream, _) = PackStream::new(&buffer[..]);
+        let mut sink = Vec::new();
+        stream.read_to_end(&mut sink).await.unwrap();
+
+        let mut trailing = buffer.clone();
+        trailing.extend_from_slice(b"junk");
+        let (mut stream, _) = PackStream::new(&trailing[..]);
+        let error = stream.read_to_end(&mut sink).await.unwrap_err();
+        assert!(error.to_string().ends_with("4 bytes"), "{}", error);
+
+        // Garbage in place of the compressed blob fails decoding instead of panicking.
+        let offset = index.find(&ids[0]).unwrap().offset() as usize + Format::V2.header_len();
+        buffer[offset..offset + 4].copy_from_slice(b"junk");
+        let empty = crate::LocalStore::in_memory();
+        let mut reader = ThinPackReader::new(&buffer[..]);
+        assert!(reader.next_object(&empty).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn thin_pack_round_trip() {
+        let base_content = b"the quick brown fox jumps over the lazy dog".repeat(100);
+        let (base, _) = Blob::from_bytes(base_content.clone(), false);
+        let (target, _) = Blob::from_bytes(
+            b"the quick brown cat jumps over the lazy dog".repeat(100),
+            true,
+        );
+        let target_id = target.object_id();
+
+        let mut bases = crate::LocalStore::in_memory();
+        let (stored, _) = Blob::from_bytes(base_content, false);
+        bases.insert_object(Object::Blob(stored)).unwrap();
+
+        let mut writer = PackWriter::new(std::io::Cursor::new(Vec::new()))
+            .await
+            .expect("failed to init writer");
+        writer.append_delta(target, base).await.unwrap();
+        let buffer = writer.finish().await.expect("failed to flush").into_inner();
+        assert!(buffer.len() < 500);
+
+        let mut reader = ThinPackReader::new(&buffer[..]);
+        match reader.next_object(&bases).await.unwrap() {
+            Some(Object::Blob(blob)) => {
+                assert_eq!(blob.object_id(), target_id);
+                assert!(blob.is_executable());
+            }
+            other => panic!("received unexpected object: {:?}", other),
+        }
+        assert!(reader.next_object(&bases).await.unwrap().is_none());
+
+        // Without access to the base, the delta cannot be reconstructed.
+        let empty = crate::LocalStore::in_memory();
+        let mut reader = ThinPackReader::new(&buffer[..]);
+        assert!(reader.next_object(&empty).await.is_err());
+
+        let stream = pack_reader(&buffer[..]);
+        pin_mut!(stream);
+        assert!(stream.next().await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn random_access() {
+        let ids: Vec<_> = example_objects().iter().map(|o| o.object_id()).collect();
+        let (buffer, index) = write_example_pack(FORMAT_VERSION).await;
+        assert_eq!(index.len(), ids.len());
+
+        let mut serialized = Vec::new();
+        index.write_to(&mut serialized).unwrap();
+        let index = PackIndex::read_from(&serialized[..]).unwrap();
+
+        let mut reader = IndexedPackReader::new(buffer, index).unwrap();
+        for id in ids.iter().rev() {
+            let object = reader.read_object(id).unwrap().expect("object not found");
+            assert_eq!(object.object_id(), *id);
+        }
+
+        let (absent, _) = Blob::from_bytes(b"absent".to_vec(), false);
+        assert!(reader.read_object(&absent.object_id()).unwrap().is_none());
+
+        // Flip a bit in the first blob and ensure the hash check catches it.
+        let offset = reader.index().find(&ids[0]).unwrap().offset() as usize;
+        let mut buffer = reader.into_inner();
+        buffer.get_mut()[offset + Format::V2.header_len()] ^= 1;
+        let index = PackIndex::read_from(&serialized[..]).unwrap();
+        let mut reader = IndexedPackReader::new(buffer, index).unwrap();
+        assert!(reader.read_object(&ids[0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_entries() {
+        let ids: Vec<_> = example_objects().iter().map(|o| o.object_id()).collect();
+        let (buffer, index) = write_example_pack(FORMAT_VERSION).await;
+
+        // Claim the tree is 16 EiB large, which must be rejected before anything is allocated.
+        let offset = index.find(&ids[2]).unwrap().offset() as usize + ObjectId::LENGTH + 1;
+        let mut buffer = buffer.into_inner();
+        buffer[offset..offset + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+
+        let mut reader = IndexedPackReader::new(std::io::Cursor::new(buffer), index).unwrap();
+        let error = reader.read_object(&ids[2]).unwrap_err();
+        assert!(format!("{:#}", error).contains("too large"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn stops_decoding_blobs_past_their_size() {
+        // A few kilobytes of zstd which expand to 256 MiB, listed as a 5 byte blob.
+        let (blob, _) = Blob::from_bytes(b"hello".to_vec(), false);
+        let zeros = std::io::repeat(0).take(256 * 1024 * 1024);
+        let bomb = zstd::encode_all(zeros, 1).unwrap();
+        let format = Format::V2;
+        let header = format.make_header(blob.object_id(), EntryKind::Blob, 5, bomb.len() as u64);
+
+        let mut buffer = format.magic().to_vec();
+        buffer.extend_from_slice(&header);
+        buffer.extend_from_slice(&bomb);
+        buffer.extend_from_slice(&[0u8; MAX_HEADER_LEN + TRAILER_LEN]);
+
+        let mut reader = std::io::Cursor::new(&buffer[..]);
+        reader.set_position(PACK_MAGIC_LEN as u64);
+        let error = read_entry(&mut reader, format).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("limit of 5 bytes"),
+            "{:#}",
+            error
+        );
+
+        let empty = crate::LocalStore::in_memory();
+        let mut reader = ThinPackReader::new(&buffer[..]);
+        let error = reader.next_object(&empty).await.unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("limit of 5 bytes"),
+            "{:#}",
+            error
+        );
     }
 }
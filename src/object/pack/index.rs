@@ -0,0 +1,243 @@
+//! Sorted index of the objects contained in a pack file.
+//!
+//! # Specification
+//!
+//! ## Header
+//!
+//! ```text
+//!  Magic value  Version
+//! +-----------+-------+
+//! | store-idx |   1   | (10 bytes)
+//! +-----------+-------+
+//! ```
+//!
+//! ## Fanout table
+//!
+//! ```text
+//! +--------------------+--------------------+-----+----------------------+
+//! | Count 0 (u32 BE)   | Count 1 (u32 BE)   | ... | Count 255 (u32 BE)   | (1024 bytes)
+//! +--------------------+--------------------+-----+----------------------+
+//! ```
+//!
+//! Count `n` is the number of objects whose ID begins with a byte less than or equal to `n`, so the
+//! last count is the total number of entries in the index.
+//!
+//! ## Entries
+//!
+//! ```text
+//!                              Index entry (49 bytes)
+//! +---------------+---------------+-----------------------+---------------------+
+//! | ID (32 bytes) | Kind (1 byte) | Offset (u64 BE bytes) | Size (u64 BE bytes) |  ... (repeat x n)
+//! +---------------+---------------+-----------------------+---------------------+
+//! ```
+//!
+//! Entries are sorted by ID. The offset points to the start of the entry header in the pack file,
+//! and the kind and size are identical to those recorded in the entry header.
+
+use std::convert::{TryFrom, TryInto};
+use std::io::{self, Read, Write};
+
+use anyhow::anyhow;
+
+use super::EntryKind;
+use crate::{ObjectId, ObjectKind};
+
+const MAGIC_VALUE: &[u8] = b"store-idx";
+const FORMAT_VERSION: u8 = 1;
+const FANOUT_LEN: usize = 256;
+const ENTRY_LEN: usize = ObjectId::LENGTH + 17;
+
+/// A single object recorded in a [`PackIndex`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IndexEntry {
+    id: ObjectId,
+    kind: EntryKind,
+    offset: u64,
+    size: u64,
+}
+
+impl IndexEntry {
+    pub(super) fn new(id: ObjectId, kind: EntryKind, offset: u64, size: u64) -> Self {
+        IndexEntry {
+            id,
+            kind,
+            offset,
+            size,
+        }
+    }
+
+    /// Returns the ID of the object.
+    pub fn id(&self) -> ObjectId {
+        self.id
+    }
+
+    /// Returns the kind of the object.
+    pub fn kind(&self) -> ObjectKind {
+        self.kind.into()
+    }
+
+    /// Returns the offset of the entry header from the start of the pack file.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the size of the object content, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// A sorted index of the objects contained in a pack file.
+#[derive(Clone, Debug)]
+pub struct PackIndex {
+    fanout: Box<[u32; FANOUT_LEN]>,
+    entries: Vec<IndexEntry>,
+}
+
+impl PackIndex {
+    /// Builds an index from the given entries, in any order.
+    ///
+    /// If several entries share the same ID, only the first one is kept.
+    pub fn new(mut entries: Vec<IndexEntry>) -> Self {
+        entries.sort_by_key(|e| e.id);
+        entries.dedup_by_key(|e| e.id);
+
+        let mut fanout = Box::new([0u32; FANOUT_LEN]);
+        for entry in &entries {
+            fanout[entry.id.as_bytes()[0] as usize] += 1;
+        }
+
+        for i in 1..FANOUT_LEN {
+            fanout[i] += fanout[i - 1];
+        }
+
+        PackIndex { fanout, entries }
+    }
+
+    /// Deserializes an index from `reader`.
+    ///
+    /// Returns `Err` if the stream is not a pack index, the entries are not sorted, or an I/O error
+    /// occurred.
+    pub fn read_from<R: Read>(mut reader: R) -> anyhow::Result<Self> {
+        let mut magic = [0u8; MAGIC_VALUE.len() + 1];
+        reader.read_exact(&mut magic)?;
+
+        match &magic[..] {
+            [m @ .., FORMAT_VERSION] if m == MAGIC_VALUE => {}
+            _ => return Err(anyhow!("magic value not found, not a store pack index")),
+        }
+
+        let mut fanout = Box::new([0u32; FANOUT_LEN]);
+        let mut buf = [0u8; 4];
+        for count in fanout.iter_mut() {
+            reader.read_exact(&mut buf)?;
+            *count = u32::from_be_bytes(buf);
+        }
+
+        let len = fanout[FANOUT_LEN - 1] as usize;
+        let mut entries = Vec::with_capacity(len);
+        let mut buf = [0u8; ENTRY_LEN];
+        for _ in 0..len {
+            reader.read_exact(&mut buf)?;
+            let id = ObjectId::from_bytes(buf[..ObjectId::LENGTH].try_into()?);
+            let kind = EntryKind::try_from(buf[ObjectId::LENGTH])?;
+            let offset = u64::from_be_bytes(buf[ObjectId::LENGTH + 1..][..8].try_into()?);
+            let size = u64::from_be_bytes(buf[ObjectId::LENGTH + 9..].try_into()?);
+            entries.push(IndexEntry::new(id, kind, offset, size));
+        }
+
+        let index = PackIndex::new(entries);
+        if index.fanout != fanout {
+            return Err(anyhow!(
+                "pack index is corrupt, fanout table does not match"
+            ));
+        }
+
+        Ok(index)
+    }
+
+    /// Serializes this index to `writer`.
+    ///
+    /// Returns `Err` if an I/O error occurred.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(MAGIC_VALUE)?;
+        writer.write_all(&[FORMAT_VERSION])?;
+
+        for count in self.fanout.iter() {
+            writer.write_all(&count.to_be_bytes())?;
+        }
+
+        for entry in &self.entries {
+            let mut buf = [0u8; ENTRY_LEN];
+            buf[..ObjectId::LENGTH].copy_from_slice(entry.id.as_bytes());
+            buf[ObjectId::LENGTH] = entry.kind as u8;
+            buf[ObjectId::LENGTH + 1..][..8].copy_from_slice(&entry.offset.to_be_bytes());
+            buf[ObjectId::LENGTH + 9..].copy_from_slice(&entry.size.to_be_bytes());
+            writer.write_all(&buf)?;
+        }
+
+        writer.flush()
+    }
+
+    /// Looks up the entry for the object `id`, if it is contained in the pack.
+    pub fn find(&self, id: &ObjectId) -> Option<&IndexEntry> {
+        let first = id.as_bytes()[0] as usize;
+        let start = match first {
+            0 => 0,
+            n => self.fanout[n - 1] as usize,
+        };
+        let end = self.fanout[first] as usize;
+
+        let bucket = &self.entries[start..end];
+        bucket
+            .binary_search_by(|e| e.id.cmp(id))
+            .ok()
+            .map(|i| &bucket[i])
+    }
+
+    /// Returns an iterator over all entries, sorted by ID.
+    pub fn iter(&self) -> impl Iterator<Item = &IndexEntry> + '_ {
+        self.entries.iter()
+    }
+
+    /// Returns the number of objects in the index.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the index contains no objects.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Blob, ContentAddressable};
+
+    #[test]
+    fn round_trip() {
+        let entries: Vec<_> = (0..100u8)
+            .map(|i| {
+                let (blob, _) = Blob::from_bytes(vec![i], false);
+                IndexEntry::new(blob.object_id(), EntryKind::Blob, i as u64 * 100, 1)
+            })
+            .collect();
+
+        let index = PackIndex::new(entries.clone());
+        let mut buffer = Vec::new();
+        index.write_to(&mut buffer).unwrap();
+        let parsed = PackIndex::read_from(&buffer[..]).unwrap();
+
+        assert_eq!(parsed.len(), 100);
+        for entry in &entries {
+            assert_eq!(parsed.find(&entry.id()), Some(entry));
+        }
+
+        let (absent, _) = Blob::from_bytes(b"absent".to_vec(), false);
+        assert_eq!(parsed.find(&absent.object_id()), None);
+    }
+}
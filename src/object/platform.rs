@@ -13,7 +13,7 @@ const HOST_ARCH: Arch = Arch::X86_64;
 #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
 compile_error!("unsupported system architecture");
 
-#[cfg(all(target_os = "macos"))]
+#[cfg(target_os = "macos")]
 const HOST_OS: Os = Os::Darwin;
 #[cfg(all(target_os = "linux", target_env = "gnu"))]
 const HOST_OS: Os = Os::Linux(Env::Gnu);
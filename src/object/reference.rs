@@ -131,6 +131,7 @@ pub struct ReferenceSink<W> {
     inner: W,
     refs: References,
     buf: Vec<u8>,
+    scan: bool,
 }
 
 impl<W: Write> ReferenceSink<W> {
@@ -140,6 +141,15 @@ impl<W: Write> ReferenceSink<W> {
             inner,
             refs: References::new(),
             buf: Vec::new(),
+            scan: true,
+        }
+    }
+
+    /// Creates a new `ReferenceSink<W>` which passes all bytes through to `inner` unscanned.
+    pub fn passthrough(inner: W) -> Self {
+        ReferenceSink {
+            scan: false,
+            ..ReferenceSink::new(inner)
         }
     }
 
@@ -152,6 +162,10 @@ impl<W: Write> ReferenceSink<W> {
 impl<W: Write> Write for ReferenceSink<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.inner.write(buf)?;
+        if !self.scan {
+            return Ok(len);
+        }
+
         self.buf.extend_from_slice(&buf[..len]);
 
         match many1_streaming(reference)(&self.buf) {
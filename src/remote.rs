@@ -0,0 +1,634 @@
+//! Accessing a store on another host over a bidirectional byte stream.
+//!
+//! A [`RemoteStore`] implements both [`Source`] and [`Destination`] by talking to a [`serve()`]
+//! loop running against a [`LocalStore`] on the far side. Any pair of `AsyncRead` and `AsyncWrite`
+//! halves can carry the connection, such as the stdin and stdout of a child process (e.g. an SSH
+//! session running `serve()` on the remote host) or a socket.
+//!
+//! # Specification
+//!
+//! Every message is sent as a length-prefixed frame containing a JSON document:
+//!
+//! ```text
+//!      Length          Body
+//! +--------------+  +--------+
+//! | (u32 BE)     |  | <JSON> |
+//! +--------------+  +--------+
+//! ```
+//!
+//! When a connection is opened, the serving side first sends a `Hello` message announcing its
+//! protocol version. From then on, the client sends one request at a time and waits for its
+//! response. Requests which fail on the serving side are answered with an `Error` message, and the
+//! connection remains usable afterwards.
+//!
+//! Pack files are carried as a series of data frames using the same length prefix, terminated by
+//! an empty frame:
+//!
+//! ```text
+//! +--------------+  +--------------+       +--------------+
+//! | Data frame   |  | Data frame   |  ...  | 0 (u32 BE)   |
+//! +--------------+  +--------------+       +--------------+
+//! ```
+//!
+//! A `SendPack` request is answered by a framed pack followed by a `Done` or `Error` message, and
+//! a `RecvPack` request is followed by a framed pack from the client, which the serving side then
+//! answers with a `Done` or `Error` message.
+//!
+//! If the connection is lost while a pack is being received, the serving side keeps the objects it
+//! verified so far. A `Checkpoint` request lists them, so that the transfer can be resumed over a
+//! new connection without sending them again.
+
+pub use self::protocol::PROTOCOL_VERSION;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Debug, Formatter};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use futures::lock::Mutex;
+use futures::{join, try_join};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use self::protocol::{Request, Response};
+use crate::closure::WireClosure;
+use crate::copy::{self, Delta, Destination, Source};
+use crate::pack;
+use crate::{Backend, Closure, LocalStore, ObjectId, ObjectKind, Objects, Signature};
+
+mod protocol;
+
+/// Capacity of the in-memory pipes used to stream pack files through the connection.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// A store on another host, reached over a bidirectional byte stream.
+///
+/// The other end of the stream must be running [`serve()`]. See the [module
+/// documentation](self) for a description of the protocol.
+pub struct RemoteStore<R, W> {
+    conn: Mutex<Connection<R, W>>,
+}
+
+impl<R, W> RemoteStore<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Opens a connection to a remote store over `reader` and `writer`.
+    ///
+    /// Returns `Err` if the serving side does not speak a compatible version of the protocol or an
+    /// I/O error occurred.
+    pub async fn connect(mut reader: R, writer: W) -> anyhow::Result<Self> {
+        match protocol::read_response(&mut reader).await? {
+            Response::Hello {
+                version: PROTOCOL_VERSION,
+            } => {}
+            Response::Hello { version } => {
+                return Err(anyhow!("unsupported remote protocol version: {}", version))
+            }
+            other => return Err(anyhow!("expected hello from remote store, got {:?}", other)),
+        }
+
+        Ok(RemoteStore {
+            conn: Mutex::new(Connection {
+                reader,
+                writer,
+                in_flight: false,
+            }),
+        })
+    }
+
+    /// Closes the connection, returning the underlying reader and writer.
+    pub fn into_inner(self) -> (R, W) {
+        let conn = self.conn.into_inner();
+        (conn.reader, conn.writer)
+    }
+}
+
+impl<R, W> Debug for RemoteStore<R, W> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("RemoteStore").finish()
+    }
+}
+
+#[async_trait(?Send)]
+impl<R, W> Source for RemoteStore<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    async fn find_missing<D>(&self, dst: &D, pkgs: BTreeSet<ObjectId>) -> anyhow::Result<Delta>
+    where
+        D: Destination + ?Sized,
+    {
+        // Fetch the full closure in one round trip, then prune it locally. The connection is not
+        // held while `dst` is queried, in case it happens to be another handle to this same store.
+        let request = Request::Closure { pkgs: pkgs.clone() };
+        let (closure, mut signatures) = match self.conn.lock().await.request(&request).await? {
+            Response::Closure {
+                closure,
+                signatures,
+            } => (Closure::from(closure), signatures),
+            other => return Err(unexpected(other)),
+        };
+
+        let graph = closure.graph();
+        let roots = graph
+            .keys()
+            .filter(|&&(id, kind, _)| kind == ObjectKind::Package && pkgs.contains(&id))
+            .copied()
+            .collect();
+
+        let (num_present, missing_ids) =
+            copy::find_missing_ids(dst, roots, |node| Ok(graph[node].iter().copied().collect()))
+                .await?;
+
+        signatures.retain(|id, _| missing_ids.contains(id));
+
+        Ok(Delta {
+            num_present,
+            missing: closure.subgraph(&missing_ids),
+            bases: Default::default(),
+            signatures,
+        })
+    }
+
+    async fn send_pack<W2>(&self, delta: &Delta, writer: &mut W2) -> anyhow::Result<()>
+    where
+        W2: AsyncWrite + Unpin,
+    {
+        let request = Request::SendPack {
+            missing: WireClosure::from(&delta.missing),
+            bases: delta.bases.clone(),
+        };
+
+        let mut conn = self.conn.lock().await;
+        conn.begin()?;
+        protocol::write_message(&mut conn.writer, &request).await?;
+        let written = protocol::read_data(&mut conn.reader, writer).await?;
+
+        match conn.finish().await? {
+            Response::Done => written.map(|_| ()).map_err(From::from),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<R, W> Destination for RemoteStore<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    async fn contains(&self, id: &ObjectId, kind: Option<ObjectKind>) -> anyhow::Result<bool> {
+        let request = Request::Contains { id: *id, kind };
+        match self.conn.lock().await.request(&request).await? {
+            Response::Contains { present } => Ok(present),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn contains_many(
+        &self,
+        objects: &[(ObjectId, Option<ObjectKind>)],
+    ) -> anyhow::Result<Vec<bool>> {
+        let request = Request::ContainsMany {
+            objects: objects.to_vec(),
+        };
+        match self.conn.lock().await.request(&request).await? {
+            Response::ContainsMany { present } => Ok(present),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn checkpoint(&self) -> anyhow::Result<BTreeSet<ObjectId>> {
+        match self.conn.lock().await.request(&Request::Checkpoint).await? {
+            Response::Checkpoint { received } => Ok(received),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn recv_pack<R2>(
+        &mut self,
+        reader: &mut R2,
+        signatures: &BTreeMap<ObjectId, BTreeSet<Signature>>,
+    ) -> anyhow::Result<()>
+    where
+        R2: AsyncRead + Unpin,
+    {
+        let conn = self.conn.get_mut();
+        conn.begin()?;
+        let request = Request::RecvPack {
+            signatures: signatures.clone(),
+        };
+        protocol::write_message(&mut conn.writer, &request).await?;
+
+        // Only copy a single pack file, since `reader` may stay open after the footer. Each end
+        // of the pipe is owned by one future, so that neither can block forever on the other. If
+        // the connection fails, copying stops right away, since nothing would drain the pipe.
+        let (mut pipe_reader, mut pipe_writer) = tokio::io::duplex(PIPE_CAPACITY);
+        let copy = async move { Ok(pack::copy_pack(reader, &mut pipe_writer).await) };
+        let conn_writer = &mut conn.writer;
+        let send = async move { protocol::write_data(&mut pipe_reader, conn_writer).await };
+
+        let (copied, _) = try_join!(copy, send)?;
+
+        let response = conn.finish().await;
+        copied?;
+        match response? {
+            Response::Done => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+struct Connection<R, W> {
+    reader: R,
+    writer: W,
+    /// Whether a request was sent whose response has not been read in full yet.
+    in_flight: bool,
+}
+
+impl<R, W> Connection<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    async fn request(&mut self, request: &Request) -> anyhow::Result<Response> {
+        self.begin()?;
+        protocol::write_message(&mut self.writer, request).await?;
+        self.finish().await
+    }
+
+    /// Marks the start of a request.
+    ///
+    /// Returns `Err` if an earlier request never read its response, because it failed partway or
+    /// its future was dropped (e.g. on a timeout). The rest of that exchange may still be in
+    /// flight, so the connection is out of sync and cannot be used anymore.
+    fn begin(&mut self) -> anyhow::Result<()> {
+        if self.in_flight {
+            return Err(anyhow!(
+                "connection to remote store was interrupted in the middle of a request"
+            ));
+        }
+
+        self.in_flight = true;
+        Ok(())
+    }
+
+    /// Reads the response which completes the current request.
+    ///
+    /// Returns `Err` if the serving side answered with an error, or the response could not be
+    /// read. Only the latter leaves the connection unusable.
+    async fn finish(&mut self) -> anyhow::Result<Response> {
+        let response = protocol::read_message::<_, Response>(&mut self.reader)
+            .await?
+            .ok_or_else(|| anyhow!("remote store closed the connection"))?;
+        self.in_flight = false;
+        response.into_result()
+    }
+}
+
+fn unexpected(response: Response) -> anyhow::Error {
+    anyhow!("unexpected response from remote store: {:?}", response)
+}
+
+/// Serves requests from a [`RemoteStore`] against `store`, until `reader` reaches EOF.
+///
+/// Requests which fail are reported back to the client, and do not stop the loop.
+///
+/// Returns `Err` if the connection was lost in the middle of a request, a malformed message was
+/// received, or an I/O error occurred.
+pub async fn serve<B, R, W>(
+    store: &mut LocalStore<B>,
+    mut reader: R,
+    mut writer: W,
+) -> anyhow::Result<()>
+where
+    B: Backend,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let hello = Response::Hello {
+        version: PROTOCOL_VERSION,
+    };
+    protocol::write_message(&mut writer, &hello).await?;
+
+    while let Some(request) = protocol::read_message(&mut reader).await? {
+        let result = match request {
+            Request::Contains { id, kind } => store
+                .contains(&id, kind)
+                .await
+                .map(|present| Response::Contains { present }),
+            Request::ContainsMany { objects } => store
+                .contains_many(&objects)
+                .await
+                .map(|present| Response::ContainsMany { present }),
+            Request::Closure { pkgs } => closure_response(store, pkgs),
+            Request::Checkpoint => store
+                .checkpoint()
+                .await
+                .map(|received| Response::Checkpoint { received }),
+            Request::SendPack { missing, bases } => {
+                let delta = Delta {
+                    num_present: 0,
+                    missing: missing.into(),
+                    bases,
+                    signatures: Default::default(),
+                };
+
+                let store = &*store;
+                let writer = &mut writer;
+                let (mut pipe_reader, mut pipe_writer) = tokio::io::duplex(PIPE_CAPACITY);
+                let send = async move { store.send_pack(&delta, &mut pipe_writer).await };
+                let copy = async move { protocol::write_data(&mut pipe_reader, writer).await };
+
+                let (sent, copied) = join!(send, copy);
+                copied?;
+                sent.map(|_| Response::Done)
+            }
+            Request::RecvPack { signatures } => {
+                let store = &mut *store;
+                let reader = &mut reader;
+                let (mut pipe_reader, mut pipe_writer) = tokio::io::duplex(PIPE_CAPACITY);
+                let recv = async move { store.recv_pack(&mut pipe_reader, &signatures).await };
+                let copy = async move { protocol::read_data(reader, &mut pipe_writer).await };
+
+                // If `recv` stops early, dropping its end of the pipe makes the remaining writes
+                // fail. Those frames are still drained, and the error from `recv` is reported.
+                let (received, copied) = join!(recv, copy);
+                let _ = copied?;
+                received.map(|_| Response::Done)
+            }
+        };
+
+        let response = result.unwrap_or_else(|e| Response::Error {
+            message: format!("{:#}", e),
+        });
+        protocol::write_message(&mut writer, &response).await?;
+    }
+
+    Ok(())
+}
+
+/// Computes the closure of `pkgs` along with the signatures of every package in it.
+fn closure_response<B: Backend>(
+    store: &LocalStore<B>,
+    pkgs: BTreeSet<ObjectId>,
+) -> anyhow::Result<Response> {
+    let closure = store.compute_closure(pkgs)?;
+
+    let mut signatures = BTreeMap::new();
+    for &(id, kind, _) in closure.iter() {
+        if kind == ObjectKind::Package {
+            let sigs = store.signatures(&id)?;
+            if !sigs.is_empty() {
+                signatures.insert(id, sigs);
+            }
+        }
+    }
+
+    Ok(Response::Closure {
+        closure: WireClosure::from(&closure),
+        signatures,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::ready;
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    use super::*;
+    use crate::test_support::{insert_package, insert_package_of_blobs};
+    use crate::{copy_closure, Blob, ContentAddressable, Memory, SecretKey, TrustPolicy};
+
+    type TestRemote = RemoteStore<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>;
+
+    fn trusting(key: &SecretKey) -> TrustPolicy {
+        let mut policy = TrustPolicy::default();
+        policy.trusted_keys.insert(key.public_key());
+        policy
+    }
+
+    async fn connect(client_io: DuplexStream) -> TestRemote {
+        let (reader, writer) = tokio::io::split(client_io);
+        RemoteStore::connect(reader, writer).await.unwrap()
+    }
+
+    async fn serve_duplex(store: &mut LocalStore<Memory>, server_io: DuplexStream) {
+        let (reader, writer) = tokio::io::split(server_io);
+        serve(store, reader, writer)
+            .await
+            .expect("serve loop failed");
+    }
+
+    #[tokio::test]
+    async fn copies_closure_to_remote() {
+        let mut src = LocalStore::in_memory();
+        let foo = insert_package(&mut src, "foo", &[]);
+        let bar = insert_package(&mut src, "bar", &[foo]);
+
+        let key = SecretKey::generate("src-1").unwrap();
+        let sig = src.sign_package(bar, &key).unwrap();
+
+        let mut server = LocalStore::in_memory();
+        insert_package(&mut server, "foo", &[]);
+        server.set_trust_policy(trusting(&key)).unwrap();
+
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let pkgs: BTreeSet<_> = std::iter::once(bar).collect();
+        let copying = async {
+            let mut remote = connect(client_io).await;
+            copy_closure(&src, &mut remote, pkgs, |_| {}).await
+        };
+
+        let (delta, ()) = join!(copying, serve_duplex(&mut server, server_io));
+        let delta = delta.unwrap();
+        assert_eq!(delta.num_present, 1);
+        assert_eq!(delta.missing.num_objects(), 3);
+
+        let pkg = server.get_package(bar).unwrap();
+        assert!(server.installed_package(&pkg.install_name()).is_some());
+        assert_eq!(
+            server.signatures(&bar).unwrap(),
+            std::iter::once(sig).collect()
+        );
+    }
+
+    #[tokio::test]
+    async fn copies_closure_from_remote() {
+        let mut server = LocalStore::in_memory();
+        let foo = insert_package(&mut server, "foo", &[]);
+        let bar = insert_package(&mut server, "bar", &[foo]);
+
+        let key = SecretKey::generate("server-1").unwrap();
+        let sig = server.sign_package(bar, &key).unwrap();
+
+        let mut dst = LocalStore::in_memory();
+        insert_package(&mut dst, "foo", &[]);
+        dst.set_trust_policy(trusting(&key)).unwrap();
+
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let pkgs: BTreeSet<_> = std::iter::once(bar).collect();
+        let copying = async {
+            let remote = connect(client_io).await;
+            copy_closure(&remote, &mut dst, pkgs.clone(), |_| {}).await
+        };
+
+        let (delta, ()) = join!(copying, serve_duplex(&mut server, server_io));
+        let delta = delta.unwrap();
+        assert_eq!(delta.num_present, 1);
+        assert_eq!(delta.missing.num_objects(), 3);
+
+        let closure = dst.compute_closure(pkgs).unwrap();
+        assert_eq!(closure.num_objects(), 6);
+        let pkg = dst.get_package(bar).unwrap();
+        assert!(dst.installed_package(&pkg.install_name()).is_some());
+        assert_eq!(
+            dst.signatures(&bar).unwrap(),
+            std::iter::once(sig).collect()
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_errors_and_keeps_serving() {
+        let mut server = LocalStore::in_memory();
+        let foo = insert_package(&mut server, "foo", &[]);
+        let (absent, _) = Blob::from_bytes(b"absent".to_vec(), false);
+        let absent = absent.object_id();
+
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let requesting = async {
+            let mut remote = connect(client_io).await;
+            let dst = LocalStore::in_memory();
+            let missing = remote
+                .find_missing(&dst, std::iter::once(absent).collect())
+                .await;
+
+            let mut garbage = &b"not a pack file"[..];
+            let received = remote.recv_pack(&mut garbage, &BTreeMap::new()).await;
+
+            let present = remote.contains(&foo, Some(ObjectKind::Package)).await;
+            (missing, received, present)
+        };
+
+        let ((missing, received, present), ()) =
+            join!(requesting, serve_duplex(&mut server, server_io));
+
+        let error = missing.unwrap_err().to_string();
+        assert!(error.starts_with("remote store error"), "{}", error);
+        assert!(received.is_err());
+        assert!(present.unwrap());
+    }
+
+    #[tokio::test]
+    async fn refuses_requests_after_an_interrupted_one() {
+        let (client_io, mut server_io) = tokio::io::duplex(4096);
+        let hello = Response::Hello {
+            version: PROTOCOL_VERSION,
+        };
+        protocol::write_message(&mut server_io, &hello)
+            .await
+            .unwrap();
+        let remote = connect(client_io).await;
+
+        // The serving side never answers, so the request is dropped halfway on a timeout.
+        let id = ObjectId::zero();
+        let wait = std::time::Duration::from_millis(10);
+        assert!(tokio::time::timeout(wait, remote.contains(&id, None))
+            .await
+            .is_err());
+
+        let error = remote.contains(&id, None).await.unwrap_err().to_string();
+        assert!(error.contains("interrupted"), "{}", error);
+    }
+
+    /// Writer which fails once `remaining` bytes have been written, like a dropped connection.
+    struct CutWriter<W> {
+        inner: W,
+        remaining: usize,
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for CutWriter<W> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.remaining == 0 {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+
+            let len = buf.len().min(self.remaining);
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..len]))?;
+            self.remaining -= written;
+            Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_interrupted_copy() {
+        let mut src = LocalStore::in_memory();
+        let big = insert_package_of_blobs(&mut src, "big", 8);
+
+        let key = SecretKey::generate("src-1").unwrap();
+        src.sign_package(big, &key).unwrap();
+        let mut server = LocalStore::in_memory();
+        server.set_trust_policy(trusting(&key)).unwrap();
+        let pkgs: BTreeSet<_> = std::iter::once(big).collect();
+
+        // Drop the connection after roughly half of the pack has been sent.
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let copying = async {
+            let (reader, writer) = tokio::io::split(client_io);
+            let writer = CutWriter {
+                inner: writer,
+                remaining: 256 * 1024,
+            };
+            let mut remote = RemoteStore::connect(reader, writer).await.unwrap();
+            copy_closure(&src, &mut remote, pkgs.clone(), |_| {}).await
+        };
+        let serving = async {
+            let (reader, writer) = tokio::io::split(server_io);
+            serve(&mut server, reader, writer).await
+        };
+
+        let (copied, served) = join!(copying, serving);
+        assert!(copied.is_err());
+        assert!(served.is_err());
+        assert!(!server.contains_object(&big, None).unwrap());
+
+        let received = server.checkpoint().await.unwrap();
+        assert!(!received.is_empty());
+        assert!(received.len() < 10);
+
+        // Only the objects which did not make it the first time are sent again.
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let copying = async {
+            let mut remote = connect(client_io).await;
+            copy_closure(&src, &mut remote, pkgs.clone(), |_| {}).await
+        };
+
+        let (delta, ()) = join!(copying, serve_duplex(&mut server, server_io));
+        let delta = delta.unwrap();
+        assert_eq!(delta.missing.num_objects(), 10 - received.len());
+
+        let pkg = server.get_package(big).unwrap();
+        assert!(server.installed_package(&pkg.install_name()).is_some());
+        assert!(server.checkpoint().await.unwrap().is_empty());
+        assert_eq!(server.compute_closure(pkgs).unwrap().num_objects(), 10);
+    }
+}
@@ -0,0 +1,212 @@
+//! Messages and framing used by the remote store protocol.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::io;
+
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::closure::WireClosure;
+use crate::{ObjectId, ObjectKind, Signature};
+
+/// Version of the protocol announced by [`serve()`](super::serve) when a connection is opened.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// Largest message frame that will be accepted, in bytes.
+const MAX_MESSAGE_LEN: usize = 256 * 1024 * 1024;
+/// Size of the data frames used to carry pack files.
+const DATA_FRAME_LEN: usize = 64 * 1024;
+
+/// A request sent by a [`RemoteStore`](super::RemoteStore) to the serving side.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum Request {
+    Contains {
+        id: ObjectId,
+        kind: Option<ObjectKind>,
+    },
+    ContainsMany {
+        objects: Vec<(ObjectId, Option<ObjectKind>)>,
+    },
+    Closure {
+        pkgs: BTreeSet<ObjectId>,
+    },
+    SendPack {
+        missing: WireClosure,
+        bases: BTreeMap<ObjectId, ObjectId>,
+    },
+    RecvPack {
+        signatures: BTreeMap<ObjectId, BTreeSet<Signature>>,
+    },
+    Checkpoint,
+}
+
+/// A response sent by the serving side.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum Response {
+    Hello {
+        version: u8,
+    },
+    Contains {
+        present: bool,
+    },
+    ContainsMany {
+        present: Vec<bool>,
+    },
+    Closure {
+        closure: WireClosure,
+        signatures: BTreeMap<ObjectId, BTreeSet<Signature>>,
+    },
+    Checkpoint {
+        received: BTreeSet<ObjectId>,
+    },
+    Done,
+    Error {
+        message: String,
+    },
+}
+
+impl Response {
+    /// Converts an `Error` response into `Err`, passing every other response through.
+    pub fn into_result(self) -> anyhow::Result<Self> {
+        match self {
+            Response::Error { message } => Err(anyhow!("remote store error: {}", message)),
+            other => Ok(other),
+        }
+    }
+}
+
+/// Serializes `msg` into a single frame and writes it to `writer`.
+///
+/// Returns `Err` if a serialization or I/O error occurred.
+pub async fn write_message<W, T>(writer: &mut W, msg: &T) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let body = serde_json::to_vec(msg)?;
+    write_frame(writer, &body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads a single frame from `reader` and deserializes it.
+///
+/// Returns `Ok(None)` if `reader` reached EOF cleanly before the frame began.
+///
+/// Returns `Err` if the frame is too large, it could not be deserialized, or an I/O error
+/// occurred.
+pub async fn read_message<R, T>(reader: &mut R) -> anyhow::Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len = [0u8; 4];
+    if reader.read(&mut len[..1]).await? == 0 {
+        return Ok(None);
+    }
+
+    reader.read_exact(&mut len[1..]).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(anyhow!("message frame of {} bytes is too large", len));
+    }
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// Reads the next response frame from `reader`, treating EOF as an error.
+///
+/// Returns `Err` if the response could not be read or it was an `Error` response.
+pub async fn read_response<R>(reader: &mut R) -> anyhow::Result<Response>
+where
+    R: AsyncRead + Unpin,
+{
+    read_message(reader)
+        .await?
+        .ok_or_else(|| anyhow!("remote store closed the connection"))
+        .and_then(Response::into_result)
+}
+
+/// Copies `reader` to `writer` as a series of data frames, followed by an empty frame.
+///
+/// Returns the number of bytes copied, or `Err` if an I/O error occurred.
+pub async fn write_data<R, W>(reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; DATA_FRAME_LEN];
+    let mut copied = 0;
+
+    loop {
+        let len = reader.read(&mut buf).await?;
+        write_frame(writer, &buf[..len]).await?;
+        copied += len as u64;
+
+        if len == 0 {
+            writer.flush().await?;
+            return Ok(copied);
+        }
+    }
+}
+
+/// Copies data frames from `reader` to `writer`, up to and including the terminating empty frame.
+///
+/// If writing to `writer` fails, the remaining frames are still read and discarded, so that the
+/// connection is left at the start of the next message.
+///
+/// Returns `Err` if the frames could not be read. Otherwise, returns the outcome of writing to
+/// `writer`, i.e. the number of bytes copied or the first write error.
+pub async fn read_data<R, W>(reader: &mut R, writer: &mut W) -> io::Result<io::Result<u64>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; DATA_FRAME_LEN];
+    let mut outcome = Ok(0);
+
+    loop {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len).await?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > DATA_FRAME_LEN {
+            let msg = format!("data frame of {} bytes is too large", len);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        } else if len == 0 {
+            break;
+        }
+
+        reader.read_exact(&mut buf[..len]).await?;
+        if let Ok(copied) = outcome.as_mut() {
+            match writer.write_all(&buf[..len]).await {
+                Ok(()) => *copied += len as u64,
+                Err(e) => outcome = Err(e),
+            }
+        }
+    }
+
+    if outcome.is_ok() {
+        if let Err(e) = writer.flush().await {
+            outcome = Err(e);
+        }
+    }
+
+    Ok(outcome)
+}
+
+async fn write_frame<W>(writer: &mut W, body: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let len = u32::try_from(body.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame is too large"))?;
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(body).await
+}
@@ -0,0 +1,363 @@
+//! Ed25519 signatures on packages.
+//!
+//! A package is signed by signing its `ObjectId`. Since the ID of a package covers its whole
+//! Merkle tree, including the IDs of every package it references, one signature vouches for the
+//! package contents without having to sign each object individually. Each signature records the
+//! name of the key which produced it, so that the matching public key can be found quickly.
+//!
+//! Keys and signatures are written as `<key name>:<hex bytes>`, much like Nix signing keys.
+
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+use ed25519_dalek::{Keypair, Signer};
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::ObjectId;
+
+/// A private key used to sign packages.
+pub struct SecretKey {
+    name: SmolStr,
+    keypair: Keypair,
+}
+
+impl SecretKey {
+    /// Generates a new random key called `name`.
+    ///
+    /// Returns `Err` if `name` is not a valid key name.
+    pub fn generate(name: &str) -> anyhow::Result<Self> {
+        let name = parse_key_name(name)?;
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng);
+        Ok(SecretKey { name, keypair })
+    }
+
+    /// Returns the name of the key.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the public half of the key, which can be used to verify its signatures.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            name: self.name.clone(),
+            key: self.keypair.public,
+        }
+    }
+
+    /// Signs the package `pkg`.
+    pub fn sign(&self, pkg: &ObjectId) -> Signature {
+        Signature {
+            key_name: self.name.clone(),
+            bytes: self.keypair.sign(pkg.as_bytes()).to_bytes(),
+        }
+    }
+}
+
+impl Debug for SecretKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        // Never print the secret half of the key.
+        f.debug_struct("SecretKey")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl Display for SecretKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            self.name,
+            hex::encode(self.keypair.secret.as_bytes())
+        )
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, bytes) = split_named(s)?;
+        let secret = ed25519_dalek::SecretKey::from_bytes(&bytes).context("invalid secret key")?;
+        Ok(SecretKey {
+            name,
+            keypair: Keypair {
+                public: (&secret).into(),
+                secret,
+            },
+        })
+    }
+}
+
+/// A public key used to verify package signatures.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PublicKey {
+    name: SmolStr,
+    key: ed25519_dalek::PublicKey,
+}
+
+impl PublicKey {
+    /// Returns the name of the key.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns `true` if `sig` is a valid signature of the package `pkg` made with this key.
+    pub fn verify(&self, pkg: &ObjectId, sig: &Signature) -> bool {
+        match ed25519_dalek::Signature::from_bytes(&sig.bytes) {
+            Ok(signature) if sig.key_name == self.name => {
+                self.key.verify_strict(pkg.as_bytes(), &signature).is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.name, hex::encode(self.key.as_bytes()))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, bytes) = split_named(s)?;
+        let key = ed25519_dalek::PublicKey::from_bytes(&bytes).context("invalid public key")?;
+        Ok(PublicKey { name, key })
+    }
+}
+
+impl PartialOrd for PublicKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PublicKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (&self.name, self.key.as_bytes()).cmp(&(&other.name, other.key.as_bytes()))
+    }
+}
+
+/// A detached signature of a package, made by a [`SecretKey`].
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Signature {
+    key_name: SmolStr,
+    bytes: [u8; ed25519_dalek::SIGNATURE_LENGTH],
+}
+
+impl Signature {
+    /// Returns the name of the key which made this signature.
+    pub fn key_name(&self) -> &str {
+        &self.key_name
+    }
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.key_name, hex::encode(&self.bytes[..]))
+    }
+}
+
+impl FromStr for Signature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key_name, bytes) = split_named(s)?;
+        let bytes = <[u8; ed25519_dalek::SIGNATURE_LENGTH]>::try_from(&bytes[..])
+            .map_err(|_| anyhow!("invalid signature length: {} bytes", bytes.len()))?;
+        Ok(Signature { key_name, bytes })
+    }
+}
+
+/// Policy deciding which packages a store accepts when they are received from another store.
+///
+/// By default, no keys are trusted and unsigned packages are refused, so nothing can be received
+/// until at least one key is trusted.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TrustPolicy {
+    /// Keys trusted to sign packages.
+    #[serde(default)]
+    pub trusted_keys: BTreeSet<PublicKey>,
+    /// Whether to accept packages without a valid signature from any trusted key.
+    #[serde(default)]
+    pub accept_unsigned: bool,
+}
+
+impl TrustPolicy {
+    /// Returns the signatures in `sigs` which are valid for `pkg` and made by a trusted key.
+    pub fn trusted_signatures(
+        &self,
+        pkg: &ObjectId,
+        sigs: &BTreeSet<Signature>,
+    ) -> BTreeSet<Signature> {
+        sigs.iter()
+            .filter(|sig| self.trusted_keys.iter().any(|key| key.verify(pkg, sig)))
+            .cloned()
+            .collect()
+    }
+
+    /// Checks whether the package `pkg` may be received, given its signatures `sigs`.
+    ///
+    /// Returns the signatures which were verified by a trusted key, or `Err` if there are none and
+    /// unsigned packages are not accepted.
+    pub fn check(
+        &self,
+        pkg: &ObjectId,
+        sigs: &BTreeSet<Signature>,
+    ) -> anyhow::Result<BTreeSet<Signature>> {
+        let trusted = self.trusted_signatures(pkg, sigs);
+        if trusted.is_empty() && !self.accept_unsigned {
+            let reason = if sigs.is_empty() {
+                "is unsigned"
+            } else {
+                "is not signed by a trusted key"
+            };
+            return Err(anyhow!("refusing package {}, which {}", pkg, reason));
+        }
+
+        Ok(trusted)
+    }
+}
+
+macro_rules! impl_serde_via_str {
+    ($($ty:ty),*) => {
+        $(
+            impl<'de> Deserialize<'de> for $ty {
+                fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+                where
+                    D: Deserializer<'de>,
+                {
+                    let s = String::deserialize(deserializer)?;
+                    s.parse().map_err(de::Error::custom)
+                }
+            }
+
+            impl Serialize for $ty {
+                fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+                where
+                    S: Serializer,
+                {
+                    serializer.collect_str(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_serde_via_str!(PublicKey, Signature);
+
+fn parse_key_name(name: &str) -> anyhow::Result<SmolStr> {
+    let is_valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+
+    if is_valid {
+        Ok(SmolStr::new(name))
+    } else {
+        Err(anyhow!("invalid key name {:?}", name))
+    }
+}
+
+/// Splits a string of the form `<key name>:<hex bytes>`.
+fn split_named(s: &str) -> anyhow::Result<(SmolStr, Vec<u8>)> {
+    let mut parts = s.splitn(2, ':');
+    let name = parts.next().unwrap_or_default();
+    let bytes = parts
+        .next()
+        .ok_or_else(|| anyhow!("expected `<key name>:<hex>`, found {:?}", s))?;
+
+    let name = parse_key_name(name)?;
+    let bytes = hex::decode(bytes).context("expected hex-encoded bytes")?;
+    Ok((name, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::insert_package;
+    use crate::{copy_closure, LocalStore, ObjectKind, Objects};
+
+    #[test]
+    fn signs_and_verifies_packages() {
+        let key = SecretKey::generate("cache.example.org-1").unwrap();
+        let other = SecretKey::generate("cache.example.org-1").unwrap();
+        let pkg = crate::Hasher::new_package().update(b"foo").finish();
+        let not_pkg = crate::Hasher::new_package().update(b"bar").finish();
+
+        let sig = key.sign(&pkg);
+        assert!(key.public_key().verify(&pkg, &sig));
+        assert!(!key.public_key().verify(&not_pkg, &sig));
+        assert!(!other.public_key().verify(&pkg, &sig));
+
+        // Keys and signatures round-trip through their string form.
+        let parsed: SecretKey = key.to_string().parse().unwrap();
+        assert_eq!(parsed.public_key(), key.public_key());
+        assert_eq!(
+            key.public_key().to_string().parse::<PublicKey>().unwrap(),
+            key.public_key()
+        );
+        assert_eq!(sig.to_string().parse::<Signature>().unwrap(), sig);
+        assert!(format!("{:?}", key)
+            .find(&hex::encode(key.keypair.secret.as_bytes()))
+            .is_none());
+    }
+
+    #[test]
+    fn trust_policy_refuses_unsigned_and_untrusted() {
+        let trusted = SecretKey::generate("trusted").unwrap();
+        let untrusted = SecretKey::generate("untrusted").unwrap();
+        let pkg = crate::Hasher::new_package().update(b"foo").finish();
+
+        let mut policy = TrustPolicy::default();
+        policy.trusted_keys.insert(trusted.public_key());
+
+        assert!(policy.check(&pkg, &BTreeSet::new()).is_err());
+        let sigs = std::iter::once(untrusted.sign(&pkg)).collect();
+        assert!(policy.check(&pkg, &sigs).is_err());
+
+        let sigs: BTreeSet<_> = vec![trusted.sign(&pkg), untrusted.sign(&pkg)]
+            .into_iter()
+            .collect();
+        let verified = policy.check(&pkg, &sigs).unwrap();
+        assert_eq!(verified, std::iter::once(trusted.sign(&pkg)).collect());
+
+        policy.accept_unsigned = true;
+        assert!(policy.check(&pkg, &BTreeSet::new()).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_unsigned_and_untrusted_packages() {
+        let mut src = LocalStore::in_memory();
+        let foo = insert_package(&mut src, "foo", &[]);
+        let pkgs: BTreeSet<_> = std::iter::once(foo).collect();
+
+        let mut dst = LocalStore::in_memory();
+        let error = copy_closure(&src, &mut dst, pkgs.clone(), |_| {})
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("unsigned"), "{}", error);
+
+        let untrusted = SecretKey::generate("untrusted").unwrap();
+        src.sign_package(foo, &untrusted).unwrap();
+        let error = copy_closure(&src, &mut dst, pkgs, |_| {})
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("trusted key"), "{}", error);
+
+        let pkg = src.get_package(foo).unwrap();
+        assert!(!dst
+            .contains_object(&foo, Some(ObjectKind::Package))
+            .unwrap());
+        assert!(dst.installed_package(&pkg.install_name()).is_none());
+    }
+}
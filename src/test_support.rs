@@ -0,0 +1,130 @@
+//! Fixtures shared by the unit tests of this crate.
+
+use std::io::Write;
+
+use crate::{
+    Backend, Blob, Entry, LocalStore, Object, ObjectId, Objects, Package, Platform, Spec, Tree,
+};
+
+/// Inserts a blob containing `content` into `store` and returns a tree entry pointing to it.
+pub(crate) fn insert_blob<B: Backend>(
+    store: &mut LocalStore<B>,
+    content: &[u8],
+    is_executable: bool,
+) -> Entry {
+    let (blob, _) = Blob::from_bytes(content.to_vec(), is_executable);
+    let id = store.insert_object(Object::Blob(blob)).unwrap();
+    Entry::Blob { id }
+}
+
+/// Inserts a tree containing `entries` into `store` and returns its ID.
+pub(crate) fn insert_tree<B, I, S>(store: &mut LocalStore<B>, entries: I) -> ObjectId
+where
+    B: Backend,
+    I: IntoIterator<Item = (S, Entry)>,
+    S: Into<String>,
+{
+    let entries = entries
+        .into_iter()
+        .map(|(name, entry)| (name.into(), entry))
+        .collect();
+    store.insert_object(Object::Tree(Tree { entries })).unwrap()
+}
+
+/// Inserts a package called `name` with the contents `tree`, which references the packages `deps`.
+pub(crate) fn insert_package_with<B: Backend>(
+    store: &mut LocalStore<B>,
+    name: &str,
+    deps: &[ObjectId],
+    tree: ObjectId,
+) -> ObjectId {
+    let pkg = Package {
+        name: name.parse().unwrap(),
+        system: Platform::host(),
+        references: deps.iter().copied().collect(),
+        self_references: Default::default(),
+        tree,
+    };
+
+    store.insert_object(Object::Package(pkg)).unwrap()
+}
+
+/// Inserts a package called `name` which references the packages `deps`.
+///
+/// The package contains a single file, `<name>.txt`, which holds its name.
+pub(crate) fn insert_package<B: Backend>(
+    store: &mut LocalStore<B>,
+    name: &str,
+    deps: &[ObjectId],
+) -> ObjectId {
+    let file = insert_blob(store, name.as_bytes(), false);
+    let tree = insert_tree(store, std::iter::once((format!("{}.txt", name), file)));
+    insert_package_with(store, name, deps, tree)
+}
+
+/// Inserts a package called `name` with no references, containing a single file, `data.bin`.
+///
+/// The file holds `data` and is split into chunks if it is large enough.
+pub(crate) fn insert_large_package<B: Backend>(
+    store: &mut LocalStore<B>,
+    name: &str,
+    data: &[u8],
+) -> ObjectId {
+    let mut writer = Blob::file_from_writer(false);
+    writer.write_all(data).unwrap();
+    let (file, _) = writer.finish_file();
+
+    let entry = store.insert_file(file).unwrap();
+    let tree = insert_tree(store, std::iter::once(("data.bin", entry)));
+    insert_package_with(store, name, &[], tree)
+}
+
+/// Inserts a package called `name` with no references, containing `num_files` files of
+/// pseudo-random data, 64 KiB each.
+pub(crate) fn insert_package_of_blobs<B: Backend>(
+    store: &mut LocalStore<B>,
+    name: &str,
+    num_files: u8,
+) -> ObjectId {
+    let entries: Vec<_> = (0..num_files)
+        .map(|i| {
+            let data = random_bytes(&[i], 64 * 1024);
+            (format!("{}.bin", i), insert_blob(store, &data, false))
+        })
+        .collect();
+
+    let tree = insert_tree(store, entries);
+    insert_package_with(store, name, &[], tree)
+}
+
+/// Inserts a spec for version 1.0.0 of `name`, which is built by running the script `builder`.
+pub(crate) fn insert_spec<B: Backend>(
+    store: &mut LocalStore<B>,
+    name: &str,
+    deps: &[ObjectId],
+    build_deps: &[ObjectId],
+    builder: &str,
+) -> ObjectId {
+    let spec = Spec {
+        name: name.parse().unwrap(),
+        version: semver::Version::new(1, 0, 0),
+        description: None,
+        license: None,
+        target: None,
+        dependencies: deps.iter().copied().collect(),
+        build_dependencies: build_deps.iter().copied().collect(),
+        builder: builder.into(),
+    };
+
+    store.insert_object(Object::Spec(spec)).unwrap()
+}
+
+/// Returns `len` bytes of pseudo-random data derived from `seed`.
+pub(crate) fn random_bytes(seed: &[u8], len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    blake3::Hasher::new()
+        .update(seed)
+        .finalize_xof()
+        .fill(&mut data);
+    data
+}
@@ -1,9 +1,11 @@
 //! Common utilities for working with I/O.
 
 use std::fs::File;
-use std::io::{self, Cursor, Read, Write};
+use std::io::{self, BufWriter, Cursor, Read, Write};
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use memmap::{Mmap, MmapOptions};
 
@@ -27,6 +29,97 @@ pub fn copy_wide<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Resul
     }
 }
 
+/// A guard which aborts the readers it creates once it is dropped.
+///
+/// This is useful for stopping a blocking task early when the future awaiting it is dropped, such
+/// as when a copy is cancelled, rather than leaving it to run to completion in the background.
+#[derive(Debug, Default)]
+pub struct AbortGuard(Arc<AtomicBool>);
+
+impl AbortGuard {
+    /// Wraps `inner` in a reader which fails once this guard is dropped.
+    pub fn reader<R: Read>(&self, inner: R) -> AbortableReader<R> {
+        AbortableReader {
+            inner,
+            aborted: self.0.clone(),
+        }
+    }
+}
+
+impl Drop for AbortGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A reader which fails once its [`AbortGuard`] is dropped.
+#[derive(Debug)]
+pub struct AbortableReader<R> {
+    inner: R,
+    aborted: Arc<AtomicBool>,
+}
+
+impl<R: Read> Read for AbortableReader<R> {
+    // `io::Error::other()` would require Rust 1.74.
+    #[allow(clippy::io_other_error)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.aborted.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "operation was aborted",
+            ));
+        }
+
+        self.inner.read(buf)
+    }
+}
+
+/// A writer which fails as soon as more than `limit` bytes are written to it.
+///
+/// This is the counterpart of `Read::take()` for decoders which push their output into a writer,
+/// such as the zstd stream decoders, so that a small compressed input cannot expand without bound.
+#[derive(Debug)]
+pub struct LimitedWriter<W> {
+    inner: W,
+    limit: u64,
+    remaining: u64,
+}
+
+impl<W: Write> LimitedWriter<W> {
+    /// Wraps `inner` in a writer which accepts at most `limit` bytes.
+    pub fn new(inner: W, limit: u64) -> Self {
+        LimitedWriter {
+            inner,
+            limit,
+            remaining: limit,
+        }
+    }
+
+    /// Returns the wrapped writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("output exceeds the limit of {} bytes", self.limit),
+            ));
+        }
+
+        let written = self.inner.write(buf)?;
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 /// Selects the most efficient strategy to open a file, optimized for large sequential reads.
 pub fn open_large_read<T, F1, F2, F3>(path: &Path, inline: F1, mmap: F2, io: F3) -> io::Result<T>
 where
@@ -43,7 +136,7 @@ where
         let mut buf = Vec::with_capacity(metadata.len() as usize);
         file.read_to_end(&mut buf)?;
         inline(Cursor::new(buf), is_executable)
-    } else if metadata.len() <= isize::max_value() as u64 {
+    } else if metadata.len() <= isize::MAX as u64 {
         // Prefer memory-mapping files wherever possible for performance.
         let handle = unsafe { MmapOptions::new().len(metadata.len() as usize).map(&file)? };
         mmap(Cursor::new(handle), is_executable)
@@ -53,6 +146,36 @@ where
     }
 }
 
+/// Atomically writes `value` as JSON to the file `p`, replacing it if it already exists.
+///
+/// The parent directory of `p` is created first, if necessary.
+pub fn persist_json<T: serde::Serialize>(p: &Path, value: &T, mode: u32) -> anyhow::Result<()> {
+    persist_with(p, mode, |writer| Ok(serde_json::to_writer(writer, value)?))
+}
+
+/// Atomically writes the file `p` with `write`, replacing it if it already exists.
+///
+/// The parent directory of `p` is created first, if necessary.
+pub fn persist_with<F>(p: &Path, mode: u32, write: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut BufWriter<&mut File>) -> anyhow::Result<()>,
+{
+    use std::os::unix::fs::PermissionsExt;
+
+    let parent_dir = p.parent().expect("file path must have parent dir");
+    std::fs::create_dir_all(parent_dir)?;
+
+    let mut temp = tempfile::NamedTempFile::new_in(parent_dir)?;
+    let mut writer = BufWriter::new(temp.as_file_mut());
+    write(&mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    std::fs::set_permissions(temp.path(), std::fs::Permissions::from_mode(mode))?;
+    temp.persist(p)?;
+
+    Ok(())
+}
+
 /// Normalizes file permissons for `p` and sets all timestamps to January 1st, 1970.
 pub fn normalize_perms(p: &Path, mode: u32) -> io::Result<()> {
     use std::os::unix::fs::PermissionsExt;